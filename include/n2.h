@@ -0,0 +1,75 @@
+/* The C API of n2, for embedding it as a build engine.  Build n2 as a
+ * shared library with the capi feature; see doc/development.md. */
+
+#ifndef N2_H
+#define N2_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct n2_session n2_session;
+
+/* Why the last call that failed on this thread did, valid until the next
+ * call that fails.  A call that panics inside n2 fails, returning NULL, 0 or
+ * -3 as it can. */
+const char *n2_last_error(void);
+
+/* Load build files, count of them, or build.ninja if count is 0.  Paths are
+ * relative to the current directory.  Returns NULL on failure. */
+n2_session *n2_open(const char *const *build_files, size_t count);
+void n2_close(n2_session *session);
+
+/* How many tasks may run at once, by default the number of cores. */
+void n2_set_parallelism(n2_session *session, size_t parallelism);
+/* How many failures a build keeps going until, 0 for any number; by default
+ * 1. */
+void n2_set_keep_going(n2_session *session, size_t failures);
+
+/* The files that builds produce, in sorted order, as they were when loaded.
+ * Names are valid until the session is closed. */
+size_t n2_target_count(const n2_session *session);
+const char *n2_target_name(const n2_session *session, size_t index);
+
+enum {
+  N2_TASK_STARTED = 0,
+  N2_TASK_OUTPUT = 1,
+  N2_TASK_FINISHED = 2,
+  N2_LOG = 3,
+};
+
+/* Something that happened during a build, valid during the callback. */
+struct n2_event {
+  int kind;
+  /* The build the event is about, other than for N2_LOG. */
+  size_t id;
+  /* The build's description or command line, for N2_TASK_STARTED and
+   * N2_TASK_FINISHED. */
+  const char *description;
+  /* The last line of output so far for N2_TASK_OUTPUT, all of the output for
+   * N2_TASK_FINISHED, or the message for N2_LOG. */
+  const char *text;
+  /* For N2_TASK_FINISHED, whether the task succeeded. */
+  int success;
+};
+
+typedef void (*n2_progress_fn)(void *user, const struct n2_event *event);
+
+/* Build count targets, or the defaults if count is 0, calling callback (if
+ * not NULL) with user as things happen.  The build files are read afresh.
+ * Returns the number of tasks run, -1 if a task failed, -2 if the build was
+ * cancelled, or -3 on any other error, panics inside n2 included. */
+long n2_build(n2_session *session, const char *const *targets, size_t count,
+              n2_progress_fn callback, void *user);
+
+/* Cancel the build in progress, from another thread, as ctl-c would.
+ * Returns once its tasks have been terminated. */
+void n2_cancel(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* N2_H */
@@ -0,0 +1,196 @@
+"""Python bindings for n2, over its C API (see include/n2.h).
+
+Build n2 as a shared library first:
+
+    cargo rustc --lib --release --features capi --crate-type cdylib
+
+and point N2PY_LIBRARY at it, or leave it in target/release of the checkout
+this file is in.  Then:
+
+    import n2py
+
+    session = n2py.Session()  # build.ninja in the current directory
+    print(session.targets())
+    tasks = session.build(["all"], progress=lambda event: print(event))
+"""
+
+import ctypes
+import os
+import sys
+
+__all__ = ["BuildError", "Cancelled", "Event", "Session", "cancel"]
+
+TASK_STARTED = 0
+TASK_OUTPUT = 1
+TASK_FINISHED = 2
+LOG = 3
+
+_KINDS = {
+    TASK_STARTED: "task_started",
+    TASK_OUTPUT: "task_output",
+    TASK_FINISHED: "task_finished",
+    LOG: "log",
+}
+
+
+class _Event(ctypes.Structure):
+    _fields_ = [
+        ("kind", ctypes.c_int),
+        ("id", ctypes.c_size_t),
+        ("description", ctypes.c_char_p),
+        ("text", ctypes.c_char_p),
+        ("success", ctypes.c_int),
+    ]
+
+
+_ProgressFn = ctypes.CFUNCTYPE(None, ctypes.c_void_p, ctypes.POINTER(_Event))
+
+
+def _library_path():
+    path = os.environ.get("N2PY_LIBRARY")
+    if path:
+        return path
+    name = {"win32": "n2.dll", "darwin": "libn2.dylib"}.get(sys.platform, "libn2.so")
+    root = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+    return os.path.join(root, "target", "release", name)
+
+
+def _load():
+    lib = ctypes.CDLL(_library_path())
+    strings = ctypes.POINTER(ctypes.c_char_p)
+    lib.n2_last_error.restype = ctypes.c_char_p
+    lib.n2_open.argtypes = [strings, ctypes.c_size_t]
+    lib.n2_open.restype = ctypes.c_void_p
+    lib.n2_close.argtypes = [ctypes.c_void_p]
+    lib.n2_set_parallelism.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+    lib.n2_set_keep_going.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+    lib.n2_target_count.argtypes = [ctypes.c_void_p]
+    lib.n2_target_count.restype = ctypes.c_size_t
+    lib.n2_target_name.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
+    lib.n2_target_name.restype = ctypes.c_char_p
+    lib.n2_build.argtypes = [ctypes.c_void_p, strings, ctypes.c_size_t, _ProgressFn, ctypes.c_void_p]
+    lib.n2_build.restype = ctypes.c_long
+    return lib
+
+
+_lib = _load()
+
+
+def _decode(b):
+    # Paths needn't be UTF-8, so neither need names nor output.
+    return b.decode("utf-8", "surrogateescape") if b is not None else None
+
+
+def _encode(s):
+    return s.encode("utf-8", "surrogateescape")
+
+
+def _array(strs):
+    encoded = [_encode(s) for s in strs]
+    return (ctypes.c_char_p * len(encoded))(*encoded), len(encoded)
+
+
+class BuildError(Exception):
+    """A build that couldn't be carried out, e.g. for an unknown target."""
+
+
+class Cancelled(Exception):
+    """A build interrupted by cancel()."""
+
+
+class Event:
+    """Something that happened during a build.
+
+    kind is one of "task_started", "task_output", "task_finished" and "log".
+    id identifies the build of a task.  description is the build's description
+    or command line, for task_started and task_finished.  text is the last line
+    of output so far for task_output, all of the output for task_finished, or
+    the message for log.  success is whether a finished task succeeded.
+    """
+
+    __slots__ = ("kind", "id", "description", "text", "success")
+
+    def __init__(self, event):
+        self.kind = _KINDS.get(event.kind, str(event.kind))
+        self.id = event.id
+        self.description = _decode(event.description)
+        self.text = _decode(event.text)
+        self.success = bool(event.success)
+
+    def __repr__(self):
+        return "Event(%s, id=%d, description=%r, text=%r, success=%r)" % (
+            self.kind,
+            self.id,
+            self.description,
+            self.text,
+            self.success,
+        )
+
+
+class Session:
+    """Build files loaded from the current directory, ready to build."""
+
+    def __init__(self, build_files=(), parallelism=None, keep_going=None):
+        array, count = _array(build_files)
+        self._handle = _lib.n2_open(array, count)
+        if not self._handle:
+            raise BuildError(_decode(_lib.n2_last_error()))
+        if parallelism is not None:
+            _lib.n2_set_parallelism(self._handle, parallelism)
+        if keep_going is not None:
+            _lib.n2_set_keep_going(self._handle, keep_going)
+
+    def close(self):
+        if self._handle:
+            _lib.n2_close(self._handle)
+            self._handle = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def targets(self):
+        """The files that builds produce, in sorted order, as loaded."""
+        return [
+            _decode(_lib.n2_target_name(self._handle, i))
+            for i in range(_lib.n2_target_count(self._handle))
+        ]
+
+    def build(self, targets=(), progress=None):
+        """Build the targets, or the defaults if there are none, calling
+        progress with an Event as things happen.
+
+        Returns the number of tasks run, or None if one failed.  Exceptions
+        from progress are raised once the build is over.
+        """
+        raised = []
+
+        def callback(_user, event):
+            if progress is None or raised:
+                return
+            try:
+                progress(Event(event.contents))
+            except BaseException as e:
+                raised.append(e)
+
+        array, count = _array(targets)
+        result = _lib.n2_build(self._handle, array, count, _ProgressFn(callback), None)
+        if raised:
+            raise raised[0]
+        if result == -1:
+            return None
+        if result == -2:
+            raise Cancelled()
+        if result < 0:
+            raise BuildError(_decode(_lib.n2_last_error()))
+        return result
+
+
+def cancel():
+    """Cancel the build in progress, from another thread."""
+    _lib.n2_cancel()
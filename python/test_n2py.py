@@ -0,0 +1,99 @@
+"""Tests of n2py against the C API library, built first unless N2PY_LIBRARY
+names one already built:
+
+    python3 python/test_n2py.py
+"""
+
+import os
+import subprocess
+import sys
+import tempfile
+import unittest
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+
+
+def _build_library():
+    subprocess.check_call(
+        ["cargo", "rustc", "--lib", "--release", "--features", "capi", "--crate-type", "cdylib"],
+        cwd=ROOT,
+    )
+
+
+if not os.environ.get("N2PY_LIBRARY"):
+    _build_library()
+sys.path.insert(0, os.path.join(ROOT, "python"))
+import n2py  # noqa: E402
+
+MANIFEST = """
+rule touch
+  command = touch $out
+  description = touch $out
+rule fail
+  command = echo oops; exit 1
+build out: touch in
+build bad: fail
+default out
+"""
+
+
+class N2pyTest(unittest.TestCase):
+    def setUp(self):
+        self._cwd = os.getcwd()
+        self._dir = tempfile.TemporaryDirectory()
+        os.chdir(self._dir.name)
+        with open("build.ninja", "w") as f:
+            f.write(MANIFEST)
+        with open("in", "w"):
+            pass
+
+    def tearDown(self):
+        os.chdir(self._cwd)
+        self._dir.cleanup()
+
+    def test_targets(self):
+        with n2py.Session() as session:
+            self.assertEqual(session.targets(), ["bad", "out"])
+
+    def test_build(self):
+        events = []
+        with n2py.Session(parallelism=1) as session:
+            self.assertEqual(session.build(progress=events.append), 1)
+            self.assertTrue(os.path.exists("out"))
+            kinds = [event.kind for event in events]
+            self.assertIn("task_started", kinds)
+            finished = [event for event in events if event.kind == "task_finished"]
+            self.assertEqual(len(finished), 1)
+            self.assertTrue(finished[0].success)
+            self.assertEqual(finished[0].description, "touch out")
+
+            # Now up to date.
+            self.assertEqual(session.build(["out"]), 0)
+
+    def test_failure(self):
+        events = []
+        with n2py.Session() as session:
+            self.assertIsNone(session.build(["bad"], progress=events.append))
+        finished = [event for event in events if event.kind == "task_finished"]
+        self.assertEqual(len(finished), 1)
+        self.assertFalse(finished[0].success)
+        self.assertIn("oops", finished[0].text)
+
+    def test_errors(self):
+        with n2py.Session() as session:
+            with self.assertRaises(n2py.BuildError):
+                session.build(["nonexistent"])
+        with self.assertRaises(n2py.BuildError):
+            n2py.Session(["missing.ninja"])
+
+    def test_progress_exception(self):
+        def progress(event):
+            raise ValueError("from progress")
+
+        with n2py.Session() as session:
+            with self.assertRaises(ValueError):
+                session.build(["out"], progress=progress)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,207 @@
+//! `--audit`: running each command under strace to find the files it read,
+//! so that Work can report those the build doesn't declare.  An input that
+//! isn't declared, or discovered through a depfile, doesn't make the build
+//! dirty when it changes, and may not be built before the command reads it,
+//! which is where most flaky incremental builds come from.
+//!
+//! Only Linux, where strace is at hand, is supported.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// strace and its arguments, up to the command to trace.
+fn strace(trace: &Path) -> Vec<String> {
+    let mut words: Vec<String> = [
+        "strace",
+        "-f",
+        "-qq",
+        "-e",
+        "signal=none",
+        "-e",
+        "trace=open,openat,openat2,creat,execve,chdir,clone,clone3,fork,vfork",
+        "-o",
+    ]
+    .iter()
+    .map(|&word| word.to_owned())
+    .collect();
+    words.push(trace.to_string_lossy().into_owned());
+    words.push("--".to_owned());
+    words
+}
+
+/// The shell and command line to run a command under strace, writing the
+/// trace to the given path; see process::run_command for shell.
+pub fn wrap(shell: Option<&[String]>, cmdline: &str, trace: &Path) -> (Vec<String>, String) {
+    crate::process::wrap_command(&strace(trace), shell, cmdline)
+}
+
+/// Undo strace's escaping of a string argument, the quotes already removed.
+fn unescape(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).unwrap_or_default();
+                out.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+            }
+            Some(digit @ b'0'..=b'7') => {
+                // Up to three octal digits.
+                let mut value = (digit - b'0') as u32;
+                let mut rest = bytes.clone();
+                for _ in 0..2 {
+                    match rest.next() {
+                        Some(digit @ b'0'..=b'7') => {
+                            value = value * 8 + (digit - b'0') as u32;
+                            bytes.next();
+                        }
+                        _ => break,
+                    }
+                }
+                out.push(value as u8);
+            }
+            Some(other) => out.push(other),
+            None => out.push(b'\\'),
+        }
+    }
+    out
+}
+
+/// The first string argument of a call, e.g. the path of an open.
+fn first_string(args: &str) -> Option<String> {
+    let start = args.find('"')? + 1;
+    let mut end = start;
+    let bytes = args.as_bytes();
+    while end < bytes.len() && bytes[end] != b'"' {
+        if bytes[end] == b'\\' {
+            end += 1;
+        }
+        end += 1;
+    }
+    let raw = args.get(start..end)?;
+    Some(String::from_utf8_lossy(&unescape(raw)).into_owned())
+}
+
+/// The files read according to the trace strace wrote, as paths relative to
+/// the directory the command started in, or absolute.  Files the command
+/// also wrote, like temporary files and its own outputs, aren't counted.
+pub fn parse_trace(trace: &str) -> Vec<String> {
+    // Calls split by another process's, by pid.
+    let mut pending: HashMap<&str, String> = HashMap::new();
+    // Working directories of processes that changed them or started in one
+    // that did, by pid.
+    let mut cwds: HashMap<String, PathBuf> = HashMap::new();
+    let mut read = HashSet::new();
+    let mut written = HashSet::new();
+    for line in trace.lines() {
+        let (pid, call) = match line.split_once(' ') {
+            Some(split) => split,
+            None => continue,
+        };
+        let call = call.trim_start();
+        let call = if let Some(resumed) = call.strip_prefix("<... ") {
+            let rest = match resumed.split_once("resumed>") {
+                Some((_, rest)) => rest,
+                None => continue,
+            };
+            match pending.remove(pid) {
+                Some(start) => start + rest,
+                None => continue,
+            }
+        } else if let Some(start) = call.strip_suffix("<unfinished ...>") {
+            pending.insert(pid, start.to_owned());
+            continue;
+        } else {
+            call.to_owned()
+        };
+
+        let (name, args) = match call.split_once('(') {
+            Some(split) => split,
+            None => continue,
+        };
+        let (args, result) = match args.rsplit_once(") = ") {
+            Some(split) => split,
+            None => continue,
+        };
+        let result = result.split(' ').next().unwrap_or_default();
+        if result.starts_with('-') || result == "?" {
+            continue;
+        }
+        let cwd = cwds.get(pid).cloned().unwrap_or_default();
+        match name {
+            "clone" | "clone3" | "fork" | "vfork" => {
+                cwds.insert(result.to_owned(), cwd);
+                continue;
+            }
+            "chdir" => {
+                if let Some(dir) = first_string(args) {
+                    cwds.insert(pid.to_owned(), cwd.join(dir));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        // openat relative to a directory other than the working one is rare
+        // enough to skip.
+        if name.starts_with("openat") && !args.starts_with("AT_FDCWD") {
+            continue;
+        }
+        let path = match first_string(args) {
+            Some(path) => cwd.join(path).to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let writes = name == "creat"
+            || ["O_WRONLY", "O_RDWR", "O_CREAT", "O_TRUNC"]
+                .iter()
+                .any(|flag| args.contains(flag));
+        if writes {
+            written.insert(path);
+        } else if !args.contains("O_DIRECTORY") {
+            read.insert(path);
+        }
+    }
+    let mut read: Vec<String> = read.difference(&written).cloned().collect();
+    read.sort_unstable();
+    read
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let trace = r#"100 execve("/usr/bin/cc", ["cc"], 0x7ffd /* 20 vars */) = 0
+100 openat(AT_FDCWD, "/etc/ld.so.cache", O_RDONLY|O_CLOEXEC) = 3
+100 openat(AT_FDCWD, "a.c", O_RDONLY) = 3
+100 openat(AT_FDCWD, "missing.h", O_RDONLY) = -1 ENOENT (No such file or directory)
+100 openat(AT_FDCWD, "gen.h", O_RDONLY <unfinished ...>
+101 openat(AT_FDCWD, "a.o", O_WRONLY|O_CREAT|O_TRUNC, 0666) = 4
+100 <... openat resumed>) = 5
+100 openat(AT_FDCWD, "a.o", O_RDONLY) = 6
+100 openat(AT_FDCWD, "include", O_RDONLY|O_DIRECTORY) = 7
+100 openat(7, "rel.h", O_RDONLY) = 8
+100 clone(child_stack=NULL, flags=SIGCHLD) = 102
+102 chdir("sub") = 0
+102 open("x\"y\303\251.h", O_RDONLY) = 3
+"#;
+        assert_eq!(
+            parse_trace(trace),
+            [
+                "/etc/ld.so.cache",
+                "/usr/bin/cc",
+                "a.c",
+                "gen.h",
+                "sub/x\"yé.h"
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Batched stats on macOS through getattrlistbulk(), which reads the
+//! attributes of a directory's entries many at a time rather than making a
+//! syscall for each file, for the stat()s at the start of a build on a cold
+//! cache.  See FileState::prefetch.
+//!
+//! Only directories holding enough of the files asked about are listed, as
+//! listing a big directory for one file costs more than statting it.  Files
+//! that aren't found in a listing, or that are symlinks to be followed, are
+//! left to plain stat().
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Directories with fewer of the files than this are left to stat().
+const DIR_MIN: usize = 8;
+
+/// Size of the buffer each getattrlistbulk() call fills, which holds a few
+/// hundred entries.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// The fsobj_type_t of a symlink, from enum vtype in <sys/vnode.h>.
+const VLNK: u32 = 5;
+
+/// What a listing found of one entry.
+struct Entry {
+    objtype: u32,
+    mtime: SystemTime,
+}
+
+fn read_u32(buf: &[u8], ofs: usize) -> u32 {
+    u32::from_ne_bytes(buf[ofs..ofs + 4].try_into().unwrap())
+}
+
+fn read_i64(buf: &[u8], ofs: usize) -> i64 {
+    i64::from_ne_bytes(buf[ofs..ofs + 8].try_into().unwrap())
+}
+
+fn timestamp(sec: i64, nsec: i64) -> SystemTime {
+    let secs = Duration::from_secs(sec.unsigned_abs());
+    let base = if sec >= 0 {
+        SystemTime::UNIX_EPOCH + secs
+    } else {
+        SystemTime::UNIX_EPOCH - secs
+    };
+    base + Duration::from_nanos(nsec as u64)
+}
+
+/// The name, type and mtime of each entry in a directory.
+fn list_dir(dir: &Path) -> io::Result<HashMap<Vec<u8>, Entry>> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: fd was just opened, and nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut attrs = libc::attrlist {
+        bitmapcount: libc::ATTR_BIT_MAP_COUNT,
+        reserved: 0,
+        commonattr: libc::ATTR_CMN_RETURNED_ATTRS
+            | libc::ATTR_CMN_NAME
+            | libc::ATTR_CMN_OBJTYPE
+            | libc::ATTR_CMN_MODTIME,
+        volattr: 0,
+        dirattr: 0,
+        fileattr: 0,
+        forkattr: 0,
+    };
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut entries = HashMap::new();
+    loop {
+        let count = unsafe {
+            libc::getattrlistbulk(
+                fd.as_raw_fd(),
+                &mut attrs as *mut libc::attrlist as *mut libc::c_void,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::FSOPT_PACK_INVAL_ATTRS as u64,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if count == 0 {
+            return Ok(entries);
+        }
+        // Each entry is its length, the attributes returned, then those
+        // asked for in the order of their bits, each 4-byte aligned: the
+        // name as an attrreference_t, which is an offset from itself and a
+        // length including the NUL, the type, then the mtime as a timespec.
+        let mut ofs = 0;
+        for _ in 0..count {
+            let entry = &buf[ofs..];
+            let len = read_u32(entry, 0) as usize;
+            let returned = read_u32(entry, 4);
+            let name_ref = 4 + std::mem::size_of::<libc::attribute_set_t>();
+            let name_offset = read_u32(entry, name_ref) as i32 as isize;
+            let name_start = (name_ref as isize + name_offset) as usize;
+            let name_len = read_u32(entry, name_ref + 4) as usize;
+            let objtype = read_u32(entry, name_ref + 8);
+            let mtime = name_ref + 12;
+            if returned & libc::ATTR_CMN_MODTIME != 0 && name_len > 0 {
+                entries.insert(
+                    entry[name_start..name_start + name_len - 1].to_vec(),
+                    Entry {
+                        objtype,
+                        mtime: timestamp(read_i64(entry, mtime), read_i64(entry, mtime + 8)),
+                    },
+                );
+            }
+            ofs += len;
+        }
+    }
+}
+
+/// The mtimes of the given paths, as with std::fs::metadata() (or
+/// symlink_metadata() if not following symlinks), for those in directories
+/// worth listing, and errors for the rest, which are left to stat().  None
+/// if there are no such directories.
+pub fn mtimes(paths: &[&Path], follow_symlinks: bool) -> Option<Vec<io::Result<SystemTime>>> {
+    let mut dirs: HashMap<&Path, Vec<usize>> = HashMap::new();
+    for (i, path) in paths.iter().enumerate() {
+        if path.file_name().is_none() {
+            continue;
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        dirs.entry(dir).or_default().push(i);
+    }
+    dirs.retain(|_, files| files.len() >= DIR_MIN);
+    if dirs.is_empty() {
+        return None;
+    }
+
+    let mut mtimes: Vec<io::Result<SystemTime>> = paths
+        .iter()
+        .map(|_| Err(io::Error::new(io::ErrorKind::Other, "not listed")))
+        .collect();
+    for (dir, files) in dirs {
+        let entries = match list_dir(dir) {
+            Ok(entries) => entries,
+            // E.g. a filesystem without getattrlistbulk(); stat() will do.
+            Err(_) => continue,
+        };
+        for i in files {
+            let name = paths[i].file_name().unwrap().as_bytes();
+            mtimes[i] = match entries.get(name) {
+                Some(entry) if follow_symlinks && entry.objtype == VLNK => continue,
+                Some(entry) => Ok(entry.mtime),
+                // Not necessarily missing, as a case-insensitive filesystem
+                // may list it under another case; stat() will tell.
+                None => continue,
+            };
+        }
+    }
+    Some(mtimes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_metadata() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for i in 0..(DIR_MIN + 10) {
+            let path = dir.path().join(format!("f{}", i));
+            std::fs::write(&path, "")?;
+            paths.push(path);
+        }
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&paths[0], &link)?;
+        paths.push(link);
+        paths.push(dir.path().join("missing"));
+        let refs: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        for follow_symlinks in [false, true] {
+            let mtimes = mtimes(&refs, follow_symlinks).unwrap();
+            assert_eq!(mtimes.len(), paths.len());
+            for (path, mtime) in paths.iter().zip(&mtimes) {
+                let meta = if follow_symlinks {
+                    std::fs::metadata(path)
+                } else {
+                    std::fs::symlink_metadata(path)
+                };
+                if let Ok(mtime) = mtime {
+                    assert_eq!(mtime, &meta?.modified()?);
+                }
+            }
+            // Only the followed symlink and the missing file are left over.
+            let left = mtimes.iter().filter(|mtime| mtime.is_err()).count();
+            assert_eq!(left, if follow_symlinks { 2 } else { 1 });
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 //! Path canonicalization.
 
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 use std::mem::MaybeUninit;
+use std::path::Path;
 
 /// An on-stack stack of values.
 /// Used for tracking locations of parent components within a path.
@@ -44,6 +47,14 @@ impl<T: Copy> StackStack<T> {
 /// Returns the new length of the path, guaranteed <= the original length.
 #[must_use]
 pub fn canon_path_fast(path: &mut str) -> usize {
+    // Safety: only whole components, which end at an ASCII separator, are
+    // moved, and only ASCII is written, so UTF-8 stays UTF-8.
+    canon_bytes_fast(unsafe { path.as_bytes_mut() })
+}
+
+/// canon_path_fast for a path as bytes.
+#[must_use]
+fn canon_bytes_fast(path: &mut [u8]) -> usize {
     assert!(!path.is_empty());
     // Safety: this traverses the path buffer to move data around.
     // We maintain the invariant that *dst always points to a point within
@@ -142,6 +153,105 @@ pub fn canon_path<T: Into<String>>(path: T) -> String {
     path
 }
 
+/// canon_path for a path as bytes, like a target named on the command line
+/// that isn't UTF-8.
+pub fn canon_bytes(mut path: Vec<u8>) -> Vec<u8> {
+    let len = canon_bytes_fast(&mut path);
+    path.truncate(len);
+    path
+}
+
+/// The bytes of an OS string, which on Unix are the bytes as paths in build
+/// files are read, and elsewhere are its UTF-8, with anything that isn't
+/// Unicode replaced.
+pub fn os_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(s.as_bytes())
+    }
+    #[cfg(not(unix))]
+    match s.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// An OS string from bytes, the reverse of os_bytes.
+pub fn os_string(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Paths at least this long are given to Windows in extended-length form.
+/// This is a bit under MAX_PATH, as directory creation fails sooner than that.
+#[cfg(windows)]
+const LONG_PATH: usize = 248;
+
+/// Convert a path for use with filesystem APIs.
+/// On Windows, paths beyond MAX_PATH only work in the `\\?\` extended-length
+/// form, which must be absolute and free of `/`, `.` and `..` as Windows does
+/// no further processing on it.  Shorter paths are returned unchanged, as is
+/// everything on other platforms.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::ffi::OsString;
+    use std::path::{Component, PathBuf, Prefix};
+
+    if path.as_os_str().len() < LONG_PATH && path.is_absolute() {
+        return Cow::Borrowed(path);
+    }
+    let abs = if path.is_absolute() {
+        Cow::Borrowed(path)
+    } else {
+        match std::env::current_dir() {
+            Ok(cwd) => Cow::Owned(cwd.join(path)),
+            Err(_) => return Cow::Borrowed(path),
+        }
+    };
+    if abs.as_os_str().len() < LONG_PATH {
+        return Cow::Borrowed(path);
+    }
+
+    let mut long = OsString::from(r"\\?\");
+    let mut parts = Vec::new();
+    for component in abs.components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(_) => long.push(prefix.as_os_str()),
+                Prefix::UNC(server, share) => {
+                    long.push(r"UNC\");
+                    long.push(server);
+                    long.push(r"\");
+                    long.push(share);
+                }
+                // Already in some verbatim or device form.
+                _ => return Cow::Borrowed(path),
+            },
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Normal(part) => parts.push(part),
+        }
+    }
+    for part in parts {
+        long.push(r"\");
+        long.push(part);
+    }
+    Cow::Owned(PathBuf::from(long))
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -200,4 +310,9 @@ mod tests {
         assert_canon_path_eq("foo/../../", "../");
         assert_canon_path_eq("foo/../../bar", "../bar");
     }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(canon_bytes(b"./out/../caf\xe9.o".to_vec()), b"caf\xe9.o");
+    }
 }
@@ -0,0 +1,412 @@
+//! A C API, for embedding n2 as the engine of an IDE or a build daemon.
+//! Built with the `capi` feature; see include/n2.h for the declarations and
+//! doc/development.md for building n2 as a shared library.
+//!
+//! Paths are relative to the current directory, as they are for the command
+//! line, and builds are run one at a time per process, as signal handling
+//! and cancellation are global.
+
+use crate::{
+    canon,
+    densemap::Index,
+    graph::{Build, BuildId},
+    load,
+    process::Termination,
+    progress::{build_message, Progress},
+    run, signal,
+    task::TaskResult,
+    work::{self, Estimate, StateCounts},
+};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_long, c_void, CStr, CString, OsString};
+
+thread_local! {
+    /// The error from the last call that failed on this thread.
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(msg: &str) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|err| *err.borrow_mut() = msg);
+}
+
+/// Run the body of an extern "C" fn, as a panic mustn't unwind into C: it's
+/// caught and reported as an error, and `failed` returned instead.
+fn guard<T>(failed: T, body: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(panic) => {
+            let msg = match panic.downcast_ref::<&str>() {
+                Some(msg) => msg,
+                None => panic
+                    .downcast_ref::<String>()
+                    .map_or("?", |msg| msg.as_str()),
+            };
+            set_error(&format!("panic: {}", msg));
+            failed
+        }
+    }
+}
+
+/// Why the last call that failed on this thread did, valid until the next
+/// call that fails.
+#[no_mangle]
+pub extern "C" fn n2_last_error() -> *const c_char {
+    guard(std::ptr::null(), || {
+        LAST_ERROR.with(|err| err.borrow().as_ptr())
+    })
+}
+
+/// Build files loaded by n2_open, and the settings to build them with.
+pub struct Session {
+    build_filenames: Vec<String>,
+    load_options: load::Options,
+    options: work::Options,
+    targets: Vec<CString>,
+}
+
+/// Copy an array of C strings.
+unsafe fn strings(ptrs: *const *const c_char, count: usize) -> Vec<String> {
+    if count == 0 {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(ptrs, count)
+        .iter()
+        .map(|&ptr| CStr::from_ptr(ptr).to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Copy an array of C strings as OS strings, as targets needn't be UTF-8.
+unsafe fn os_strings(ptrs: *const *const c_char, count: usize) -> Vec<OsString> {
+    if count == 0 {
+        return Vec::new();
+    }
+    std::slice::from_raw_parts(ptrs, count)
+        .iter()
+        .map(|&ptr| canon::os_string(CStr::from_ptr(ptr).to_bytes().to_vec()))
+        .collect()
+}
+
+/// Load build files, `count` of them at `build_files`, or build.ninja if
+/// `count` is 0.  Returns NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn n2_open(build_files: *const *const c_char, count: usize) -> *mut Session {
+    guard(std::ptr::null_mut(), || {
+        let mut build_filenames = strings(build_files, count);
+        if build_filenames.is_empty() {
+            build_filenames.push(String::from("build.ninja"));
+        }
+        let load_options = load::Options::default();
+        let manifest = match load::read_manifest(&build_filenames, &load_options) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                set_error(&err.to_string());
+                return std::ptr::null_mut();
+            }
+        };
+        let graph = &manifest.graph;
+        let mut targets: Vec<CString> = graph
+            .files
+            .all_ids()
+            .filter(|&id| graph.file(id).input.is_some())
+            .filter_map(|id| CString::new(graph.file(id).name.as_str()).ok())
+            .collect();
+        targets.sort();
+        let options = work::Options {
+            parallelism: std::thread::available_parallelism().map_or(1, usize::from),
+            failures_left: Some(1),
+            follow_symlinks: true,
+            embedded: true,
+            no_stdin: true,
+            ..Default::default()
+        };
+        Box::into_raw(Box::new(Session {
+            build_filenames,
+            load_options,
+            options,
+            targets,
+        }))
+    })
+}
+
+/// Free a session from n2_open.
+#[no_mangle]
+pub unsafe extern "C" fn n2_close(session: *mut Session) {
+    guard((), || {
+        if !session.is_null() {
+            drop(Box::from_raw(session));
+        }
+    })
+}
+
+/// How many tasks may run at once, by default the number of cores.
+#[no_mangle]
+pub unsafe extern "C" fn n2_set_parallelism(session: *mut Session, parallelism: usize) {
+    guard((), || {
+        let session = &mut *session;
+        session.options.parallelism = parallelism.max(1);
+    })
+}
+
+/// How many failures a build keeps going until, with 0 meaning any number;
+/// by default 1.
+#[no_mangle]
+pub unsafe extern "C" fn n2_set_keep_going(session: *mut Session, failures: usize) {
+    guard((), || {
+        let session = &mut *session;
+        session.options.failures_left = Some(failures).filter(|&n| n > 0);
+    })
+}
+
+/// The number of files that builds produce, as they were when loaded.
+#[no_mangle]
+pub unsafe extern "C" fn n2_target_count(session: *const Session) -> usize {
+    guard(0, || {
+        let session = &*session;
+        session.targets.len()
+    })
+}
+
+/// The name of a file that a build produces, in sorted order, valid until the
+/// session is closed.
+#[no_mangle]
+pub unsafe extern "C" fn n2_target_name(session: *const Session, index: usize) -> *const c_char {
+    guard(std::ptr::null(), || {
+        let session = &*session;
+        match session.targets.get(index) {
+            Some(name) => name.as_ptr(),
+            None => std::ptr::null(),
+        }
+    })
+}
+
+pub const N2_TASK_STARTED: c_int = 0;
+pub const N2_TASK_OUTPUT: c_int = 1;
+pub const N2_TASK_FINISHED: c_int = 2;
+pub const N2_LOG: c_int = 3;
+
+/// Something that happened during a build; see include/n2.h.
+#[repr(C)]
+pub struct Event {
+    kind: c_int,
+    /// The build the event is about, other than for N2_LOG.
+    id: usize,
+    description: *const c_char,
+    /// The last line of output so far, all of the output, or the message
+    /// logged.
+    text: *const c_char,
+    /// For N2_TASK_FINISHED, whether the task succeeded.
+    success: c_int,
+}
+
+pub type ProgressFn = Option<unsafe extern "C" fn(user: *mut c_void, event: *const Event)>;
+
+/// Passes the progress of a build to a C callback.
+struct CallbackProgress {
+    callback: ProgressFn,
+    user: *mut c_void,
+}
+
+impl CallbackProgress {
+    fn send(&self, kind: c_int, id: usize, description: &str, text: &[u8], success: bool) {
+        let callback = match self.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        // Interior NULs would cut the strings short anyway.
+        let cstring = |bytes: &[u8]| {
+            CString::new(
+                bytes
+                    .iter()
+                    .map(|&b| if b == 0 { b' ' } else { b })
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+        let description = cstring(description.as_bytes());
+        let text = cstring(text);
+        let event = Event {
+            kind,
+            id,
+            description: description.as_ptr(),
+            text: text.as_ptr(),
+            success: success as c_int,
+        };
+        unsafe { callback(self.user, &event) };
+    }
+}
+
+impl Progress for CallbackProgress {
+    fn update(&self, _counts: &StateCounts, _estimate: &Estimate) {}
+
+    fn task_started(&self, id: BuildId, build: &Build, _expected: Option<std::time::Duration>) {
+        self.send(
+            N2_TASK_STARTED,
+            id.index(),
+            build_message(build),
+            b"",
+            false,
+        );
+    }
+
+    fn task_output(&self, id: BuildId, line: Vec<u8>) {
+        self.send(N2_TASK_OUTPUT, id.index(), "", &line, false);
+    }
+
+    fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
+        self.send(
+            N2_TASK_FINISHED,
+            id.index(),
+            build_message(build),
+            &result.output,
+            result.termination == Termination::Success,
+        );
+    }
+
+    fn log(&self, msg: &str) {
+        self.send(N2_LOG, 0, "", msg.as_bytes(), false);
+    }
+
+    fn interact(&self, f: &mut dyn FnMut()) {
+        f();
+    }
+}
+
+/// Build `count` targets at `targets`, or the defaults if `count` is 0,
+/// calling `callback` with `user` as things happen.  Returns the number of
+/// tasks run, -1 if a task failed, -2 if the build was cancelled, or -3 on
+/// any other error, including a panic.  The build files are read afresh.
+#[no_mangle]
+pub unsafe extern "C" fn n2_build(
+    session: *mut Session,
+    targets: *const *const c_char,
+    count: usize,
+    callback: ProgressFn,
+    user: *mut c_void,
+) -> c_long {
+    guard(-3, || {
+        let session = &*session;
+        let progress = CallbackProgress { callback, user };
+        // Any cancel from before has nothing to do with this build.
+        signal::clear_interrupted();
+        let result = run::build(
+            session.options.clone(),
+            session.build_filenames.clone(),
+            os_strings(targets, count),
+            &progress,
+            &session.load_options,
+            None,
+            run::Pick::Never,
+        );
+        let interrupted = signal::was_interrupted();
+        signal::clear_interrupted();
+        match result {
+            Ok(Some(tasks)) => tasks as c_long,
+            Ok(None) if interrupted => -2,
+            Ok(None) => -1,
+            Err(err) => {
+                set_error(&err.to_string());
+                -3
+            }
+        }
+    })
+}
+
+/// Cancel the build in progress, from another thread, as ctl-c would.
+/// Returns once its tasks have been terminated.
+#[no_mangle]
+pub extern "C" fn n2_cancel() {
+    guard((), || {
+        signal::interrupt();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(user: *mut c_void, event: *const Event) {
+        let events = &mut *(user as *mut Vec<(c_int, String)>);
+        let event = &*event;
+        let text = CStr::from_ptr(event.text).to_string_lossy().into_owned();
+        events.push((event.kind, text));
+    }
+
+    #[test]
+    fn panic() {
+        assert_eq!(guard(-3, || panic!("oops")), -3);
+        let err = unsafe { CStr::from_ptr(n2_last_error()) };
+        assert_eq!(err.to_str(), Ok("panic: oops"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manifest = dir.path().join("build.ninja");
+        let out = dir.path().join("out");
+        std::fs::write(
+            &manifest,
+            format!(
+                "builddir = {}\nrule echo\n  command = echo hello > $out\nbuild {}: echo\n",
+                dir.path().display(),
+                out.display()
+            ),
+        )?;
+        let manifest = CString::new(manifest.to_str().unwrap())?;
+        let out = CString::new(out.to_str().unwrap())?;
+        unsafe {
+            let session = n2_open(&manifest.as_ptr(), 1);
+            assert!(!session.is_null());
+            assert_eq!(n2_target_count(session), 1);
+            assert_eq!(CStr::from_ptr(n2_target_name(session, 0)), out.as_c_str());
+
+            let mut events: Vec<(c_int, String)> = Vec::new();
+            let tasks = n2_build(
+                session,
+                &out.as_ptr(),
+                1,
+                Some(collect),
+                &mut events as *mut _ as *mut c_void,
+            );
+            assert_eq!(tasks, 1);
+            assert_eq!(events.last().unwrap().0, N2_TASK_FINISHED);
+
+            let unknown = CString::new("unknown")?;
+            let tasks = n2_build(session, &unknown.as_ptr(), 1, None, std::ptr::null_mut());
+            assert_eq!(tasks, -3);
+            let err = CStr::from_ptr(n2_last_error()).to_str()?;
+            assert!(err.contains("unknown path requested"));
+            n2_close(session);
+        }
+        Ok(())
+    }
+
+    /// With no targets and no defaults, everything is built, even with a
+    /// terminal at hand, as the picker is only for the command line.
+    #[cfg(unix)]
+    #[test]
+    fn build_without_defaults() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manifest = dir.path().join("build.ninja");
+        let out = |name: &str| dir.path().join(name).display().to_string();
+        std::fs::write(
+            &manifest,
+            format!(
+                "builddir = {}\nrule touch\n  command = touch $out\nbuild {}: touch\nbuild {}: touch\n",
+                dir.path().display(),
+                out("a"),
+                out("b")
+            ),
+        )?;
+        let manifest = CString::new(manifest.to_str().unwrap())?;
+        unsafe {
+            let session = n2_open(&manifest.as_ptr(), 1);
+            assert!(!session.is_null());
+            let tasks = n2_build(session, std::ptr::null(), 0, None, std::ptr::null_mut());
+            assert_eq!(tasks, 2);
+            n2_close(session);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! `--check`: load the build files and report what would stop a build, without
+//! building anything or touching the db, so changes to a generator can be
+//! checked before they're merged.
+//!
+//! Loading stops at the first syntax error, so that is all that's reported
+//! of a file that doesn't parse.  A graph that loads is checked for
+//! dependency cycles, for inputs that are missing with no build to make them,
+//! and for depfiles left by earlier builds that don't parse.
+
+use crate::{
+    canon,
+    densemap::DenseMap,
+    graph::{BuildId, FileId, Graph},
+    load, task,
+};
+use std::path::Path;
+
+/// Where the depth first search has got to with a build.
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    /// On the stack, so reaching it again is a cycle.
+    Open,
+    Done,
+}
+
+/// The dependency cycles in the graph, each as the files around it, once per
+/// build the search first finds it from.
+fn cycles(graph: &Graph) -> Vec<String> {
+    let mut visits: DenseMap<BuildId, Visit> =
+        DenseMap::new_sized(graph.builds.next_id(), Visit::New);
+    let mut found = Vec::new();
+    for root in graph.build_ids() {
+        if visits[root] != Visit::New {
+            continue;
+        }
+        // The builds being searched, each with the file it was reached
+        // through and how many of its inputs have been looked at.
+        let mut stack: Vec<(BuildId, Option<FileId>, usize)> = vec![(root, None, 0)];
+        visits[root] = Visit::Open;
+        while let Some(top) = stack.last_mut() {
+            let (id, _, next) = *top;
+            let ins = graph.builds[id].ordering_ins();
+            if next == ins.len() {
+                visits[id] = Visit::Done;
+                stack.pop();
+                continue;
+            }
+            top.2 += 1;
+            let file = ins[next];
+            let input = match graph.file(file).input {
+                Some(input) => input,
+                None => continue,
+            };
+            match visits[input] {
+                Visit::New => {
+                    visits[input] = Visit::Open;
+                    stack.push((input, Some(file), 0));
+                }
+                Visit::Open => {
+                    let start = stack.iter().position(|&(id, _, _)| id == input).unwrap();
+                    let mut names: Vec<&str> = stack[start + 1..]
+                        .iter()
+                        .filter_map(|&(_, via, _)| Some(graph.file(via?).name.as_str()))
+                        .collect();
+                    names.push(&graph.file(file).name);
+                    names.insert(0, &graph.file(file).name);
+                    found.push(format!("dependency cycle: {}", names.join(" -> ")));
+                }
+                Visit::Done => {}
+            }
+        }
+    }
+    found
+}
+
+/// Check the build files, printing what's wrong with them, and return the
+/// exit code: 1 if anything is.
+pub fn run(build_filenames: &[String], options: &load::Options) -> anyhow::Result<i32> {
+    let manifest = match load::read_manifest(build_filenames, options) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            println!("n2: error: {}", err);
+            return Ok(1);
+        }
+    };
+    let graph = &manifest.graph;
+    let mut errors = cycles(graph);
+
+    let mut missing: Vec<&str> = graph
+        .files
+        .all_ids()
+        .map(|id| graph.file(id))
+        .filter(|file| {
+            file.input.is_none()
+                && !file.dependents.is_empty()
+                && std::fs::symlink_metadata(canon::long_path(Path::new(&file.name))).is_err()
+        })
+        .map(|file| file.name.as_str())
+        .collect();
+    missing.sort_unstable();
+    errors.extend(
+        missing
+            .into_iter()
+            .map(|name| format!("{:?} is missing, and no build makes it", name)),
+    );
+
+    for id in graph.build_ids() {
+        let build = &graph.builds[id];
+        if let Some(depfile) = &build.depfile {
+            if let Err(err) = task::read_depfile(Path::new(depfile)) {
+                errors.push(format!("{}: {}", build.location, err));
+            }
+        }
+    }
+
+    for error in &errors {
+        println!("n2: error: {}", error);
+    }
+    if !errors.is_empty() {
+        println!(
+            "n2: {} problem{} found",
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" }
+        );
+        return Ok(1);
+    }
+    println!("n2: no problems found");
+    Ok(0)
+}
@@ -0,0 +1,181 @@
+//! `-t compare`: what the last run of n2 did differently from the one before,
+//! for working out why an incremental build took as long as it did.
+
+use crate::{
+    db,
+    graph::Graph,
+    hash::{self, ManifestParts},
+    load,
+    progress::format_duration,
+};
+use anyhow::{anyhow, bail};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::time::Duration;
+
+/// Compare the last two runs recorded in the db: which builds ran in the
+/// last but not the one before and why, and how the durations of those that
+/// ran in both changed.
+pub fn run(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+) -> anyhow::Result<i32> {
+    if !targets.is_empty() {
+        bail!("-t compare takes no targets");
+    }
+    let mut manifest = load::read_manifest(build_filenames, options)?;
+    let path = &manifest.db_path;
+    if !path.exists() {
+        println!("no db at {}", path.display());
+        return Ok(1);
+    }
+    let contents = db::read_contents(path, &mut manifest.graph)
+        .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    let graph = &manifest.graph;
+
+    let (a, b) = match contents.runs.as_slice() {
+        [.., a, b] => (a, b),
+        runs => {
+            println!(
+                "the db has records of {} run(s) that built anything, 2 are needed",
+                runs.len()
+            );
+            return Ok(1);
+        }
+    };
+    print_run("A", &contents, a);
+    print_run("B", &contents, b);
+
+    // The builds that ran in A, with their records.
+    let in_a: HashMap<_, _> = contents.records[a.records.clone()]
+        .iter()
+        .filter_map(|r| Some((r.build?, r)))
+        .collect();
+
+    let mut only_b = Vec::new();
+    let mut both = Vec::new();
+    for (i, record) in contents.records[b.records.clone()]
+        .iter()
+        .enumerate()
+        .map(|(i, r)| (b.records.start + i, r))
+    {
+        let id = match record.build {
+            Some(id) => id,
+            None => continue,
+        };
+        match in_a.get(&id) {
+            None => only_b.push((record, why_ran(graph, &contents, i))),
+            Some(prev) => both.push((prev, record)),
+        }
+    }
+
+    println!();
+    println!("ran in B but not A: {}", only_b.len());
+    only_b.sort_by_key(|(record, _)| std::cmp::Reverse(record.fields.duration));
+    for (record, why) in only_b {
+        println!(
+            "  {:>7}  {}{}: {}",
+            secs(record.fields.duration),
+            name(graph, record),
+            failed(record),
+            why
+        );
+    }
+
+    println!();
+    println!("ran in both: {}", both.len());
+    let delta = |(prev, record): &(&&db::Record, &db::Record)| {
+        record.fields.duration.as_millis() as i64 - prev.fields.duration.as_millis() as i64
+    };
+    both.sort_by_key(|pair| std::cmp::Reverse(delta(pair).abs()));
+    for pair in &both {
+        let (prev, record) = pair;
+        let delta = delta(pair);
+        println!(
+            "  {:>7}  {}{}: {} -> {}",
+            format!("{:+.1}s", delta as f64 / 1000.0),
+            name(graph, record),
+            failed(record),
+            secs(prev.fields.duration),
+            secs(record.fields.duration)
+        );
+    }
+    Ok(0)
+}
+
+fn print_run(label: &str, contents: &db::Contents, run: &db::Run) {
+    let records = &contents.records[run.records.clone()];
+    let total: Duration = records.iter().map(|r| r.fields.duration).sum();
+    let ago = db::now_secs().saturating_sub(run.started);
+    println!(
+        "{}: started {} ago, ran {} tasks taking {} in total",
+        label,
+        format_duration(Duration::from_secs(ago as u64)),
+        records.len(),
+        secs(total)
+    );
+}
+
+/// Task durations are often well under a second, so show a fraction.
+fn secs(d: Duration) -> String {
+    format!("{:.1}s", d.as_secs_f64())
+}
+
+fn name<'a>(graph: &'a Graph, record: &db::Record) -> &'a str {
+    match record.outs.first() {
+        Some(&out) => &graph.file(out).name,
+        None => "?",
+    }
+}
+
+fn failed(record: &db::Record) -> &'static str {
+    if record.fields.failed {
+        " (failed)"
+    } else {
+        ""
+    }
+}
+
+/// Why the build of the record at index i ran, as told by how its record
+/// differs from the one before it.
+fn why_ran(graph: &Graph, contents: &db::Contents, i: usize) -> String {
+    let record = &contents.records[i];
+    let prev = match contents.records[..i]
+        .iter()
+        .rev()
+        .find(|r| r.build == record.build)
+    {
+        Some(prev) => prev,
+        None => return String::from("no earlier record"),
+    };
+    if prev.fields.failed {
+        return String::from("failed last time");
+    }
+    if record.fields.failed {
+        // A failure records nothing to compare.
+        return String::from("unknown");
+    }
+    // Outputs get new mtimes each time they're built, so comparing them
+    // after the fact says nothing.
+    let prev_parts = ManifestParts {
+        outs: record.fields.parts.outs,
+        ..prev.fields.parts
+    };
+    let changes = record.fields.parts.describe_changes(&prev_parts);
+    if changes.is_empty() {
+        return String::from("inputs and command unchanged, so an output was missing or modified");
+    }
+    let mut why = changes.join(", ");
+    // With --input-digests, the inputs that changed can be named.
+    if !record.digests.is_empty() && !prev.digests.is_empty() {
+        let changed: Vec<&str> = hash::changed_inputs(&prev.digests, &record.digests)
+            .iter()
+            .map(|input| graph.file(input.id).name.as_str())
+            .collect();
+        if !changed.is_empty() {
+            why.push_str(&format!(" ({})", changed.join(", ")));
+        }
+    }
+    why
+}
@@ -2,22 +2,39 @@
 //! which files are up to date.
 
 use crate::{
-    densemap, densemap::DenseMap, graph::BuildId, graph::FileId, graph::Graph, graph::Hashes,
-    hash::BuildHash,
+    densemap, densemap::DenseMap, densemap::Index as _, graph::BuildId, graph::Durations,
+    graph::FileId, graph::Graph, graph::Hashes, graph::RawId, hash::BuildHash, hash::InputDigest,
+    hash::ManifestParts, process::ResourceUsage,
 };
 use anyhow::{anyhow, bail};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
 use std::io::Write;
+use std::ops::Range;
 use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-const VERSION: u32 = 1;
+/// Version 1 recorded just each build's hash, which is computed differently
+/// now, so a db of it is discarded, by compact(), and the builds it recorded
+/// run again.
+const VERSION: u32 = 2;
 
-/// Files are identified by integers that are stable across n2 executions.
+/// The db is compacted once it has at least this many build records...
+const COMPACT_MIN_RECORDS: usize = 100;
+/// ...and this many times as many as there are builds they're for.
+const COMPACT_RATIO: usize = 3;
+
+/// How long compaction keeps the records of builds that are no longer in the
+/// manifest, in case they come back, as on switching back to another branch.
+const RETAIN_OBSOLETE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Files are identified by integers that are stable across n2 executions,
+/// as wide as graph::RawId.  The db can name at most RawId::MAX files; that's
+/// any file a build record refers to, whether or not it's still in the
+/// manifest, until compaction drops the records of those that are gone.
 #[derive(Debug, Clone, Copy)]
-pub struct Id(u32);
+pub struct Id(RawId);
 impl densemap::Index for Id {
     fn index(&self) -> usize {
         self.0 as usize
@@ -25,7 +42,7 @@ impl densemap::Index for Id {
 }
 impl From<usize> for Id {
     fn from(u: usize) -> Id {
-        Id(u as u32)
+        Id(RawId::try_from(u).expect("db id out of range"))
     }
 }
 
@@ -49,12 +66,16 @@ impl RecordWriter {
         self.0.extend_from_slice(buf);
     }
 
+    fn write_u8(&mut self, n: u8) {
+        self.write(&[n]);
+    }
+
     fn write_u16(&mut self, n: u16) {
         self.write(&n.to_le_bytes());
     }
 
-    fn write_u24(&mut self, n: u32) {
-        self.write(&n.to_le_bytes()[..3]);
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
     }
 
     fn write_u64(&mut self, n: u64) {
@@ -67,10 +88,7 @@ impl RecordWriter {
     }
 
     fn write_id(&mut self, id: Id) {
-        if id.0 > (1 << 24) {
-            panic!("too many fileids");
-        }
-        self.write_u24(id.0);
+        self.write(&id.0.to_le_bytes());
     }
 
     fn finish(&self, w: &mut impl Write) -> std::io::Result<()> {
@@ -78,27 +96,134 @@ impl RecordWriter {
     }
 }
 
+/// Durations are only used for estimates and reports, so millisecond
+/// precision (saturating at ~49 days) is plenty.
+fn millis_u32(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+/// The current time, in seconds since the epoch, as build records note when
+/// they were written.
+pub fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// The fixed-size part of a build record, following its lists of files.
+#[derive(Clone)]
+pub struct BuildFields {
+    pub hash: BuildHash,
+    pub parts: ManifestParts,
+    pub duration: Duration,
+    pub usage: ResourceUsage,
+    /// When the record was written, in seconds since the epoch.
+    pub written: u32,
+    /// Whether the build failed, in which case the record only notes that,
+    /// along with how long it ran; see Writer::write_failure.
+    pub failed: bool,
+}
+
+/// A build record as read back, for compaction or inspection.
+pub struct Record {
+    /// The build it's for, if it's still in the graph.
+    pub build: Option<BuildId>,
+    pub outs: Vec<FileId>,
+    pub deps: Vec<FileId>,
+    /// See Hashes::get_digests; empty unless they were recorded.
+    pub digests: Vec<(FileId, InputDigest)>,
+    pub fields: BuildFields,
+}
+
+/// The directory of the build a db is for, recorded in it so that one db
+/// isn't unknowingly shared by different builds.
+pub struct Root {
+    pub dir: String,
+    /// Whether the db's location was chosen explicitly, in which case finding
+    /// another directory recorded in it is an error.  Otherwise, as when the
+    /// build directory has been moved, the db is rewritten for the new one.
+    pub explicit: bool,
+}
+
+/// Everything in a db.
+pub struct Contents {
+    /// The directory of the build it's for, unless it's from before that was
+    /// recorded.
+    pub root: Option<String>,
+    /// How many file names it holds.
+    pub files: usize,
+    /// Its build records, oldest first.
+    pub records: Vec<Record>,
+    /// The runs of n2 that wrote them, as far as they're known, oldest first.
+    pub runs: Vec<Run>,
+}
+
+/// A run of n2 that recorded builds.
+pub struct Run {
+    /// When it started, in seconds since the epoch.
+    pub started: u32,
+    /// Its records, as indices into Contents::records.
+    pub records: Range<usize>,
+}
+
 /// An opened database, ready for writes.
 pub struct Writer {
     ids: IdMap,
-    w: File,
+    /// None if the db was opened read-only, in which case writes are dropped.
+    w: Option<File>,
+    /// When this run of n2 started, until the mark of that is written ahead
+    /// of its first record, so that runs that build nothing leave no mark.
+    run_mark: Option<u32>,
 }
 
 impl Writer {
-    fn create(path: &Path) -> std::io::Result<Self> {
+    fn create(path: &Path, root: &str) -> std::io::Result<Self> {
         let f = std::fs::File::create(path)?;
         let mut w = Self::from_opened(IdMap::default(), f);
-        w.write_signature()?;
+        w.write_signature(root)?;
         Ok(w)
     }
 
     fn from_opened(ids: IdMap, w: File) -> Self {
-        Writer { ids, w }
+        Writer {
+            ids,
+            w: Some(w),
+            run_mark: None,
+        }
+    }
+
+    /// Carry on the run of n2 that prev, the same db opened earlier in it,
+    /// was recording, as when the db is reopened after the build files were
+    /// regenerated: the run is marked once, whichever writes first.
+    pub fn continue_run(&mut self, prev: Writer) {
+        self.run_mark = prev.run_mark;
+    }
+
+    /// Whether writes to this db are dropped; see open_read_only.
+    pub fn read_only(&self) -> bool {
+        self.w.is_none()
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.w.as_mut().expect("write to read-only db")
     }
 
-    fn write_signature(&mut self) -> std::io::Result<()> {
-        self.w.write_all("n2db".as_bytes())?;
-        self.w.write_all(&u32::to_le_bytes(VERSION))
+    fn write_signature(&mut self, root: &str) -> std::io::Result<()> {
+        let mut w = RecordWriter::default();
+        w.write("n2db".as_bytes());
+        w.write_u32(VERSION);
+        w.write_u8(std::mem::size_of::<RawId>() as u8);
+        w.write_str(root);
+        w.finish(self.file())
+    }
+
+    /// An empty path, as no file has, marks the start of a run of n2, and is
+    /// followed by the time it started.
+    fn write_run_mark(&mut self, started: u32) -> std::io::Result<()> {
+        let mut w = RecordWriter::default();
+        w.write_str("");
+        w.write_u32(started);
+        w.finish(self.file())
     }
 
     fn write_path(&mut self, name: &str) -> std::io::Result<()> {
@@ -107,13 +232,19 @@ impl Writer {
         }
         let mut w = RecordWriter::default();
         w.write_str(&name);
-        w.finish(&mut self.w)
+        w.finish(self.file())
     }
 
     fn ensure_id(&mut self, graph: &Graph, fileid: FileId) -> std::io::Result<Id> {
         let id = match self.ids.db_ids.get(&fileid) {
             Some(&id) => id,
             None => {
+                if self.ids.db_ids.len() as u64 >= RawId::MAX as u64 {
+                    return Err(std::io::Error::other(format!(
+                        "more than {} files to record in the db; build n2 with the wide-ids feature for more",
+                        RawId::MAX
+                    )));
+                }
                 let id = self.ids.fileids.push(fileid);
                 self.ids.db_ids.insert(fileid, id);
                 self.write_path(&graph.file(fileid).name)?;
@@ -123,15 +254,55 @@ impl Writer {
         Ok(id)
     }
 
+    /// Record a build that ran, with the digests of its inputs if they're
+    /// wanted; see hash::hash_inputs.
     pub fn write_build(
         &mut self,
         graph: &Graph,
         id: BuildId,
-        hash: BuildHash,
+        digests: &[(FileId, InputDigest)],
+        fields: &BuildFields,
     ) -> std::io::Result<()> {
         let build = &graph.builds[id];
+        self.write_record(graph, build.outs(), build.discovered_ins(), digests, fields)
+    }
+
+    /// Record a build that failed, so that it's started ahead of others next
+    /// time.  The build's last record of a success is still what counts in
+    /// telling whether it's up to date.
+    pub fn write_failure(
+        &mut self,
+        graph: &Graph,
+        id: BuildId,
+        duration: Duration,
+        usage: ResourceUsage,
+    ) -> std::io::Result<()> {
+        let fields = BuildFields {
+            hash: BuildHash(0),
+            parts: ManifestParts::default(),
+            duration,
+            usage,
+            written: now_secs(),
+            failed: true,
+        };
+        self.write_record(graph, graph.builds[id].outs(), &[], &[], &fields)
+    }
+
+    fn write_record(
+        &mut self,
+        graph: &Graph,
+        outs: &[FileId],
+        deps: &[FileId],
+        digests: &[(FileId, InputDigest)],
+        fields: &BuildFields,
+    ) -> std::io::Result<()> {
+        if self.read_only() {
+            return Ok(());
+        }
+        if let Some(started) = self.run_mark.take() {
+            self.write_run_mark(started)?;
+        }
         let mut w = RecordWriter::default();
-        let outs = build.outs();
         let mark = (outs.len() as u16) | 0b1000_0000_0000_0000;
         w.write_u16(mark);
         for &out in outs {
@@ -139,138 +310,221 @@ impl Writer {
             w.write_id(id);
         }
 
-        let deps = build.discovered_ins();
         w.write_u16(deps.len() as u16);
         for &dep in deps {
             let id = self.ensure_id(graph, dep)?;
             w.write_id(id);
         }
 
-        w.write_u64(hash.0);
-        w.finish(&mut self.w)
+        w.write_u64(fields.hash.0);
+        w.write_u64(fields.parts.ins);
+        w.write_u64(fields.parts.discovered);
+        w.write_u64(fields.parts.cmdline);
+        w.write_u64(fields.parts.outs);
+        w.write_u32(millis_u32(fields.duration));
+        w.write_u64(fields.usage.peak_rss);
+        w.write_u32(millis_u32(fields.usage.user));
+        w.write_u32(millis_u32(fields.usage.sys));
+        w.write_u32(fields.written);
+
+        // They're counted in a u16; a build with more inputs than that goes
+        // without, as if they weren't recorded, rather than with a count
+        // that's wrapped around.
+        let digests = if digests.len() > u16::MAX as usize {
+            &[]
+        } else {
+            digests
+        };
+        w.write_u16(digests.len() as u16);
+        for &(file, digest) in digests {
+            let id = self.ensure_id(graph, file)?;
+            w.write_id(id);
+            w.write_u32(digest.digest);
+            w.write_u64(digest.mtime);
+        }
+        w.write_u8(fields.failed as u8);
+        w.finish(self.file())
     }
 }
 
+/// A db as read from disk, with files still identified by their db Ids, as
+/// needs no graph, so this can be done on another thread while the graph is
+/// loaded; see Decoded::load.
+pub struct Decoded {
+    version: u32,
+    /// How many bytes each id is written in.
+    id_bytes: usize,
+    root: Option<String>,
+    /// File names, indexed by Id.
+    names: Vec<String>,
+    records: Vec<RawRecord>,
+    /// Where each run of n2 started, as the index of its first record and
+    /// the time.
+    runs: Vec<(usize, u32)>,
+    /// The files of all records, in the ranges given by each RawRecord.
+    files: Vec<Id>,
+    digests: Vec<(Id, InputDigest)>,
+}
+
+/// A build record as read, before its files are mapped to FileIds.
+struct RawRecord {
+    /// Into Decoded::files.
+    outs: Range<usize>,
+    /// Into Decoded::files.
+    deps: Range<usize>,
+    /// Into Decoded::digests.
+    digests: Range<usize>,
+    fields: BuildFields,
+}
+
 struct Reader<'a> {
-    r: BufReader<&'a mut File>,
-    ids: IdMap,
-    graph: &'a mut Graph,
-    hashes: &'a mut Hashes,
+    buf: &'a [u8],
+    pos: usize,
+    d: Decoded,
 }
 
 impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        let buf = self.buf;
+        match buf.get(self.pos..self.pos + len) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
     fn read_u16(&mut self) -> std::io::Result<u16> {
         let mut buf: [u8; 2] = [0; 2];
-        self.r.read_exact(&mut buf[..])?;
+        buf.copy_from_slice(self.read_bytes(2)?);
         Ok(u16::from_le_bytes(buf))
     }
 
-    fn read_u24(&mut self) -> std::io::Result<u32> {
+    fn read_u32(&mut self) -> std::io::Result<u32> {
         let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..3])?;
+        buf.copy_from_slice(self.read_bytes(4)?);
         Ok(u32::from_le_bytes(buf))
     }
 
     fn read_u64(&mut self) -> std::io::Result<u64> {
         let mut buf: [u8; 8] = [0; 8];
-        self.r.read_exact(&mut buf)?;
+        buf.copy_from_slice(self.read_bytes(8)?);
         Ok(u64::from_le_bytes(buf))
     }
 
-    fn read_id(&mut self) -> std::io::Result<Id> {
-        self.read_u24().map(Id)
+    fn read_id(&mut self) -> anyhow::Result<Id> {
+        let mut buf: [u8; 8] = [0; 8];
+        buf[..self.d.id_bytes].copy_from_slice(self.read_bytes(self.d.id_bytes)?);
+        let id = u64::from_le_bytes(buf);
+        // Records only refer to files named before them.
+        match RawId::try_from(id) {
+            Ok(raw) if id < self.d.names.len() as u64 => Ok(Id(raw)),
+            _ => bail!("bad file id {}", id),
+        }
     }
 
     fn read_str(&mut self, len: usize) -> std::io::Result<String> {
-        let mut buf = vec![0; len];
-        self.r.read_exact(buf.as_mut_slice())?;
+        let buf = self.read_bytes(len)?.to_vec();
         Ok(unsafe { String::from_utf8_unchecked(buf) })
     }
 
     fn read_path(&mut self, len: usize) -> std::io::Result<()> {
         let name = self.read_str(len)?;
-        // No canonicalization needed, paths were written canonicalized.
-        let fileid = self.graph.files.id_from_canonical(name);
-        let dbid = self.ids.fileids.push(fileid);
-        self.ids.db_ids.insert(fileid, dbid);
+        self.d.names.push(name);
         Ok(())
     }
 
-    fn read_build(&mut self, len: usize) -> std::io::Result<()> {
-        // This record logs a build.  We expect all the outputs to be
-        // outputs of the same build id; if not, that means the graph has
-        // changed since this log, in which case we just ignore it.
-        //
-        // It's possible we log a build that generates files A B, then
-        // change the build file such that it only generates file A; this
-        // logic will still attach the old dependencies to A, but it
-        // shouldn't matter because the changed command line will cause us
-        // to rebuild A regardless, and these dependencies are only used
-        // to affect dirty checking, not build order.
-
-        let mut unique_bid = None;
-        let mut obsolete = false;
+    fn read_build(&mut self, len: usize) -> anyhow::Result<()> {
+        let start = self.d.files.len();
         for _ in 0..len {
-            let fileid = self.read_id()?;
-            if obsolete {
-                // Even though we know we don't want this record, we must
-                // keep reading to parse through it.
-                continue;
-            }
-            match self.graph.file(self.ids.fileids[fileid]).input {
-                None => {
-                    obsolete = true;
-                }
-                Some(bid) => {
-                    match unique_bid {
-                        None => unique_bid = Some(bid),
-                        Some(unique_bid) if unique_bid == bid => {
-                            // Ok, matches the existing id.
-                        }
-                        Some(_) => {
-                            // Mismatch.
-                            unique_bid = None;
-                            obsolete = true;
-                        }
-                    }
-                }
-            }
+            let id = self.read_id()?;
+            self.d.files.push(id);
         }
+        let outs = start..self.d.files.len();
 
         let len = self.read_u16()?;
-        let mut deps = Vec::new();
         for _ in 0..len {
             let id = self.read_id()?;
-            deps.push(self.ids.fileids[id]);
+            self.d.files.push(id);
         }
+        let deps = outs.end..self.d.files.len();
 
         let hash = BuildHash(self.read_u64()?);
+        let parts = ManifestParts {
+            ins: self.read_u64()?,
+            discovered: self.read_u64()?,
+            cmdline: self.read_u64()?,
+            outs: self.read_u64()?,
+        };
+        let duration = Duration::from_millis(self.read_u32()? as u64);
+        // Resource usage is recorded for inspecting past builds, but isn't
+        // needed to build.
+        let usage = ResourceUsage {
+            peak_rss: self.read_u64()?,
+            user: Duration::from_millis(self.read_u32()? as u64),
+            sys: Duration::from_millis(self.read_u32()? as u64),
+        };
+        let written = self.read_u32()?;
 
-        // unique_bid is set here if this record is valid.
-        if let Some(id) = unique_bid {
-            // Common case: only one associated build.
-            self.graph.builds[id].set_discovered_ins(deps);
-            self.hashes.set(id, hash);
+        let start = self.d.digests.len();
+        let len = self.read_u16()?;
+        for _ in 0..len {
+            let id = self.read_id()?;
+            let digest = self.read_u32()?;
+            let mtime = self.read_u64()?;
+            self.d.digests.push((id, InputDigest { digest, mtime }));
         }
+        let digests = start..self.d.digests.len();
+        let failed = self.read_u8()? != 0;
+
+        self.d.records.push(RawRecord {
+            outs,
+            deps,
+            digests,
+            fields: BuildFields {
+                hash,
+                parts,
+                duration,
+                usage,
+                written,
+                failed,
+            },
+        });
         Ok(())
     }
 
     fn read_signature(&mut self) -> anyhow::Result<()> {
-        let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..])?;
-        if buf.as_slice() != "n2db".as_bytes() {
+        if self.read_bytes(4)? != "n2db".as_bytes() {
             bail!("invalid db signature");
         }
-        self.r.read_exact(&mut buf[..])?;
-        let version = u32::from_le_bytes(buf);
-        if version != VERSION {
-            bail!("db version mismatch: got {version}, expected {VERSION}; TODO: db upgrades etc");
+        let version = self.read_u32()?;
+        if version > VERSION {
+            bail!("db version {version} is newer than this n2's {VERSION}");
+        }
+        self.d.version = version;
+        if version < VERSION {
+            // Nothing more of it is read.
+            return Ok(());
+        }
+        self.d.id_bytes = self.read_u8()? as usize;
+        if !(1..=8).contains(&self.d.id_bytes) {
+            bail!("bad id width {}", self.d.id_bytes);
         }
+        let len = self.read_u16()?;
+        self.d.root = Some(self.read_str(len as usize)?);
         Ok(())
     }
 
     fn read_file(&mut self) -> anyhow::Result<()> {
         self.read_signature()?;
+        if self.d.version < VERSION {
+            return Ok(());
+        }
         loop {
             let mut len = match self.read_u16() {
                 Ok(r) => r,
@@ -278,7 +532,10 @@ impl<'a> Reader<'a> {
                 Err(err) => bail!(err),
             };
             let mask = 0b1000_0000_0000_0000;
-            if len & mask == 0 {
+            if len == 0 {
+                let started = self.read_u32()?;
+                self.d.runs.push((self.d.records.len(), started));
+            } else if len & mask == 0 {
                 self.read_path(len as usize)?;
             } else {
                 len &= !mask;
@@ -287,36 +544,627 @@ impl<'a> Reader<'a> {
         }
         Ok(())
     }
+}
 
-    /// Reads an on-disk database, loading its state into the provided Graph/Hashes.
-    fn read(f: &mut File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<IdMap> {
+impl Decoded {
+    fn decode(buf: &[u8]) -> anyhow::Result<Decoded> {
         let mut r = Reader {
-            r: std::io::BufReader::new(f),
-            ids: IdMap::default(),
-            graph,
-            hashes,
+            buf,
+            pos: 0,
+            d: Decoded {
+                version: VERSION,
+                id_bytes: std::mem::size_of::<RawId>(),
+                root: None,
+                names: Vec::new(),
+                records: Vec::new(),
+                runs: Vec::new(),
+                files: Vec::new(),
+                digests: Vec::new(),
+            },
         };
         r.read_file()?;
+        Ok(r.d)
+    }
 
-        Ok(r.ids)
+    /// Map the db's files to FileIds, adding them to the graph as needed.
+    fn intern(&mut self, graph: &mut Graph) -> anyhow::Result<IdMap> {
+        let mut ids = IdMap::default();
+        for name in std::mem::take(&mut self.names) {
+            // No canonicalization needed, paths were written canonicalized.
+            let fileid = graph.files.id_from_canonical(name)?;
+            let dbid = ids.fileids.push(fileid);
+            ids.db_ids.insert(fileid, dbid);
+        }
+        Ok(ids)
+    }
+
+    /// The build a record is for, if it's still in the graph.
+    fn build_of(&self, record: &RawRecord, ids: &IdMap, graph: &Graph) -> Option<BuildId> {
+        // This record logs a build.  We expect all the outputs to be
+        // outputs of the same build id; if not, that means the graph has
+        // changed since this log, in which case we just ignore it.
+        //
+        // It's possible we log a build that generates files A B, then
+        // change the build file such that it only generates file A; this
+        // logic will still attach the old dependencies to A, but it
+        // shouldn't matter because the changed command line will cause us
+        // to rebuild A regardless, and these dependencies are only used
+        // to affect dirty checking, not build order.
+        let mut unique_bid = None;
+        for &id in &self.files[record.outs.clone()] {
+            match graph.file(ids.fileids[id]).input {
+                None => break,
+                Some(bid) => match unique_bid {
+                    None => unique_bid = Some(bid),
+                    Some(unique_bid) if unique_bid == bid => {
+                        // Ok, matches the existing id.
+                    }
+                    Some(_) => {
+                        // Mismatch.
+                        return None;
+                    }
+                },
+            }
+        }
+        unique_bid
+    }
+
+    fn files(&self, range: &Range<usize>, ids: &IdMap) -> Vec<FileId> {
+        self.files[range.clone()]
+            .iter()
+            .map(|&id| ids.fileids[id])
+            .collect()
     }
-}
 
-/// Opens or creates an on-disk database, loading its state into the provided Graph.
-pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<Writer> {
-    match std::fs::OpenOptions::new()
-        .read(true)
-        .append(true)
-        .open(path)
-    {
-        Ok(mut f) => {
-            let ids = Reader::read(&mut f, graph, hashes)?;
-            Ok(Writer::from_opened(ids, f))
+    fn digests(&self, range: &Range<usize>, ids: &IdMap) -> Vec<(FileId, InputDigest)> {
+        self.digests[range.clone()]
+            .iter()
+            .map(|&(id, digest)| (ids.fileids[id], digest))
+            .collect()
+    }
+
+    /// Load the db's state into the provided Graph/Hashes/Durations.
+    /// Returns whether it's due for compaction.
+    fn load(
+        &mut self,
+        root: &Root,
+        graph: &mut Graph,
+        hashes: &mut Hashes,
+        durations: &mut Durations,
+    ) -> anyhow::Result<(IdMap, bool)> {
+        let ids = self.intern(graph)?;
+        // The builds with records, of which later ones take precedence.
+        let mut live = HashSet::new();
+        for record in &self.records {
+            let id = match self.build_of(record, &ids, graph) {
+                Some(id) => id,
+                None => continue,
+            };
+            let fields = &record.fields;
+            live.insert(id);
+            if fields.failed {
+                durations.set_failed(id);
+                continue;
+            }
+            graph.builds[id].set_discovered_ins(self.files(&record.deps, &ids));
+            hashes.set(id, fields.hash, fields.parts, fields.written);
+            hashes.set_digests(id, self.digests(&record.digests, &ids));
+            durations.set(id, fields.duration);
         }
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-            let w = Writer::create(path)?;
-            Ok(w)
+
+        let moved = match &self.root {
+            Some(dir) if *dir != root.dir => {
+                if root.explicit {
+                    bail!("in use by the build in {}, not {}", dir, root.dir);
+                }
+                true
+            }
+            _ => false,
+        };
+        let records = self.records.len();
+        let compact = self.version != VERSION
+            || self.id_bytes != std::mem::size_of::<RawId>()
+            || moved
+            || (records >= COMPACT_MIN_RECORDS && records > COMPACT_RATIO * live.len());
+        Ok((ids, compact))
+    }
+
+    /// The db's build records, given the FileIds of its files.
+    fn records(&self, ids: &IdMap, graph: &Graph) -> Vec<Record> {
+        self.records
+            .iter()
+            .map(|record| Record {
+                build: self.build_of(record, ids, graph),
+                outs: self.files(&record.outs, ids),
+                deps: self.files(&record.deps, ids),
+                digests: self.digests(&record.digests, ids),
+                fields: record.fields.clone(),
+            })
+            .collect()
+    }
+
+    /// The runs of n2 that wrote the records, with their ranges of them.
+    fn runs(&self) -> Vec<Run> {
+        let mut runs = Vec::new();
+        for (i, &(start, started)) in self.runs.iter().enumerate() {
+            let end = self.runs.get(i + 1).map_or(self.records.len(), |r| r.0);
+            runs.push(Run {
+                started,
+                records: start..end,
+            });
         }
+        runs
+    }
+}
+
+/// Read a db from disk, as for open(); None if there's none.  This doesn't
+/// need the graph, so it can be run while that's still being loaded.
+pub fn decode(path: &Path) -> anyhow::Result<Option<Decoded>> {
+    match std::fs::read(path) {
+        Ok(buf) => Ok(Some(Decoded::decode(&buf)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Read what's in a db, as for `-t dbinfo`, without changing it.
+pub fn read_contents(path: &Path, graph: &mut Graph) -> anyhow::Result<Contents> {
+    let mut decoded = Decoded::decode(&std::fs::read(path)?)?;
+    let ids = decoded.intern(graph)?;
+    Ok(Contents {
+        root: decoded.root.take(),
+        files: ids.fileids.next_id().index(),
+        records: decoded.records(&ids, graph),
+        runs: decoded.runs(),
+    })
+}
+
+/// Rewrite the db with only the latest record for each build, dropping those
+/// for builds that are no longer in the graph once they're older than
+/// RETAIN_OBSOLETE, along with the names of files no record refers to.
+fn compact(
+    path: &Path,
+    root: &Root,
+    decoded: &Decoded,
+    ids: &IdMap,
+    graph: &mut Graph,
+) -> anyhow::Result<Writer> {
+    let records = decoded.records(ids, graph);
+
+    // The index in `records` of the latest record of a success and of a
+    // failure for each build, or for each set of outputs for those no longer
+    // in the graph.
+    type Key<'a> = (Result<BuildId, &'a [FileId]>, bool);
+    let mut latest: HashMap<Key, usize> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        latest.insert(
+            (record.build.ok_or(&record.outs[..]), record.fields.failed),
+            i,
+        );
+    }
+    // A failure only matters until the build next succeeds.
+    let mut keep: Vec<usize> = latest
+        .iter()
+        .filter(|&(&(key, failed), &i)| {
+            !failed
+                || latest
+                    .get(&(key, false))
+                    .map_or(true, |&success| success < i)
+        })
+        .map(|(_, &i)| i)
+        .collect();
+    keep.sort_unstable();
+    let cutoff = now_secs().saturating_sub(RETAIN_OBSOLETE.as_secs() as u32);
+    keep.retain(|&i| records[i].build.is_some() || records[i].fields.written >= cutoff);
+
+    // Write to the side and rename, so the db is never left half-written.
+    // The last run's records are all the latest for their builds, so its mark
+    // is kept too, for comparison with the run to come.
+    let tmp = path.with_extension("tmp");
+    let mut w = Writer::create(&tmp, &root.dir)?;
+    let mut last_run = decoded.runs.last().copied();
+    for i in keep {
+        if let Some((_, started)) = last_run.filter(|&(start, _)| i >= start) {
+            w.write_run_mark(started)?;
+            last_run = None;
+        }
+        let record = &records[i];
+        w.write_record(
+            graph,
+            &record.outs,
+            &record.deps,
+            &record.digests,
+            &record.fields,
+        )?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(w)
+}
+
+/// Opens or creates an on-disk database, given what decode() read of it,
+/// loading its state into the provided Graph.
+pub fn open(
+    path: &Path,
+    root: &Root,
+    decoded: Option<Decoded>,
+    graph: &mut Graph,
+    hashes: &mut Hashes,
+    durations: &mut Durations,
+) -> anyhow::Result<Writer> {
+    let mut w = match decoded {
+        None => Writer::create(path, &root.dir)?,
+        Some(mut decoded) => {
+            let (ids, due) = decoded.load(root, graph, hashes, durations)?;
+            if due {
+                compact(path, root, &decoded, &ids, graph)?
+            } else {
+                let f = std::fs::OpenOptions::new().append(true).open(path)?;
+                Writer::from_opened(ids, f)
+            }
+        }
+    };
+    w.run_mark = Some(now_secs());
+    Ok(w)
+}
+
+/// Like open, but the db is never created, compacted or written to: the
+/// Writer silently drops everything it's given.
+pub fn open_read_only(
+    root: &Root,
+    decoded: Option<Decoded>,
+    graph: &mut Graph,
+    hashes: &mut Hashes,
+    durations: &mut Durations,
+) -> anyhow::Result<Writer> {
+    let ids = match decoded {
+        Some(mut decoded) => decoded.load(root, graph, hashes, durations)?.0,
+        None => IdMap::default(),
+    };
+    Ok(Writer {
+        ids,
+        w: None,
+        run_mark: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load;
+
+    const MANIFEST: &str = "rule touch\n  command = touch $out\nbuild kept: touch\n";
+
+    fn fields(hash: u64, written: u32) -> BuildFields {
+        BuildFields {
+            hash: BuildHash(hash),
+            parts: ManifestParts::default(),
+            duration: Duration::ZERO,
+            usage: ResourceUsage::default(),
+            written,
+            failed: false,
+        }
+    }
+
+    #[test]
+    fn compaction() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        let open = |manifest: &str| -> anyhow::Result<(Graph, Hashes, Writer)> {
+            let mut graph = load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+            let mut hashes = Hashes::default();
+            let root = Root {
+                dir: "/build".to_owned(),
+                explicit: false,
+            };
+            let w = open(
+                &path,
+                &root,
+                decode(&path)?,
+                &mut graph,
+                &mut hashes,
+                &mut Durations::default(),
+            )?;
+            Ok((graph, hashes, w))
+        };
+
+        let (mut graph, _, mut w) = open(&format!("{}build gone: touch\n", MANIFEST))?;
+        let kept = graph
+            .file(graph.files.lookup("kept").unwrap())
+            .input
+            .unwrap();
+        let gone = graph
+            .file(graph.files.lookup("gone").unwrap())
+            .input
+            .unwrap();
+        for i in 0..COMPACT_MIN_RECORDS as u64 {
+            w.write_build(&graph, kept, &[], &fields(i, now_secs()))?;
+        }
+        w.write_build(&graph, gone, &[], &fields(0, now_secs()))?;
+        // A record of a build that's been gone for long.
+        let stale = graph.files.id_from_canonical("stale".to_owned())?;
+        w.write_record(&graph, &[stale], &[], &[], &fields(0, 0))?;
+        drop(w);
+        let before = std::fs::metadata(&path)?.len();
+
+        // Without "gone" in the manifest, its record is obsolete, but recent
+        // enough to keep.
+        let (graph, hashes, w) = open(MANIFEST)?;
+        drop(w);
+        let kept = graph
+            .file(graph.files.lookup("kept").unwrap())
+            .input
+            .unwrap();
+        assert_eq!(
+            hashes.get(kept),
+            Some(BuildHash(COMPACT_MIN_RECORDS as u64 - 1))
+        );
+        let db = std::fs::read(&path)?;
+        assert!(db.len() < before as usize / 10);
+        let contains = |name: &[u8]| db.windows(name.len()).any(|w| w == name);
+        assert!(contains(b"gone"));
+        assert!(!contains(b"stale"));
+
+        // Nothing more to compact.
+        let (_, hashes, w) = open(MANIFEST)?;
+        drop(w);
+        assert_eq!(
+            hashes.get(kept),
+            Some(BuildHash(COMPACT_MIN_RECORDS as u64 - 1))
+        );
+        assert_eq!(std::fs::read(&path)?, db);
+        Ok(())
+    }
+
+    #[test]
+    fn discard_v1() -> anyhow::Result<()> {
+        // A db as n2 first wrote it: a path and a build record with 24-bit
+        // ids and just a hash.
+        let mut w = RecordWriter::default();
+        w.write(b"n2db");
+        w.write_u32(1);
+        w.write_str("kept");
+        w.write_u16(1 | 0b1000_0000_0000_0000);
+        w.write(&[0, 0, 0]);
+        w.write_u16(0);
+        w.write_u64(7);
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        std::fs::write(&path, &w.0)?;
+
+        let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let mut hashes = Hashes::default();
+        let root = Root {
+            dir: "/build".to_owned(),
+            explicit: false,
+        };
+        let w = open(
+            &path,
+            &root,
+            decode(&path)?,
+            &mut graph,
+            &mut hashes,
+            &mut Durations::default(),
+        )?;
+        drop(w);
+        let kept = graph
+            .file(graph.files.lookup("kept").unwrap())
+            .input
+            .unwrap();
+        assert_eq!(hashes.get(kept), None);
+
+        // It's rewritten empty, as the current version.
+        let decoded = decode(&path)?.unwrap();
+        assert_eq!(decoded.version, VERSION);
+        assert!(decoded.records.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn failures() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        let open = || -> anyhow::Result<(Graph, BuildId, Hashes, Durations, Writer)> {
+            let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+            let mut hashes = Hashes::default();
+            let mut durations = Durations::default();
+            let root = Root {
+                dir: "/build".to_owned(),
+                explicit: false,
+            };
+            let w = open(
+                &path,
+                &root,
+                decode(&path)?,
+                &mut graph,
+                &mut hashes,
+                &mut durations,
+            )?;
+            let kept = graph
+                .file(graph.files.lookup("kept").unwrap())
+                .input
+                .unwrap();
+            Ok((graph, kept, hashes, durations, w))
+        };
+
+        let (graph, kept, _, _, mut w) = open()?;
+        for i in 0..COMPACT_MIN_RECORDS as u64 {
+            w.write_build(&graph, kept, &[], &fields(i, now_secs()))?;
+        }
+        w.write_failure(&graph, kept, Duration::ZERO, ResourceUsage::default())?;
+        drop(w);
+
+        // The failure survives compaction, and leaves the last success as
+        // what the build is judged by.
+        for _ in 0..2 {
+            let (_, kept, hashes, durations, w) = open()?;
+            drop(w);
+            assert!(durations.failed(kept));
+            assert_eq!(
+                hashes.get(kept),
+                Some(BuildHash(COMPACT_MIN_RECORDS as u64 - 1))
+            );
+        }
+
+        let (graph, kept, _, _, mut w) = open()?;
+        w.write_build(&graph, kept, &[], &fields(0, now_secs()))?;
+        drop(w);
+        let (_, kept, hashes, durations, _) = open()?;
+        assert!(!durations.failed(kept));
+        assert_eq!(hashes.get(kept), Some(BuildHash(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn digest_count() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        let open = || -> anyhow::Result<(Graph, BuildId, Hashes, Writer)> {
+            let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+            let mut hashes = Hashes::default();
+            let root = Root {
+                dir: "/build".to_owned(),
+                explicit: false,
+            };
+            let w = open(
+                &path,
+                &root,
+                decode(&path)?,
+                &mut graph,
+                &mut hashes,
+                &mut Durations::default(),
+            )?;
+            let kept = graph
+                .file(graph.files.lookup("kept").unwrap())
+                .input
+                .unwrap();
+            Ok((graph, kept, hashes, w))
+        };
+
+        // As many digests as fit are kept, and one more than that, none.
+        for (count, kept_count) in [
+            (u16::MAX as usize, u16::MAX as usize),
+            (u16::MAX as usize + 1, 0),
+        ] {
+            let (mut graph, kept, _, mut w) = open()?;
+            let digests: Vec<(FileId, InputDigest)> = (0..count)
+                .map(|i| {
+                    let digest = InputDigest {
+                        digest: i as u32,
+                        mtime: 1000 + i as u64,
+                    };
+                    (
+                        graph.files.id_from_canonical(format!("in{}", i)).unwrap(),
+                        digest,
+                    )
+                })
+                .collect();
+            w.write_build(&graph, kept, &digests, &fields(0, now_secs()))?;
+            drop(w);
+            let (_, kept, hashes, _) = open()?;
+            let read = hashes.get_digests(kept).unwrap_or_default();
+            assert_eq!(read.len(), kept_count);
+            if let Some((_, last)) = read.last() {
+                assert_eq!(*last, digests[kept_count - 1].1);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn runs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        let open = || -> anyhow::Result<(Graph, BuildId, Writer)> {
+            let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+            let root = Root {
+                dir: "/build".to_owned(),
+                explicit: false,
+            };
+            let w = open(
+                &path,
+                &root,
+                decode(&path)?,
+                &mut graph,
+                &mut Hashes::default(),
+                &mut Durations::default(),
+            )?;
+            let kept = graph
+                .file(graph.files.lookup("kept").unwrap())
+                .input
+                .unwrap();
+            Ok((graph, kept, w))
+        };
+        let runs = || -> anyhow::Result<Vec<Range<usize>>> {
+            let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+            let contents = read_contents(&path, &mut graph)?;
+            Ok(contents.runs.into_iter().map(|run| run.records).collect())
+        };
+
+        // Enough records to be compacted next time.
+        let (graph, kept, mut w) = open()?;
+        for i in 0..COMPACT_MIN_RECORDS as u64 {
+            w.write_build(&graph, kept, &[], &fields(i, now_secs()))?;
+        }
+        drop(w);
+        assert_eq!(runs()?, vec![0..COMPACT_MIN_RECORDS]);
+
+        // A run that records nothing leaves no mark.  Compaction keeps the
+        // last run's, for its remaining record.
+        drop(open()?);
+        assert_eq!(runs()?, vec![0..1]);
+
+        // Reopened within the one run, the db is marked the once.
+        let (graph, kept, mut w) = open()?;
+        w.write_build(&graph, kept, &[], &fields(0, now_secs()))?;
+        let (graph, kept, mut reopened) = open()?;
+        reopened.continue_run(w);
+        reopened.write_build(&graph, kept, &[], &fields(0, now_secs()))?;
+        drop(reopened);
+        assert_eq!(runs()?, vec![0..1, 1..3]);
+
+        let (graph, kept, mut w) = open()?;
+        w.write_build(&graph, kept, &[], &fields(0, now_secs()))?;
+        w.write_failure(&graph, kept, Duration::ZERO, ResourceUsage::default())?;
+        drop(w);
+        assert_eq!(runs()?, vec![0..1, 1..3, 3..5]);
+        Ok(())
+    }
+
+    #[test]
+    fn root_mismatch() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        // Returns the hash recorded for the build by the previous open.
+        let open = |dir: &str, explicit: bool| -> anyhow::Result<Option<BuildHash>> {
+            let mut graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+            let mut hashes = Hashes::default();
+            let root = Root {
+                dir: dir.to_owned(),
+                explicit,
+            };
+            let mut w = open(
+                &path,
+                &root,
+                decode(&path)?,
+                &mut graph,
+                &mut hashes,
+                &mut Durations::default(),
+            )?;
+            let kept = graph
+                .file(graph.files.lookup("kept").unwrap())
+                .input
+                .unwrap();
+            w.write_build(&graph, kept, &[], &fields(1, now_secs()))?;
+            Ok(hashes.get(kept))
+        };
+
+        assert_eq!(open("/a", true)?, None);
+        let err = open("/b", true).err().unwrap();
+        assert_eq!(err.to_string(), "in use by the build in /a, not /b");
+        // At the default location, the build is assumed to have moved, and
+        // its state is kept.
+        assert_eq!(open("/b", false)?, Some(BuildHash(1)));
+        assert_eq!(open("/b", true)?, Some(BuildHash(1)));
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! `-t dbinfo`: what the db holds, for working out why n2 thinks something
+//! changed.
+
+use crate::{db, graph::Graph, load, progress::format_duration};
+use anyhow::anyhow;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::time::Duration;
+
+/// Print statistics about the db, then what it last recorded for each of
+/// the targets.
+pub fn run(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+) -> anyhow::Result<i32> {
+    let mut manifest = load::read_manifest(build_filenames, options)?;
+    let path = &manifest.db_path;
+    let size = match std::fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            println!("no db at {}", path.display());
+            return Ok(1);
+        }
+        Err(err) => return Err(anyhow!("{}: {}", path.display(), err)),
+    };
+    let contents = db::read_contents(path, &mut manifest.graph)
+        .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    let graph = &manifest.graph;
+
+    let records = contents.records.len();
+    let live = contents
+        .records
+        .iter()
+        .filter_map(|record| record.build)
+        .collect::<HashSet<_>>()
+        .len();
+    let deps: usize = contents.records.iter().map(|r| r.deps.len()).sum();
+    println!("path: {}", path.display());
+    if let Some(root) = &contents.root {
+        println!("for the build in: {}", root);
+    }
+    println!("size: {} bytes", size);
+    println!("files: {}", contents.files);
+    println!("build records: {}", records);
+    println!("  for builds in the manifest: {}", live);
+    println!(
+        "  superseded or obsolete: {} ({:.0}%)",
+        records - live,
+        percent(records - live, records)
+    );
+    println!("discovered deps: {}", deps);
+
+    for name in targets {
+        println!();
+        print_target(graph, &contents, name)?;
+    }
+    Ok(0)
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// How long ago a record was written, for display.
+fn age(written: u32) -> String {
+    let secs = db::now_secs().saturating_sub(written);
+    format_duration(Duration::from_secs(secs as u64))
+}
+
+fn print_target(graph: &Graph, contents: &db::Contents, name: &OsStr) -> anyhow::Result<()> {
+    let id = graph.lookup_target(name)?;
+    println!("{}:", name.to_string_lossy());
+    let input = graph.file(id).input;
+    match input {
+        Some(bid) => println!("  build: {}", graph.builds[bid].location),
+        None => println!("  build: none in the manifest"),
+    }
+    let mut records = contents
+        .records
+        .iter()
+        .rev()
+        .filter(|r| r.outs.contains(&id))
+        .peekable();
+    if let Some(failure) = records.next_if(|r| r.fields.failed) {
+        println!("  failed: {} ago", age(failure.fields.written));
+    }
+    let record = match records.find(|r| !r.fields.failed) {
+        Some(record) => record,
+        None => {
+            println!("  no record");
+            return Ok(());
+        }
+    };
+    let fields = &record.fields;
+    println!("  recorded: {} ago", age(fields.written));
+    if record.build.is_none() || record.build != input {
+        println!("  (for a build that's no longer in the manifest)");
+    }
+    println!("  hash: {:016x}", fields.hash.0);
+    println!("    ins: {:016x}", fields.parts.ins);
+    println!("    discovered: {:016x}", fields.parts.discovered);
+    println!("    cmdline: {:016x}", fields.parts.cmdline);
+    println!("    outs: {:016x}", fields.parts.outs);
+    println!("  outs:");
+    for &out in &record.outs {
+        println!("    {}", graph.file(out).name);
+    }
+    println!("  discovered deps:");
+    for &dep in &record.deps {
+        println!("    {}", graph.file(dep).name);
+    }
+    if !record.digests.is_empty() {
+        println!("  input digests: {}", record.digests.len());
+    }
+    println!("  duration: {:?}", fields.duration);
+    println!(
+        "  usage: peak rss {} bytes, user {:?}, sys {:?}",
+        fields.usage.peak_rss, fields.usage.user, fields.usage.sys
+    );
+    Ok(())
+}
@@ -41,6 +41,10 @@ impl<K: Index, V> DenseMap<K, V> {
         self.vec.get(k.index())
     }
 
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
     pub fn next_id(&self) -> K {
         K::from(self.vec.len())
     }
@@ -6,6 +6,7 @@ use rustc_hash::FxHashMap;
 use crate::smallmap::SmallMap;
 use std::borrow::Borrow;
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// An environment providing a mapping of variable name to variable value.
 /// This represents one "frame" of evaluation context, a given EvalString may
@@ -19,6 +20,110 @@ pub trait Env {
 pub enum EvalPart<T: AsRef<str>> {
     Literal(T),
     VarRef(T),
+    /// A function of `pragma functions` applied to a variable, as the text
+    /// of the call, e.g. `replace(in, .c, .o)`; see Call.
+    Call(T),
+}
+
+/// The functions that `pragma functions` enables.  Each applies to every
+/// space-separated path of a variable's value, so that `$in` and `$out` with
+/// more than one file work as they should.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Func {
+    /// The path with any directory removed.
+    Basename,
+    /// The directory of the path, or `.` if it has none.
+    Dirname,
+    /// The path with every occurrence of the first argument replaced with the
+    /// second.
+    Replace,
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<Func> {
+        Some(match name {
+            "basename" => Func::Basename,
+            "dirname" => Func::Dirname,
+            "replace" => Func::Replace,
+            _ => return None,
+        })
+    }
+
+    /// How many arguments the function takes after the variable.
+    fn arg_count(self) -> usize {
+        match self {
+            Func::Basename | Func::Dirname => 0,
+            Func::Replace => 2,
+        }
+    }
+
+    fn apply_path(self, path: &str, args: &[&str]) -> String {
+        match self {
+            Func::Basename => match path.rfind('/') {
+                Some(slash) => path[slash + 1..].to_owned(),
+                None => path.to_owned(),
+            },
+            Func::Dirname => match path.rfind('/') {
+                Some(0) => "/".to_owned(),
+                Some(slash) => path[..slash].to_owned(),
+                None => ".".to_owned(),
+            },
+            Func::Replace if args[0].is_empty() => path.to_owned(),
+            Func::Replace => path.replace(args[0], args[1]),
+        }
+    }
+
+    pub fn apply(self, value: &str, args: &[&str]) -> String {
+        let mut result = String::with_capacity(value.len());
+        for (i, path) in value.split(' ').enumerate() {
+            if i > 0 {
+                result.push(' ');
+            }
+            if !path.is_empty() {
+                result.push_str(&self.apply_path(path, args));
+            }
+        }
+        result
+    }
+}
+
+/// A call of a function, as in `$basename(out)` or `$replace(in, .c, .o)`:
+/// the function, the name of the variable it applies to, and any further
+/// arguments, which are taken literally with surrounding spaces trimmed.
+#[derive(Debug, PartialEq)]
+pub struct Call<'a> {
+    pub func: Func,
+    pub var: &'a str,
+    pub args: Vec<&'a str>,
+}
+
+impl<'a> Call<'a> {
+    /// Parse the text of an EvalPart::Call, like `basename(out)`.
+    pub fn parse(text: &'a str) -> Result<Call<'a>, String> {
+        let (name, rest) = text
+            .split_once('(')
+            .ok_or_else(|| format!("expected ( in {:?}", text))?;
+        let func = Func::from_name(name).ok_or_else(|| format!("unknown function {:?}", name))?;
+        let inner = rest
+            .strip_suffix(')')
+            .ok_or_else(|| format!("expected ) in {:?}", text))?;
+        let mut args = inner.split(',').map(str::trim);
+        let var = args.next().unwrap_or_default();
+        if var.is_empty() {
+            return Err(format!("{} needs a variable name", name));
+        }
+        let args: Vec<&str> = args.collect();
+        if args.len() != func.arg_count() {
+            return Err(format!(
+                "{} takes {} argument{} after the variable, not {}",
+                name,
+                func.arg_count(),
+                if func.arg_count() == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        Ok(Call { func, var, args })
+    }
 }
 
 /// A parsed but unexpanded variable-reference string, e.g. "cc $in -o $out".
@@ -32,18 +137,16 @@ impl<T: AsRef<str>> EvalString<T> {
         EvalString(parts)
     }
 
+    pub fn parts(&self) -> &[EvalPart<T>] {
+        &self.0
+    }
+
     fn evaluate_inner(&self, result: &mut String, envs: &[&dyn Env]) {
         for part in &self.0 {
             match part {
                 EvalPart::Literal(s) => result.push_str(s.as_ref()),
-                EvalPart::VarRef(v) => {
-                    for (i, env) in envs.iter().enumerate() {
-                        if let Some(v) = env.get_var(v.as_ref()) {
-                            v.evaluate_inner(result, &envs[i + 1..]);
-                            break;
-                        }
-                    }
-                }
+                EvalPart::VarRef(v) => evaluate_var(v.as_ref(), result, envs),
+                EvalPart::Call(c) => result.push_str(&evaluate_call(c.as_ref(), envs)),
             }
         }
     }
@@ -61,6 +164,7 @@ impl<T: AsRef<str>> EvalString<T> {
                     }
                     0
                 }
+                EvalPart::Call(c) => evaluate_call(c.as_ref(), envs).len(),
             })
             .sum()
     }
@@ -78,6 +182,23 @@ impl<T: AsRef<str>> EvalString<T> {
     }
 }
 
+fn evaluate_var(var: &str, result: &mut String, envs: &[&dyn Env]) {
+    for (i, env) in envs.iter().enumerate() {
+        if let Some(v) = env.get_var(var) {
+            v.evaluate_inner(result, &envs[i + 1..]);
+            break;
+        }
+    }
+}
+
+fn evaluate_call(text: &str, envs: &[&dyn Env]) -> String {
+    // The parser only makes a Call of text that parses.
+    let call = Call::parse(text).unwrap();
+    let mut value = String::new();
+    evaluate_var(call.var, &mut value, envs);
+    call.func.apply(&value, &call.args)
+}
+
 impl EvalString<&str> {
     pub fn into_owned(self) -> EvalString<String> {
         EvalString(
@@ -86,6 +207,7 @@ impl EvalString<&str> {
                 .map(|part| match part {
                     EvalPart::Literal(s) => EvalPart::Literal(s.to_owned()),
                     EvalPart::VarRef(s) => EvalPart::VarRef(s.to_owned()),
+                    EvalPart::Call(s) => EvalPart::Call(s.to_owned()),
                 })
                 .collect(),
         )
@@ -100,6 +222,7 @@ impl EvalString<String> {
                 .map(|part| match part {
                     EvalPart::Literal(s) => EvalPart::Literal(Cow::Borrowed(s.as_ref())),
                     EvalPart::VarRef(s) => EvalPart::VarRef(Cow::Borrowed(s.as_ref())),
+                    EvalPart::Call(s) => EvalPart::Call(Cow::Borrowed(s.as_ref())),
                 })
                 .collect(),
         )
@@ -114,22 +237,37 @@ impl EvalString<&str> {
                 .map(|part| match part {
                     EvalPart::Literal(s) => EvalPart::Literal(Cow::Borrowed(*s)),
                     EvalPart::VarRef(s) => EvalPart::VarRef(Cow::Borrowed(*s)),
+                    EvalPart::Call(s) => EvalPart::Call(Cow::Borrowed(*s)),
                 })
                 .collect(),
         )
     }
 }
 
+/// Source of Vars::generation.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
 /// A single scope's worth of variable definitions.
 #[derive(Debug, Default)]
-pub struct Vars<'text>(FxHashMap<&'text str, String>);
+pub struct Vars<'text> {
+    vars: FxHashMap<&'text str, String>,
+    generation: u64,
+}
 
 impl<'text> Vars<'text> {
     pub fn insert(&mut self, key: &'text str, val: String) {
-        self.0.insert(key, val);
+        self.vars.insert(key, val);
+        self.generation = GENERATION.fetch_add(1, Ordering::Relaxed);
     }
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
+        self.vars.get(key)
+    }
+
+    /// Identifies the definitions in this scope, for caching what's derived
+    /// from them: it changes with every insert, and no two scopes share one
+    /// unless both are empty.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 }
 impl<'a> Env for Vars<'a> {
@@ -0,0 +1,190 @@
+//! `-t flamegraph`: the recorded durations of builds laid out along the
+//! dependency chains of the targets, in the "collapsed stack" format of
+//! flamegraph.pl, which speedscope and others read too.
+//!
+//! Each line is a build's own duration in milliseconds, under the stack of
+//! builds from a target down to it.  The dependencies of a build form a graph
+//! rather than a tree, so each build is counted once, under the first build
+//! found to depend on it, with the dependencies on the longest chains tried
+//! first.  That way the critical path shows up as the widest stack.
+
+use crate::{
+    db,
+    densemap::DenseMap,
+    graph::{BuildId, Graph},
+    load,
+};
+use anyhow::{anyhow, bail};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::time::Duration;
+
+pub fn run(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+) -> anyhow::Result<i32> {
+    let mut manifest = load::read_manifest(build_filenames, options)?;
+    let path = &manifest.db_path;
+    if !path.exists() {
+        bail!("no db at {}, so no recorded durations", path.display());
+    }
+    let contents = db::read_contents(path, &mut manifest.graph)
+        .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    let graph = &manifest.graph;
+
+    // Records are oldest first, so the last one for a build wins.
+    let mut durations = HashMap::new();
+    for record in &contents.records {
+        if let Some(id) = record.build.filter(|_| !record.fields.failed) {
+            durations.insert(id, record.fields.duration);
+        }
+    }
+
+    let roots: Vec<BuildId> = if !targets.is_empty() {
+        let mut roots = Vec::new();
+        for name in targets {
+            let id = graph.lookup_target(name)?;
+            roots.extend(graph.file(id).input);
+        }
+        roots
+    } else if !manifest.default.is_empty() {
+        manifest
+            .default
+            .iter()
+            .filter_map(|&id| graph.file(id).input)
+            .collect()
+    } else {
+        // As when building everything: the outputs nothing else needs.
+        graph
+            .files
+            .all_ids()
+            .filter(|&id| graph.file(id).dependents.is_empty())
+            .filter_map(|id| graph.file(id).input)
+            .collect()
+    };
+
+    let stdout = std::io::stdout();
+    let mut w = std::io::BufWriter::new(stdout.lock());
+    write_stacks(&mut w, graph, &durations, &roots)?;
+    w.flush()?;
+    Ok(0)
+}
+
+/// The builds a build waits for.
+fn inputs(graph: &Graph, id: BuildId) -> impl Iterator<Item = BuildId> + '_ {
+    graph.builds[id]
+        .ordering_ins()
+        .iter()
+        .filter_map(move |&file| graph.file(file).input)
+}
+
+/// For each build under the roots, the longest total duration of a chain of
+/// builds from it down, itself included.
+fn chains(
+    graph: &Graph,
+    durations: &HashMap<BuildId, Duration>,
+    roots: &[BuildId],
+) -> DenseMap<BuildId, Option<Duration>> {
+    let mut chains = DenseMap::new_sized(graph.builds.next_id(), None);
+    // Visit each build after its inputs, without recursing as deep as the
+    // graph is.
+    let mut stack: Vec<(BuildId, bool)> = roots.iter().map(|&id| (id, false)).collect();
+    while let Some((id, inputs_done)) = stack.pop() {
+        if chains[id].is_some() {
+            continue;
+        }
+        if !inputs_done {
+            stack.push((id, true));
+            stack.extend(
+                inputs(graph, id)
+                    .filter(|&input| chains[input].is_none())
+                    .map(|input| (input, false)),
+            );
+            continue;
+        }
+        let longest = inputs(graph, id)
+            .filter_map(|input| chains[input])
+            .max()
+            .unwrap_or_default();
+        let own = durations.get(&id).copied().unwrap_or_default();
+        chains[id] = Some(own + longest);
+    }
+    chains
+}
+
+/// Write a line for each build with a recorded duration; see the module doc.
+fn write_stacks(
+    w: &mut dyn Write,
+    graph: &Graph,
+    durations: &HashMap<BuildId, Duration>,
+    roots: &[BuildId],
+) -> std::io::Result<()> {
+    let chains = chains(graph, durations, roots);
+    // Frames are named for the build's first output; `;` separates them.
+    let frame = |id: BuildId| match graph.builds[id].outs().first() {
+        Some(&out) => graph.file(out).name.replace(';', "_"),
+        None => String::from("?"),
+    };
+    let mut seen = DenseMap::new_sized(graph.builds.next_id(), false);
+    // Popped from the end, so pushed in reverse of the order to visit.
+    let mut stack: Vec<(BuildId, String)> = Vec::new();
+    for &root in roots.iter().rev() {
+        stack.push((root, frame(root)));
+    }
+    while let Some((id, path)) = stack.pop() {
+        if seen[id] {
+            continue;
+        }
+        seen[id] = true;
+        let ms = durations.get(&id).map_or(0, |d| d.as_millis());
+        if ms > 0 {
+            writeln!(w, "{} {}", path, ms)?;
+        }
+        let mut next: Vec<BuildId> = inputs(graph, id).filter(|&input| !seen[input]).collect();
+        next.sort_by_key(|&input| (chains[input], input));
+        next.dedup();
+        for input in next {
+            stack.push((input, format!("{};{}", path, frame(input))));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc
+build gen: cc
+build a: cc gen
+build b: cc gen
+build all: phony a b
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let id = |name: &str| {
+            let file = graph.files.lookup(name).unwrap();
+            graph.file(file).input.unwrap()
+        };
+        let ms = Duration::from_millis;
+        let durations: HashMap<BuildId, Duration> =
+            vec![(id("gen"), ms(5)), (id("a"), ms(10)), (id("b"), ms(30))]
+                .into_iter()
+                .collect();
+
+        let mut out = Vec::new();
+        write_stacks(&mut out, &graph, &durations, &[id("all")])?;
+        // b is on the longer chain, so gen is counted under it.
+        assert_eq!(String::from_utf8(out)?, "all;b 30\nall;b;gen 5\nall;a 10\n");
+        Ok(())
+    }
+}
@@ -3,16 +3,53 @@
 use rustc_hash::FxHashMap;
 
 use crate::{
+    canon,
     densemap::{self, DenseMap},
-    hash::BuildHash,
+    hash::{BuildHash, InputDigest, ManifestParts},
 };
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+/// The integer underlying FileId and BuildId.  32 bits keep the graph
+/// compact; the `wide-ids` feature makes it 64, for graphs of more than 4
+/// billion files or builds.
+#[cfg(not(feature = "wide-ids"))]
+pub type RawId = u32;
+#[cfg(feature = "wide-ids")]
+pub type RawId = u64;
+
+/// Fail if there are already as many of what as there are ids for, before
+/// another is added, so that raw_id needn't.
+fn check_room(count: usize, what: &str) -> anyhow::Result<()> {
+    if count as u64 >= RawId::MAX as u64 {
+        anyhow::bail!(
+            "more than {} {} in the graph; build n2 with the wide-ids feature for more",
+            RawId::MAX,
+            what
+        );
+    }
+    Ok(())
+}
+
+/// Convert the index of a new file or build to a RawId, which fails loudly
+/// rather than wrapping around to the id of another.
+fn raw_id(index: usize, what: &str) -> RawId {
+    match RawId::try_from(index) {
+        Ok(id) => id,
+        Err(_) => panic!(
+            "more than {} {} in the graph; build n2 with the wide-ids feature for more",
+            RawId::MAX,
+            what
+        ),
+    }
+}
 
 /// Id for File nodes in the Graph.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-pub struct FileId(u32);
+pub struct FileId(RawId);
 impl densemap::Index for FileId {
     fn index(&self) -> usize {
         self.0 as usize
@@ -20,13 +57,13 @@ impl densemap::Index for FileId {
 }
 impl From<usize> for FileId {
     fn from(u: usize) -> FileId {
-        FileId(u as u32)
+        FileId(raw_id(u, "files"))
     }
 }
 
 /// Id for Build nodes in the Graph.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-pub struct BuildId(u32);
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BuildId(RawId);
 impl densemap::Index for BuildId {
     fn index(&self) -> usize {
         self.0 as usize
@@ -34,7 +71,7 @@ impl densemap::Index for BuildId {
 }
 impl From<usize> for BuildId {
     fn from(u: usize) -> BuildId {
-        BuildId(u as u32)
+        BuildId(raw_id(u, "builds"))
     }
 }
 
@@ -58,7 +95,7 @@ impl File {
 /// A textual location within a build.ninja file, used in error messages.
 #[derive(Debug)]
 pub struct FileLoc {
-    pub filename: std::rc::Rc<PathBuf>,
+    pub filename: std::sync::Arc<PathBuf>,
     pub line: usize,
 }
 impl std::fmt::Display for FileLoc {
@@ -112,6 +149,16 @@ impl BuildOuts {
         }
         self.ids = ids;
     }
+
+    /// Remove the given files from the output list.
+    pub fn remove(&mut self, remove: &[FileId]) {
+        let explicit_removed = self.ids[..self.explicit]
+            .iter()
+            .filter(|id| remove.contains(id))
+            .count();
+        self.explicit -= explicit_removed;
+        self.ids.retain(|id| !remove.contains(id));
+    }
 }
 
 #[cfg(test)]
@@ -121,6 +168,16 @@ mod tests {
     }
 
     use super::*;
+    #[test]
+    fn fnv_hasher() {
+        use std::hash::Hasher;
+        // Reference values of 64-bit FNV-1a.
+        assert_eq!(FnvHasher::default().finish(), 0xcbf29ce484222325);
+        let mut hasher = FnvHasher::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+
     #[test]
     fn remove_dups_explicit() {
         let mut outs = BuildOuts {
@@ -142,6 +199,87 @@ mod tests {
         assert_eq!(outs.ids, fileids(vec![1, 2]));
         assert_eq!(outs.explicit, 2);
     }
+
+    #[test]
+    fn remove_outs() {
+        let mut outs = BuildOuts {
+            ids: fileids(vec![1, 2, 3, 4]),
+            explicit: 2,
+        };
+        outs.remove(&fileids(vec![2, 3]));
+        assert_eq!(outs.ids, fileids(vec![1, 4]));
+        assert_eq!(outs.explicit, 1);
+    }
+
+    #[test]
+    fn out_of_ids() {
+        assert!(check_room(0, "files").is_ok());
+        let err = check_room(RawId::MAX as usize, "files").unwrap_err();
+        assert!(err.to_string().contains("wide-ids"));
+    }
+
+    #[test]
+    fn fold_case() {
+        let mut files = GraphFiles::default();
+        files.fold_case = true;
+        let id = files.id_from_canonical("include/Foo.h".to_owned()).unwrap();
+        assert_eq!(
+            files.id_from_canonical("include/foo.h".to_owned()).unwrap(),
+            id
+        );
+        assert_eq!(files.lookup("INCLUDE/FOO.H"), Some(id));
+        assert_eq!(files.by_id[id].name, "include/Foo.h");
+    }
+
+    #[test]
+    fn similar_targets() {
+        assert_eq!(edit_distance("libfoo.a", "libfoo.a", 2), Some(0));
+        assert_eq!(edit_distance("libfo.a", "libfoo.so", 3), Some(3));
+        assert_eq!(edit_distance("libfo.a", "libfoo.so", 2), None);
+        assert_eq!(edit_distance("", "ab", 2), Some(2));
+
+        let mut graph = Graph::default();
+        for (name, built) in [
+            ("src/foo.c", false),
+            ("out/foo.o", true),
+            ("out/bar.o", true),
+            ("out/foobar.o", true),
+            ("all", true),
+            ("libfoo.a", true),
+        ] {
+            let id = graph.files.id_from_canonical(name.to_owned()).unwrap();
+            if built {
+                graph.files.by_id[id].input = Some(BuildId(0));
+            }
+        }
+        assert_eq!(graph.similar_targets("out/fo.o"), ["out/foo.o"]);
+        assert_eq!(
+            graph.similar_targets("out/baz.o"),
+            ["out/bar.o", "out/foo.o"]
+        );
+        assert_eq!(graph.similar_targets("al"), ["all"]);
+        assert_eq!(graph.similar_targets("lib"), ["libfoo.a"]);
+        // Sources aren't targets.
+        assert!(graph.similar_targets("src/foo.h").is_empty());
+
+        let err = graph
+            .lookup_target(OsStr::new("out/fo.o"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "unknown path requested: \"out/fo.o\", did you mean \"out/foo.o\"?"
+        );
+    }
+
+    #[cfg(all(target_pointer_width = "64", not(feature = "wide-ids")))]
+    #[test]
+    #[should_panic(expected = "more than 4294967295 files")]
+    fn ids_overflow() {
+        let last = FileId::from(u32::MAX as usize);
+        assert_eq!(last, FileId(u32::MAX));
+        FileId::from(u32::MAX as usize + 1);
+    }
 }
 
 /// A single build action, generating File outputs from File inputs with a command.
@@ -149,6 +287,9 @@ pub struct Build {
     /// Source location this Build was declared.
     pub location: FileLoc,
 
+    /// Name of the rule the build uses, for reporting.
+    pub rule: String,
+
     /// User-provided description of the build step.
     pub desc: Option<String>,
 
@@ -167,6 +308,62 @@ pub struct Build {
     /// Pool to execute this build in, if any.
     pub pool: Option<String>,
 
+    /// If true, run the command with its output attached to a pseudo-terminal
+    /// rather than a pipe (Unix only).
+    pub pty: bool,
+
+    /// If true, run the command without network access (`network = none`),
+    /// so a build meant to be hermetic that reaches for the network fails
+    /// rather than quietly depending on it; see process::isolate_network.
+    pub no_network: bool,
+
+    /// Environment variables to set for the command, from `env_NAME = value`
+    /// bindings.  Sorted by name.
+    pub env: Vec<(String, String)>,
+
+    /// Information about the build for tools, such as the team that owns it,
+    /// from `meta_NAME = value` bindings.  Sorted by name.  It has no effect
+    /// on the build itself.
+    pub meta: Vec<(String, String)>,
+
+    /// Program to run the command under, from the `wrapper` variable.
+    /// Overrides the --wrap flag; an empty value disables wrapping.
+    pub wrapper: Option<String>,
+
+    /// Shell to run the command with in place of the platform's default,
+    /// from the `shell` variable, split into words.  Empty to run the command
+    /// without a shell.
+    pub shell: Option<Vec<String>>,
+
+    /// Memory the command needs in bytes, from the `mem` variable.  Builds
+    /// are only started while their needs fit in work::Options::mem_limit.
+    pub mem: u64,
+
+    /// Cores the command keeps busy, from the `cores` variable.  Builds are
+    /// only started while their cores fit in the -j parallelism.
+    pub cores: usize,
+
+    /// Lines of the command's output to hide, from the `filter_output`
+    /// variable.
+    pub filter_output: Option<regex::bytes::Regex>,
+
+    /// If true, the command succeeding without creating all its outputs isn't
+    /// an error.
+    pub allow_missing_outputs: bool,
+
+    /// If true, $out in the command refers to staging paths next to the
+    /// explicit outputs, which are moved into place only if the command
+    /// succeeds, as are any other outputs it names after them; see
+    /// staged_path.  So an interrupted command can't leave behind
+    /// half-written outputs.
+    pub atomic_outputs: bool,
+
+    /// If true, the build regenerates build files (`generator = 1`).
+    pub generator: bool,
+
+    /// If true, the build might leave its outputs untouched (`restat = 1`).
+    pub restat: bool,
+
     pub ins: BuildIns,
 
     /// Additional inputs discovered from a previous build.
@@ -179,12 +376,26 @@ impl Build {
     pub fn new(loc: FileLoc, ins: BuildIns, outs: BuildOuts) -> Self {
         Build {
             location: loc,
+            rule: String::new(),
             desc: None,
             cmdline: None,
             depfile: None,
             parse_showincludes: false,
             rspfile: None,
             pool: None,
+            pty: false,
+            no_network: false,
+            env: Vec::new(),
+            meta: Vec::new(),
+            wrapper: None,
+            shell: None,
+            mem: 0,
+            cores: 1,
+            filter_output: None,
+            allow_missing_outputs: false,
+            atomic_outputs: false,
+            generator: false,
+            restat: false,
             ins,
             discovered_ins: Vec::new(),
             outs,
@@ -236,8 +447,28 @@ impl Build {
     pub fn outs(&self) -> &[FileId] {
         &self.outs.ids
     }
+
+    /// With atomic_outputs, the path the command writes the file named name
+    /// at before it's moved into place: for an explicit output, its staging
+    /// path, as $out refers to, and for a file named after one, as `$out.d`
+    /// or `${out}.pdb` name them, the same name made from the staging path.
+    /// None for any other file, which the command writes in place.
+    pub fn staged_path(&self, files: &GraphFiles, name: &str) -> Option<PathBuf> {
+        let out = self
+            .explicit_outs()
+            .iter()
+            .map(|&id| files.by_id[id].name.as_str())
+            .filter(|out| name.starts_with(out))
+            .max_by_key(|out| out.len())?;
+        let (_, rest) = name.split_at(out.len());
+        Some(PathBuf::from(format!("{}{}{}", out, STAGING_SUFFIX, rest)))
+    }
 }
 
+/// Appended to the names of outputs to get their staging paths; see
+/// Build::atomic_outputs.
+pub const STAGING_SUFFIX: &str = ".n2tmp";
+
 /// The build graph: owns Files/Builds and maps FileIds/BuildIds to them.
 #[derive(Default)]
 pub struct Graph {
@@ -251,6 +482,10 @@ pub struct Graph {
 pub struct GraphFiles {
     pub by_id: DenseMap<FileId, File>,
     by_name: FxHashMap<String, FileId>,
+    /// If true, names that differ only in case refer to the same file, as on
+    /// case-insensitive filesystems.  Files keep the spelling they were first
+    /// seen with.  Must be set before any files are added.
+    pub fold_case: bool,
 }
 
 impl Graph {
@@ -259,22 +494,98 @@ impl Graph {
         &self.files.by_id[id]
     }
 
+    pub fn build_ids(&self) -> impl Iterator<Item = BuildId> {
+        (0..self.builds.next_id().0).map(BuildId)
+    }
+
+    /// Look up a target named on the command line, with an error naming
+    /// similar targets if there's no such file, as most likely it's a typo.
+    pub fn lookup_target(&self, name: &OsStr) -> anyhow::Result<FileId> {
+        let bytes = canon::os_bytes(name);
+        if !bytes.is_empty() {
+            if let Some(id) = self
+                .files
+                .lookup_bytes(&canon::canon_bytes(bytes.into_owned()))
+            {
+                return Ok(id);
+            }
+        }
+        let similar = self.similar_targets(&name.to_string_lossy());
+        match similar.as_slice() {
+            [] => anyhow::bail!("unknown path requested: {:?}", name),
+            [one] => anyhow::bail!(
+                "unknown path requested: {:?}, did you mean {:?}?",
+                name,
+                one
+            ),
+            _ => anyhow::bail!(
+                "unknown path requested: {:?}, did you mean one of {}?",
+                name,
+                similar
+                    .iter()
+                    .map(|similar| format!("{:?}", similar))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// Up to three outputs, including phony ones, with names within a few
+    /// edits of the given one, closest first, or failing that, the shortest
+    /// of those it's the start of.
+    fn similar_targets(&self, name: &str) -> Vec<&str> {
+        let max = (name.chars().count() / 3).clamp(1, 3);
+        let mut close = Vec::new();
+        let mut longer = Vec::new();
+        for id in self.files.all_ids() {
+            let file = self.file(id);
+            if file.input.is_none() {
+                continue;
+            }
+            if let Some(distance) = edit_distance(name, &file.name, max) {
+                close.push((distance, file.name.as_str()));
+            } else if file.name.starts_with(name) {
+                longer.push((file.name.len(), file.name.as_str()));
+            }
+        }
+        let mut similar = if close.is_empty() { longer } else { close };
+        similar.sort_unstable();
+        similar.into_iter().take(3).map(|(_, name)| name).collect()
+    }
+
     /// Add a new Build, generating a BuildId for it.
-    pub fn add_build(&mut self, mut build: Build) -> anyhow::Result<()> {
+    /// If dupbuild_warn is set, an output already produced by another Build
+    /// is only warned about and dropped from this one, rather than an error.
+    /// Returns the warnings, for the caller to report.
+    pub fn add_build(
+        &mut self,
+        mut build: Build,
+        dupbuild_warn: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        check_room(self.builds.len(), "builds")?;
         let new_id = self.builds.next_id();
         for &id in &build.ins.ids {
             self.files.by_id[id].dependents.push(new_id);
         }
         let mut fixup_dups = false;
+        let mut taken = Vec::new();
+        let mut warnings = Vec::new();
         for &id in &build.outs.ids {
             let f = &mut self.files.by_id[id];
             match f.input {
                 Some(prev) if prev == new_id => {
                     fixup_dups = true;
-                    println!(
+                    warnings.push(format!(
                         "n2: warn: {}: {:?} is repeated in output list",
                         build.location, f.name,
-                    );
+                    ));
+                }
+                Some(prev) if dupbuild_warn => {
+                    warnings.push(format!(
+                        "n2: warn: {}: {:?} is already an output at {}; ignoring it here",
+                        build.location, f.name, self.builds[prev].location
+                    ));
+                    taken.push(id);
                 }
                 Some(prev) => {
                     anyhow::bail!(
@@ -290,14 +601,45 @@ impl Graph {
         if fixup_dups {
             build.outs.remove_duplicates();
         }
+        if !taken.is_empty() {
+            build.outs.remove(&taken);
+        }
         self.builds.push(build);
-        Ok(())
+        Ok(warnings)
     }
 }
 
+/// The Levenshtein distance between two strings, in chars, if it's at most
+/// max.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    // The distances from a prefix of a to each prefix of b, a row at a time.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+        if row.iter().min().unwrap() > &max {
+            return None;
+        }
+    }
+    Some(row[b.len()]).filter(|&distance| distance <= max)
+}
+
 impl GraphFiles {
     /// Look up a file by its name.  Name must have been canonicalized already.
     pub fn lookup(&self, file: &str) -> Option<FileId> {
+        if self.fold_case {
+            return self.by_name.get(&file.to_lowercase()).copied();
+        }
         self.by_name.get(file).copied()
     }
 
@@ -308,22 +650,49 @@ impl GraphFiles {
     /// of this function that accepts string references that is more optimized
     /// for the case where the entry already exists. But so far, all of our
     /// usages of this function have an owned string easily accessible anyways.
-    pub fn id_from_canonical(&mut self, file: String) -> FileId {
+    /// Fails only if there's no id left for a new file.
+    pub fn id_from_canonical(&mut self, file: String) -> anyhow::Result<FileId> {
         // TODO: so many string copies :<
-        match self.by_name.entry(file) {
-            Entry::Occupied(o) => *o.get(),
+        let (key, name) = if self.fold_case {
+            (file.to_lowercase(), Some(file))
+        } else {
+            (file, None)
+        };
+        match self.by_name.entry(key) {
+            Entry::Occupied(o) => Ok(*o.get()),
             Entry::Vacant(v) => {
+                check_room(self.by_id.len(), "files")?;
                 let id = self.by_id.push(File {
-                    name: v.key().clone(),
+                    name: name.unwrap_or_else(|| v.key().clone()),
                     input: None,
                     dependents: Vec::new(),
                 });
                 v.insert(id);
-                id
+                Ok(id)
             }
         }
     }
 
+    /// Look up a file by its name as bytes, which needn't be UTF-8, as with
+    /// targets named on the command line.  Name must have been canonicalized
+    /// already.
+    pub fn lookup_bytes(&self, file: &[u8]) -> Option<FileId> {
+        if let Ok(file) = std::str::from_utf8(file) {
+            return self.lookup(file);
+        }
+        // Only names read from build files can be other than UTF-8, and
+        // they can only be matched byte by byte.  Such trees are rare enough
+        // that a search will do.
+        self.all_ids().find(|&id| {
+            let name = self.by_id[id].name.as_bytes();
+            if self.fold_case {
+                name.eq_ignore_ascii_case(file)
+            } else {
+                name == file
+            }
+        })
+    }
+
     pub fn all_ids(&self) -> impl Iterator<Item = FileId> {
         (0..self.by_id.next_id().0).map(|id| FileId(id))
     }
@@ -338,11 +707,98 @@ pub enum MTime {
     Stamp(SystemTime),
 }
 
+impl MTime {
+    /// Whether the timestamp looks like it came from a filesystem with coarse
+    /// (millisecond or worse) granularity, like FAT or some network mounts,
+    /// on which back-to-back edits can leave a file's mtime unchanged.
+    pub fn is_coarse(&self) -> bool {
+        match self {
+            MTime::Missing => false,
+            MTime::Stamp(mtime) => match mtime.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(since_epoch) => since_epoch.subsec_nanos() % 1_000_000 == 0,
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+/// Files at least this large only have their start and end hashed by
+/// fingerprint().
+const FINGERPRINT_SAMPLE: u64 = 64 << 10;
+
+/// 64-bit FNV-1a, for hashes that are recorded and compared across runs of
+/// n2, which DefaultHasher's may not be, as its algorithm may change between
+/// Rust releases.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl std::hash::Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A quick digest of a file's content, used to supplement coarse mtimes.
+/// Covers the size and content of the file, though for large files only the
+/// start and end are read.  None if the file can't be read, e.g. because it's
+/// a directory or a dangling symlink.
+pub fn fingerprint(path: &Path) -> Option<u64> {
+    use std::hash::Hasher;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(crate::canon::long_path(path)).ok()?;
+    let meta = file.metadata().ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let size = meta.len();
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(size);
+    let mut buf = Vec::new();
+    if size <= 2 * FINGERPRINT_SAMPLE {
+        file.read_to_end(&mut buf).ok()?;
+    } else {
+        buf.resize(FINGERPRINT_SAMPLE as usize, 0);
+        file.read_exact(&mut buf).ok()?;
+        hasher.write(&buf);
+        file.seek(SeekFrom::End(-(FINGERPRINT_SAMPLE as i64)))
+            .ok()?;
+        file.read_exact(&mut buf).ok()?;
+    }
+    hasher.write(&buf);
+    Some(hasher.finish())
+}
+
 /// stat() an on-disk path, producing its MTime.
-pub fn stat(path: &Path) -> std::io::Result<MTime> {
+/// If follow_symlinks is false a symlink has its own mtime, as with lstat(),
+/// rather than that of its target.  Either way a dangling symlink counts as
+/// present, as creating one is a legitimate thing for a build step to do.
+pub fn stat(path: &Path, follow_symlinks: bool) -> std::io::Result<MTime> {
     // TODO: On Windows, use FindFirstFileEx()/FindNextFile() to get timestamps per
     //       directory, for better stat perf.
-    Ok(match std::fs::metadata(path) {
+    let path = crate::canon::long_path(path);
+    let meta = if follow_symlinks {
+        match std::fs::metadata(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::symlink_metadata(&path)
+            }
+            meta => meta,
+        }
+    } else {
+        std::fs::symlink_metadata(&path)
+    };
+    Ok(match meta {
         Ok(meta) => MTime::Stamp(meta.modified().unwrap()),
         Err(err) => {
             if err.kind() == std::io::ErrorKind::NotFound {
@@ -354,36 +810,233 @@ pub fn stat(path: &Path) -> std::io::Result<MTime> {
     })
 }
 
+/// Set the mtime of a file to now, returning the resulting MTime.
+fn clamp_mtime(path: &Path, now: SystemTime) -> std::io::Result<MTime> {
+    let file = std::fs::File::options()
+        .write(true)
+        .open(crate::canon::long_path(path))?;
+    file.set_modified(now)?;
+    Ok(MTime::Stamp(file.metadata()?.modified()?))
+}
+
 /// Gathered state of on-disk files.
 /// Due to discovered deps this map may grow after graph initialization.
-pub struct FileState(DenseMap<FileId, Option<MTime>>);
+pub struct FileState {
+    mtimes: DenseMap<FileId, Option<MTime>>,
+    /// Content fingerprints of files with coarse mtimes, as read since they
+    /// were last statted; see read_fingerprint.
+    fingerprints: DenseMap<FileId, Option<u64>>,
+    /// See stat().
+    follow_symlinks: bool,
+    /// If true, mtimes in the future are reset to the present on disk.
+    clamp_future: bool,
+    /// Files found with mtimes in the future that haven't been reported yet,
+    /// along with how far in the future they were.
+    future: Vec<(FileId, Duration)>,
+    /// Files already found with mtimes in the future, so each is only
+    /// reported once.
+    seen_future: HashSet<FileId>,
+    /// States known to be current without statting, e.g. because Watchman
+    /// says the file hasn't changed; each is used in place of the first stat.
+    trusted: HashMap<FileId, (MTime, Option<u64>)>,
+}
+
+/// Mtimes this far ahead of our clock are plausibly due to clock skew with a
+/// network filesystem, and aren't worth reporting.
+const FUTURE_SLACK: Duration = Duration::from_secs(2);
+
+/// Below this many files, FileState::prefetch isn't worth setting up for.
+#[cfg(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos"))]
+const PREFETCH_MIN: usize = 64;
 
 impl FileState {
-    pub fn new(graph: &Graph) -> Self {
-        FileState(DenseMap::new_sized(graph.files.by_id.next_id(), None))
+    pub fn new(graph: &Graph, follow_symlinks: bool, clamp_future: bool) -> Self {
+        FileState {
+            mtimes: DenseMap::new_sized(graph.files.by_id.next_id(), None),
+            fingerprints: DenseMap::new_sized(graph.files.by_id.next_id(), None),
+            follow_symlinks,
+            clamp_future,
+            future: Vec::new(),
+            seen_future: HashSet::new(),
+            trusted: HashMap::new(),
+        }
+    }
+
+    /// Take a file's mtime and fingerprint as current, in place of statting
+    /// it.  Only the first stat is skipped, as later ones follow a build
+    /// writing the file.
+    pub fn trust(&mut self, id: FileId, mtime: MTime, fingerprint: Option<u64>) {
+        self.trusted.insert(id, (mtime, fingerprint));
+    }
+
+    /// Stat many files at once, where the platform can do that faster than
+    /// one at a time, trusting the results as with trust().  Any that fail are
+    /// left to stat().
+    pub fn prefetch(&mut self, files: &[(FileId, &Path)]) {
+        #[cfg(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos"))]
+        {
+            if files.len() < PREFETCH_MIN {
+                return;
+            }
+            let paths: Vec<&Path> = files.iter().map(|&(_, path)| path).collect();
+            #[cfg(target_os = "linux")]
+            let mtimes = crate::uring::mtimes(&paths, self.follow_symlinks);
+            #[cfg(target_os = "macos")]
+            let mtimes = crate::bulkstat::mtimes(&paths, self.follow_symlinks);
+            let mtimes = match mtimes {
+                Some(mtimes) => mtimes,
+                None => return,
+            };
+            let now = SystemTime::now();
+            for (&(id, _), mtime) in files.iter().zip(mtimes) {
+                let mtime = match mtime {
+                    Ok(mtime) if mtime <= now => MTime::Stamp(mtime),
+                    _ => continue,
+                };
+                self.trusted.insert(id, (mtime, None));
+            }
+        }
+        #[cfg(not(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos")))]
+        let _ = files;
+    }
+
+    /// The mtime and fingerprint of a file, as statted or trusted.
+    pub fn known(&self, id: FileId) -> Option<(MTime, Option<u64>)> {
+        match self.get(id) {
+            Some(mtime) => Some((mtime, self.fingerprint(id))),
+            None => self.trusted.get(&id).copied(),
+        }
+    }
+
+    /// Take the files found since the last call to have mtimes in the future,
+    /// which usually means a bad clock or an extracted archive.  Such files
+    /// can make for confusing rebuilds once the clock catches up.
+    pub fn take_future(&mut self) -> Vec<(FileId, Duration)> {
+        std::mem::take(&mut self.future)
     }
 
     pub fn get(&self, id: FileId) -> Option<MTime> {
-        self.0.lookup(id).copied().unwrap_or(None)
+        self.mtimes.lookup(id).copied().unwrap_or(None)
+    }
+
+    /// The content fingerprint of a file, if read_fingerprint read it.
+    pub fn fingerprint(&self, id: FileId) -> Option<u64> {
+        self.fingerprints.lookup(id).copied().unwrap_or(None)
+    }
+
+    /// Read the content fingerprint of a statted file, if its mtime is too
+    /// coarse to rely on alone.  Reading the content is costly, so this is
+    /// only done where an unchanged mtime is to be double checked.
+    pub fn read_fingerprint(&mut self, id: FileId, path: &Path) -> Option<u64> {
+        if let Some(fingerprint) = self.fingerprint(id) {
+            return Some(fingerprint);
+        }
+        if !self.get(id)?.is_coarse() {
+            return None;
+        }
+        let fingerprint = fingerprint(path)?;
+        self.fingerprints.set_grow(id, Some(fingerprint), None);
+        Some(fingerprint)
     }
 
     pub fn stat(&mut self, id: FileId, path: &Path) -> anyhow::Result<MTime> {
-        let mtime = stat(path).map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
-        self.0.set_grow(id, Some(mtime), None);
+        if let Some((mtime, fingerprint)) = self.trusted.remove(&id) {
+            self.mtimes.set_grow(id, Some(mtime), None);
+            self.fingerprints.set_grow(id, fingerprint, None);
+            return Ok(mtime);
+        }
+        let mut mtime = stat(path, self.follow_symlinks)
+            .map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+        if let MTime::Stamp(stamp) = mtime {
+            let now = SystemTime::now();
+            if let Ok(ahead) = stamp.duration_since(now) {
+                if ahead > FUTURE_SLACK {
+                    if self.seen_future.insert(id) {
+                        self.future.push((id, ahead));
+                    }
+                    if self.clamp_future {
+                        // Not worth failing over, e.g. for a read-only file.
+                        if let Ok(clamped) = clamp_mtime(path, now) {
+                            mtime = clamped;
+                        }
+                    }
+                }
+            }
+        }
+        self.mtimes.set_grow(id, Some(mtime), None);
+        self.fingerprints.set_grow(id, None, None);
         Ok(mtime)
     }
 }
 
+/// The manifest hashes of each Build as recorded in the db.
 #[derive(Default)]
-pub struct Hashes(HashMap<BuildId, BuildHash>);
+pub struct Hashes {
+    /// Along with when they were recorded, in seconds since the epoch.
+    hashes: HashMap<BuildId, (BuildHash, ManifestParts, u32)>,
+    /// Only for builds whose input digests were recorded.
+    digests: HashMap<BuildId, Vec<(FileId, InputDigest)>>,
+}
 
 impl Hashes {
-    pub fn set(&mut self, id: BuildId, hash: BuildHash) {
-        self.0.insert(id, hash);
+    pub fn set(&mut self, id: BuildId, hash: BuildHash, parts: ManifestParts, written: u32) {
+        self.hashes.insert(id, (hash, parts, written));
     }
 
     pub fn get(&self, id: BuildId) -> Option<BuildHash> {
-        self.0.get(&id).copied()
+        self.hashes.get(&id).map(|&(hash, _, _)| hash)
+    }
+
+    pub fn get_parts(&self, id: BuildId) -> Option<ManifestParts> {
+        self.hashes.get(&id).map(|&(_, parts, _)| parts)
+    }
+
+    pub fn get_written(&self, id: BuildId) -> Option<u32> {
+        self.hashes.get(&id).map(|&(_, _, written)| written)
+    }
+
+    /// Set the digests of a build's inputs, as made by hash::hash_inputs.
+    pub fn set_digests(&mut self, id: BuildId, digests: Vec<(FileId, InputDigest)>) {
+        if digests.is_empty() {
+            self.digests.remove(&id);
+        } else {
+            self.digests.insert(id, digests);
+        }
+    }
+
+    pub fn get_digests(&self, id: BuildId) -> Option<&[(FileId, InputDigest)]> {
+        self.digests.get(&id).map(|digests| digests.as_slice())
+    }
+}
+
+/// How each Build went the last time it ran, as recorded in the db: how long
+/// it took, used to estimate the time remaining in a build, and whether it
+/// failed, in which case it's started ahead of others.
+#[derive(Default)]
+pub struct Durations {
+    durations: HashMap<BuildId, Duration>,
+    failed: HashSet<BuildId>,
+}
+
+impl Durations {
+    /// Note a run of the build that succeeded.
+    pub fn set(&mut self, id: BuildId, duration: Duration) {
+        self.durations.insert(id, duration);
+        self.failed.remove(&id);
+    }
+
+    pub fn get(&self, id: BuildId) -> Option<Duration> {
+        self.durations.get(&id).copied()
+    }
+
+    /// Note a run of the build that failed, leaving the duration of the last
+    /// that succeeded.
+    pub fn set_failed(&mut self, id: BuildId) {
+        self.failed.insert(id);
+    }
+
+    pub fn failed(&self, id: BuildId) -> bool {
+        self.failed.contains(&id)
     }
 }
 
@@ -396,7 +1049,7 @@ fn stat_mtime_resolution() {
 
     // Write once and stat.
     std::fs::write(&filename, "foo").unwrap();
-    let mtime1 = match stat(&filename).unwrap() {
+    let mtime1 = match stat(&filename, true).unwrap() {
         MTime::Stamp(mtime) => mtime,
         _ => panic!("File not found: {}", filename.display()),
     };
@@ -406,7 +1059,7 @@ fn stat_mtime_resolution() {
 
     // Write twice and stat.
     std::fs::write(&filename, "foo").unwrap();
-    let mtime2 = match stat(&filename).unwrap() {
+    let mtime2 = match stat(&filename, true).unwrap() {
         MTime::Stamp(mtime) => mtime,
         _ => panic!("File not found: {}", filename.display()),
     };
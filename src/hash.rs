@@ -4,9 +4,9 @@
 //! See "Manifests instead of mtime order" in
 //!   https://neugierig.org/software/blog/2022/03/n2.html
 
-use crate::graph::{Build, FileId, FileState, GraphFiles, MTime, RspFile};
+use crate::graph::{Build, FileId, FileState, FnvHasher, GraphFiles, MTime, RspFile};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     fmt::Write,
     hash::{Hash, Hasher},
     time::SystemTime,
@@ -17,6 +17,52 @@ use std::{
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct BuildHash(pub u64);
 
+/// Separate hashes of the parts of a build's manifest.  Recorded alongside
+/// the BuildHash so that "-d explain" can say which part changed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ManifestParts {
+    pub ins: u64,
+    pub discovered: u64,
+    /// Covers the rspfile too.
+    pub cmdline: u64,
+    pub outs: u64,
+}
+
+impl ManifestParts {
+    /// Each part's name, for machine consumption, and description of its
+    /// change, along with its hash.
+    fn parts(&self) -> [(&'static str, &'static str, u64); 4] {
+        [
+            ("inputs", "inputs changed", self.ins),
+            ("discovered", "discovered deps changed", self.discovered),
+            ("cmdline", "command line changed", self.cmdline),
+            ("outputs", "outputs changed", self.outs),
+        ]
+    }
+
+    /// The names and descriptions of the parts that differ between self and
+    /// a previous state.
+    fn changes(&self, prev: &ManifestParts) -> impl Iterator<Item = (&'static str, &'static str)> {
+        IntoIterator::into_iter(self.parts())
+            .zip(IntoIterator::into_iter(prev.parts()))
+            .filter(|((_, _, hash), (_, _, prev))| hash != prev)
+            .map(|((name, description, _), _)| (name, description))
+    }
+
+    /// Name the parts that differ between self and a previous state, for
+    /// machine consumption.
+    pub fn changed_parts(&self, prev: &ManifestParts) -> Vec<&'static str> {
+        self.changes(prev).map(|(name, _)| name).collect()
+    }
+
+    /// Describe the parts that differ between self and a previous state.
+    pub fn describe_changes(&self, prev: &ManifestParts) -> Vec<&'static str> {
+        self.changes(prev)
+            .map(|(_, description)| description)
+            .collect()
+    }
+}
+
 /// A trait for computing a build's manifest.  Indirected as a trait so we can
 /// implement it a second time for "-d explain" debug purposes.
 trait Manifest {
@@ -30,6 +76,7 @@ trait Manifest {
     );
     fn write_rsp(&mut self, rspfile: &RspFile);
     fn write_cmdline(&mut self, cmdline: &str);
+    fn write_env(&mut self, env: &[(String, String)]);
 }
 
 fn get_fileid_status<'a>(
@@ -92,6 +139,11 @@ impl Manifest for TerseHash {
     fn write_rsp(&mut self, rspfile: &RspFile) {
         rspfile.hash(&mut self.0);
     }
+
+    fn write_env(&mut self, env: &[(String, String)]) {
+        env.hash(&mut self.0);
+        self.write_separator();
+    }
 }
 
 fn build_manifest<M: Manifest>(
@@ -102,7 +154,17 @@ fn build_manifest<M: Manifest>(
 ) {
     manifest.write_files("in", files, file_state, build.dirtying_ins());
     manifest.write_files("discovered", files, file_state, build.discovered_ins());
-    manifest.write_cmdline(build.cmdline.as_deref().unwrap_or(""));
+    let cmdline = build.cmdline.as_deref().unwrap_or("");
+    match &build.shell {
+        // Only folded in when set so as to not change the hashes of other
+        // builds.
+        Some(shell) => manifest.write_cmdline(&format!("{:?} {}", shell, cmdline)),
+        None => manifest.write_cmdline(cmdline),
+    }
+    // Skipped when empty so as to not change the hashes of other builds.
+    if !build.env.is_empty() {
+        manifest.write_env(&build.env);
+    }
     if let Some(rspfile) = &build.rspfile {
         manifest.write_rsp(rspfile);
     }
@@ -119,6 +181,157 @@ pub fn hash_build(files: &GraphFiles, file_state: &FileState, build: &Build) ->
     hasher.finish()
 }
 
+/// A BuildHasher that hashes each part of the manifest separately.
+#[derive(Default)]
+struct PartsHash(ManifestParts);
+
+impl Manifest for PartsHash {
+    fn write_files(
+        &mut self,
+        desc: &str,
+        files: &GraphFiles,
+        file_state: &FileState,
+        ids: &[FileId],
+    ) {
+        let mut terse = TerseHash::default();
+        terse.write_files(desc, files, file_state, ids);
+        let hash = terse.finish().0;
+        match desc {
+            "in" => self.0.ins = hash,
+            "discovered" => self.0.discovered = hash,
+            _ => self.0.outs = hash,
+        }
+    }
+
+    fn write_rsp(&mut self, rspfile: &RspFile) {
+        let mut h = DefaultHasher::new();
+        self.0.cmdline.hash(&mut h);
+        rspfile.hash(&mut h);
+        self.0.cmdline = h.finish();
+    }
+
+    fn write_cmdline(&mut self, cmdline: &str) {
+        let mut terse = TerseHash::default();
+        terse.write_cmdline(cmdline);
+        self.0.cmdline = terse.finish().0;
+    }
+
+    fn write_env(&mut self, env: &[(String, String)]) {
+        let mut h = DefaultHasher::new();
+        self.0.cmdline.hash(&mut h);
+        env.hash(&mut h);
+        self.0.cmdline = h.finish();
+    }
+}
+
+/// Hashes each part of the manifest of a build separately.
+/// Prerequisite: as with hash_build.
+pub fn hash_build_parts(
+    files: &GraphFiles,
+    file_state: &FileState,
+    build: &Build,
+) -> ManifestParts {
+    let mut hasher = PartsHash::default();
+    build_manifest(&mut hasher, files, file_state, build);
+    hasher.0
+}
+
+/// The recorded state of one of a build's inputs; see hash_inputs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InputDigest {
+    /// A digest of its mtime and any fingerprint.
+    pub digest: u32,
+    /// Its mtime in milliseconds since the epoch, for reporting a change;
+    /// 0 if unknown, as in databases from before these were recorded.
+    pub mtime: u64,
+}
+
+/// A file's mtime in milliseconds since the epoch.
+pub fn millis(mtime: SystemTime) -> u64 {
+    mtime
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// A digest of the state of each of a build's inputs, discovered or not, so
+/// that when the inputs' hash changes, which of them changed can be told, and
+/// when it doesn't, whether the content of those with coarse mtimes did.
+/// Digests are truncated to 32 bits, which is plenty to tell a change, as
+/// they're only compared with those of the same file.
+/// Prerequisite: as with hash_build, and any fingerprints wanted read with
+/// FileState::read_fingerprint.
+pub fn hash_inputs(
+    files: &GraphFiles,
+    file_state: &FileState,
+    build: &Build,
+) -> Vec<(FileId, InputDigest)> {
+    let ins = build.dirtying_ins().iter().chain(build.discovered_ins());
+    ins.map(|&id| {
+        let (_, mtime) = get_fileid_status(files, file_state, id);
+        let mut h = FnvHasher::default();
+        mtime.hash(&mut h);
+        file_state.fingerprint(id).hash(&mut h);
+        let digest = InputDigest {
+            digest: h.finish() as u32,
+            mtime: millis(mtime),
+        };
+        (id, digest)
+    })
+    .collect()
+}
+
+/// An input of a build that changed since it last ran.
+#[derive(Debug, Copy, Clone)]
+pub struct ChangedInput {
+    pub id: FileId,
+    /// Its mtime when the build last ran, in milliseconds since the epoch,
+    /// if known.
+    pub old_mtime: Option<u64>,
+    /// Its mtime now.
+    pub mtime: u64,
+}
+
+/// Given the digests of a build's inputs when it last ran and now, the inputs
+/// that are new or changed since.
+pub fn changed_inputs(
+    prev: &[(FileId, InputDigest)],
+    digests: &[(FileId, InputDigest)],
+) -> Vec<ChangedInput> {
+    let prev: HashMap<FileId, InputDigest> = prev.iter().copied().collect();
+    let mut seen = HashSet::new();
+    digests
+        .iter()
+        .filter(|&&(id, digest)| {
+            prev.get(&id).map(|prev| prev.digest) != Some(digest.digest) && seen.insert(id)
+        })
+        .map(|&(id, digest)| ChangedInput {
+            id,
+            old_mtime: prev
+                .get(&id)
+                .map(|prev| prev.mtime)
+                .filter(|&mtime| mtime != 0),
+            mtime: digest.mtime,
+        })
+        .collect()
+}
+
+/// Lacking digests, the inputs likely to have changed since a build last
+/// ran: those modified since it was recorded, at `written` seconds since the
+/// epoch, though what their mtimes were before is unknown.
+pub fn inputs_modified_since(digests: &[(FileId, InputDigest)], written: u32) -> Vec<ChangedInput> {
+    let written = written as u64 * 1000;
+    let mut seen = HashSet::new();
+    digests
+        .iter()
+        .filter(|&&(id, digest)| digest.mtime >= written && seen.insert(id))
+        .map(|&(id, digest)| ChangedInput {
+            id,
+            old_mtime: None,
+            mtime: digest.mtime,
+        })
+        .collect()
+}
+
 /// A BuildHasher that records human-readable text for "-d explain" debugging.
 #[derive(Default)]
 struct ExplainHash {
@@ -136,11 +349,14 @@ impl Manifest for ExplainHash {
         writeln!(&mut self.text, "{desc}:").unwrap();
         for &id in ids {
             let (name, mtime) = get_fileid_status(files, file_state, id);
-            let millis = mtime
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            writeln!(&mut self.text, "  {millis} {name}").unwrap();
+            write!(&mut self.text, "  {} {name}", millis(mtime)).unwrap();
+            if let Some(fingerprint) = file_state.fingerprint(id) {
+                write!(&mut self.text, " (content {fingerprint:x})").unwrap();
+            }
+            if mtime > SystemTime::now() {
+                write!(&mut self.text, " (in the future)").unwrap();
+            }
+            writeln!(&mut self.text).unwrap();
         }
     }
 
@@ -155,6 +371,13 @@ impl Manifest for ExplainHash {
     fn write_cmdline(&mut self, cmdline: &str) {
         writeln!(&mut self.text, "cmdline: {}", cmdline).unwrap();
     }
+
+    fn write_env(&mut self, env: &[(String, String)]) {
+        writeln!(&mut self.text, "env:").unwrap();
+        for (name, value) in env {
+            writeln!(&mut self.text, "  {name}={value}").unwrap();
+        }
+    }
 }
 
 /// Logs human-readable state of all the inputs used for hashing a given build.
@@ -0,0 +1,200 @@
+//! Watching the directories of the files a build statted with inotify, so
+//! that later builds of the same `--serve` process can trust the states
+//! recorded then for the files that haven't changed since, as with Watchman.
+//! See Work::use_inotify.
+//!
+//! The watcher lives as long as the process.  A file's state is only trusted
+//! if its directory was already watched when it was statted, so directories
+//! are watched as builds first record files in them, and those files are
+//! statted once more before they're trusted.  If the kernel's event queue
+//! overflows, or a watched directory is deleted or moved, everything is
+//! statted again.
+
+use crate::watchman::{Cache, Changes};
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The events that could change a file's state, or its directory's.
+const MASK: u32 = libc::IN_ATTRIB
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+
+/// Events after which nothing can be trusted.
+const LOST: u32 =
+    libc::IN_Q_OVERFLOW | libc::IN_IGNORED | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+
+struct Watcher {
+    fd: libc::c_int,
+    /// Distinguishes this process's clocks from those of others.
+    token: u128,
+    /// How many times query has been asked; the clock.
+    generation: u64,
+    /// Watched directories, relative to the working directory, by watch
+    /// descriptor, and the reverse.
+    dirs: HashMap<libc::c_int, String>,
+    watches: HashMap<String, libc::c_int>,
+}
+
+static WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+
+/// The directory part of a file name as recorded in a Cache, "" for the
+/// working directory.
+fn dir_of(name: &str) -> &str {
+    name.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+impl Watcher {
+    fn new() -> anyhow::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(anyhow!(
+                "inotify_init1: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(Watcher {
+            fd,
+            token: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+            generation: 0,
+            dirs: HashMap::new(),
+            watches: HashMap::new(),
+        })
+    }
+
+    fn clock(&self) -> String {
+        format!(
+            "{}:{:x}:{}",
+            std::process::id(),
+            self.token,
+            self.generation
+        )
+    }
+
+    /// Read the events since the last call, returning the names of the files
+    /// they're about, or None if they can't say.
+    fn read_events(&mut self) -> anyhow::Result<Option<HashSet<String>>> {
+        let mut changed = Some(HashSet::new());
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(anyhow!("read inotify: {}", err));
+            }
+            let mut events = &buf[..n as usize];
+            // struct inotify_event: wd, mask, cookie, len, then len bytes
+            // of nul-padded name.
+            while events.len() >= 16 {
+                let field = |i: usize| {
+                    u32::from_ne_bytes([
+                        events[i * 4],
+                        events[i * 4 + 1],
+                        events[i * 4 + 2],
+                        events[i * 4 + 3],
+                    ])
+                };
+                let (wd, mask, len) = (field(0) as libc::c_int, field(1), field(3) as usize);
+                let name = &events[16..16 + len];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(len)];
+                if mask & LOST != 0 {
+                    changed = None;
+                    if mask & libc::IN_IGNORED != 0 {
+                        if let Some(dir) = self.dirs.remove(&wd) {
+                            self.watches.remove(&dir);
+                        }
+                    }
+                } else if let (Some(changed), Some(dir)) = (&mut changed, self.dirs.get(&wd)) {
+                    let name = String::from_utf8_lossy(name);
+                    changed.insert(if dir.is_empty() {
+                        name.into_owned()
+                    } else {
+                        format!("{}/{}", dir, name)
+                    });
+                }
+                events = &events[16 + len..];
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Watch a directory, if it isn't already.
+    fn watch(&mut self, dir: &str) {
+        if self.watches.contains_key(dir) {
+            return;
+        }
+        let path = Path::new(if dir.is_empty() { "." } else { dir });
+        let path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        // Failures, e.g. for a directory that doesn't exist or with too many
+        // watches already, just leave the files in it to be statted.
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), MASK) };
+        if wd < 0 {
+            return;
+        }
+        self.dirs.insert(wd, dir.to_string());
+        self.watches.insert(dir.to_string(), wd);
+    }
+}
+
+/// Say which of the cached files changed since the cache was written, by the
+/// events of the directories watched since, and watch the directories of the
+/// others for next time.
+pub fn query(cache: Option<&Cache>) -> anyhow::Result<Changes> {
+    let cwd = std::env::current_dir()?;
+    let dir = cwd
+        .to_str()
+        .ok_or_else(|| anyhow!("non-UTF-8 working directory {:?}", cwd))?
+        .to_string();
+    let mut watcher = WATCHER.lock().unwrap();
+    if watcher.is_none() {
+        *watcher = Some(Watcher::new()?);
+    }
+    let watcher = watcher.as_mut().unwrap();
+
+    let events = watcher.read_events()?;
+    let cache = cache.filter(|cache| cache.dir == dir && cache.clock == watcher.clock());
+    let changed = match (cache, events) {
+        (Some(cache), Some(mut changed)) => {
+            // Those in directories that weren't watched when they were
+            // statted may have changed unseen.
+            for (name, _, _) in &cache.entries {
+                if !watcher.watches.contains_key(dir_of(name)) {
+                    changed.insert(name.clone());
+                }
+            }
+            Some(changed)
+        }
+        _ => None,
+    };
+    if let Some(cache) = cache {
+        for (name, _, _) in &cache.entries {
+            watcher.watch(dir_of(name));
+        }
+    }
+
+    watcher.generation += 1;
+    Ok(Changes {
+        dir,
+        clock: watcher.clock(),
+        changed,
+    })
+}
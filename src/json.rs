@@ -0,0 +1,263 @@
+//! A minimal JSON reader, for talking to other tools.
+
+use anyhow::bail;
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys in the order they appeared.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Look up a key of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> anyhow::Result<Value> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        ofs: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.ofs < parser.text.len() {
+        bail!("json: trailing data at offset {}", parser.ofs);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    ofs: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.ofs).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.ofs += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> anyhow::Result<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            bail!("json: expected {:?} at offset {}", c as char, self.ofs);
+        }
+        self.ofs += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> anyhow::Result<Value> {
+        if !self.text[self.ofs..].starts_with(word.as_bytes()) {
+            bail!("json: bad literal at offset {}", self.ofs);
+        }
+        self.ofs += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'[') => {
+                self.ofs += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.ofs += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.ofs += 1,
+                        Some(b']') => {
+                            self.ofs += 1;
+                            return Ok(Value::Array(values));
+                        }
+                        _ => bail!("json: expected ',' or ']' at offset {}", self.ofs),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.ofs += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.ofs += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.ofs += 1,
+                        Some(b'}') => {
+                            self.ofs += 1;
+                            return Ok(Value::Object(fields));
+                        }
+                        _ => bail!("json: expected ',' or '}}' at offset {}", self.ofs),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.ofs;
+                while matches!(
+                    self.peek(),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.ofs += 1;
+                }
+                let text = std::str::from_utf8(&self.text[start..self.ofs])?;
+                match text.parse() {
+                    Ok(n) => Ok(Value::Number(n)),
+                    Err(_) => bail!("json: bad number {:?}", text),
+                }
+            }
+            _ => bail!("json: unexpected input at offset {}", self.ofs),
+        }
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = match self.text.get(self.ofs..self.ofs + 4) {
+            Some(digits) => std::str::from_utf8(digits)?,
+            None => bail!("json: truncated \\u escape"),
+        };
+        self.ofs += 4;
+        match u32::from_str_radix(digits, 16) {
+            Ok(n) => Ok(n),
+            Err(_) => bail!("json: bad \\u escape {:?}", digits),
+        }
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        if self.peek() != Some(b'"') {
+            bail!("json: expected string at offset {}", self.ofs);
+        }
+        self.ofs += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => bail!("json: unterminated string"),
+            };
+            self.ofs += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek();
+                    self.ofs += 1;
+                    let c = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let mut n = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&n)
+                                && self.text[self.ofs..].starts_with(b"\\u")
+                            {
+                                self.ofs += 2;
+                                let low = self.hex4()?;
+                                n = 0x10000 + ((n - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            char::from_u32(n).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => bail!("json: bad escape at offset {}", self.ofs),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c => out.push(c),
+            }
+        }
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() -> anyhow::Result<()> {
+        let value = parse(
+            r#" {"a": [1, -2.5e1, true, false, null], "b": {}, "c": [],
+                 "s": "x\"\\\/\né😀"} "#,
+        )?;
+        assert_eq!(
+            value.get("a"),
+            Some(&Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-25.0),
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Null,
+            ]))
+        );
+        assert_eq!(value.get("b"), Some(&Value::Object(vec![])));
+        assert_eq!(value.get("c").and_then(Value::as_array), Some(&[][..]));
+        assert_eq!(
+            value.get("s").and_then(Value::as_str),
+            Some("x\"\\/\né\u{1f600}")
+        );
+        assert_eq!(value.get("missing"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("").is_err());
+        assert!(parse("[1,]").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("\"abc").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("nul").is_err());
+    }
+}
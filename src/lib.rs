@@ -1,30 +1,58 @@
+mod audit;
+#[cfg(target_os = "macos")]
+mod bulkstat;
 pub mod canon;
+#[cfg(feature = "capi")]
+mod capi;
+mod check;
+mod compare;
 mod db;
+mod dbinfo;
 mod densemap;
 mod depfile;
 mod eval;
+mod flamegraph;
 mod graph;
 mod hash;
+#[cfg(target_os = "linux")]
+mod inotify;
+mod json;
 pub mod load;
+mod metrics;
 pub mod parse;
+mod pick;
+mod plan;
+mod prestat;
 mod process;
 #[cfg(unix)]
 mod process_posix;
 #[cfg(windows)]
 mod process_win;
 mod progress;
+mod query;
+mod report;
 pub mod run;
 pub mod scanner;
+mod serve;
 mod signal;
 mod smallmap;
 mod task;
 mod terminal;
 mod trace;
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod uring;
+#[cfg(windows)]
+mod usn;
+#[cfg(any(target_arch = "wasm32", test))]
+mod wasm;
+mod watchman;
 mod work;
 
-#[cfg(not(any(windows, target_arch = "wasm32")))]
+#[cfg(not(any(windows, target_arch = "wasm32", feature = "capi")))]
 use jemallocator::Jemalloc;
 
-#[cfg(not(any(windows, target_arch = "wasm32")))]
+// jemalloc's thread-locals keep a shared library from being loaded with
+// dlopen, as by Python's ctypes, so the C API makes do with the system's.
+#[cfg(not(any(windows, target_arch = "wasm32", feature = "capi")))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
@@ -1,73 +1,501 @@
 //! Graph loading: runs .ninja parsing and constructs the build graph from it.
 
 use crate::{
-    canon::{canon_path, canon_path_fast},
+    canon::{self, canon_path, canon_path_fast},
+    densemap::DenseMap,
     eval::{EvalPart, EvalString},
-    graph::{FileId, RspFile},
+    graph::{BuildId, File, FileId, RspFile},
     parse::Statement,
+    prestat::Prestat,
     scanner,
     smallmap::SmallMap,
     {db, eval, graph, parse, trace},
 };
 use anyhow::{anyhow, bail};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
 use std::path::PathBuf;
-use std::{borrow::Cow, path::Path};
+use std::sync::Arc;
 
 /// A variable lookup environment for magic $in/$out variables.
 struct BuildImplicitVars<'a> {
-    graph: &'a graph::Graph,
-    build: &'a graph::Build,
+    files: &'a DenseMap<FileId, File>,
+    ins: &'a [FileId],
+    outs: &'a [FileId],
+    /// If true, $out refers to the outputs' staging paths; see
+    /// Build::atomic_outputs.
+    staged: bool,
 }
 impl<'a> BuildImplicitVars<'a> {
-    fn file_list(&self, ids: &[FileId], sep: char) -> String {
-        let mut out = String::new();
-        for &id in ids {
-            if !out.is_empty() {
+    fn file_list(&self, out: &mut String, ids: &[FileId], sep: char, suffix: &str) {
+        for (i, &id) in ids.iter().enumerate() {
+            if i > 0 {
                 out.push(sep);
             }
-            out.push_str(&self.graph.file(id).name);
+            out.push_str(&self.files[id].name);
+            out.push_str(suffix);
+        }
+    }
+
+    fn out_suffix(&self) -> &'static str {
+        if self.staged {
+            graph::STAGING_SUFFIX
+        } else {
+            ""
+        }
+    }
+
+    /// Append the value of one of IMPLICIT_VARS.
+    fn push_var(&self, out: &mut String, var: &str) {
+        match var {
+            "in" => self.file_list(out, self.ins, ' ', ""),
+            "in_newline" => self.file_list(out, self.ins, '\n', ""),
+            "out" => self.file_list(out, self.outs, ' ', self.out_suffix()),
+            "out_newline" => self.file_list(out, self.outs, '\n', self.out_suffix()),
+            _ => unreachable!("{}", var),
+        }
+    }
+
+    /// Append the files of one of IMPLICIT_VARS as separate arguments.
+    fn push_args(&self, argv: &mut Vec<String>, var: &str) {
+        let (ids, suffix) = match var {
+            "in" | "in_newline" => (self.ins, ""),
+            "out" | "out_newline" => (self.outs, self.out_suffix()),
+            _ => unreachable!("{}", var),
+        };
+        argv.extend(
+            ids.iter()
+                .map(|&id| format!("{}{}", self.files[id].name, suffix)),
+        );
+    }
+}
+
+/// The variables BuildImplicitVars provides, which take precedence over any
+/// others of the same name.
+const IMPLICIT_VARS: [&str; 4] = ["in", "in_newline", "out", "out_newline"];
+
+/// One piece of a rule variable, as expanded against the scope the rule is
+/// used in.
+#[derive(Debug, PartialEq)]
+enum RuleVarPart {
+    Literal(String),
+    /// One of IMPLICIT_VARS.
+    Implicit(&'static str),
+    /// A reference that a build's own binding of the name overrides, and
+    /// otherwise has the given value from the enclosing scope.
+    Scoped {
+        name: String,
+        value: String,
+    },
+    /// A function applied to another part, a variable reference.
+    Call {
+        func: eval::Func,
+        args: Vec<String>,
+        var: Box<RuleVarPart>,
+    },
+}
+
+impl RuleVarPart {
+    fn var(name: &str, env: &eval::Vars) -> RuleVarPart {
+        match IMPLICIT_VARS.iter().find(|&&v| v == name) {
+            Some(var) => RuleVarPart::Implicit(var),
+            None => RuleVarPart::Scoped {
+                name: name.to_owned(),
+                value: env.get(name).cloned().unwrap_or_default(),
+            },
+        }
+    }
+
+    fn call(text: &str, env: &eval::Vars) -> RuleVarPart {
+        // The parser only makes a Call of text that parses.
+        let call = eval::Call::parse(text).unwrap();
+        RuleVarPart::Call {
+            func: call.func,
+            args: call.args.iter().map(|&arg| arg.to_owned()).collect(),
+            var: Box::new(RuleVarPart::var(call.var, env)),
+        }
+    }
+
+    fn args(args: &[String]) -> Vec<&str> {
+        args.iter().map(String::as_str).collect()
+    }
+}
+
+/// A rule variable with everything but per-build references already
+/// substituted.
+#[derive(Debug, PartialEq)]
+struct RuleVar(Vec<RuleVarPart>);
+
+impl RuleVar {
+    fn new(val: &EvalString<String>, env: &eval::Vars) -> RuleVar {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        for part in val.parts() {
+            match part {
+                EvalPart::Literal(s) => literal.push_str(s),
+                EvalPart::VarRef(name) => {
+                    if !literal.is_empty() {
+                        parts.push(RuleVarPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(RuleVarPart::var(name, env));
+                }
+                EvalPart::Call(text) => {
+                    if !literal.is_empty() {
+                        parts.push(RuleVarPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(RuleVarPart::call(text, env));
+                }
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(RuleVarPart::Literal(literal));
+        }
+        RuleVar(parts)
+    }
+
+    fn evaluate(
+        &self,
+        implicit_vars: &BuildImplicitVars,
+        build_vars: &parse::VarList,
+        env: &eval::Vars,
+    ) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            Self::evaluate_part(&mut out, part, implicit_vars, build_vars, env);
+        }
+        out
+    }
+
+    fn evaluate_part(
+        out: &mut String,
+        part: &RuleVarPart,
+        implicit_vars: &BuildImplicitVars,
+        build_vars: &parse::VarList,
+        env: &eval::Vars,
+    ) {
+        match part {
+            RuleVarPart::Literal(s) => out.push_str(s),
+            RuleVarPart::Implicit(var) => implicit_vars.push_var(out, var),
+            RuleVarPart::Scoped { name, value } => match build_vars.get(name.as_str()) {
+                Some(val) => out.push_str(&val.evaluate(&[env])),
+                None => out.push_str(value),
+            },
+            RuleVarPart::Call { func, args, var } => {
+                let mut value = String::new();
+                Self::evaluate_part(&mut value, var, implicit_vars, build_vars, env);
+                out.push_str(&func.apply(&value, &RuleVarPart::args(args)));
+            }
+        }
+    }
+
+    /// Substitute a build's own bindings, leaving only the implicit variables
+    /// to be filled in.  Shares the rule's copy when the build has none that
+    /// matter, as is usual.
+    fn bind(self: &Arc<Self>, build_vars: &parse::VarList, env: &eval::Vars) -> Arc<RuleVar> {
+        fn overridden(part: &RuleVarPart, build_vars: &parse::VarList) -> bool {
+            match part {
+                RuleVarPart::Scoped { name, .. } => build_vars.get(name.as_str()).is_some(),
+                RuleVarPart::Call { var, .. } => overridden(var, build_vars),
+                _ => false,
+            }
+        }
+        fn bind_part(
+            part: &RuleVarPart,
+            build_vars: &parse::VarList,
+            env: &eval::Vars,
+        ) -> RuleVarPart {
+            match part {
+                RuleVarPart::Literal(s) => RuleVarPart::Literal(s.clone()),
+                RuleVarPart::Implicit(var) => RuleVarPart::Implicit(var),
+                RuleVarPart::Scoped { name, value } => RuleVarPart::Scoped {
+                    name: name.clone(),
+                    value: match build_vars.get(name.as_str()) {
+                        Some(val) => val.evaluate(&[env]),
+                        None => value.clone(),
+                    },
+                },
+                RuleVarPart::Call { func, args, var } => RuleVarPart::Call {
+                    func: *func,
+                    args: args.clone(),
+                    var: Box::new(bind_part(var, build_vars, env)),
+                },
+            }
+        }
+        if !self.0.iter().any(|part| overridden(part, build_vars)) {
+            return self.clone();
+        }
+        let parts = self
+            .0
+            .iter()
+            .map(|part| bind_part(part, build_vars, env))
+            .collect();
+        Arc::new(RuleVar(parts))
+    }
+
+    /// Evaluate a RuleVar that's already been bound to its build.
+    fn evaluate_bound(&self, implicit_vars: &BuildImplicitVars) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            Self::evaluate_bound_part(&mut out, part, implicit_vars);
         }
         out
     }
+
+    fn evaluate_bound_part(
+        out: &mut String,
+        part: &RuleVarPart,
+        implicit_vars: &BuildImplicitVars,
+    ) {
+        match part {
+            RuleVarPart::Literal(s) | RuleVarPart::Scoped { value: s, .. } => out.push_str(s),
+            RuleVarPart::Implicit(var) => implicit_vars.push_var(out, var),
+            RuleVarPart::Call { func, args, var } => {
+                let mut value = String::new();
+                Self::evaluate_bound_part(&mut value, var, implicit_vars);
+                out.push_str(&func.apply(&value, &RuleVarPart::args(args)));
+            }
+        }
+    }
 }
-impl<'a> eval::Env for BuildImplicitVars<'a> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
-        let string_to_evalstring =
-            |s: String| Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(s))]));
-        match var {
-            "in" => string_to_evalstring(self.file_list(self.build.explicit_ins(), ' ')),
-            "in_newline" => string_to_evalstring(self.file_list(self.build.explicit_ins(), '\n')),
-            "out" => string_to_evalstring(self.file_list(self.build.explicit_outs(), ' ')),
-            "out_newline" => string_to_evalstring(self.file_list(self.build.explicit_outs(), '\n')),
-            _ => None,
+
+/// A piece of a `command_argv` to parse.
+enum ArgvPiece<'a> {
+    Char(char),
+    /// The value of a variable, taken as is.
+    Value(Cow<'a, str>),
+    /// One of IMPLICIT_VARS.
+    Implicit(&'static str),
+}
+
+/// Evaluate a bound `command_argv`, an array of strings as in JSON, e.g.
+/// `["cc", "-c", "$in", "-o", "$out"]`.  The values of variables are taken as
+/// they are rather than parsed, so they needn't be escaped, and an element
+/// that's just `$in` or `$out` is an argument for each of the files.
+fn evaluate_argv(var: &RuleVar, implicit_vars: &BuildImplicitVars) -> anyhow::Result<Vec<String>> {
+    let mut pieces = Vec::new();
+    for part in &var.0 {
+        match part {
+            RuleVarPart::Literal(s) => pieces.extend(s.chars().map(ArgvPiece::Char)),
+            RuleVarPart::Scoped { value, .. } => pieces.push(ArgvPiece::Value(value.into())),
+            RuleVarPart::Implicit(var) => pieces.push(ArgvPiece::Implicit(var)),
+            RuleVarPart::Call { .. } => {
+                let mut value = String::new();
+                RuleVar::evaluate_bound_part(&mut value, part, implicit_vars);
+                pieces.push(ArgvPiece::Value(value.into()));
+            }
+        }
+    }
+    let mut pieces = pieces.into_iter().peekable();
+    // The next piece that isn't whitespace between elements.
+    fn token<'a>(
+        pieces: &mut std::iter::Peekable<impl Iterator<Item = ArgvPiece<'a>>>,
+    ) -> Option<ArgvPiece<'a>> {
+        while let Some(ArgvPiece::Char(c)) = pieces.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            pieces.next();
+        }
+        pieces.next()
+    }
+    if !matches!(token(&mut pieces), Some(ArgvPiece::Char('['))) {
+        bail!("expected [");
+    }
+    let mut argv = Vec::new();
+    loop {
+        if !matches!(token(&mut pieces), Some(ArgvPiece::Char('"'))) {
+            bail!("expected a quoted string");
+        }
+        let mut arg = String::new();
+        let mut files = None;
+        loop {
+            match pieces.next() {
+                Some(ArgvPiece::Char('"')) => break,
+                Some(ArgvPiece::Char('\\')) => match pieces.next() {
+                    Some(ArgvPiece::Char(c @ ('"' | '\\' | '/'))) => arg.push(c),
+                    Some(ArgvPiece::Char('n')) => arg.push('\n'),
+                    Some(ArgvPiece::Char('t')) => arg.push('\t'),
+                    _ => bail!("invalid \\ escape"),
+                },
+                Some(ArgvPiece::Char(c)) => arg.push(c),
+                Some(ArgvPiece::Value(value)) => arg.push_str(&value),
+                Some(ArgvPiece::Implicit(var)) => {
+                    if arg.is_empty() && matches!(pieces.peek(), Some(ArgvPiece::Char('"'))) {
+                        files = Some(var);
+                    } else {
+                        implicit_vars.push_var(&mut arg, var);
+                    }
+                }
+                None => bail!("unterminated string"),
+            }
+        }
+        match files {
+            Some(var) => implicit_vars.push_args(&mut argv, var),
+            None => argv.push(arg),
+        }
+        match token(&mut pieces) {
+            Some(ArgvPiece::Char(',')) => continue,
+            Some(ArgvPiece::Char(']')) => break,
+            _ => bail!("expected , or ]"),
+        }
+    }
+    if token(&mut pieces).is_some() {
+        bail!("unexpected text after ]");
+    }
+    if argv.first().map_or(true, |program| program.is_empty()) {
+        bail!("no program to run");
+    }
+    Ok(argv)
+}
+
+/// The variables of a build that may be left to Loader::expand_deferred, as
+/// they're the ones that get long, with lists of files.
+struct Deferred {
+    build: BuildId,
+    staged: bool,
+    command: Option<Arc<RuleVar>>,
+    description: Option<Arc<RuleVar>>,
+    rspfile_content: Option<Arc<RuleVar>>,
+}
+
+impl Deferred {
+    fn evaluate(
+        &self,
+        files: &DenseMap<FileId, File>,
+        ins: &[FileId],
+        outs: &[FileId],
+    ) -> [Option<String>; 3] {
+        let implicit_vars = BuildImplicitVars {
+            files,
+            ins,
+            outs,
+            staged: false,
+        };
+        // Only what the command is given refers to the staging paths.
+        let command_vars = BuildImplicitVars {
+            staged: self.staged,
+            ..implicit_vars
+        };
+        [
+            (&self.command, &command_vars),
+            (&self.description, &implicit_vars),
+            (&self.rspfile_content, &command_vars),
+        ]
+        .map(|(var, vars)| Some(var.as_ref()?.evaluate_bound(vars)))
+    }
+
+    fn store(build: &mut graph::Build, values: [Option<String>; 3]) {
+        let [command, description, rspfile_content] = values;
+        build.cmdline = command;
+        build.desc = description;
+        if let (Some(rspfile), Some(content)) = (&mut build.rspfile, rspfile_content) {
+            rspfile.content = content;
+        }
+    }
+}
+
+/// Below this many builds, expanding them isn't worth starting threads for.
+const PARALLEL_EXPAND_MIN: usize = 1024;
+
+/// A rule's variables, along with their expansion against the scope of the
+/// builds that last used it.  Builds in a row usually share a scope, so this
+/// saves looking up the same variables for each.
+#[derive(Default)]
+struct Rule {
+    vars: SmallMap<String, EvalString<String>>,
+    expanded: SmallMap<String, Arc<RuleVar>>,
+    /// The eval::Vars::generation that `expanded` is for.
+    generation: Option<u64>,
+}
+
+impl Rule {
+    fn expand(&mut self, env: &eval::Vars) {
+        if self.generation == Some(env.generation()) {
+            return;
+        }
+        self.expanded = SmallMap::default();
+        for (name, val) in self.vars.iter() {
+            self.expanded
+                .insert(name.clone(), Arc::new(RuleVar::new(val, env)));
         }
+        self.generation = Some(env.generation());
     }
 }
 
+/// Parse a boolean build variable, which is either "1" or "0"/unset.
+fn bool_var(key: &str, val: Option<String>) -> anyhow::Result<bool> {
+    Ok(match val.as_deref() {
+        None | Some("") | Some("0") => false,
+        Some("1") => true,
+        Some(other) => bail!("invalid {} attribute {:?}", key, other),
+    })
+}
+
+/// Parse a size in bytes, with an optional K/M/G/T suffix for powers of 1024,
+/// like "512M" or "8G".
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        b'T' | b't' => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
 /// Internal state used while loading.
 #[derive(Default)]
 pub struct Loader {
     graph: graph::Graph,
     default: Vec<FileId>,
-    /// rule name -> list of (key, val)
-    rules: HashMap<String, SmallMap<String, eval::EvalString<String>>>,
+    rules: HashMap<String, Rule>,
     pools: SmallMap<String, usize>,
     builddir: Option<String>,
+    /// See Options::dupbuild_warn.
+    dupbuild_warn: bool,
+    /// See Options::top_level_defaults.
+    top_level_defaults: bool,
+    /// How many subninjas deep the file being read is.
+    subninja_depth: usize,
+    /// The files currently being read, outermost first, to catch cycles of
+    /// include/subninja.
+    reading: Vec<FileId>,
+    /// How many threads expand_deferred may use.  With just the one,
+    /// nothing is deferred, as a separate pass would only add to the work.
+    threads: usize,
+    /// Builds' variables still to be evaluated, in order of BuildId.
+    deferred: Vec<Deferred>,
+    /// See Background; only used by read().
+    background: Background,
+    /// See Manifest::sources.
+    sources: Vec<(PathBuf, u64)>,
+    /// See Manifest::warnings.
+    warnings: Vec<String>,
+    /// See Options::files.
+    files: Option<Arc<HashMap<String, Vec<u8>>>>,
+    /// The filter_output regexes compiled so far, by their source, as a rule's
+    /// builds usually share one.
+    filters: HashMap<String, regex::bytes::Regex>,
 }
 
 impl Loader {
     pub fn new() -> Self {
-        let mut loader = Loader::default();
+        let mut loader = Loader {
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            ..Loader::default()
+        };
 
-        loader.rules.insert("phony".to_owned(), SmallMap::default());
+        loader.rules.insert("phony".to_owned(), Rule::default());
 
         loader
     }
 
     /// Convert a path string to a FileId.  For performance reasons
     /// this requires an owned 'path' param.
-    fn path(&mut self, mut path: String) -> FileId {
+    fn path(&mut self, mut path: String) -> anyhow::Result<FileId> {
         // Perf: this is called while parsing build.ninja files.  We go to
         // some effort to avoid allocating in the common case of a path that
         // refers to a file that is already known.
@@ -76,7 +504,11 @@ impl Loader {
         self.graph.files.id_from_canonical(path)
     }
 
-    fn evaluate_path(&mut self, path: EvalString<&str>, envs: &[&dyn eval::Env]) -> FileId {
+    fn evaluate_path(
+        &mut self,
+        path: EvalString<&str>,
+        envs: &[&dyn eval::Env],
+    ) -> anyhow::Result<FileId> {
         self.path(path.evaluate(envs))
     }
 
@@ -84,7 +516,7 @@ impl Loader {
         &mut self,
         paths: Vec<EvalString<&str>>,
         envs: &[&dyn eval::Env],
-    ) -> Vec<FileId> {
+    ) -> anyhow::Result<Vec<FileId>> {
         paths
             .into_iter()
             .map(|path| self.evaluate_path(path, envs))
@@ -93,19 +525,19 @@ impl Loader {
 
     fn add_build(
         &mut self,
-        filename: std::rc::Rc<PathBuf>,
+        filename: std::sync::Arc<PathBuf>,
         env: &eval::Vars,
         b: parse::Build,
     ) -> anyhow::Result<()> {
         let ins = graph::BuildIns {
-            ids: self.evaluate_paths(b.ins, &[&b.vars, env]),
+            ids: self.evaluate_paths(b.ins, &[&b.vars, env])?,
             explicit: b.explicit_ins,
             implicit: b.implicit_ins,
             order_only: b.order_only_ins,
             // validation is implied by the other counts
         };
         let outs = graph::BuildOuts {
-            ids: self.evaluate_paths(b.outs, &[&b.vars, env]),
+            ids: self.evaluate_paths(b.outs, &[&b.vars, env])?,
             explicit: b.explicit_outs,
         };
         let mut build = graph::Build::new(
@@ -117,28 +549,46 @@ impl Loader {
             outs,
         );
 
-        let rule = match self.rules.get(b.rule) {
+        let rule = match self.rules.get_mut(b.rule) {
             Some(r) => r,
             None => bail!("unknown rule {:?}", b.rule),
         };
-
-        let implicit_vars = BuildImplicitVars {
-            graph: &self.graph,
-            build: &build,
-        };
+        rule.expand(env);
+        let rule = &*rule;
+        build.rule = b.rule.to_owned();
 
         // temp variable in order to not move all of b into the closure
         let build_vars = &b.vars;
-        let lookup = |key: &str| -> Option<String> {
+        let lookup_in = |implicit_vars: &BuildImplicitVars, key: &str| -> Option<String> {
             // Look up `key = ...` binding in build and rule block.
-            Some(match rule.get(key) {
-                Some(val) => val.evaluate(&[&implicit_vars, build_vars, env]),
+            Some(match rule.expanded.get(key) {
+                Some(val) => val.evaluate(implicit_vars, build_vars, env),
                 None => build_vars.get(key)?.evaluate(&[env]),
             })
         };
 
-        let cmdline = lookup("command");
-        let desc = lookup("description");
+        let implicit_vars = BuildImplicitVars {
+            files: &self.graph.files.by_id,
+            ins: build.explicit_ins(),
+            outs: build.explicit_outs(),
+            staged: false,
+        };
+        let lookup = |key: &str| lookup_in(&implicit_vars, key);
+        // This changes what $out refers to in the command.
+        let atomic_outputs = bool_var("atomic_outputs", lookup("atomic_outputs"))?;
+        // Like lookup, but leaving the evaluation to expand_deferred.
+        let defer = |key: &str| -> Option<Arc<RuleVar>> {
+            Some(match rule.expanded.get(key) {
+                Some(val) => val.bind(build_vars, env),
+                None => {
+                    let val = build_vars.get(key)?.evaluate(&[env]);
+                    Arc::new(RuleVar(vec![RuleVarPart::Literal(val)]))
+                }
+            })
+        };
+
+        let mut command = defer("command");
+        let description = defer("description");
         let depfile = lookup("depfile");
         let parse_showincludes = match lookup("deps").as_deref() {
             None => false,
@@ -147,35 +597,224 @@ impl Loader {
             Some(other) => bail!("invalid deps attribute {:?}", other),
         };
         let pool = lookup("pool");
+        // As in Ninja, these are set by any non-empty value.
+        let generator = lookup("generator").is_some_and(|val| !val.is_empty());
+        let restat = lookup("restat").is_some_and(|val| !val.is_empty());
+        let pty = bool_var("pty", lookup("pty"))?;
+        let no_network = match lookup("network").as_deref() {
+            None | Some("") => false,
+            Some("none") => true,
+            Some(other) => bail!("invalid network attribute {:?}, expected none", other),
+        };
+        // The `PREFIX_NAME = value` bindings of the rule and build, by NAME,
+        // looked up with get.
+        let prefixed = |prefix: &str, get: &dyn Fn(&str) -> Option<String>| {
+            let mut names: Vec<&str> = rule
+                .vars
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .chain(build_vars.iter().map(|(name, _)| *name))
+                .filter(|name| name.starts_with(prefix))
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            names
+                .into_iter()
+                .filter_map(|name| Some((name[prefix.len()..].to_owned(), get(name)?)))
+                .collect::<Vec<_>>()
+        };
+        let command_env = prefixed("env_", &lookup);
+        // Unlike the variables a rule uses, a build's metadata is its own
+        // rather than the rule's, so a build's binding overrides the rule's.
+        let meta = prefixed("meta_", &|name| match build_vars.get(name) {
+            Some(val) => Some(val.evaluate(&[env])),
+            None => lookup(name),
+        });
+        let wrapper = lookup("wrapper");
+        // Unlike other variables, this may be set for all rules at the top
+        // level, as n2_shell there, since manifests may well have a top-level
+        // `shell` of their own for other uses.
+        let mut shell = match lookup("shell").or_else(|| env.get("n2_shell").cloned()) {
+            None => None,
+            Some(val) => Some(
+                crate::process::split_words(&val)
+                    .map_err(|err| anyhow!("invalid shell attribute: {}", err))?,
+            ),
+        };
+        // The command as the arguments to run it with directly, so there's no
+        // shell to quote them for.
+        if let Some(var) = defer("command_argv") {
+            if command.is_some() {
+                bail!("command and command_argv can't both be set");
+            }
+            let command_vars = BuildImplicitVars {
+                staged: atomic_outputs,
+                ..implicit_vars
+            };
+            let argv = evaluate_argv(&var, &command_vars)
+                .map_err(|err| anyhow!("invalid command_argv attribute: {}", err))?;
+            let cmdline = crate::process::join_words(&argv);
+            command = Some(Arc::new(RuleVar(vec![RuleVarPart::Literal(cmdline)])));
+            shell = Some(Vec::new());
+        }
+        let mem = match lookup("mem").as_deref() {
+            None | Some("") => 0,
+            Some(val) => {
+                parse_size(val).ok_or_else(|| anyhow!("invalid mem attribute {:?}", val))?
+            }
+        };
+        let filter_output = match lookup("filter_output") {
+            None => None,
+            Some(val) if val.is_empty() => None,
+            Some(val) => Some(match self.filters.get(&val) {
+                Some(filter) => filter.clone(),
+                None => {
+                    let filter = regex::bytes::Regex::new(&val)
+                        .map_err(|err| anyhow!("invalid filter_output attribute: {}", err))?;
+                    self.filters.insert(val, filter.clone());
+                    filter
+                }
+            }),
+        };
+        let cores = match lookup("cores").as_deref() {
+            None | Some("") => 1,
+            Some(val) => val
+                .parse()
+                .map_err(|_| anyhow!("invalid cores attribute {:?}", val))?,
+        };
+        let allow_missing_outputs =
+            bool_var("allow_missing_outputs", lookup("allow_missing_outputs"))?;
 
         let rspfile_path = lookup("rspfile");
-        let rspfile_content = lookup("rspfile_content");
-        let rspfile = match (rspfile_path, rspfile_content) {
+        let rspfile_content = defer("rspfile_content");
+        let rspfile = match (rspfile_path, &rspfile_content) {
             (None, None) => None,
-            (Some(path), Some(content)) => Some(RspFile {
+            (Some(path), Some(_)) => Some(RspFile {
                 path: std::path::PathBuf::from(path),
-                content,
+                content: String::new(),
             }),
             _ => bail!("rspfile and rspfile_content need to be both specified"),
         };
 
-        build.cmdline = cmdline;
-        build.desc = desc;
         build.depfile = depfile;
         build.parse_showincludes = parse_showincludes;
         build.rspfile = rspfile;
         build.pool = pool;
+        build.pty = pty;
+        build.no_network = no_network;
+        build.env = command_env;
+        build.meta = meta;
+        build.wrapper = wrapper;
+        build.shell = shell;
+        build.mem = mem;
+        build.cores = cores;
+        build.filter_output = filter_output;
+        build.allow_missing_outputs = allow_missing_outputs;
+        build.atomic_outputs = atomic_outputs;
+        build.generator = generator;
+        build.restat = restat;
+
+        let deferred = Deferred {
+            build: self.graph.builds.next_id(),
+            staged: atomic_outputs,
+            command,
+            description,
+            rspfile_content,
+        };
+        // Evaluated only once added, as that drops repeated outputs.
+        let warnings = self.graph.add_build(build, self.dupbuild_warn)?;
+        self.warnings.extend(warnings);
+        if let Some(prestat) = &mut self.background.prestat {
+            let build = &self.graph.builds[deferred.build];
+            for &id in build.ordering_ins() {
+                prestat.add(id, self.graph.file(id).path());
+            }
+        }
+        if self.threads > 1 {
+            self.deferred.push(deferred);
+        } else {
+            let build = &self.graph.builds[deferred.build];
+            let values = deferred.evaluate(
+                &self.graph.files.by_id,
+                build.explicit_ins(),
+                build.explicit_outs(),
+            );
+            Deferred::store(&mut self.graph.builds[deferred.build], values);
+        }
+        Ok(())
+    }
 
-        self.graph.add_build(build)
+    /// Evaluate the variables add_build left for later, spread over threads
+    /// for large graphs.  Each build's are evaluated independently of the
+    /// others', so the result doesn't depend on how they're split up.
+    fn expand_deferred(&mut self) {
+        let deferred = std::mem::take(&mut self.deferred);
+        let files = &self.graph.files.by_id;
+        let builds = &self.graph.builds;
+        let jobs: Vec<(&Deferred, &[FileId], &[FileId])> = deferred
+            .iter()
+            .map(|d| {
+                let build = &builds[d.build];
+                (d, build.explicit_ins(), build.explicit_outs())
+            })
+            .collect();
+        let expand = |jobs: &[(&Deferred, &[FileId], &[FileId])]| -> Vec<[Option<String>; 3]> {
+            jobs.iter()
+                .map(|&(d, ins, outs)| d.evaluate(files, ins, outs))
+                .collect()
+        };
+
+        let expanded = if self.threads == 1 || jobs.len() < PARALLEL_EXPAND_MIN {
+            expand(&jobs)
+        } else {
+            let chunk_size = jobs.len().div_ceil(self.threads);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = jobs
+                    .chunks(chunk_size)
+                    .map(|chunk| scope.spawn(move || expand(chunk)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        };
+
+        for (d, values) in deferred.iter().zip(expanded) {
+            Deferred::store(&mut self.graph.builds[d.build], values);
+        }
     }
 
     fn read_file(&mut self, id: FileId) -> anyhow::Result<()> {
+        if let Some(pos) = self.reading.iter().position(|&r| r == id) {
+            let cycle: Vec<String> = self.reading[pos..]
+                .iter()
+                .chain(std::iter::once(&id))
+                .map(|&r| self.graph.file(r).name.clone())
+                .collect();
+            bail!("include cycle: {}", cycle.join(" -> "));
+        }
         let path = self.graph.file(id).path().to_path_buf();
-        let bytes = match trace::scope("read file", || scanner::read_file_with_nul(&path)) {
-            Ok(b) => b,
-            Err(e) => bail!("read {}: {}", path.display(), e),
+        let bytes = match &self.files {
+            Some(files) => match files.get(&self.graph.file(id).name) {
+                Some(content) => {
+                    let mut bytes = Vec::with_capacity(content.len() + 1);
+                    bytes.extend_from_slice(content);
+                    bytes.push(0);
+                    bytes
+                }
+                None => bail!("read {}: no such file", path.display()),
+            },
+            None => match trace::scope("read file", || scanner::read_file_with_nul(&path)) {
+                Ok(b) => b,
+                Err(e) => bail!("read {}: {}", path.display(), e),
+            },
         };
-        self.parse(path, &bytes)
+        self.sources.push((path.clone(), content_hash(&bytes)));
+        self.reading.push(id);
+        let result = self.parse(path, &bytes);
+        self.reading.pop();
+        result
     }
 
     fn evaluate_and_read_file(
@@ -183,12 +822,12 @@ impl Loader {
         file: EvalString<&str>,
         envs: &[&dyn eval::Env],
     ) -> anyhow::Result<()> {
-        let evaluated = self.evaluate_path(file, envs);
+        let evaluated = self.evaluate_path(file, envs)?;
         self.read_file(evaluated)
     }
 
     pub fn parse(&mut self, path: PathBuf, bytes: &[u8]) -> anyhow::Result<()> {
-        let filename = std::rc::Rc::new(path);
+        let filename = std::sync::Arc::new(path);
 
         let mut parser = parse::Parser::new(&bytes);
 
@@ -203,13 +842,22 @@ impl Loader {
             match stmt {
                 Statement::Include(id) => trace::scope("include", || {
                     self.evaluate_and_read_file(id, &[&parser.vars])
-                })?,
+                })
+                .map_err(|err| included_from(err, &filename, parser.line()))?,
                 // TODO: implement scoping for subninja
-                Statement::Subninja(id) => trace::scope("subninja", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
-                })?,
+                Statement::Subninja(id) => {
+                    self.subninja_depth += 1;
+                    let result = trace::scope("subninja", || {
+                        self.evaluate_and_read_file(id, &[&parser.vars])
+                    });
+                    self.subninja_depth -= 1;
+                    result.map_err(|err| included_from(err, &filename, parser.line()))?
+                }
+                // As in Ninja, defaults from all files count, including
+                // subninjas, unless only top-level ones are wanted.
+                Statement::Default(_) if self.top_level_defaults && self.subninja_depth > 0 => {}
                 Statement::Default(defaults) => {
-                    let evaluated = self.evaluate_paths(defaults, &[&parser.vars]);
+                    let evaluated = self.evaluate_paths(defaults, &[&parser.vars])?;
                     self.default.extend(evaluated);
                 }
                 Statement::Rule(rule) => {
@@ -220,9 +868,26 @@ impl Loader {
                         // memory.
                         vars.insert(name.to_owned(), val.into_owned());
                     }
-                    self.rules.insert(rule.name.to_owned(), vars);
+                    self.rules.insert(
+                        rule.name.to_owned(),
+                        Rule {
+                            vars,
+                            ..Rule::default()
+                        },
+                    );
+                }
+                Statement::Build(build) => {
+                    // builddir is bound before any builds by convention, so
+                    // by now the db's location is known, if only in the
+                    // build file given rather than one it includes.
+                    if self.background.expect_db && self.reading.len() <= 1 {
+                        self.background.expect_db = false;
+                        let builddir = parser.vars.get("builddir").map_or("", |dir| dir.as_str());
+                        let path = Path::new(builddir).join(".n2_db");
+                        self.background.db = Some(DbRead::start(path));
+                    }
+                    self.add_build(filename.clone(), &parser.vars, build)?
                 }
-                Statement::Build(build) => self.add_build(filename.clone(), &parser.vars, build)?,
                 Statement::Pool(pool) => {
                     self.pools.insert(pool.name.to_string(), pool.depth);
                 }
@@ -233,44 +898,275 @@ impl Loader {
     }
 }
 
+/// Work read() has going on other threads while the build files load.
+#[derive(Default)]
+struct Background {
+    /// If set, where to send the files builds read to be statted early.
+    prestat: Option<Prestat>,
+    /// The db, being read from where it's expected to be.
+    db: Option<DbRead>,
+    /// If true, start reading the db as soon as its location is known.
+    expect_db: bool,
+}
+
+/// A db being read on another thread; see db::decode.
+struct DbRead {
+    path: PathBuf,
+    thread: std::thread::JoinHandle<anyhow::Result<Option<db::Decoded>>>,
+}
+
+impl DbRead {
+    fn start(path: PathBuf) -> Self {
+        let thread = std::thread::spawn({
+            let path = path.clone();
+            move || db::decode(&path)
+        });
+        DbRead { path, thread }
+    }
+
+    /// Read the db at path, through this if it was reading the right one.
+    fn finish(read: Option<DbRead>, path: &Path) -> anyhow::Result<Option<db::Decoded>> {
+        match read {
+            Some(read) if read.path == path => match read.thread.join() {
+                Ok(result) => result,
+                Err(_) => bail!("reading thread panicked"),
+            },
+            // A read of the wrong file is left to finish on its own.
+            _ => db::decode(path),
+        }
+    }
+}
+
+/// Extends an error from an included file with where it was included from,
+/// so that errors deep in an include chain can be traced back to the root.
+fn included_from(err: anyhow::Error, filename: &Path, line: usize) -> anyhow::Error {
+    let mut msg = err.to_string();
+    if !msg.ends_with('\n') {
+        msg.push('\n');
+    }
+    msg.push_str(&format!("included from {}:{}", filename.display(), line));
+    anyhow!(msg)
+}
+
 /// State loaded by read().
 pub struct State {
     pub graph: graph::Graph,
     pub db: db::Writer,
     pub hashes: graph::Hashes,
+    pub durations: graph::Durations,
     pub default: Vec<FileId>,
     pub pools: SmallMap<String, usize>,
+    /// Where the db is, which other state kept between builds goes beside.
+    pub db_path: PathBuf,
+    /// The `builddir` of the first build file, or "" if it has none, where
+    /// other metadata like traces goes.
+    pub builddir: PathBuf,
+    /// Mtimes of files builds read, statted while loading; see prestat.
+    pub prestat: Vec<(FileId, graph::MTime)>,
+    /// See Manifest::sources.
+    pub sources: Vec<(PathBuf, u64)>,
+    /// See Manifest::warnings.
+    pub warnings: Vec<String>,
+}
+
+/// Options affecting how build files are loaded.
+#[derive(Default)]
+pub struct Options {
+    /// See GraphFiles::fold_case.
+    pub fold_case: bool,
+    /// If true, outputs declared by more than one build are a warning rather
+    /// than an error, with the first build keeping the output.
+    pub dupbuild_warn: bool,
+    /// If true, `default` statements only count in the build files given
+    /// and those they include, not in subninjas.
+    pub top_level_defaults: bool,
+    /// If true, the db is only read, and nothing learned by building is
+    /// written back; see db::open_read_only.
+    pub no_db_write: bool,
+    /// Where to keep the db, in place of `.n2_db` in the builddir.
+    pub db_path: Option<PathBuf>,
+    /// If true, symlinks are statted as with lstat(); see graph::stat.
+    pub lstat: bool,
+    /// The contents of the build files by path, to read in place of the
+    /// filesystem's, for where there is none, as in a browser.
+    pub files: Option<Arc<HashMap<String, Vec<u8>>>>,
+}
+
+/// The build graph as read from the build files, without the db's state.
+pub struct Manifest {
+    pub graph: graph::Graph,
+    pub default: Vec<FileId>,
+    pub pools: SmallMap<String, usize>,
+    /// See State::db_path.
+    pub db_path: PathBuf,
+    /// See State::builddir.
+    pub builddir: PathBuf,
+    /// The build files read, including includes and subninjas, each with a
+    /// hash of its contents; see sources_changed.
+    pub sources: Vec<(PathBuf, u64)>,
+    /// Warnings about the build files, like outputs repeated under
+    /// dupbuild=warn, for the caller to report as it reports progress.
+    pub warnings: Vec<String>,
+}
+
+/// Hash the contents of a build file, as read with its trailing nul.
+fn content_hash(bytes: &[u8]) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Whether any of the build files read differ now from what was read, e.g.
+/// because the build that generates them wrote something new.
+pub fn sources_changed(sources: &[(PathBuf, u64)]) -> bool {
+    sources
+        .iter()
+        .any(|(path, hash)| match scanner::read_file_with_nul(path) {
+            Ok(bytes) => content_hash(&bytes) != *hash,
+            Err(_) => true,
+        })
 }
 
 /// Load build.ninja/.n2_db and return the loaded build graph and state.
-pub fn read(build_filename: &str) -> anyhow::Result<State> {
-    let mut loader = Loader::new();
-    trace::scope("loader.read_file", || {
-        let id = loader
-            .graph
-            .files
-            .id_from_canonical(canon_path(build_filename));
-        loader.read_file(id)
-    })?;
+///
+/// More than one build file may be given, in which case they're loaded in
+/// order into the same graph, so later files can use earlier ones' rules.
+/// Only the first file's builddir determines where .n2_db goes.
+pub fn read(build_filenames: &[String], options: &Options) -> anyhow::Result<State> {
+    // With only the one thread, reading the db and statting files early
+    // would just get in the way of parsing.
+    let mut background = Background::default();
+    if std::thread::available_parallelism().map_or(1, |n| n.get()) > 1 {
+        background.prestat = Some(Prestat::start(!options.lstat));
+        match &options.db_path {
+            Some(path) => background.db = Some(DbRead::start(path.clone())),
+            None => background.expect_db = true,
+        }
+    }
+    let (
+        Manifest {
+            mut graph,
+            default,
+            pools,
+            db_path,
+            builddir,
+            sources,
+            warnings,
+        },
+        background,
+    ) = load_manifest(build_filenames, options, background)?;
+    let Background {
+        prestat,
+        db: db_read,
+        ..
+    } = background;
     let mut hashes = graph::Hashes::default();
+    let mut durations = graph::Durations::default();
     let db = trace::scope("db::open", || {
-        let mut db_path = PathBuf::from(".n2_db");
-        if let Some(builddir) = &loader.builddir {
-            db_path = Path::new(&builddir).join(db_path);
-            if let Some(parent) = db_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
+        let root = db::Root {
+            dir: std::env::current_dir()?.to_string_lossy().into_owned(),
+            explicit: options.db_path.is_some(),
         };
-        db::open(&db_path, &mut loader.graph, &mut hashes)
+        let decoded = DbRead::finish(db_read, &db_path)?;
+        if options.no_db_write {
+            return db::open_read_only(&root, decoded, &mut graph, &mut hashes, &mut durations);
+        }
+        // The builddir, if any.
+        if let Some(parent) = db_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(canon::long_path(parent))?;
+        }
+        db::open(
+            &db_path,
+            &root,
+            decoded,
+            &mut graph,
+            &mut hashes,
+            &mut durations,
+        )
     })
-    .map_err(|err| anyhow!("load .n2_db: {}", err))?;
+    .map_err(|err| anyhow!("load {}: {}", db_path.display(), err))?;
+    let prestat = trace::scope("prestat.finish", || {
+        prestat.map_or_else(Vec::new, Prestat::finish)
+    });
     Ok(State {
-        graph: loader.graph,
+        graph,
         db,
         hashes,
+        durations,
+        default,
+        pools,
+        db_path,
+        builddir,
+        prestat,
+        sources,
+        warnings,
+    })
+}
+
+/// Load just the build files; see read().  For the tools, whose output may
+/// be read by other programs, any warnings are printed to stderr.
+pub fn read_manifest(build_filenames: &[String], options: &Options) -> anyhow::Result<Manifest> {
+    let (manifest, _) = load_manifest(build_filenames, options, Background::default())?;
+    for warning in &manifest.warnings {
+        eprintln!("{}", warning);
+    }
+    Ok(manifest)
+}
+
+/// Load the build files, starting what background work is asked for, which is
+/// returned to be finished once there's nothing else to do.
+fn load_manifest(
+    build_filenames: &[String],
+    options: &Options,
+    background: Background,
+) -> anyhow::Result<(Manifest, Background)> {
+    let mut loader = Loader::new();
+    loader.background = background;
+    loader.graph.files.fold_case = options.fold_case;
+    loader.dupbuild_warn = options.dupbuild_warn;
+    loader.top_level_defaults = options.top_level_defaults;
+    loader.files = options.files.clone();
+    let mut builddir = None;
+    for (i, build_filename) in build_filenames.iter().enumerate() {
+        trace::scope("loader.read_file", || {
+            if build_filename == "-" {
+                // Relative paths in a manifest read from stdin, including those
+                // of includes, are relative to the working directory like any
+                // other.
+                let mut bytes = Vec::new();
+                if let Err(e) = std::io::stdin().read_to_end(&mut bytes) {
+                    bail!("read stdin: {}", e);
+                }
+                bytes.push(0);
+                return loader.parse(PathBuf::from("<stdin>"), &bytes);
+            }
+            let id = loader
+                .graph
+                .files
+                .id_from_canonical(canon_path(build_filename))?;
+            loader.read_file(id)
+        })?;
+        if i == 0 {
+            builddir = loader.builddir.take();
+        }
+    }
+    trace::scope("loader.expand_deferred", || loader.expand_deferred());
+    let builddir = PathBuf::from(builddir.unwrap_or_default());
+    let db_path = match &options.db_path {
+        Some(path) => path.clone(),
+        None => builddir.join(".n2_db"),
+    };
+    let manifest = Manifest {
+        graph: loader.graph,
         default: loader.default,
         pools: loader.pools,
-    })
+        db_path,
+        builddir,
+        sources: loader.sources,
+        warnings: loader.warnings,
+    };
+    Ok((manifest, loader.background))
 }
 
 /// Parse a single file's content.
@@ -281,5 +1177,214 @@ pub fn parse(name: &str, mut content: Vec<u8>) -> anyhow::Result<graph::Graph> {
     trace::scope("loader.read_file", || {
         loader.parse(PathBuf::from(name), &content)
     })?;
+    loader.expand_deferred();
     Ok(loader.graph)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("123"), Some(123));
+        assert_eq!(parse_size("4k"), Some(4 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("8G"), Some(8 << 30));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn dupbuild_warnings_returned() -> anyhow::Result<()> {
+        let mut loader = Loader::new();
+        loader.dupbuild_warn = true;
+        loader.parse(
+            PathBuf::from("build.ninja"),
+            b"
+rule touch
+  command = touch $out
+build out: touch
+build out other: touch
+\0",
+        )?;
+        assert_eq!(loader.warnings.len(), 1);
+        assert!(loader.warnings[0].contains("\"out\" is already an output"));
+        Ok(())
+    }
+
+    #[test]
+    fn rule_vars_follow_scope() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            b"
+flags = -O1
+rule cc
+  command = cc $flags $extra $in -o $out
+build a.o: cc a.c
+build b.o: cc b.c
+  flags = -O2 $flags
+flags = -O3
+extra = -g
+build c.o: cc c.c
+build d.o: cc d.c
+"
+            .to_vec(),
+        )?;
+        let cmdlines: Vec<&str> = (0..4)
+            .map(|i| {
+                graph.builds[graph::BuildId::from(i)]
+                    .cmdline
+                    .as_deref()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            cmdlines,
+            [
+                "cc -O1  a.c -o a.o",
+                "cc -O2 -O1  b.c -o b.o",
+                "cc -O3 -g c.c -o c.o",
+                "cc -O3 -g d.c -o d.o",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn path_functions() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            b"
+pragma functions
+objdir = out/obj
+src = src/main.c
+obj = $objdir/$basename(src)
+rule cc
+  command = cc -c $in -o $out -MF $replace(out, .o, .d) -I$dirname(in)
+build $replace(obj, .c, .o): cc $src
+build top.o: cc top.c
+  objdir = ignored
+"
+            .to_vec(),
+        )?;
+        let cmdlines: Vec<&str> = (0..2)
+            .map(|i| {
+                graph.builds[graph::BuildId::from(i)]
+                    .cmdline
+                    .as_deref()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            cmdlines,
+            [
+                "cc -c src/main.c -o out/obj/main.o -MF out/obj/main.d -Isrc",
+                "cc -c top.c -o top.o -MF top.d -I.",
+            ]
+        );
+
+        let err = match parse(
+            "build.ninja",
+            b"pragma functions\nx = $replace(y, a)\n".to_vec(),
+        ) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err,
+        };
+        assert!(err
+            .to_string()
+            .contains("replace takes 2 arguments after the variable, not 1"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_argv() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            br#"
+rule cc
+  command_argv = ["cc", "-c", "$in", "-o", "$out", "-DNAME=$name", "a\"b"]
+build a$ b.o: cc x$ y.c z.c
+  name = "it's"
+"#
+            .to_vec(),
+        )?;
+        let build = &graph.builds[graph::BuildId::from(0)];
+        assert_eq!(build.shell, Some(vec![]));
+        assert_eq!(
+            crate::process::split_words(build.cmdline.as_deref().unwrap())?,
+            vec![
+                "cc",
+                "-c",
+                "x y.c",
+                "z.c",
+                "-o",
+                "a b.o",
+                "-DNAME=\"it's\"",
+                "a\"b"
+            ]
+        );
+
+        let err = match parse(
+            "build.ninja",
+            b"
+rule cc
+  command_argv = [cc]
+build a: cc
+"
+            .to_vec(),
+        ) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("expected a quoted string"));
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_expansion_matches() -> anyhow::Result<()> {
+        let mut manifest = String::from(
+            "rule link\n  command = ld @$out.rsp -o $out $flags\n  description = LINK $out\n  \
+             rspfile = $out.rsp\n  rspfile_content = $in_newline\n",
+        );
+        for i in 0..PARALLEL_EXPAND_MIN * 2 {
+            manifest.push_str(&format!("build out{}: link a{} b{}\n", i, i, i));
+            if i % 3 == 0 {
+                manifest.push_str(&format!("  flags = -v{}\n", i));
+            }
+        }
+        manifest.push('\0');
+        let load = |threads: usize| -> anyhow::Result<graph::Graph> {
+            let mut loader = Loader::new();
+            loader.threads = threads;
+            loader.parse(PathBuf::from("build.ninja"), manifest.as_bytes())?;
+            loader.expand_deferred();
+            Ok(loader.graph)
+        };
+        let serial = load(1)?;
+        let parallel = load(4)?;
+        for i in 0..PARALLEL_EXPAND_MIN * 2 {
+            let (a, b) = (
+                &serial.builds[graph::BuildId::from(i)],
+                &parallel.builds[graph::BuildId::from(i)],
+            );
+            assert_eq!(a.cmdline, b.cmdline);
+            assert_eq!(a.desc, b.desc);
+            assert_eq!(
+                a.rspfile.as_ref().unwrap().content,
+                b.rspfile.as_ref().unwrap().content
+            );
+        }
+        let flagged = &parallel.builds[graph::BuildId::from(PARALLEL_EXPAND_MIN * 2 - 2)];
+        assert_eq!(
+            flagged.cmdline.as_deref(),
+            Some("ld @out2046.rsp -o out2046 -v2046")
+        );
+        assert_eq!(flagged.rspfile.as_ref().unwrap().content, "a2046\nb2046");
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Pushing numbers about a finished build to a metrics service, either statsd
+//! or a Prometheus pushgateway, so they can be tracked over many builds.
+
+use anyhow::{anyhow, bail};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Numbers describing a build.
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    /// Wall time of the whole build.
+    pub duration: Duration,
+    /// Tasks that ran and succeeded.
+    pub tasks_run: usize,
+    /// Tasks that ran and failed.
+    pub tasks_failed: usize,
+    /// Builds with commands that were checked and found up to date.
+    pub up_to_date: usize,
+    /// Total time spent running tasks, over all of them.
+    pub busy: Duration,
+    /// Maximum number of tasks that could run at once.
+    pub parallelism: usize,
+}
+
+impl Stats {
+    /// The fraction of builds checked that didn't need to run, akin to a
+    /// cache hit rate.
+    fn hit_rate(&self) -> f64 {
+        let checked = self.up_to_date + self.tasks_run + self.tasks_failed;
+        if checked == 0 {
+            return 1.0;
+        }
+        self.up_to_date as f64 / checked as f64
+    }
+
+    /// The fraction of the available parallelism that was used, on average.
+    fn utilization(&self) -> f64 {
+        let available = self.duration.as_secs_f64() * self.parallelism as f64;
+        if available == 0.0 {
+            return 0.0;
+        }
+        self.busy.as_secs_f64() / available
+    }
+}
+
+#[derive(Clone)]
+enum Endpoint {
+    /// host:port to send UDP packets to.
+    Statsd(String),
+    /// host:port and path to POST to.
+    Pushgateway { host: String, path: String },
+}
+
+/// Where to push metrics, and the labels to tag them with.
+#[derive(Clone)]
+pub struct Sink {
+    endpoint: Endpoint,
+    labels: Vec<(String, String)>,
+}
+
+impl Sink {
+    /// Parse a URL, either statsd://host:port or http://host:port/path for a
+    /// pushgateway (where the path defaults to /metrics/job/n2), and labels
+    /// of the form key=value.
+    pub fn parse(url: &str, labels: &[String]) -> anyhow::Result<Sink> {
+        let endpoint = if let Some(host) = url.strip_prefix("statsd://") {
+            Endpoint::Statsd(host.to_string())
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(slash) if slash + 1 < rest.len() => (&rest[..slash], &rest[slash..]),
+                Some(slash) => (&rest[..slash], "/metrics/job/n2"),
+                None => (rest, "/metrics/job/n2"),
+            };
+            Endpoint::Pushgateway {
+                host: host.to_string(),
+                path: path.to_string(),
+            }
+        } else {
+            bail!("metrics URL {:?} must start with statsd:// or http://", url);
+        };
+        let labels = labels
+            .iter()
+            .map(|label| {
+                let (key, value) = label
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("metrics label {:?} must be key=value", label))?;
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Sink { endpoint, labels })
+    }
+
+    pub fn push(&self, stats: &Stats) -> anyhow::Result<()> {
+        match &self.endpoint {
+            Endpoint::Statsd(host) => {
+                let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+                socket.send_to(format_statsd(stats, &self.labels).as_bytes(), host.as_str())?;
+            }
+            Endpoint::Pushgateway { host, path } => {
+                let body = format_prometheus(stats, &self.labels);
+                post(host, path, &body)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The metrics as (name, statsd type, value).
+fn metrics(stats: &Stats) -> [(&'static str, &'static str, String); 6] {
+    [
+        (
+            "duration_seconds",
+            "g",
+            format!("{:.3}", stats.duration.as_secs_f64()),
+        ),
+        ("tasks_run", "c", stats.tasks_run.to_string()),
+        ("tasks_failed", "c", stats.tasks_failed.to_string()),
+        ("up_to_date", "c", stats.up_to_date.to_string()),
+        ("hit_rate", "g", format!("{:.3}", stats.hit_rate())),
+        ("utilization", "g", format!("{:.3}", stats.utilization())),
+    ]
+}
+
+/// Format as statsd lines, with labels as DogStatsD-style tags.
+fn format_statsd(stats: &Stats, labels: &[(String, String)]) -> String {
+    let tags = if labels.is_empty() {
+        String::new()
+    } else {
+        let tags: Vec<String> = labels.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+        format!("|#{}", tags.join(","))
+    };
+    let mut out = String::new();
+    for (name, kind, value) in metrics(stats) {
+        out.push_str(&format!("n2.{}:{}|{}{}\n", name, value, kind, tags));
+    }
+    out
+}
+
+/// Format in the Prometheus text exposition format.
+fn format_prometheus(stats: &Stats, labels: &[(String, String)]) -> String {
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| {
+                let v = v
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{}=\"{}\"", k, v)
+            })
+            .collect();
+        format!("{{{}}}", labels.join(","))
+    };
+    let mut out = String::new();
+    for (name, _, value) in metrics(stats) {
+        out.push_str(&format!("# TYPE n2_{} gauge\n", name));
+        out.push_str(&format!("n2_{}{} {}\n", name, labels, value));
+    }
+    out
+}
+
+/// How long pushing to a pushgateway may take at each step, so that one
+/// that's unreachable or stuck doesn't hold up the end of the build.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connect to the first of host's addresses that accepts within TIMEOUT.
+fn connect(host: &str) -> anyhow::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match last_err {
+        Some(err) => Err(err.into()),
+        None => bail!("{}: no addresses", host),
+    }
+}
+
+/// A minimal HTTP POST, which is all a pushgateway needs.
+fn post(host: &str, path: &str, body: &str) -> anyhow::Result<()> {
+    let mut stream = connect(host)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or("");
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("POST {}{}: {:?}", host, path, status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        Stats {
+            duration: Duration::from_secs(2),
+            tasks_run: 3,
+            tasks_failed: 1,
+            up_to_date: 4,
+            busy: Duration::from_secs(4),
+            parallelism: 4,
+        }
+    }
+
+    #[test]
+    fn statsd() {
+        let labels = vec![("host".to_string(), "ci".to_string())];
+        assert_eq!(
+            format_statsd(&stats(), &labels),
+            "n2.duration_seconds:2.000|g|#host:ci\n\
+             n2.tasks_run:3|c|#host:ci\n\
+             n2.tasks_failed:1|c|#host:ci\n\
+             n2.up_to_date:4|c|#host:ci\n\
+             n2.hit_rate:0.500|g|#host:ci\n\
+             n2.utilization:0.500|g|#host:ci\n"
+        );
+    }
+
+    #[test]
+    fn prometheus() {
+        let labels = vec![("branch".to_string(), "a\"b".to_string())];
+        let out = format_prometheus(&stats(), &labels);
+        assert!(out.starts_with(
+            "# TYPE n2_duration_seconds gauge\n\
+             n2_duration_seconds{branch=\"a\\\"b\"} 2.000\n"
+        ));
+        assert!(out.contains("n2_hit_rate{branch=\"a\\\"b\"} 0.500\n"));
+    }
+
+    #[test]
+    fn parse() {
+        let sink = Sink::parse("http://localhost:9091", &["a=b=c".to_string()]).unwrap();
+        match sink.endpoint {
+            Endpoint::Pushgateway { host, path } => {
+                assert_eq!(host, "localhost:9091");
+                assert_eq!(path, "/metrics/job/n2");
+            }
+            _ => panic!("expected pushgateway"),
+        }
+        assert_eq!(sink.labels, vec![("a".to_string(), "b=c".to_string())]);
+        assert!(Sink::parse("udp://x", &[]).is_err());
+        assert!(Sink::parse("statsd://x:1", &["novalue".to_string()]).is_err());
+    }
+}
@@ -6,7 +6,7 @@
 //! text, marked with the lifetime `'text`.
 
 use crate::{
-    eval::{EvalPart, EvalString, Vars},
+    eval::{Call, EvalPart, EvalString, Vars},
     scanner::{ParseError, ParseResult, Scanner},
     smallmap::SmallMap,
 };
@@ -49,12 +49,37 @@ pub enum Statement<'text> {
     Pool(Pool<'text>),
 }
 
+/// An `if` directive being read.
+struct Conditional {
+    /// Whether the lines around the `if` are read, rather than skipped.
+    outer: bool,
+    /// Whether the condition held.
+    held: bool,
+    /// Whether the `else` has been seen.
+    in_else: bool,
+}
+
+impl Conditional {
+    /// Whether the lines of the current branch are read.
+    fn active(&self) -> bool {
+        self.outer && self.held != self.in_else
+    }
+}
+
 pub struct Parser<'text> {
     scanner: Scanner<'text>,
     pub vars: Vars<'text>,
     /// Reading EvalStrings is very hot when parsing, so we always read into
     /// this buffer and then clone it afterwards.
     eval_buf: Vec<EvalPart<&'text str>>,
+    /// Whether `if`/`else`/`endif` are directives, as enabled by
+    /// `pragma conditionals`, rather than ordinary variable names.
+    conditionals: bool,
+    /// Whether `$name(...)` calls a function, as enabled by
+    /// `pragma functions`, rather than being `$name` followed by text.
+    functions: bool,
+    /// The `if`s enclosing the current line, innermost last.
+    conds: Vec<Conditional>,
 }
 
 impl<'text> Parser<'text> {
@@ -63,6 +88,9 @@ impl<'text> Parser<'text> {
             scanner: Scanner::new(buf),
             vars: Vars::default(),
             eval_buf: Vec::with_capacity(16),
+            conditionals: false,
+            functions: false,
+            conds: Vec::new(),
         }
     }
 
@@ -70,9 +98,21 @@ impl<'text> Parser<'text> {
         self.scanner.format_parse_error(filename, err)
     }
 
+    /// The line the parser is currently on, 1-based.
+    pub fn line(&self) -> usize {
+        self.scanner.line
+    }
+
     pub fn read(&mut self) -> ParseResult<Option<Statement<'text>>> {
         loop {
+            if !self.active() {
+                self.skip_inactive_line()?;
+                continue;
+            }
             match self.scanner.peek() {
+                '\0' if !self.conds.is_empty() => {
+                    return self.scanner.parse_error("expected endif")
+                }
                 '\0' => return Ok(None),
                 '\n' | '\r' => self.scanner.next(),
                 '#' => self.skip_comment()?,
@@ -91,6 +131,13 @@ impl<'text> Parser<'text> {
                             return Ok(Some(Statement::Subninja(self.read_eval(false)?)));
                         }
                         "pool" => return Ok(Some(Statement::Pool(self.read_pool()?))),
+                        // Anything else is a variable, if followed by '='.
+                        "pragma" if self.scanner.peek() != '=' => self.read_pragma()?,
+                        "if" | "else" | "endif"
+                            if self.conditionals && self.scanner.peek() != '=' =>
+                        {
+                            self.read_directive(ident)?
+                        }
                         ident => {
                             // TODO: The evaluation of global variables should
                             // be moved out of the parser, so that we can run
@@ -105,6 +152,153 @@ impl<'text> Parser<'text> {
         }
     }
 
+    /// Whether the current line is read, rather than skipped by an `if`.
+    fn active(&self) -> bool {
+        self.conds.last().map_or(true, Conditional::active)
+    }
+
+    fn read_pragma(&mut self) -> ParseResult<()> {
+        let name = self.read_ident()?;
+        match name {
+            "conditionals" => self.conditionals = true,
+            "functions" => self.functions = true,
+            _ => {
+                return self
+                    .scanner
+                    .parse_error(format!("unknown pragma {:?}", name))
+            }
+        }
+        self.skip_spaces();
+        self.scanner.skip('\r');
+        self.scanner.expect('\n')
+    }
+
+    /// Read the rest of an `if`, `else` or `endif` line.
+    fn read_directive(&mut self, directive: &str) -> ParseResult<()> {
+        match directive {
+            "if" => {
+                let outer = self.active();
+                // Conditions in skipped lines aren't evaluated, as they may
+                // well refer to variables that are only set elsewhere.
+                let held = if outer {
+                    self.read_condition()?
+                } else {
+                    self.skip_line();
+                    false
+                };
+                self.conds.push(Conditional {
+                    outer,
+                    held,
+                    in_else: false,
+                });
+                return Ok(());
+            }
+            "else" => match self.conds.last_mut() {
+                Some(cond) if !cond.in_else => cond.in_else = true,
+                Some(_) => return self.scanner.parse_error("else after else"),
+                None => return self.scanner.parse_error("else without if"),
+            },
+            "endif" => {
+                if self.conds.pop().is_none() {
+                    return self.scanner.parse_error("endif without if");
+                }
+            }
+            _ => unreachable!(),
+        }
+        self.skip_spaces();
+        self.scanner.skip('\r');
+        self.scanner.expect('\n')
+    }
+
+    /// Read the condition of an `if`, which is one of `A == B`, `A != B`, or
+    /// just `A` to test that A isn't empty.
+    fn read_condition(&mut self) -> ParseResult<bool> {
+        let lhs = self.read_operand()?;
+        self.skip_spaces();
+        let held = if self.scanner.peek_newline() {
+            !lhs.is_empty()
+        } else {
+            let equal = match self.scanner.read() {
+                '=' => true,
+                '!' => false,
+                _ => {
+                    self.scanner.back();
+                    return self.scanner.parse_error("expected == or !=");
+                }
+            };
+            self.scanner.expect('=')?;
+            self.skip_spaces();
+            let rhs = self.read_operand()?;
+            self.skip_spaces();
+            (lhs == rhs) == equal
+        };
+        self.scanner.skip('\r');
+        self.scanner.expect('\n')?;
+        Ok(held)
+    }
+
+    fn read_operand(&mut self) -> ParseResult<String> {
+        if self.scanner.peek_newline() {
+            return Ok(String::new());
+        }
+        let start = self.scanner.ofs;
+        let operand = self.read_eval(true)?.evaluate(&[&self.vars]);
+        // Operands end at spaces, so `$os==mac` would be the one operand,
+        // never equal to anything but itself being non-empty.
+        let text = self.scanner.slice(start, self.scanner.ofs);
+        if text.contains("==") || text.contains("!=") {
+            return self.scanner.parse_error("expected spaces around == or !=");
+        }
+        Ok(operand)
+    }
+
+    /// Skip a line within the branch of an `if` that isn't taken, minding
+    /// only the directives that end it.
+    fn skip_inactive_line(&mut self) -> ParseResult<()> {
+        match self.scanner.peek() {
+            '\0' => self.scanner.parse_error("expected endif"),
+            '\n' | '\r' => {
+                self.scanner.next();
+                Ok(())
+            }
+            '#' => self.skip_comment(),
+            ' ' | '\t' => {
+                self.skip_line();
+                Ok(())
+            }
+            _ => {
+                if let Ok(ident @ ("if" | "else" | "endif")) = self.read_ident() {
+                    self.skip_spaces();
+                    if self.scanner.peek() != '=' {
+                        return self.read_directive(ident);
+                    }
+                }
+                self.skip_line();
+                Ok(())
+            }
+        }
+    }
+
+    /// Skip to the start of the next line, following `$` continuations.
+    fn skip_line(&mut self) {
+        loop {
+            match self.scanner.read() {
+                '\0' => {
+                    self.scanner.back();
+                    return;
+                }
+                '\n' => return,
+                '$' => {
+                    self.scanner.skip('\r');
+                    if self.scanner.peek() != '\0' {
+                        self.scanner.next();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
     /// Read the `= ...` part of a variable definition.
     fn read_vardef(&mut self) -> ParseResult<EvalString<&'text str>> {
         self.skip_spaces();
@@ -147,20 +341,32 @@ impl<'text> Parser<'text> {
         self.scanner.skip('\r');
         self.scanner.expect('\n')?;
         let vars = self.read_scoped_vars(|var| {
-            matches!(
-                var,
-                "command"
-                    | "depfile"
-                    | "dyndep"
-                    | "description"
-                    | "deps"
-                    | "generator"
-                    | "pool"
-                    | "restat"
-                    | "rspfile"
-                    | "rspfile_content"
-                    | "msvc_deps_prefix"
-            )
+            var.starts_with("env_")
+                || var.starts_with("meta_")
+                || matches!(
+                    var,
+                    "allow_missing_outputs"
+                        | "atomic_outputs"
+                        | "command"
+                        | "command_argv"
+                        | "cores"
+                        | "depfile"
+                        | "dyndep"
+                        | "description"
+                        | "deps"
+                        | "filter_output"
+                        | "generator"
+                        | "mem"
+                        | "network"
+                        | "pool"
+                        | "pty"
+                        | "restat"
+                        | "rspfile"
+                        | "rspfile_content"
+                        | "shell"
+                        | "msvc_deps_prefix"
+                        | "wrapper"
+                )
         })?;
         Ok(Rule { name, vars })
     }
@@ -419,8 +625,26 @@ impl<'text> Parser<'text> {
             _ => {
                 // '$' followed by some other text.
                 self.scanner.back();
+                let start = self.scanner.ofs;
                 let var = self.read_simple_varname()?;
-                EvalPart::VarRef(var)
+                if !(self.functions && self.scanner.peek() == '(') {
+                    return Ok(EvalPart::VarRef(var));
+                }
+                loop {
+                    match self.scanner.read() {
+                        ')' => break,
+                        '\0' | '\n' => {
+                            self.scanner.back();
+                            return self.scanner.parse_error("expected )");
+                        }
+                        _ => {}
+                    }
+                }
+                let call = self.scanner.slice(start, self.scanner.ofs);
+                if let Err(err) = Call::parse(call) {
+                    return self.scanner.parse_error(err);
+                }
+                EvalPart::Call(call)
             }
         })
     }
@@ -522,4 +746,105 @@ mod tests {
             },
         );
     }
+
+    /// Read all statements, returning the names of rules and the outputs of
+    /// builds, as a quick summary.
+    fn read_names(buf: &[u8]) -> ParseResult<(Vec<String>, Parser)> {
+        let mut parser = Parser::new(buf);
+        let mut names = Vec::new();
+        while let Some(stmt) = parser.read()? {
+            match stmt {
+                Statement::Rule(rule) => names.push(rule.name.to_string()),
+                Statement::Build(build) => names.push(build.outs[0].evaluate(&[])),
+                _ => {}
+            }
+        }
+        Ok((names, parser))
+    }
+
+    #[test]
+    fn parse_conditionals() {
+        test_for_line_endings(
+            &[
+                "pragma conditionals",
+                "os = linux",
+                "debug =",
+                "if $os == linux",
+                "rule linux",
+                "  command = x",
+                "build always: linux",
+                "  if = not a directive here",
+                "if $debug",
+                "build debug: linux",
+                "else",
+                "build release: linux",
+                "endif",
+                "else",
+                "# an unbalanced else or endif in a comment",
+                "build mac$",
+                "  os: $ ",
+                "  endif",
+                "if $os != mac",
+                "build nested: x",
+                "endif",
+                "endif",
+                "if $os ==",
+                "flags = none",
+                "else",
+                "flags = some",
+                "endif",
+                "",
+            ],
+            |test_case| {
+                let buf = test_case_buffer(test_case);
+                let (names, parser) = read_names(&buf).unwrap();
+                assert_eq!(names, ["linux", "always", "release"]);
+                assert_eq!(parser.vars.get("flags").unwrap(), "some");
+            },
+        );
+    }
+
+    #[test]
+    fn parse_conditionals_opt_in() {
+        // Without the pragma, these are ordinary variables.
+        let buf = test_case_buffer("if = 1\nendif = 2\n");
+        let (_, parser) = read_names(&buf).unwrap();
+        assert_eq!(parser.vars.get("endif").unwrap(), "2");
+        assert!(read_names(&test_case_buffer("if $x\nendif\n")).is_err());
+        assert!(read_names(&test_case_buffer(
+            "pragma conditionals\nif $os==mac\nendif\n"
+        ))
+        .is_err());
+        assert!(read_names(&test_case_buffer(
+            "pragma conditionals\nif $os == mac!=linux\nendif\n"
+        ))
+        .is_err());
+
+        let buf = test_case_buffer("pragma conditionals\nif = 1\n");
+        let (_, parser) = read_names(&buf).unwrap();
+        assert_eq!(parser.vars.get("if").unwrap(), "1");
+        assert!(read_names(&test_case_buffer("pragma nonsense\n")).is_err());
+        assert!(read_names(&test_case_buffer("pragma conditionals\nif x\n")).is_err());
+        assert!(read_names(&test_case_buffer("pragma conditionals\nendif\n")).is_err());
+        assert!(read_names(&test_case_buffer(
+            "pragma conditionals\nif x\nelse\nelse\nendif\n"
+        ))
+        .is_err());
+        assert!(read_names(&test_case_buffer("pragma conditionals\nif x < y\nendif\n")).is_err());
+    }
+
+    #[test]
+    fn parse_functions_opt_in() {
+        // Without the pragma, a parenthesis after a variable is just text.
+        let buf = test_case_buffer("x = a/b\ny = $x(1)\n");
+        let (_, parser) = read_names(&buf).unwrap();
+        assert_eq!(parser.vars.get("y").unwrap(), "a/b(1)");
+
+        let buf = test_case_buffer("pragma functions\nx = a/b c/d\ny = $basename(x)\n");
+        let (_, parser) = read_names(&buf).unwrap();
+        assert_eq!(parser.vars.get("y").unwrap(), "b d");
+        assert!(read_names(&test_case_buffer("pragma functions\ny = $nonsense(x)\n")).is_err());
+        assert!(read_names(&test_case_buffer("pragma functions\ny = $basename(x\n")).is_err());
+        assert!(read_names(&test_case_buffer("pragma functions\ny = $basename()\n")).is_err());
+    }
 }
@@ -0,0 +1,295 @@
+//! Interactive fuzzy finder, for picking targets to build.
+
+use crate::terminal;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+/// Number of matches shown at once; the list scrolls to follow the cursor.
+const ROWS: usize = 10;
+
+/// How well `query` fuzzily matches `name`, higher being better, or None if
+/// the query's characters don't all appear in order in the name.  Matches
+/// that are consecutive, or at the start of a path component or word, count
+/// for more.
+pub fn score(query: &str, name: &str) -> Option<usize> {
+    let mut query = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut score = 0;
+    let mut prev: Option<char> = None;
+    let mut prev_matched = false;
+    for c in String::from_utf8_lossy(name.as_bytes()).chars() {
+        let q = match query.peek() {
+            Some(&q) => q,
+            None => break,
+        };
+        let matched = c.to_lowercase().eq(std::iter::once(q));
+        if matched {
+            query.next();
+            score += 1;
+            if prev_matched {
+                score += 4;
+            }
+            if prev.map_or(true, |p| matches!(p, '/' | '\\' | '.' | '_' | '-')) {
+                score += 2;
+            }
+        }
+        prev = Some(c);
+        prev_matched = matched;
+    }
+    if query.peek().is_some() {
+        return None;
+    }
+    Some(score)
+}
+
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    Tab,
+    Enter,
+    Cancel,
+}
+
+/// Read a key from the terminal in raw mode, or None for keys we ignore.
+fn read_key(r: &mut impl Read) -> std::io::Result<Option<Key>> {
+    let mut read_byte = || -> std::io::Result<Option<u8>> {
+        let mut buf = [0u8];
+        Ok(match r.read(&mut buf)? {
+            0 => None,
+            _ => Some(buf[0]),
+        })
+    };
+    let b = match read_byte()? {
+        Some(b) => b,
+        None => return Ok(Some(Key::Cancel)),
+    };
+    Ok(match b {
+        // ctl-c, ctl-d, ctl-g
+        3 | 4 | 7 => Some(Key::Cancel),
+        b'\r' | b'\n' => Some(Key::Enter),
+        b'\t' => Some(Key::Tab),
+        8 | 127 => Some(Key::Backspace),
+        // ctl-p, ctl-n
+        16 => Some(Key::Up),
+        14 => Some(Key::Down),
+        27 => match read_byte()? {
+            Some(b'[') | Some(b'O') => match read_byte()? {
+                Some(b'A') => Some(Key::Up),
+                Some(b'B') => Some(Key::Down),
+                _ => None,
+            },
+            _ => Some(Key::Cancel),
+        },
+        b' '..=b'~' => Some(Key::Char(b as char)),
+        _ => None,
+    })
+}
+
+/// The state of the finder, apart from the terminal.
+struct Picker<'a> {
+    names: &'a [String],
+    query: String,
+    /// Indices into names of those matching the query, best first.
+    matches: Vec<usize>,
+    /// Index into matches of the highlighted entry.
+    cursor: usize,
+    /// Indices into names of the entries marked with tab.
+    selected: BTreeSet<usize>,
+}
+
+impl<'a> Picker<'a> {
+    fn new(names: &'a [String]) -> Self {
+        let mut picker = Picker {
+            names,
+            query: String::new(),
+            matches: Vec::new(),
+            cursor: 0,
+            selected: BTreeSet::new(),
+        };
+        picker.filter();
+        picker
+    }
+
+    fn filter(&mut self) {
+        let mut scored: Vec<(usize, usize)> = self
+            .names
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| score(&self.query, name).map(|s| (s, i)))
+            .collect();
+        // Best score first, then shortest, then in name order.
+        scored.sort_by(|&(sa, a), &(sb, b)| {
+            sb.cmp(&sa)
+                .then(self.names[a].len().cmp(&self.names[b].len()))
+                .then(a.cmp(&b))
+        });
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.cursor = 0;
+    }
+
+    /// Handle a key, returning the outcome once the user is done: the picked
+    /// names, or None if they gave up.
+    fn key(&mut self, key: Key) -> Option<Option<Vec<String>>> {
+        match key {
+            Key::Char(c) => {
+                self.query.push(c);
+                self.filter();
+            }
+            Key::Backspace => {
+                if self.query.pop().is_some() {
+                    self.filter();
+                }
+            }
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => {
+                if self.cursor + 1 < self.matches.len() {
+                    self.cursor += 1;
+                }
+            }
+            Key::Tab => {
+                if let Some(&i) = self.matches.get(self.cursor) {
+                    if !self.selected.remove(&i) {
+                        self.selected.insert(i);
+                    }
+                }
+            }
+            Key::Enter => {
+                if !self.selected.is_empty() {
+                    let picked = self.selected.iter().map(|&i| self.names[i].clone());
+                    return Some(Some(picked.collect()));
+                }
+                if let Some(&i) = self.matches.get(self.cursor) {
+                    return Some(Some(vec![self.names[i].clone()]));
+                }
+            }
+            Key::Cancel => return Some(None),
+        }
+        None
+    }
+
+    /// Draw the prompt and the visible matches, leaving the cursor at the
+    /// end of the prompt.
+    fn render(&self, w: &mut impl Write, cols: usize) -> std::io::Result<()> {
+        write!(
+            w,
+            "\r\x1b[J> {}  [{}/{}]",
+            self.query,
+            self.matches.len(),
+            self.names.len()
+        )?;
+        // Scroll so the cursor is always visible.
+        let first = (self.cursor + 1).saturating_sub(ROWS);
+        let mut lines = 0;
+        for (i, &m) in self.matches.iter().enumerate().skip(first).take(ROWS) {
+            let marker = if i == self.cursor { '>' } else { ' ' };
+            let selected = if self.selected.contains(&m) { '*' } else { ' ' };
+            let name: String = String::from_utf8_lossy(self.names[m].as_bytes())
+                .chars()
+                .take(cols.saturating_sub(4))
+                .collect();
+            write!(w, "\r\n{}{} {}", marker, selected, name)?;
+            lines += 1;
+        }
+        if lines > 0 {
+            write!(w, "\x1b[{}A", lines)?;
+        }
+        write!(w, "\r\x1b[{}C", 2 + self.query.chars().count())?;
+        w.flush()
+    }
+}
+
+/// Let the user pick some of `names` on the terminal, returning None if they
+/// gave up.
+pub fn pick(names: &[String]) -> anyhow::Result<Option<Vec<String>>> {
+    let _raw = terminal::RawMode::enter()?;
+    let mut picker = Picker::new(names);
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    let cols = terminal::get_cols().unwrap_or(80);
+    loop {
+        picker.render(&mut stdout, cols)?;
+        let key = match read_key(&mut stdin)? {
+            Some(key) => key,
+            None => continue,
+        };
+        if let Some(outcome) = picker.key(key) {
+            write!(stdout, "\r\x1b[J")?;
+            stdout.flush()?;
+            return Ok(outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores() {
+        assert_eq!(score("", "foo"), Some(0));
+        assert_eq!(score("xyz", "foo"), None);
+        assert_eq!(score("of", "foo"), None);
+        assert!(score("fb", "foo/bar").unwrap() > score("fb", "foobxr").unwrap());
+        assert!(score("bar", "foo/bar").unwrap() > score("bar", "b/a/r").unwrap());
+        assert_eq!(score("FOO", "foo"), score("foo", "FOO"));
+    }
+
+    #[test]
+    fn keys() {
+        let mut input: &[u8] = b"a\x1b[A\x1b[B\x7f\t\r\x03\x1bx\x01";
+        let mut keys = Vec::new();
+        while !input.is_empty() {
+            keys.push(read_key(&mut input).unwrap());
+        }
+        assert_eq!(
+            keys,
+            vec![
+                Some(Key::Char('a')),
+                Some(Key::Up),
+                Some(Key::Down),
+                Some(Key::Backspace),
+                Some(Key::Tab),
+                Some(Key::Enter),
+                Some(Key::Cancel),
+                Some(Key::Cancel),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn picking() {
+        let names: Vec<String> = ["lib/foo.o", "lib/bar.o", "bin/foo", "all"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut picker = Picker::new(&names);
+        assert_eq!(picker.matches.len(), 4);
+        for c in "foo".chars() {
+            assert_eq!(picker.key(Key::Char(c)), None);
+        }
+        assert_eq!(picker.matches, vec![2, 0]);
+        assert_eq!(picker.key(Key::Down), None);
+        assert_eq!(
+            picker.key(Key::Enter),
+            Some(Some(vec!["lib/foo.o".to_string()]))
+        );
+
+        let mut picker = Picker::new(&names);
+        picker.key(Key::Tab);
+        picker.key(Key::Down);
+        picker.key(Key::Down);
+        picker.key(Key::Tab);
+        assert_eq!(
+            picker.key(Key::Enter),
+            Some(Some(vec!["lib/foo.o".to_string(), "all".to_string()]))
+        );
+
+        let mut picker = Picker::new(&names);
+        picker.key(Key::Char('z'));
+        assert_eq!(picker.key(Key::Enter), None);
+        assert_eq!(picker.key(Key::Cancel), Some(None));
+    }
+}
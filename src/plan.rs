@@ -0,0 +1,96 @@
+//! Writes the builds that would run, as computed by --dump-plan, as JSON for
+//! the use of other tools.
+
+use crate::graph::{BuildId, Durations, FileId, Graph};
+use std::io::Write;
+
+/// Write s as a JSON string literal.  JSON is UTF-8, so any bytes of s that
+/// aren't (as paths needn't be) are replaced.
+pub fn write_string(w: &mut dyn Write, s: &str) -> std::io::Result<()> {
+    write!(w, "\"")?;
+    for c in String::from_utf8_lossy(s.as_bytes()).chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            '\n' => write!(w, "\\n")?,
+            '\r' => write!(w, "\\r")?,
+            '\t' => write!(w, "\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    write!(w, "\"")
+}
+
+/// Write the names of files as a JSON array of strings.
+pub fn write_files(w: &mut dyn Write, graph: &Graph, ids: &[FileId]) -> std::io::Result<()> {
+    write!(w, "[")?;
+    for (i, &id) in ids.iter().enumerate() {
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+        write_string(w, &graph.file(id).name)?;
+    }
+    write!(w, "]")
+}
+
+/// Write a build's `meta_NAME = value` bindings as a JSON object.
+pub fn write_meta(w: &mut dyn Write, meta: &[(String, String)]) -> std::io::Result<()> {
+    write!(w, "{{")?;
+    for (i, (name, value)) in meta.iter().enumerate() {
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+        write_string(w, name)?;
+        write!(w, ": ")?;
+        write_string(w, value)?;
+    }
+    write!(w, "}}")
+}
+
+/// Write the planned builds, which are in dependency order, as a JSON array
+/// of objects.  predicted_ms is the duration of the build's last run, if
+/// known.
+pub fn write_json(
+    w: &mut dyn Write,
+    graph: &Graph,
+    plan: &[BuildId],
+    durations: &Durations,
+) -> std::io::Result<()> {
+    writeln!(w, "[")?;
+    for (i, &id) in plan.iter().enumerate() {
+        let build = &graph.builds[id];
+        write!(w, "  {{\"outputs\": ")?;
+        write_files(w, graph, build.outs())?;
+        write!(w, ", \"inputs\": ")?;
+        write_files(w, graph, build.dirtying_ins())?;
+        write!(w, ", \"command\": ")?;
+        write_string(w, build.cmdline.as_deref().unwrap_or(""))?;
+        write!(w, ", \"pool\": ")?;
+        match &build.pool {
+            Some(pool) => write_string(w, pool)?,
+            None => write!(w, "null")?,
+        }
+        write!(w, ", \"meta\": ")?;
+        write_meta(w, &build.meta)?;
+        write!(w, ", \"predicted_ms\": ")?;
+        match durations.get(id) {
+            Some(duration) => write!(w, "{}", duration.as_millis())?,
+            None => write!(w, "null")?,
+        }
+        writeln!(w, "}}{}", if i + 1 < plan.len() { "," } else { "" })?;
+    }
+    writeln!(w, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings() {
+        let mut out = Vec::new();
+        write_string(&mut out, "a \"b\"\\c\n\u{1b}é").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), r#""a \"b\"\\c\n\u001bé""#);
+    }
+}
@@ -0,0 +1,115 @@
+//! Statting the files builds read on another thread while the manifest is
+//! still being parsed, so that the IO overlaps with the parsing rather than
+//! following it.  See load::read.
+
+use crate::densemap::Index as _;
+use crate::graph::{self, FileId, MTime};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+/// Files are handed to the thread this many at a time, as sending each on
+/// its own would cost about as much as statting it.
+const BATCH: usize = 256;
+
+pub struct Prestat {
+    tx: mpsc::Sender<Vec<(FileId, PathBuf)>>,
+    batch: Vec<(FileId, PathBuf)>,
+    /// Indexed by FileId, whether the file has been added already.
+    added: Vec<bool>,
+    /// Set once the results are wanted, whether or not all files were statted.
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<(FileId, MTime)>>,
+}
+
+impl Prestat {
+    /// Start the thread.  See graph::stat for follow_symlinks.
+    pub fn start(follow_symlinks: bool) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<(FileId, PathBuf)>>();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut stats = Vec::new();
+                for batch in rx {
+                    for (id, path) in batch {
+                        if stop.load(Ordering::Relaxed) {
+                            return stats;
+                        }
+                        // As with FileState::prefetch, files with mtimes in
+                        // the future are left to FileState::stat.
+                        match graph::stat(&path, follow_symlinks) {
+                            Ok(MTime::Stamp(mtime)) if mtime <= SystemTime::now() => {
+                                stats.push((id, MTime::Stamp(mtime)));
+                            }
+                            _ => continue,
+                        }
+                    }
+                }
+                stats
+            }
+        });
+        Prestat {
+            tx,
+            batch: Vec::with_capacity(BATCH),
+            added: Vec::new(),
+            stop,
+            thread,
+        }
+    }
+
+    /// Queue a file to be statted, unless it already was.
+    pub fn add(&mut self, id: FileId, path: &Path) {
+        let index = id.index();
+        if index >= self.added.len() {
+            self.added.resize(index + 1, false);
+        } else if self.added[index] {
+            return;
+        }
+        self.added[index] = true;
+        self.batch.push((id, path.to_owned()));
+        if self.batch.len() == BATCH {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH));
+            // The thread only stops after finish().
+            let _ = self.tx.send(batch);
+        }
+    }
+
+    /// Stop statting, and return the mtimes found so far.  Files the thread
+    /// hadn't got to yet are left to be statted as usual.
+    pub fn finish(self) -> Vec<(FileId, MTime)> {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.tx);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_metadata() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut prestat = Prestat::start(true);
+        let mut paths = Vec::new();
+        // Enough to need more than one batch.
+        for i in 0..(BATCH + 10) {
+            let path = dir.path().join(format!("f{}", i));
+            std::fs::write(&path, "")?;
+            prestat.add(FileId::from(i), &path);
+            paths.push(path);
+        }
+        let missing = BATCH + 10;
+        prestat.add(FileId::from(missing), &dir.path().join("missing"));
+        // The thread may have got to any number of them by now.
+        for (id, mtime) in prestat.finish() {
+            assert_ne!(id.index(), missing);
+            let modified = std::fs::metadata(&paths[id.index()])?.modified()?;
+            assert_eq!(mtime, MTime::Stamp(modified));
+        }
+        Ok(())
+    }
+}
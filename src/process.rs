@@ -1,21 +1,381 @@
 //! Exposes process::run_command, a wrapper around platform-native process execution.
 
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 #[cfg(unix)]
-pub use crate::process_posix::run_command;
+pub use crate::process_posix::{any_running, run_command, signal_all, terminate_all};
 #[cfg(windows)]
-pub use crate::process_win::run_command;
+pub use crate::process_win::{ansi_is_utf8, run_command, terminate_all};
 
 #[cfg(target_arch = "wasm32")]
-fn run_command(
-    cmdline: &str,
-    mut output_cb: impl FnMut(&[u8]),
-) -> anyhow::Result<(Termination, Vec<u8>)> {
+pub fn run_command(
+    _cmdline: &str,
+    _shell: Option<&[String]>,
+    _pty: bool,
+    _stdin: bool,
+    _env: &Env,
+    _output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, ResourceUsage)> {
     anyhow::bail!("wasm cannot run commands");
 }
 
+#[cfg(target_arch = "wasm32")]
+pub fn terminate_all() {}
+
+/// The environment a command runs in: ours, as it was when the Env was made,
+/// with some variables set.  Ours is copied the once for all the commands of
+/// a build rather than for each, which adds up for many small commands.
+#[derive(Clone)]
+pub struct Env {
+    /// Our variables, each as NAME=VALUE.
+    ours: Arc<Vec<CString>>,
+    /// The variables set, in place of any of ours by the same name.
+    pub set: Vec<(String, String)>,
+}
+
+impl Env {
+    /// Our environment as it is now, with nothing set.
+    pub fn ours() -> Self {
+        let ours = std::env::vars_os()
+            .filter_map(|(name, value)| {
+                let mut var = crate::canon::os_bytes(&name).into_owned();
+                var.push(b'=');
+                var.extend_from_slice(&crate::canon::os_bytes(&value));
+                CString::new(var).ok()
+            })
+            .collect();
+        Env {
+            ours: Arc::new(ours),
+            set: Vec::new(),
+        }
+    }
+
+    /// The same environment with the given variables set instead.
+    pub fn with(&self, set: Vec<(String, String)>) -> Self {
+        Env {
+            ours: self.ours.clone(),
+            set,
+        }
+    }
+
+    /// Our variables that aren't set over, as NAME=VALUE.
+    pub fn inherited(&self) -> impl Iterator<Item = &CStr> {
+        self.ours
+            .iter()
+            .map(|var| var.as_c_str())
+            .filter(move |var| {
+                let name = var_name(var.to_bytes());
+                !self
+                    .set
+                    .iter()
+                    .any(|(set, _)| same_var_name(set.as_bytes(), name))
+            })
+    }
+}
+
+/// The name of a NAME=VALUE variable.  On Windows, names of the hidden
+/// variables for the current directories of drives, like `=C:`, start with
+/// the `=`.
+fn var_name(var: &[u8]) -> &[u8] {
+    match var.iter().skip(1).position(|&b| b == b'=') {
+        Some(i) => &var[..i + 1],
+        None => var,
+    }
+}
+
+/// Whether two variable names are the same, which on Windows is regardless
+/// of case.
+fn same_var_name(a: &[u8], b: &[u8]) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+/// How long subprocesses get to exit when asked to by terminate_all(), in
+/// milliseconds, before they are killed outright.
+static TERMINATE_GRACE_MS: AtomicU64 = AtomicU64::new(500);
+
+/// Set how long terminate_all() waits for subprocesses to exit on their own,
+/// e.g. to give tools that clean up their temporary files time to do so.
+pub fn set_terminate_grace(grace: Duration) {
+    TERMINATE_GRACE_MS.store(grace.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn terminate_grace() -> Duration {
+    Duration::from_millis(TERMINATE_GRACE_MS.load(Ordering::Relaxed))
+}
+
+/// Split a command line into words as a POSIX shell would, as far as quoting
+/// goes: single quotes keep everything in them, double quotes keep all but
+/// the backslash before `"`, `\`, `$` or `` ` ``, and a backslash elsewhere
+/// keeps the character after it.  Nothing is expanded.
+pub fn split_words(cmdline: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = cmdline.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("unterminated ' in {:?}", cmdline),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => anyhow::bail!("unterminated \" in {:?}", cmdline),
+                        },
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("unterminated \" in {:?}", cmdline),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => anyhow::bail!("trailing \\ in {:?}", cmdline),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Join words into a command line that split_words splits back into them.
+#[cfg(not(windows))]
+pub fn join_words(words: &[String]) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    let quoted: Vec<String> = words
+        .iter()
+        .map(|word| {
+            if !word.is_empty() && word.chars().all(is_plain) {
+                word.clone()
+            } else {
+                format!("'{}'", word.replace('\'', r"'\''"))
+            }
+        })
+        .collect();
+    quoted.join(" ")
+}
+
+/// Join words into a command line that CreateProcess's programs split back
+/// into them, following the rules of CommandLineToArgvW.
+#[cfg(windows)]
+pub fn join_words(words: &[String]) -> String {
+    let mut cmdline = String::new();
+    for word in words {
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        if !word.is_empty() && !word.contains([' ', '\t', '\n', '"']) {
+            cmdline.push_str(word);
+            continue;
+        }
+        cmdline.push('"');
+        // Backslashes are only special before a quote.
+        let mut backslashes = 0;
+        for c in word.chars() {
+            match c {
+                '\\' => backslashes += 1,
+                '"' => {
+                    cmdline.extend(std::iter::repeat('\\').take(backslashes * 2 + 1));
+                    backslashes = 0;
+                }
+                _ => {
+                    cmdline.extend(std::iter::repeat('\\').take(backslashes));
+                    backslashes = 0;
+                }
+            }
+            if c != '\\' {
+                cmdline.push(c);
+            }
+        }
+        cmdline.extend(std::iter::repeat('\\').take(backslashes * 2));
+        cmdline.push('"');
+    }
+    cmdline
+}
+
+/// The shell and command line with which run_command runs a command under
+/// another program, such as strace, given as its words up to the command.
+/// Where there's no shell, the program runs /bin/sh as run_command would.
+pub fn wrap_command(
+    prefix: &[String],
+    shell: Option<&[String]>,
+    cmdline: &str,
+) -> (Vec<String>, String) {
+    let mut words = prefix.to_vec();
+    match shell {
+        None => {
+            words.push("/bin/sh".to_owned());
+            words.push("-c".to_owned());
+            (words, cmdline.to_owned())
+        }
+        // The command is split into words and run directly, so the program
+        // must be too.
+        Some([]) => (Vec::new(), format!("{} {}", join_words(&words), cmdline)),
+        Some(shell) => {
+            words.extend(shell.iter().cloned());
+            (words, cmdline.to_owned())
+        }
+    }
+}
+
+/// The shell and command line to run a command without network access, for
+/// `network = none`: on Linux in a network namespace of its own, which has
+/// nothing but a loopback device that is down, and on macOS in a sandbox
+/// that denies it.
+pub fn isolate_network(
+    shell: Option<&[String]>,
+    cmdline: &str,
+) -> anyhow::Result<(Vec<String>, String)> {
+    let prefix: &[&str] = if cfg!(target_os = "linux") {
+        &["unshare", "--net", "--map-current-user", "--"]
+    } else if cfg!(target_os = "macos") {
+        &[
+            "sandbox-exec",
+            "-p",
+            "(version 1) (allow default) (deny network*)",
+        ]
+    } else {
+        anyhow::bail!("network = none isn't supported on this platform");
+    };
+    let prefix: Vec<String> = prefix.iter().map(|&word| word.to_owned()).collect();
+    Ok(wrap_command(&prefix, shell, cmdline))
+}
+
+/// The words to run commands under so that they can't write to the given
+/// absolute directories, for --read-only-sources; see wrap_command.
+///
+/// On Linux they are bind mounted over themselves and made read-only in a
+/// mount namespace of the command's own.  That takes being root in a user
+/// namespace, which is then left for one with our own ids, so commands don't
+/// run as root.  On macOS a sandbox denies writes under them.
+pub fn read_only_prefix(dirs: &[std::path::PathBuf]) -> anyhow::Result<Vec<String>> {
+    let dirs = dirs.iter().map(|dir| dir.to_string_lossy().into_owned());
+    #[cfg(target_os = "linux")]
+    {
+        const MOUNT: &str = r#"while [ "$1" != -- ]; do
+  mount --bind "$1" "$1" && mount -o remount,bind,ro "$1" || exit 1
+  shift
+done
+shift
+exec "$@""#;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let mut words: Vec<String> = ["unshare", "--mount", "--map-root-user", "--"]
+            .iter()
+            .chain(&["/bin/sh", "-c", MOUNT, "sh"])
+            .map(|&word| word.to_owned())
+            .collect();
+        words.extend(dirs);
+        words.extend([
+            "--".to_owned(),
+            "unshare".to_owned(),
+            "--user".to_owned(),
+            format!("--map-user={}", uid),
+            format!("--map-group={}", gid),
+            "--".to_owned(),
+        ]);
+        Ok(words)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let mut profile = String::from("(version 1) (allow default)");
+        for dir in dirs {
+            let dir = dir.replace('\\', "\\\\").replace('"', "\\\"");
+            profile.push_str(&format!(" (deny file-write* (subpath \"{}\"))", dir));
+        }
+        Ok(vec!["sandbox-exec".to_owned(), "-p".to_owned(), profile])
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = dirs;
+        anyhow::bail!("--read-only-sources isn't supported on this platform");
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Termination {
     Success,
     Interrupted,
     Failure,
 }
+
+/// Resources used by a finished subprocess, as far as the platform reports
+/// them.  Fields the platform can't report are left zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResourceUsage {
+    /// Peak resident set size, in bytes.
+    pub peak_rss: u64,
+    /// CPU time spent in user mode.
+    pub user: Duration,
+    /// CPU time spent in the kernel.
+    pub sys: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env() {
+        let ours = ["KEPT=1", "SET=1", "=C:=C:\\n2"];
+        let env = Env {
+            ours: Arc::new(ours.iter().map(|&var| CString::new(var).unwrap()).collect()),
+            set: Vec::new(),
+        };
+        let env = env.with(vec![("SET".to_owned(), "2".to_owned())]);
+        let inherited: Vec<&[u8]> = env.inherited().map(|var| var.to_bytes()).collect();
+        assert_eq!(inherited, [&b"KEPT=1"[..], b"=C:=C:\\n2"]);
+    }
+
+    #[test]
+    fn split() -> anyhow::Result<()> {
+        assert_eq!(
+            split_words(r#"bash -e  -c 'a b' "c \"d\" \e" f\ g ''"#)?,
+            vec!["bash", "-e", "-c", "a b", r#"c "d" \e"#, "f g", ""]
+        );
+        assert!(split_words("echo 'a").is_err());
+        assert!(split_words("echo \\").is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrap() -> anyhow::Result<()> {
+        let prefix = ["strace".to_owned(), "-o".to_owned(), "a b".to_owned()];
+        let (shell, cmdline) = wrap_command(&prefix, None, "cc -c a.c && true");
+        assert_eq!(shell, ["strace", "-o", "a b", "/bin/sh", "-c"]);
+        assert_eq!(cmdline, "cc -c a.c && true");
+
+        let (shell, cmdline) = wrap_command(&prefix, Some(&[]), "cc -c a.c");
+        assert!(shell.is_empty());
+        assert_eq!(
+            split_words(&cmdline)?,
+            ["strace", "-o", "a b", "cc", "-c", "a.c"]
+        );
+
+        let (shell, _) = wrap_command(&prefix, Some(&["bash".to_owned(), "-c".to_owned()]), "x");
+        assert_eq!(shell, ["strace", "-o", "a b", "bash", "-c"]);
+        Ok(())
+    }
+}
@@ -1,16 +1,12 @@
 //! Implements run_command on posix using posix_spawn.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{ResourceUsage, Termination};
 use std::io::{Error, Read};
 use std::os::fd::FromRawFd;
 use std::os::unix::process::ExitStatusExt;
-
-// https://github.com/rust-lang/libc/issues/2520
-// libc crate doesn't expose the 'environ' pointer.
-extern "C" {
-    static environ: *const *mut libc::c_char;
-}
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 fn check_posix_spawn(func: &str, ret: libc::c_int) -> anyhow::Result<()> {
     if ret != 0 {
@@ -56,6 +52,15 @@ impl PosixSpawnAttr {
             )
         }
     }
+
+    fn setpgroup(&mut self, pgroup: libc::pid_t) -> anyhow::Result<()> {
+        unsafe {
+            check_posix_spawn(
+                "posix_spawnattr_setpgroup",
+                libc::posix_spawnattr_setpgroup(self.as_ptr(), pgroup),
+            )
+        }
+    }
 }
 
 impl Drop for PosixSpawnAttr {
@@ -150,28 +155,305 @@ fn pipe2() -> anyhow::Result<[libc::c_int; 2]> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Create a pseudo-terminal as in libc::openpty(), returning its
+/// [master, slave] fds with the CLOEXEC flag set.
+/// The terminal gets our width, so that progress bars render sensibly, and
+/// doesn't translate "\n" into "\r\n", so output reads the same as from a pipe.
+fn openpty() -> anyhow::Result<[libc::c_int; 2]> {
+    unsafe {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        let mut winsize: libc::winsize = std::mem::zeroed();
+        winsize.ws_col = crate::terminal::get_cols().unwrap_or(80) as u16;
+        winsize.ws_row = 24;
+        check_ret_errno(
+            "openpty",
+            libc::openpty(
+                &mut fds[0],
+                &mut fds[1],
+                std::ptr::null_mut(),
+                std::ptr::null_mut::<libc::termios>(),
+                std::ptr::addr_of_mut!(winsize),
+            ),
+        )?;
+        for fd in fds {
+            check_ret_errno("fcntl", libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        }
+
+        let mut termios: libc::termios = std::mem::zeroed();
+        check_ret_errno("tcgetattr", libc::tcgetattr(fds[1], &mut termios))?;
+        termios.c_oflag &= !libc::ONLCR;
+        check_ret_errno(
+            "tcsetattr",
+            libc::tcsetattr(fds[1], libc::TCSANOW, &termios),
+        )?;
+
+        Ok(fds)
+    }
+}
+
+/// The currently running subprocesses, and whether each leads a process
+/// group of its own.
+/// Most do, so that signalling the group also reaches anything it spawned,
+/// like the compiler processes run by a compiler driver.  Those that read
+/// our stdin stay in our group instead, and are signalled by themselves.
+static PROCESS_GROUPS: Mutex<Vec<(libc::pid_t, bool)>> = Mutex::new(Vec::new());
+
+/// Send a signal to all running subprocesses, including their descendants
+/// where they lead a group.  Those in our group already got any SIGINT from
+/// the terminal, so aren't sent another.
+pub fn signal_all(sig: libc::c_int) {
+    for &(pid, group) in PROCESS_GROUPS.lock().unwrap().iter() {
+        match group {
+            true => unsafe { libc::kill(-pid, sig) },
+            false if sig == libc::SIGINT => continue,
+            false => unsafe { libc::kill(pid, sig) },
+        };
+    }
+}
+
+/// Whether any subprocesses are running.
+pub fn any_running() -> bool {
+    !PROCESS_GROUPS.lock().unwrap().is_empty()
+}
+
+/// Terminate all running subprocesses, including their descendants.
+/// Sends SIGTERM, then SIGKILL to anything that hasn't exited within the grace
+/// period; see process::set_terminate_grace.
+pub fn terminate_all() {
+    signal_all(libc::SIGTERM);
+    let deadline = Instant::now() + crate::process::terminate_grace();
+    while Instant::now() < deadline {
+        if !any_running() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    signal_all(libc::SIGKILL);
+}
+
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+/// Wait for a subprocess to exit and reap it.
+fn wait(pid: libc::pid_t) -> anyhow::Result<(std::process::ExitStatus, ResourceUsage)> {
+    unsafe {
+        // Wait without reaping first, so the process group is unregistered
+        // while its id is still reserved; otherwise signal_all() could race
+        // with the id being reused by some unrelated process.
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        check_ret_errno(
+            "waitid",
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            ),
+        )?;
+        let mut groups = PROCESS_GROUPS.lock().unwrap();
+        let group = groups.iter().any(|&(other, group)| other == pid && group);
+        if group && crate::signal::was_interrupted() {
+            // Don't leave behind anything the subprocess spawned that survived
+            // the SIGINT, e.g. background jobs of a shell, which ignore it.
+            libc::kill(-pid, libc::SIGKILL);
+        }
+        groups.retain(|&(other, _)| other != pid);
+        drop(groups);
+
+        let mut status: i32 = 0;
+        let mut rusage: libc::rusage = std::mem::zeroed();
+        check_ret_errno("wait4", libc::wait4(pid, &mut status, 0, &mut rusage))?;
+
+        // ru_maxrss is in bytes on macOS, but kilobytes elsewhere.
+        #[cfg(target_os = "macos")]
+        let rss_unit = 1;
+        #[cfg(not(target_os = "macos"))]
+        let rss_unit = 1024;
+        let usage = ResourceUsage {
+            peak_rss: rusage.ru_maxrss as u64 * rss_unit,
+            user: timeval_duration(rusage.ru_utime),
+            sys: timeval_duration(rusage.ru_stime),
+        };
+        Ok((std::process::ExitStatus::from_raw(status), usage))
+    }
+}
+
+/// Words that mean something to the shell when they start a command, so that
+/// running a program of that name instead would do the wrong thing.
+const SHELL_WORDS: &[&str] = &[
+    ".", ":", "alias", "bg", "break", "case", "cd", "command", "continue", "do", "done", "elif",
+    "else", "esac", "eval", "exec", "exit", "export", "fg", "fi", "for", "function", "getopts",
+    "hash", "if", "in", "jobs", "read", "readonly", "return", "select", "set", "shift", "source",
+    "then", "time", "times", "trap", "type", "ulimit", "umask", "unalias", "unset", "until",
+    "wait", "while",
+];
+
+/// If the shell would do nothing with cmdline but split it into words,
+/// returns those words, so the command can be run without paying for a shell.
+fn split_simple_cmdline(cmdline: &str) -> Option<Vec<&str>> {
+    let is_plain = |c: u8| {
+        c.is_ascii_alphanumeric()
+            || matches!(
+                c,
+                b' ' | b'\t' | b'%' | b'+' | b',' | b'-' | b'.' | b'/' | b':' | b'=' | b'@' | b'_'
+            )
+    };
+    // Bytes, as the command needn't be UTF-8.
+    if !cmdline.bytes().all(is_plain) {
+        return None;
+    }
+    let words: Vec<&str> = cmdline.split_ascii_whitespace().collect();
+    let program = *words.first()?;
+    // Variable assignments and builtins need the shell.
+    if program.contains('=') || SHELL_WORDS.contains(&program) {
+        return None;
+    }
+    Some(words)
+}
+
+/// The program of the given name in one of the directories of path, as the
+/// shell would find it, or the name itself if it has a directory.
+fn find_program(program: &str, path: &str) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    if program.contains('/') {
+        return Some(program.to_owned());
+    }
+    path.split(':')
+        .map(|dir| if dir.is_empty() { "." } else { dir })
+        .map(|dir| format!("{}/{}", dir, program))
+        .find(|candidate| {
+            std::fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// posix_spawnp the program named by the first of words, returning what
+/// posix_spawnp did.  posix_spawnp looks for the program in our PATH, so if
+/// the command's is given, it's looked for there instead.
+unsafe fn spawnp(
+    pid: &mut libc::pid_t,
+    words: &[impl AsRef<str>],
+    path: Option<&str>,
+    actions: &mut PosixSpawnFileActions,
+    attr: &mut PosixSpawnAttr,
+    envp: *const *mut libc::c_char,
+) -> libc::c_int {
+    let program = match path {
+        None => None,
+        Some(path) => match find_program(words[0].as_ref(), path) {
+            Some(program) => Some(std::ffi::CString::new(program).unwrap()),
+            None => return libc::ENOENT,
+        },
+    };
+    let words: Vec<std::ffi::CString> = words
+        .iter()
+        .map(|word| std::ffi::CString::new(word.as_ref()).unwrap())
+        .collect();
+    let mut argv: Vec<*mut libc::c_char> = words
+        .iter()
+        .map(|word| word.as_ptr() as *mut libc::c_char)
+        .collect();
+    argv.push(std::ptr::null_mut());
+    match program {
+        Some(program) => libc::posix_spawn(
+            pid,
+            program.as_ptr(),
+            actions.as_ptr(),
+            attr.as_ptr(),
+            argv.as_ptr(),
+            envp,
+        ),
+        None => libc::posix_spawnp(
+            pid,
+            argv[0],
+            actions.as_ptr(),
+            attr.as_ptr(),
+            argv.as_ptr(),
+            envp,
+        ),
+    }
+}
+
+/// Runs a command, passing its output to output_cb as it arrives.
+/// If pty is set the output goes to a pseudo-terminal instead of a pipe, for
+/// the sake of tools that only print progress or color to a terminal.
+/// If stdin is set the command reads our stdin, a terminal, rather than
+/// /dev/null, for commands that ask the user something.  Only the terminal's
+/// foreground process group may read it, so the command then stays in ours,
+/// and gets any ctrl-c from the terminal directly; other signals reach only
+/// the command itself, not anything it spawned.
+/// The command runs in env.
+/// If shell is given, the command line is passed as the last argument to it
+/// rather than to /bin/sh -c, or split into words and run directly if it's
+/// empty.
+pub fn run_command(
+    cmdline: &str,
+    shell: Option<&[String]>,
+    pty: bool,
+    stdin: bool,
+    env: &crate::process::Env,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, ResourceUsage)> {
+    // The program and its arguments, if not left to /bin/sh.
+    let words = match shell {
+        None => None,
+        Some([]) => {
+            let words = crate::process::split_words(cmdline)?;
+            if words.is_empty() {
+                anyhow::bail!("command is empty");
+            }
+            Some(words)
+        }
+        Some(shell) => Some(
+            shell
+                .iter()
+                .cloned()
+                .chain(std::iter::once(cmdline.to_owned()))
+                .collect(),
+        ),
+    };
+
     // Spawn the subprocess using posix_spawn with output redirected to the pipe.
     // We don't use Rust's process spawning because of issue #14 and because
     // we want to feed both stdout and stderr into the same pipe, which cannot
     // be done with the existing std::process API.
     let (pid, mut pipe) = unsafe {
-        let pipe = pipe2()?;
+        // Hold the lock across the spawn, so a concurrent signal_all() can't
+        // miss the new process group.  openpty() can't atomically set CLOEXEC,
+        // so this also keeps other spawns from inheriting its fds.
+        let mut groups = PROCESS_GROUPS.lock().unwrap();
+
+        let pipe = if pty { openpty()? } else { pipe2()? };
 
         let mut attr = PosixSpawnAttr::new()?;
 
+        // Put the subprocess in a new process group of its own, unless it's
+        // to read stdin; see PROCESS_GROUPS.
+        #[allow(unused_mut)]
+        let mut flags = if stdin {
+            0
+        } else {
+            libc::POSIX_SPAWN_SETPGROUP
+        };
         // Apple-specific extension: close any open fds.
         #[cfg(target_os = "macos")]
-        attr.setflags(libc::POSIX_SPAWN_CLOEXEC_DEFAULT as _)?;
+        {
+            flags |= libc::POSIX_SPAWN_CLOEXEC_DEFAULT;
+        }
+        attr.setflags(flags as _)?;
+        attr.setpgroup(0)?;
 
         let mut actions = PosixSpawnFileActions::new()?;
-        // open /dev/null over stdin
-        actions.addopen(
-            0,
-            std::ffi::CStr::from_bytes_with_nul_unchecked(b"/dev/null\0"),
-            libc::O_RDONLY,
-            0,
-        )?;
+        if !stdin {
+            // open /dev/null over stdin
+            actions.addopen(
+                0,
+                std::ffi::CStr::from_bytes_with_nul_unchecked(b"/dev/null\0"),
+                libc::O_RDONLY,
+                0,
+            )?;
+        }
         // stdout/stderr => pipe
         actions.adddup2(pipe[1], 1)?;
         actions.adddup2(pipe[1], 2)?;
@@ -179,29 +461,65 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         actions.addclose(pipe[0])?;
         actions.addclose(pipe[1])?;
 
+        let set: Vec<std::ffi::CString> = env
+            .set
+            .iter()
+            .filter_map(|(name, value)| std::ffi::CString::new(format!("{}={}", name, value)).ok())
+            .collect();
+        let envp: Vec<*mut libc::c_char> = env
+            .inherited()
+            .chain(set.iter().map(|var| var.as_c_str()))
+            .map(|var| var.as_ptr() as *mut libc::c_char)
+            .chain(std::iter::once(std::ptr::null_mut()))
+            .collect();
+        let envp = envp.as_ptr();
+
         let mut pid: libc::pid_t = 0;
-        let path = std::ffi::CStr::from_bytes_with_nul_unchecked(b"/bin/sh\0");
-        let cmdline_nul = std::ffi::CString::new(cmdline).unwrap();
-        let argv: [*const libc::c_char; 4] = [
-            path.as_ptr(),
-            b"-c\0".as_ptr() as *const _,
-            cmdline_nul.as_ptr(),
-            std::ptr::null(),
-        ];
-
-        check_posix_spawn(
-            "posix_spawn",
-            libc::posix_spawn(
-                &mut pid,
+        let path = env
+            .set
+            .iter()
+            .find(|(name, _)| name == "PATH")
+            .map(|(_, value)| value.as_str());
+
+        let direct = match &words {
+            Some(words) => {
+                let ret = spawnp(&mut pid, words, path, &mut actions, &mut attr, envp);
+                check_posix_spawn(&format!("posix_spawnp {}", words[0]), ret)?;
+                true
+            }
+            // Run simple commands directly.  If that fails, e.g. because the
+            // program doesn't exist, fall back to the shell to report it.
+            None => match split_simple_cmdline(cmdline) {
+                Some(words) => spawnp(&mut pid, &words, path, &mut actions, &mut attr, envp) == 0,
+                None => false,
+            },
+        };
+
+        if !direct {
+            let path = std::ffi::CStr::from_bytes_with_nul_unchecked(b"/bin/sh\0");
+            let cmdline_nul = std::ffi::CString::new(cmdline).unwrap();
+            let argv: [*const libc::c_char; 4] = [
                 path.as_ptr(),
-                actions.as_ptr(),
-                attr.as_ptr(),
-                // posix_spawn wants mutable argv:
-                // https://stackoverflow.com/questions/50596439/can-string-literals-be-passed-in-posix-spawns-argv
-                argv.as_ptr() as *const *mut _,
-                environ,
-            ),
-        )?;
+                b"-c\0".as_ptr() as *const _,
+                cmdline_nul.as_ptr(),
+                std::ptr::null(),
+            ];
+            check_posix_spawn(
+                "posix_spawn",
+                libc::posix_spawn(
+                    &mut pid,
+                    path.as_ptr(),
+                    actions.as_ptr(),
+                    attr.as_ptr(),
+                    // posix_spawn wants mutable argv:
+                    // https://stackoverflow.com/questions/50596439/can-string-literals-be-passed-in-posix-spawns-argv
+                    argv.as_ptr() as *const *mut _,
+                    envp,
+                ),
+            )?;
+        }
+        groups.push((pid, !stdin));
+        drop(groups);
 
         check_ret_errno("close", libc::close(pipe[1]))?;
 
@@ -210,7 +528,12 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
     loop {
-        let n = pipe.read(&mut buf)?;
+        let n = match pipe.read(&mut buf) {
+            Ok(n) => n,
+            // Reading a pty whose other end was closed fails with EIO on Linux.
+            Err(err) if pty && err.raw_os_error() == Some(libc::EIO) => 0,
+            Err(err) => return Err(err.into()),
+        };
         if n == 0 {
             break;
         }
@@ -218,11 +541,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
     }
     drop(pipe);
 
-    let status = unsafe {
-        let mut status: i32 = 0;
-        check_ret_errno("waitpid", libc::waitpid(pid, &mut status, 0))?;
-        std::process::ExitStatus::from_raw(status)
-    };
+    let (status, usage) = wait(pid)?;
 
     let termination = if status.success() {
         Termination::Success
@@ -241,5 +560,36 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         Termination::Failure
     };
 
-    Ok(termination)
+    Ok((termination, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_cmdlines() {
+        assert_eq!(
+            split_simple_cmdline("cc -c  foo.c -o foo.o -DX=1"),
+            Some(vec!["cc", "-c", "foo.c", "-o", "foo.o", "-DX=1"])
+        );
+        assert_eq!(
+            split_simple_cmdline("touch out"),
+            Some(vec!["touch", "out"])
+        );
+        assert_eq!(split_simple_cmdline(""), None);
+        assert_eq!(split_simple_cmdline("  "), None);
+    }
+
+    #[test]
+    fn shell_cmdlines() {
+        assert_eq!(split_simple_cmdline("echo hi > out"), None);
+        assert_eq!(split_simple_cmdline("a && b"), None);
+        assert_eq!(split_simple_cmdline("echo $HOME"), None);
+        assert_eq!(split_simple_cmdline("echo 'a b'"), None);
+        assert_eq!(split_simple_cmdline("rm *.o"), None);
+        assert_eq!(split_simple_cmdline("cd dir"), None);
+        assert_eq!(split_simple_cmdline("FOO=1 cc"), None);
+        assert_eq!(split_simple_cmdline("echo a\nb"), None);
+    }
 }
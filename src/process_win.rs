@@ -1,16 +1,26 @@
 //! Implements run_command on Windows using native Windows calls.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{ResourceUsage, Termination};
 use std::ffi::c_void;
 use std::io::Read;
 use std::os::windows::io::{FromRawHandle, OwnedHandle};
 use std::os::windows::prelude::AsRawHandle;
 use std::pin::{pin, Pin};
+use std::sync::Mutex;
+use std::time::Duration;
 use windows_sys::Win32::{
     Foundation::*,
+    Globalization::{GetACP, GetOEMCP, IsDBCSLeadByteEx, MultiByteToWideChar, CP_UTF8},
     Security::SECURITY_ATTRIBUTES,
-    System::{Console::*, Diagnostics::Debug::*, Pipes::CreatePipe, Threading::*},
+    System::{
+        Console::*,
+        Diagnostics::Debug::*,
+        JobObjects::*,
+        Pipes::CreatePipe,
+        ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        Threading::*,
+    },
 };
 
 fn get_error_string(err: u32) -> String {
@@ -35,6 +45,12 @@ fn get_error_string(err: u32) -> String {
         .to_owned()
 }
 
+/// Whether the ANSI code page, in which CreateProcessA takes commands, is
+/// UTF-8.  Otherwise the bytes of build files go to commands in that code page.
+pub fn ansi_is_utf8() -> bool {
+    unsafe { GetACP() == CP_UTF8 }
+}
+
 /// Construct an error from GetLastError().
 fn windows_error(func: &str) -> anyhow::Error {
     let err = unsafe { GetLastError() };
@@ -153,11 +169,315 @@ impl<'a> Drop for ProcThreadAttributeList<'a> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// The job object that all subprocesses are assigned to, or 0 if not yet
+/// created.  It is never closed explicitly; see job().
+static JOB: Mutex<HANDLE> = Mutex::new(0);
+
+/// Get the job object that all subprocesses are assigned to, creating it on
+/// first use.
+/// Processes spawned by a process in a job are also in the job, and the job is
+/// configured to kill all of them when its last handle is closed.  That
+/// happens when n2 exits for any reason (including Ctrl-C), so no subprocess
+/// or anything it in turn spawned (e.g. mspdbsrv.exe) outlives n2.
+fn job() -> anyhow::Result<HANDLE> {
+    let mut job = JOB.lock().unwrap();
+    if *job == 0 {
+        unsafe {
+            let handle = CreateJobObjectA(std::ptr::null(), std::ptr::null());
+            if handle == 0 {
+                win_bail!(CreateJobObjectA);
+            }
+            let mut info = std::mem::zeroed::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            if SetInformationJobObject(
+                handle,
+                JobObjectExtendedLimitInformation,
+                &info as *const _ as *const c_void,
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            ) == 0
+            {
+                let err = windows_error("SetInformationJobObject");
+                CloseHandle(handle);
+                return Err(err);
+            }
+            *job = handle;
+        }
+    }
+    Ok(*job)
+}
+
+/// Ids of the currently running subprocesses.  Each is started in a process
+/// group of its own, whose id is the same as the process's.
+static PROCESS_IDS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Registers a running subprocess in PROCESS_IDS for as long as it lives.
+struct RunningProcess(u32);
+
+impl RunningProcess {
+    fn new(pid: u32) -> Self {
+        PROCESS_IDS.lock().unwrap().push(pid);
+        RunningProcess(pid)
+    }
+}
+
+impl Drop for RunningProcess {
+    fn drop(&mut self) {
+        PROCESS_IDS.lock().unwrap().retain(|&pid| pid != self.0);
+    }
+}
+
+/// Terminate all running subprocesses, including their descendants.
+/// Sends CTRL_BREAK to each, then terminates the job, and with it anything
+/// that hasn't exited within the grace period; see
+/// process::set_terminate_grace.
+pub fn terminate_all() {
+    for &pid in PROCESS_IDS.lock().unwrap().iter() {
+        unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) };
+    }
+    let deadline = std::time::Instant::now() + crate::process::terminate_grace();
+    while std::time::Instant::now() < deadline {
+        if PROCESS_IDS.lock().unwrap().is_empty() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let job = JOB.lock().unwrap();
+    if *job != 0 {
+        unsafe { TerminateJobObject(*job, 1) };
+    }
+}
+
+/// Encodings of subprocess output that get transcoded to UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    /// Not enough output seen yet to tell.
+    Unknown,
+    Utf8,
+    Utf16,
+    /// The OEM code page, which MSVC tools use when not told otherwise.
+    Oem,
+}
+
+/// Guess the encoding of the start of some output.
+fn sniff_encoding(buf: &[u8]) -> Encoding {
+    match buf {
+        [] | [_] => Encoding::Unknown,
+        // Byte order mark, or an ASCII character in UTF-16LE.
+        [0xFF, 0xFE, ..] => Encoding::Utf16,
+        [c, 0, ..] if *c != 0 => Encoding::Utf16,
+        _ => Encoding::Utf8,
+    }
+}
+
+/// Transcodes subprocess output to UTF-8 as it arrives, so it neither
+/// displays as mojibake nor trips up /showIncludes parsing.
+/// Output is assumed to be UTF-8 until it proves otherwise, at which point
+/// the OEM code page is used instead.
+struct OutputDecoder {
+    encoding: Encoding,
+    /// Bytes of a character split across reads, held until the next read.
+    pending: Vec<u8>,
+}
+
+impl OutputDecoder {
+    fn new() -> Self {
+        OutputDecoder {
+            encoding: Encoding::Unknown,
+            pending: Vec::new(),
+        }
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(buf);
+        if self.encoding == Encoding::Unknown {
+            self.encoding = sniff_encoding(&self.pending);
+        }
+        let mut data = std::mem::take(&mut self.pending);
+        match self.encoding {
+            Encoding::Unknown => {
+                self.pending = data;
+                Vec::new()
+            }
+            Encoding::Utf8 => match std::str::from_utf8(&data) {
+                Ok(_) => data,
+                Err(err) if err.error_len().is_none() => {
+                    self.pending = data.split_off(err.valid_up_to());
+                    data
+                }
+                Err(_) => {
+                    self.encoding = Encoding::Oem;
+                    self.decode_oem(data)
+                }
+            },
+            Encoding::Utf16 => {
+                if data.len() % 2 == 1 {
+                    self.pending = data.split_off(data.len() - 1);
+                }
+                let mut units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                if let Some(&last) = units.last() {
+                    if (0xD800..0xDC00).contains(&last) {
+                        // High surrogate; wait for its other half.
+                        units.pop();
+                        self.pending.splice(0..0, last.to_le_bytes());
+                    }
+                }
+                units.retain(|&u| u != 0xFEFF);
+                String::from_utf16_lossy(&units).into_bytes()
+            }
+            Encoding::Oem => self.decode_oem(data),
+        }
+    }
+
+    fn decode_oem(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        let codepage = unsafe { GetOEMCP() };
+        // Hold back a trailing lead byte of a double-byte character.
+        let mut i = 0;
+        while i < data.len() {
+            i += if unsafe { IsDBCSLeadByteEx(codepage, data[i]) } != 0 {
+                2
+            } else {
+                1
+            };
+        }
+        if i > data.len() {
+            self.pending = data.split_off(data.len() - 1);
+        }
+        oem_to_utf8(codepage, &data)
+    }
+
+    /// Decode whatever is left over at the end of the output.
+    fn finish(&mut self) -> Vec<u8> {
+        let data = std::mem::take(&mut self.pending);
+        if data.is_empty() {
+            return data;
+        }
+        match self.encoding {
+            Encoding::Unknown | Encoding::Utf8 => {
+                String::from_utf8_lossy(&data).into_owned().into_bytes()
+            }
+            Encoding::Utf16 => "\u{FFFD}".as_bytes().to_vec(),
+            Encoding::Oem => oem_to_utf8(unsafe { GetOEMCP() }, &data),
+        }
+    }
+}
+
+/// Convert text in the given code page to UTF-8.
+fn oem_to_utf8(codepage: u32, data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    unsafe {
+        let len = MultiByteToWideChar(
+            codepage,
+            0,
+            data.as_ptr(),
+            data.len() as i32,
+            std::ptr::null_mut(),
+            0,
+        );
+        if len <= 0 {
+            return String::from_utf8_lossy(data).into_owned().into_bytes();
+        }
+        let mut wide = vec![0u16; len as usize];
+        MultiByteToWideChar(
+            codepage,
+            0,
+            data.as_ptr(),
+            data.len() as i32,
+            wide.as_mut_ptr(),
+            len,
+        );
+        String::from_utf16_lossy(&wide).into_bytes()
+    }
+}
+
+/// Build an environment block for CreateProcess: our environment with the
+/// given variables set, as a sequence of nul-terminated "name=value"
+/// strings, sorted by name and ending in an extra nul.
+fn env_block(env: &crate::process::Env) -> Vec<u8> {
+    let mut vars: Vec<Vec<u8>> = env
+        .inherited()
+        .map(|var| var.to_bytes().to_vec())
+        .chain(
+            env.set
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value).into_bytes()),
+        )
+        .collect();
+    // Names are case-insensitive, so sort them uppercased.
+    vars.sort_by_key(|var| var.to_ascii_uppercase());
+
+    let mut block = Vec::new();
+    for var in vars {
+        block.extend_from_slice(&var);
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+fn filetime_duration(time: &FILETIME) -> Duration {
+    let ticks = ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+    // FILETIME counts 100ns ticks.
+    Duration::from_nanos(ticks * 100)
+}
+
+/// Read the resources used by an exited process.  The processes share one
+/// job object, so this can't count the process's own subprocesses.
+/// Failures just leave the numbers zero, as they're only informational.
+fn process_usage(process: HANDLE) -> ResourceUsage {
+    let mut usage = ResourceUsage::default();
+    unsafe {
+        let mut creation: FILETIME = std::mem::zeroed();
+        let mut exit: FILETIME = std::mem::zeroed();
+        let mut kernel: FILETIME = std::mem::zeroed();
+        let mut user: FILETIME = std::mem::zeroed();
+        if GetProcessTimes(process, &mut creation, &mut exit, &mut kernel, &mut user) != 0 {
+            usage.user = filetime_duration(&user);
+            usage.sys = filetime_duration(&kernel);
+        }
+
+        let mut counters: PROCESS_MEMORY_COUNTERS = std::mem::zeroed();
+        if K32GetProcessMemoryInfo(
+            process,
+            &mut counters,
+            std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32,
+        ) != 0
+        {
+            usage.peak_rss = counters.PeakWorkingSetSize as u64;
+        }
+    }
+    usage
+}
+
+/// Commands are run without a shell, so a shell given is put in front of the
+/// command line, its words quoted as needed, as `cmd /c` or
+/// `powershell -Command` expect, and an empty one changes nothing.
+/// Pseudo-terminals aren't supported on Windows, so `_pty` is ignored.
+/// Commands always inherit our stdin, so `_stdin` is ignored too.
+pub fn run_command(
+    cmdline: &str,
+    shell: Option<&[String]>,
+    _pty: bool,
+    _stdin: bool,
+    env: &crate::process::Env,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, ResourceUsage)> {
     // Don't want to run `cmd /c` since that limits cmd line length to 8192 bytes.
     // std::process::Command can't take a string and pass it through to CreateProcess unchanged,
     // so call that ourselves.
     // https://github.com/rust-lang/rust/issues/38227
+    let with_shell;
+    let cmdline = match shell {
+        Some(shell) if !shell.is_empty() => {
+            with_shell = format!("{} {}", crate::process::join_words(shell), cmdline);
+            with_shell.as_str()
+        }
+        _ => cmdline,
+    };
 
     let (pipe_read, pipe_write) = unsafe {
         let mut pipe_read: HANDLE = 0;
@@ -180,9 +500,13 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         )
     };
 
+    let job = job()?;
     let process_info = unsafe {
         // TODO: Set this to just 0 for console pool jobs.
-        let process_flags = CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT;
+        // The process starts suspended so it can be put in the job before it
+        // has a chance to spawn any children of its own.
+        let process_flags =
+            CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT | CREATE_SUSPENDED;
 
         let mut startup_info = std::mem::zeroed::<STARTUPINFOEXA>();
         startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXA>() as u32;
@@ -204,6 +528,8 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         let mut cmdline_nul: Vec<u8> = String::from(cmdline).into_bytes();
         cmdline_nul.push(0);
 
+        let environment = env_block(env);
+
         if CreateProcessA(
             std::ptr::null_mut(),
             cmdline_nul.as_mut_ptr(),
@@ -211,7 +537,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
             std::ptr::null_mut(),
             /*inherit handles = */ TRUE,
             process_flags,
-            std::ptr::null_mut(),
+            environment.as_ptr() as *const c_void,
             std::ptr::null_mut(),
             &mut startup_info.StartupInfo,
             process_info.as_mut_ptr(),
@@ -232,17 +558,37 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         }
         drop(pipe_write);
 
+        if AssignProcessToJobObject(job, process_info.hProcess) == 0 {
+            let err = windows_error("AssignProcessToJobObject");
+            TerminateProcess(process_info.hProcess, 1);
+            return Err(err);
+        }
+        if ResumeThread(process_info.hThread) == u32::MAX {
+            let err = windows_error("ResumeThread");
+            TerminateProcess(process_info.hProcess, 1);
+            return Err(err);
+        }
+
         process_info
     };
+    let _running = RunningProcess::new(process_info.dwProcessId);
 
     let mut pipe = std::fs::File::from(pipe_read);
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
+    let mut decoder = OutputDecoder::new();
     loop {
         let n = pipe.read(&mut buf)?;
         if n == 0 {
             break;
         }
-        output_cb(&buf[0..n]);
+        let decoded = decoder.decode(&buf[0..n]);
+        if !decoded.is_empty() {
+            output_cb(&decoded);
+        }
+    }
+    let decoded = decoder.finish();
+    if !decoded.is_empty() {
+        output_cb(&decoded);
     }
 
     let exit_code = unsafe {
@@ -257,6 +603,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         exit_code
     };
+    let usage = process_usage(process_info.hProcess);
 
     let termination = match exit_code {
         0 => Termination::Success,
@@ -264,28 +611,55 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         _ => Termination::Failure,
     };
 
-    Ok(termination)
+    Ok((termination, usage))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::process::Env;
 
     /// Simple command that is expected to succeed.
     #[test]
     fn run_echo() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        run_command("cmd /c echo hello", |buf| output.extend_from_slice(buf))?;
+        run_command(
+            "cmd /c echo hello",
+            None,
+            false,
+            false,
+            &Env::ours(),
+            |buf| output.extend_from_slice(buf),
+        )?;
         assert_eq!(output, b"hello\r\n");
         Ok(())
     }
 
+    /// The shell's words are quoted, the command line passed as it is.
+    #[test]
+    fn shell_words_quoted() -> anyhow::Result<()> {
+        let shell = ["cmd", "/c", "echo", "a b"].map(String::from);
+        let mut output = Vec::new();
+        run_command(
+            "c  d",
+            Some(&shell[..]),
+            false,
+            false,
+            &Env::ours(),
+            |buf| output.extend_from_slice(buf),
+        )?;
+        assert_eq!(output, b"\"a b\" c  d\r\n");
+        Ok(())
+    }
+
     /// Expect empty command to be specially handled in errors.
     #[test]
     fn empty_command() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err =
-            run_command("", |buf| output.extend_from_slice(buf)).expect_err("expected failure");
+        let err = run_command("", None, false, false, &Env::ours(), |buf| {
+            output.extend_from_slice(buf)
+        })
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command is empty"));
         Ok(())
     }
@@ -294,9 +668,42 @@ mod tests {
     #[test]
     fn initial_space() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err = run_command(" cmd /c echo hello", |buf| output.extend_from_slice(buf))
-            .expect_err("expected failure");
+        let err = run_command(
+            " cmd /c echo hello",
+            None,
+            false,
+            false,
+            &Env::ours(),
+            |buf| output.extend_from_slice(buf),
+        )
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command has leading whitespace"));
         Ok(())
     }
+
+    /// Decode output fed in the given pieces.
+    fn decode_pieces(pieces: &[&[u8]]) -> String {
+        let mut decoder = OutputDecoder::new();
+        let mut out = Vec::new();
+        for piece in pieces {
+            out.extend(decoder.decode(piece));
+        }
+        out.extend(decoder.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn decode_utf8_split() {
+        let text = "caf\u{e9}".as_bytes();
+        assert_eq!(decode_pieces(&[&text[..4], &text[4..]]), "caf\u{e9}");
+    }
+
+    #[test]
+    fn decode_utf16() {
+        let text: Vec<u8> = "h\u{e9}llo"
+            .encode_utf16()
+            .flat_map(|u| u.to_le_bytes())
+            .collect();
+        assert_eq!(decode_pieces(&[&text[..3], &text[3..]]), "h\u{e9}llo");
+    }
 }
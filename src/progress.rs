@@ -3,10 +3,11 @@
 
 use crate::{
     graph::Build, graph::BuildId, process::Termination, task::TaskResult, terminal,
-    work::BuildState, work::StateCounts,
+    work::BuildState, work::Estimate, work::StateCounts,
 };
 use std::cell::Cell;
 use std::collections::VecDeque;
+use std::ffi::OsString;
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::Condvar;
@@ -26,10 +27,11 @@ pub fn build_message(build: &Build) -> &str {
 /// Trait for build progress notifications.
 pub trait Progress {
     /// Called as individual build tasks progress through build states.
-    fn update(&self, counts: &StateCounts);
+    fn update(&self, counts: &StateCounts, estimate: &Estimate);
 
-    /// Called when a task starts.
-    fn task_started(&self, id: BuildId, build: &Build);
+    /// Called when a task starts.  `expected` is how long it took last time,
+    /// if known.
+    fn task_started(&self, id: BuildId, build: &Build, expected: Option<Duration>);
 
     /// Called when a task's last line of output changes.
     fn task_output(&self, id: BuildId, line: Vec<u8>);
@@ -42,6 +44,10 @@ pub trait Progress {
     /// used when a task fails; we want the final output to show that failed
     /// task's output even if we do more work after it fails.
     fn log(&self, msg: &str);
+
+    /// Run `f`, which interacts with the user on the console, such as with a
+    /// prompt, without the progress display getting in its way.
+    fn interact(&self, f: &mut dyn FnMut());
 }
 
 /// Currently running build task, as tracked for progress updates.
@@ -49,14 +55,20 @@ struct Task {
     id: BuildId,
     /// When the task started running.
     start: Instant,
+    /// How long the task took the last time it ran, if known.
+    expected: Option<Duration>,
     /// Build status message for the task.
     message: String,
     /// Last line of output from the task.
     last_line: Option<String>,
+    /// The job slot the task runs in, from 0 up, for the slot display.
+    slot: usize,
 }
 
+/// How often the dumb console prints a status line, by default.
+const DUMB_STATUS_EVERY: Duration = Duration::from_secs(10);
+
 /// Progress implementation for "dumb" console, without any overprinting.
-#[derive(Default)]
 pub struct DumbConsoleProgress {
     /// Whether to print command lines of started programs.
     verbose: bool,
@@ -64,23 +76,43 @@ pub struct DumbConsoleProgress {
     /// The id of the last command printed, used to avoid printing it twice
     /// when we have two updates from the same command in a row.
     last_started: Cell<Option<BuildId>>,
+
+    /// The least time between status lines.
+    status_every: Duration,
+    /// When the last status line was printed, or the start.
+    last_status: Cell<Instant>,
 }
 
 impl DumbConsoleProgress {
-    pub fn new(verbose: bool) -> Self {
+    /// Between the lines for tasks, a line with the counts of tasks done and
+    /// running is printed when they change, at most every `status_every`
+    /// (by default DUMB_STATUS_EVERY), so that logs of long builds show how
+    /// far along they got without a line for every change.
+    pub fn new(verbose: bool, status_every: Option<Duration>) -> Self {
         Self {
             verbose,
             last_started: Default::default(),
+            status_every: status_every.unwrap_or(DUMB_STATUS_EVERY),
+            last_status: Cell::new(Instant::now()),
         }
     }
 }
 
 impl Progress for DumbConsoleProgress {
-    fn update(&self, _counts: &StateCounts) {
-        // ignore
+    fn update(&self, counts: &StateCounts, _estimate: &Estimate) {
+        let now = Instant::now();
+        if now.duration_since(self.last_status.get()) < self.status_every {
+            return;
+        }
+        self.last_status.set(now);
+        self.log(&format!(
+            "[{}{} running]",
+            done_counts(counts),
+            counts.get(BuildState::Running)
+        ));
     }
 
-    fn task_started(&self, id: BuildId, build: &Build) {
+    fn task_started(&self, id: BuildId, build: &Build, _expected: Option<Duration>) {
         self.log(if self.verbose {
             build.cmdline.as_ref().unwrap()
         } else {
@@ -113,6 +145,10 @@ impl Progress for DumbConsoleProgress {
     fn log(&self, msg: &str) {
         println!("{}", msg);
     }
+
+    fn interact(&self, f: &mut dyn FnMut()) {
+        f();
+    }
 }
 
 /// Progress implementation for "fancy" console, with progress bar etc.
@@ -128,16 +164,44 @@ pub struct FancyConsoleProgress {
 /// of printing in the case of rapid updates.  This helps with terminal flicker.
 const UPDATE_DELAY: Duration = std::time::Duration::from_millis(50);
 
+/// Without updates, the screen is still redrawn this often, to show how long
+/// lengthy build steps have been running.
+const IDLE_REFRESH: Duration = std::time::Duration::from_millis(500);
+
 impl FancyConsoleProgress {
-    pub fn new(verbose: bool) -> Self {
+    /// With slots, the display has a line for each of the parallelism job
+    /// slots, showing the task in it, if any, rather than the oldest tasks.
+    /// `refresh`, if not the default UPDATE_DELAY, is the least time between
+    /// redraws, for slow connections where redrawing often costs too much.
+    /// With a title, the terminal's title shows the percentage done, then
+    /// "n2" and it, as made by title_suffix, until the build is over.
+    pub fn new(
+        verbose: bool,
+        parallelism: usize,
+        slots: bool,
+        refresh: Option<Duration>,
+        title: Option<String>,
+    ) -> Self {
+        let refresh = refresh.unwrap_or(UPDATE_DELAY);
+        if title.is_some() {
+            // Save the title to restore in cleanup, where terminals keep a
+            // stack of them as xterm does.
+            std::io::stdout().write_all(b"\x1b[22;0t").unwrap();
+        }
         let dirty_cond = Arc::new(Condvar::new());
         let state = Arc::new(Mutex::new(FancyState {
             done: false,
             dirty: false,
             dirty_cond: dirty_cond.clone(),
             counts: StateCounts::default(),
+            estimate: Estimate::default(),
             tasks: VecDeque::new(),
             verbose,
+            parallelism,
+            slots,
+            throughput: Throughput::new(Instant::now()),
+            title,
+            title_shown: None,
         }));
 
         // Thread to debounce status updates -- waits a bit, then prints after
@@ -145,15 +209,15 @@ impl FancyConsoleProgress {
         std::thread::spawn({
             let state = state.clone();
             move || loop {
-                // Wait to be notified of a display update, or timeout at 500ms.
-                // The timeout is for the case where there are lengthy build
-                // steps and the progress will show how long they've been
-                // running.
+                // Wait to be notified of a display update, or timeout at
+                // IDLE_REFRESH, or refresh if that's longer.  The timeout is
+                // for the case where there are lengthy build steps and the
+                // progress will show how long they've been running.
                 {
                     let (state, _) = dirty_cond
                         .wait_timeout_while(
                             state.lock().unwrap(),
-                            Duration::from_millis(500),
+                            IDLE_REFRESH.max(refresh),
                             |state| !state.dirty,
                         )
                         .unwrap();
@@ -163,7 +227,7 @@ impl FancyConsoleProgress {
                 }
 
                 // Delay a little bit in case more display updates come in.
-                std::thread::sleep(UPDATE_DELAY);
+                std::thread::sleep(refresh);
 
                 // Update regardless of whether we timed out or not.
                 state.lock().unwrap().print_progress();
@@ -175,12 +239,12 @@ impl FancyConsoleProgress {
 }
 
 impl Progress for FancyConsoleProgress {
-    fn update(&self, counts: &StateCounts) {
-        self.state.lock().unwrap().update(counts);
+    fn update(&self, counts: &StateCounts, estimate: &Estimate) {
+        self.state.lock().unwrap().update(counts, estimate);
     }
 
-    fn task_started(&self, id: BuildId, build: &Build) {
-        self.state.lock().unwrap().task_started(id, build);
+    fn task_started(&self, id: BuildId, build: &Build, expected: Option<Duration>) {
+        self.state.lock().unwrap().task_started(id, build, expected);
     }
 
     fn task_output(&self, id: BuildId, line: Vec<u8>) {
@@ -194,6 +258,14 @@ impl Progress for FancyConsoleProgress {
     fn log(&self, msg: &str) {
         self.state.lock().unwrap().log(msg);
     }
+
+    fn interact(&self, f: &mut dyn FnMut()) {
+        // Holding the lock keeps the display thread from printing meanwhile.
+        let mut state = self.state.lock().unwrap();
+        state.clear_progress();
+        f();
+        state.dirty();
+    }
 }
 
 impl Drop for FancyConsoleProgress {
@@ -209,11 +281,23 @@ struct FancyState {
 
     /// Counts of tasks in each state.  TODO: pass this as function args?
     counts: StateCounts,
+    /// Recorded durations of queued tasks, for the time left estimate.
+    estimate: Estimate,
     /// Build tasks that are currently executing.
     /// Pushed to as tasks are started, so it's always in order of age.
     tasks: VecDeque<Task>,
     /// Whether to print command lines of started programs.
     verbose: bool,
+    /// Maximum number of tasks that run at once, for the time left estimate.
+    parallelism: usize,
+    /// Whether to show a line per job slot; see FancyConsoleProgress::new.
+    slots: bool,
+    throughput: Throughput,
+    /// What follows "n2" in the terminal's title, if it's to show progress;
+    /// see FancyConsoleProgress::new.
+    title: Option<String>,
+    /// The percentage done last shown in the title.
+    title_shown: Option<usize>,
 }
 
 impl FancyState {
@@ -222,21 +306,27 @@ impl FancyState {
         self.dirty_cond.notify_one();
     }
 
-    fn update(&mut self, counts: &StateCounts) {
+    fn update(&mut self, counts: &StateCounts, estimate: &Estimate) {
         self.counts = counts.clone();
+        self.estimate = estimate.clone();
         self.dirty();
     }
 
-    fn task_started(&mut self, id: BuildId, build: &Build) {
+    fn task_started(&mut self, id: BuildId, build: &Build, expected: Option<Duration>) {
         if self.verbose {
             self.log(build.cmdline.as_ref().unwrap());
         }
         let message = build_message(build);
+        let slot = (0..)
+            .find(|&slot| self.tasks.iter().all(|t| t.slot != slot))
+            .unwrap();
         self.tasks.push_back(Task {
             id,
             start: Instant::now(),
+            expected,
             message: message.to_string(),
             last_line: None,
+            slot,
         });
         self.dirty();
     }
@@ -250,6 +340,7 @@ impl FancyState {
     fn task_finished(&mut self, id: BuildId, build: &Build, result: &TaskResult) {
         self.tasks
             .remove(self.tasks.iter().position(|t| t.id == id).unwrap());
+        self.throughput.add(Instant::now());
         match result.termination {
             Termination::Success => {
                 if result.output.is_empty() {
@@ -275,6 +366,12 @@ impl FancyState {
 
     fn cleanup(&mut self) {
         self.clear_progress();
+        if self.title.is_some() {
+            // Where there's no stack of titles, the empty one is the default.
+            std::io::stdout()
+                .write_all(b"\x1b]0;\x07\x1b[23;0t")
+                .unwrap();
+        }
         self.done = true;
         self.dirty(); // let thread quit
     }
@@ -287,16 +384,18 @@ impl FancyState {
 
     fn print_progress(&mut self) {
         self.clear_progress();
-        let failed = self.counts.get(BuildState::Failed);
+        if let Some(title) = &self.title {
+            let percent = percent_done(&self.counts);
+            if self.title_shown != Some(percent) {
+                print!("\x1b]0;[{}%] n2{}\x07", percent, title);
+                self.title_shown = Some(percent);
+            }
+        }
         let mut progress_line = format!(
-            "[{}] {}/{} done, ",
+            "[{}] {}",
             progress_bar(&self.counts, 40),
-            self.counts.get(BuildState::Done) + failed,
-            self.counts.total()
+            done_counts(&self.counts)
         );
-        if failed > 0 {
-            progress_line.push_str(&format!("{} failed, ", failed));
-        }
         progress_line.push_str(&format!(
             "{}/{} running",
             self.tasks.len(),
@@ -304,12 +403,33 @@ impl FancyState {
                 + self.counts.get(BuildState::Running)
                 + self.counts.get(BuildState::Ready),
         ));
+        let now = Instant::now();
+        let running: Vec<_> = self
+            .tasks
+            .iter()
+            .map(|task| (task.expected, now.duration_since(task.start)))
+            .collect();
+        let left = time_left(&self.estimate, &running, self.parallelism)
+            .map(|left| format!(", ~{} left", format_duration(left)))
+            .unwrap_or_default();
+        let max_cols = terminal::get_cols().unwrap_or(80);
+        let progress_line =
+            fit_status_line(progress_line, self.throughput.show(now), &left, max_cols);
         println!("{}", progress_line);
         let mut lines = 1;
 
-        let max_cols = terminal::get_cols().unwrap_or(80);
+        if self.slots {
+            // Leave room for the progress line and the line after the display.
+            let max_lines = terminal::get_rows().unwrap_or(24).saturating_sub(2);
+            for line in slot_lines(&self.tasks, self.parallelism, now, max_cols, max_lines) {
+                println!("{}", line);
+                lines += 1;
+            }
+            print!("\x1b[{}A", lines);
+            self.dirty = false;
+            return;
+        }
         let max_tasks = 8;
-        let now = Instant::now();
         for task in self.tasks.iter().take(max_tasks) {
             let delta = now.duration_since(task.start).as_secs() as usize;
             println!("{}", task_message(&task.message, delta, max_cols));
@@ -333,6 +453,178 @@ impl FancyState {
     }
 }
 
+/// The status line, from what comes before the throughput, the throughput
+/// and its sparkline, and what comes after, in at most max_cols columns: a
+/// line that wrapped would throw off how far up the display is redrawn from.
+/// To fit, the sparkline is dropped first, then the end cut off.
+fn fit_status_line(
+    mut line: String,
+    throughput: Option<(f64, String)>,
+    after: &str,
+    max_cols: usize,
+) -> String {
+    if let Some((rate, sparkline)) = throughput {
+        let rate = format!(", {:.1}/s", rate);
+        let width = line.chars().count() + rate.chars().count() + 1 + sparkline.chars().count();
+        line.push_str(&rate);
+        if width + after.chars().count() <= max_cols {
+            line.push(' ');
+            line.push_str(&sparkline);
+        }
+    }
+    line.push_str(after);
+    match line.char_indices().nth(max_cols) {
+        Some((end, _)) => line[..end].to_owned(),
+        None => line,
+    }
+}
+
+/// How much of the build is done, or failed, as a whole percentage.
+fn percent_done(counts: &StateCounts) -> usize {
+    let done = counts.get(BuildState::Done) + counts.get(BuildState::Failed);
+    (done * 100).checked_div(counts.total()).unwrap_or(0)
+}
+
+/// What follows "n2" in the terminal's title: ": " and the targets, with any
+/// control characters, which could end the title early, dropped.
+pub fn title_suffix(targets: &[OsString]) -> String {
+    if targets.is_empty() {
+        return String::new();
+    }
+    let targets: Vec<_> = targets.iter().map(|t| t.to_string_lossy()).collect();
+    let targets = targets.join(" ");
+    format!(": {}", targets.replace(|c: char| c.is_control(), ""))
+}
+
+/// The start of a status line: how many tasks are done out of the total, and
+/// how many of those failed, if any.  Ends with ", " for what follows.
+fn done_counts(counts: &StateCounts) -> String {
+    let failed = counts.get(BuildState::Failed);
+    let mut line = format!(
+        "{}/{} done, ",
+        counts.get(BuildState::Done) + failed,
+        counts.total()
+    );
+    if failed > 0 {
+        line.push_str(&format!("{} failed, ", failed));
+    }
+    line
+}
+
+/// How far back Throughput looks.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(20);
+/// The sparkline covers THROUGHPUT_WINDOW in this many bars.
+const SPARKLINE_BARS: u32 = 10;
+
+/// When recent tasks finished, to show the rate they're finishing at and how
+/// that's changed, which makes it plain when only one task at a time can run.
+struct Throughput {
+    start: Instant,
+    /// Oldest first, within THROUGHPUT_WINDOW of the last.
+    finished: VecDeque<Instant>,
+}
+
+impl Throughput {
+    fn new(start: Instant) -> Self {
+        Throughput {
+            start,
+            finished: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, now: Instant) {
+        self.finished.push_back(now);
+        while let Some(&first) = self.finished.front() {
+            if now.duration_since(first) <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.finished.pop_front();
+        }
+    }
+
+    /// The tasks per second finished over the window, or since the start if
+    /// that was more recent, along with a sparkline of the rate over the
+    /// window, oldest first.  None until a task has finished.
+    fn show(&self, now: Instant) -> Option<(f64, String)> {
+        if self.finished.is_empty() {
+            return None;
+        }
+        let window = THROUGHPUT_WINDOW.min(now.duration_since(self.start));
+        let bar = THROUGHPUT_WINDOW / SPARKLINE_BARS;
+        let mut counts = [0usize; SPARKLINE_BARS as usize];
+        let mut total = 0;
+        for &finished in &self.finished {
+            let age = now.duration_since(finished);
+            if age >= THROUGHPUT_WINDOW {
+                continue;
+            }
+            total += 1;
+            counts[counts.len() - 1 - (age.as_nanos() / bar.as_nanos()) as usize] += 1;
+        }
+        let rate = total as f64 / window.as_secs_f64().max(1.0);
+
+        // Only bars since the start mean anything.
+        let bars = (now.duration_since(self.start).as_nanos() / bar.as_nanos()) as usize + 1;
+        let counts = &counts[counts.len().saturating_sub(bars)..];
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+        const LEVELS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let sparkline = counts
+            .iter()
+            .map(|&count| LEVELS[(count * 8).div_ceil(max)])
+            .collect();
+        Some((rate, sparkline))
+    }
+}
+
+/// Estimate the wall time left in the build, given the recorded durations of
+/// queued tasks and the (expected, elapsed) durations of running tasks.
+/// Tasks without history are assumed to take the average of those with it.
+/// Returns None if there is no history to base an estimate on, in which case
+/// the display falls back to just the task counts.
+fn time_left(
+    queued: &Estimate,
+    running: &[(Option<Duration>, Duration)],
+    parallelism: usize,
+) -> Option<Duration> {
+    let mut known = queued.known;
+    let mut known_count = queued.known_count;
+    for &(expected, _) in running {
+        if let Some(expected) = expected {
+            known += expected;
+            known_count += 1;
+        }
+    }
+    if known_count == 0 {
+        return None;
+    }
+    let average = known / known_count as u32;
+
+    let mut work = queued.known + average * queued.unknown_count as u32;
+    let mut longest = Duration::ZERO;
+    for &(expected, elapsed) in running {
+        let left = expected.unwrap_or(average).saturating_sub(elapsed);
+        work += left;
+        longest = longest.max(left);
+    }
+
+    // Near the end of a build there are fewer tasks than slots to run them in.
+    let tasks = queued.known_count + queued.unknown_count + running.len();
+    let slots = parallelism.min(tasks).max(1);
+    Some((work / slots as u32).max(longest))
+}
+
+/// Format a duration compactly for the status line, e.g. "2m30s".
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / (60 * 60), (secs / 60) % 60)
+    }
+}
+
 /// Format a task's status message to optionally include how long it has been running
 /// and also to fit within a maximum number of terminal columns.
 fn task_message(message: &str, seconds: usize, max_cols: usize) -> String {
@@ -350,6 +642,46 @@ fn task_message(message: &str, seconds: usize, max_cols: usize) -> String {
     out
 }
 
+/// The lines of the slot display: one for each job slot, numbered from 1,
+/// with the task running in it, if any, fitting in max_lines.  Once there are
+/// more slots than that, the last line counts the tasks in those not shown.
+fn slot_lines(
+    tasks: &VecDeque<Task>,
+    slots: usize,
+    now: Instant,
+    max_cols: usize,
+    max_lines: usize,
+) -> Vec<String> {
+    // Tasks can outnumber slots, e.g. for a build.ninja regenerated under -j1.
+    let slots = tasks.iter().map(|t| t.slot + 1).fold(slots, usize::max);
+    let shown = if slots > max_lines {
+        max_lines.saturating_sub(1)
+    } else {
+        slots
+    };
+    let width = slots.to_string().len();
+    let mut lines: Vec<String> = (0..shown)
+        .map(|slot| match tasks.iter().find(|t| t.slot == slot) {
+            Some(task) => {
+                let delta = now.duration_since(task.start).as_secs() as usize;
+                let max_cols = max_cols.saturating_sub(width + 1).max(4);
+                format!(
+                    "{:>width$} {}",
+                    slot + 1,
+                    task_message(&task.message, delta, max_cols),
+                    width = width
+                )
+            }
+            None => format!("{:>width$}", slot + 1, width = width),
+        })
+        .collect();
+    if shown < slots {
+        let hidden = tasks.iter().filter(|t| t.slot >= shown).count();
+        lines.push(format!("...and {} more running", hidden));
+    }
+    lines
+}
+
 fn truncate(s: &str, mut max: usize) -> &str {
     if max >= s.len() {
         return s;
@@ -399,6 +731,41 @@ fn progress_bar(counts: &StateCounts, bar_size: usize) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn title() {
+        let mut counts = StateCounts::default();
+        assert_eq!(percent_done(&counts), 0);
+        counts.add(BuildState::Want, 3);
+        counts.add(BuildState::Done, 1);
+        counts.add(BuildState::Failed, 1);
+        assert_eq!(percent_done(&counts), 40);
+
+        assert_eq!(title_suffix(&[]), "");
+        assert_eq!(
+            title_suffix(&["out/a".into(), "b\x07c".into()]),
+            ": out/a bc"
+        );
+    }
+
+    #[test]
+    fn status_line_width() {
+        let line = || "[bar] 1/2 done, 1/1 running".to_owned();
+        let throughput = || Some((1.5, "▁▂▃".to_owned()));
+        assert_eq!(
+            fit_status_line(line(), throughput(), ", ~1s left", 80),
+            "[bar] 1/2 done, 1/1 running, 1.5/s ▁▂▃, ~1s left"
+        );
+        assert_eq!(
+            fit_status_line(line(), throughput(), ", ~1s left", 45),
+            "[bar] 1/2 done, 1/1 running, 1.5/s, ~1s left"
+        );
+        assert_eq!(
+            fit_status_line(line(), throughput(), ", ~1s left", 20),
+            "[bar] 1/2 done, 1/1 "
+        );
+        assert_eq!(fit_status_line(line(), None, "", 80), line());
+    }
+
     #[test]
     fn progress_bar_rendering() {
         let mut counts = StateCounts::default();
@@ -443,6 +810,109 @@ mod tests {
         assert_eq!(task_message("building foo.o", 5, 10), "bu... (5s)");
     }
 
+    #[test]
+    fn slot_rendering() {
+        let now = Instant::now();
+        let task = |slot: usize, message: &str| Task {
+            id: BuildId::from(slot),
+            start: now,
+            expected: None,
+            message: message.to_owned(),
+            last_line: None,
+            slot,
+        };
+        let tasks = VecDeque::from(vec![task(0, "cc a.c"), task(2, "cc c.c"), task(10, "link")]);
+        assert_eq!(
+            slot_lines(&tasks, 4, now, 80, 20),
+            [
+                " 1 cc a.c",
+                " 2",
+                " 3 cc c.c",
+                " 4",
+                " 5",
+                " 6",
+                " 7",
+                " 8",
+                " 9",
+                "10",
+                "11 link"
+            ]
+        );
+        assert_eq!(
+            slot_lines(&tasks, 12, now, 80, 4),
+            [" 1 cc a.c", " 2", " 3 cc c.c", "...and 1 more running"]
+        );
+    }
+
+    #[test]
+    fn throughput() {
+        let start = Instant::now();
+        let secs = |n: u64| start + Duration::from_secs(n);
+        let mut throughput = Throughput::new(start);
+        assert_eq!(throughput.show(secs(1)), None);
+
+        // A burst of tasks, then one at a time.
+        for _ in 0..8 {
+            throughput.add(secs(1));
+        }
+        for n in 2..6 {
+            throughput.add(secs(n * 2));
+        }
+        let (rate, sparkline) = throughput.show(secs(11)).unwrap();
+        assert_eq!(rate, 12.0 / 11.0);
+        assert_eq!(sparkline, "█ ▁▁▁▁");
+
+        // The burst passes out of the window.
+        let (rate, sparkline) = throughput.show(secs(25)).unwrap();
+        assert_eq!(rate, 3.0 / 20.0);
+        assert_eq!(sparkline, "███       ");
+    }
+
+    #[test]
+    fn time_left_estimate() {
+        let secs = Duration::from_secs;
+
+        // No history at all: no estimate.
+        let mut queued = Estimate::default();
+        queued.add(None);
+        assert_eq!(time_left(&queued, &[(None, secs(1))], 4), None);
+
+        // Two 10s tasks queued, plenty of parallelism: bounded by one task.
+        let mut queued = Estimate::default();
+        queued.add(Some(secs(10)));
+        queued.add(Some(secs(10)));
+        assert_eq!(time_left(&queued, &[], 4), Some(secs(10)));
+        // Serialized, they take twice as long.
+        assert_eq!(time_left(&queued, &[], 1), Some(secs(20)));
+
+        // Unknown tasks are assumed to take the average.
+        queued.add(None);
+        assert_eq!(time_left(&queued, &[], 1), Some(secs(30)));
+        queued.remove(None);
+
+        // Running tasks contribute whatever is left of their duration, and
+        // the longest running task bounds the estimate from below.
+        let queued = Estimate::default();
+        assert_eq!(
+            time_left(&queued, &[(Some(secs(60)), secs(20))], 4),
+            Some(secs(40))
+        );
+        assert_eq!(
+            time_left(&queued, &[(Some(secs(60)), secs(90))], 4),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn duration_rendering() {
+        assert_eq!(format_duration(Duration::from_millis(4500)), "4s");
+        assert_eq!(format_duration(Duration::from_secs(150)), "2m30s");
+        assert_eq!(
+            format_duration(Duration::from_secs(3 * 3600 + 5 * 60)),
+            "3h05m"
+        );
+    }
+
     #[test]
     fn truncate_utf8() {
         let text = "utf8 progress bar: ━━━━━━━━━━━━";
@@ -0,0 +1,352 @@
+//! The tools that print what the build graph says: `-t targets`, `-t query`,
+//! `-t commands` and `-t deps`.  They print text much like Ninja's tools of
+//! the same names, or with `--format json`, JSON whose shape stays put for
+//! scripts to rely on.
+
+use crate::{
+    db,
+    graph::{BuildId, FileId, Graph},
+    load, plan,
+};
+use anyhow::{anyhow, bail};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::Write;
+
+/// How the tools print what they find.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> anyhow::Result<Format> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => bail!("unknown --format {:?}, expected text or json", name),
+        }
+    }
+}
+
+/// Print with a buffered stdout, as the output may be long.
+fn print(f: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) -> anyhow::Result<i32> {
+    let stdout = std::io::stdout();
+    let mut w = std::io::BufWriter::new(stdout.lock());
+    f(&mut w)?;
+    w.flush()?;
+    Ok(0)
+}
+
+/// Write items as a JSON array, one per line.
+fn write_array<T>(
+    w: &mut dyn Write,
+    items: &[T],
+    mut write_item: impl FnMut(&mut dyn Write, &T) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    writeln!(w, "[")?;
+    for (i, item) in items.iter().enumerate() {
+        write!(w, "  ")?;
+        write_item(w, item)?;
+        writeln!(w, "{}", if i + 1 < items.len() { "," } else { "" })?;
+    }
+    writeln!(w, "]")
+}
+
+/// `-t targets`: every file that some build produces, with the rule of that
+/// build, as Ninja's `-t targets all` prints them.
+pub fn targets(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+    format: Format,
+) -> anyhow::Result<i32> {
+    if !(targets.is_empty() || targets == ["all"]) {
+        bail!("-t targets takes no targets, other than Ninja's \"all\"");
+    }
+    let manifest = load::read_manifest(build_filenames, options)?;
+    let graph = &manifest.graph;
+    let mut files: Vec<(&str, &str)> = graph
+        .files
+        .all_ids()
+        .filter_map(|id| {
+            let file = graph.file(id);
+            Some((file.name.as_str(), graph.builds[file.input?].rule.as_str()))
+        })
+        .collect();
+    files.sort_unstable();
+    print(|w| match format {
+        Format::Text => {
+            for (name, rule) in &files {
+                writeln!(w, "{}: {}", name, rule)?;
+            }
+            Ok(())
+        }
+        Format::Json => write_array(w, &files, |w, (name, rule)| {
+            write!(w, "{{\"target\": ")?;
+            plan::write_string(w, name)?;
+            write!(w, ", \"rule\": ")?;
+            plan::write_string(w, rule)?;
+            write!(w, "}}")
+        }),
+    })
+}
+
+/// `-t query`: the build that produces each target, with its inputs and
+/// metadata, and the outputs of the builds that use it.
+pub fn query(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+    format: Format,
+) -> anyhow::Result<i32> {
+    if targets.is_empty() {
+        bail!("-t query needs a target");
+    }
+    let manifest = load::read_manifest(build_filenames, options)?;
+    let graph = &manifest.graph;
+    let ids = targets
+        .iter()
+        .map(|name| graph.lookup_target(name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    print(|w| match format {
+        Format::Text => {
+            for &id in &ids {
+                write_query(w, graph, id)?;
+            }
+            Ok(())
+        }
+        Format::Json => write_array(w, &ids, |w, &id| write_query_json(w, graph, id)),
+    })
+}
+
+/// The outputs of the builds that use a file.
+fn dependent_outs(graph: &Graph, id: FileId) -> Vec<FileId> {
+    graph
+        .file(id)
+        .dependents
+        .iter()
+        .flat_map(|&dependent| graph.builds[dependent].outs().iter().copied())
+        .collect()
+}
+
+fn write_query(w: &mut dyn Write, graph: &Graph, id: FileId) -> std::io::Result<()> {
+    let file = graph.file(id);
+    writeln!(w, "{}:", file.name)?;
+    if let Some(bid) = file.input {
+        let build = &graph.builds[bid];
+        writeln!(w, "  input: {}", build.rule)?;
+        let explicit = build.explicit_ins().len();
+        let dirtying = build.dirtying_ins().len();
+        for (i, &input) in build.ordering_ins().iter().enumerate() {
+            let prefix = if i < explicit {
+                ""
+            } else if i < dirtying {
+                "| "
+            } else {
+                "|| "
+            };
+            writeln!(w, "    {}{}", prefix, graph.file(input).name)?;
+        }
+        for &validation in build.validation_ins() {
+            writeln!(w, "    |@ {}", graph.file(validation).name)?;
+        }
+        if !build.meta.is_empty() {
+            writeln!(w, "  meta:")?;
+            for (name, value) in &build.meta {
+                writeln!(w, "    {} = {}", name, value)?;
+            }
+        }
+    }
+    writeln!(w, "  outputs:")?;
+    for out in dependent_outs(graph, id) {
+        writeln!(w, "    {}", graph.file(out).name)?;
+    }
+    Ok(())
+}
+
+fn write_query_json(w: &mut dyn Write, graph: &Graph, id: FileId) -> std::io::Result<()> {
+    let file = graph.file(id);
+    write!(w, "{{\"target\": ")?;
+    plan::write_string(w, &file.name)?;
+    write!(w, ", \"input\": ")?;
+    match file.input {
+        None => write!(w, "null")?,
+        Some(bid) => {
+            let build = &graph.builds[bid];
+            let explicit = build.explicit_ins();
+            let dirtying = build.dirtying_ins();
+            write!(w, "{{\"rule\": ")?;
+            plan::write_string(w, &build.rule)?;
+            write!(w, ", \"explicit\": ")?;
+            plan::write_files(w, graph, explicit)?;
+            write!(w, ", \"implicit\": ")?;
+            plan::write_files(w, graph, &dirtying[explicit.len()..])?;
+            write!(w, ", \"order_only\": ")?;
+            plan::write_files(w, graph, &build.ordering_ins()[dirtying.len()..])?;
+            write!(w, ", \"validations\": ")?;
+            plan::write_files(w, graph, build.validation_ins())?;
+            write!(w, ", \"meta\": ")?;
+            plan::write_meta(w, &build.meta)?;
+            write!(w, "}}")?;
+        }
+    }
+    write!(w, ", \"outputs\": ")?;
+    plan::write_files(w, graph, &dependent_outs(graph, id))?;
+    write!(w, "}}")
+}
+
+/// The builds the targets need, or the defaults if there are none, or else
+/// everything, in the order they'd have to run, each once.
+fn builds_needed(manifest: &load::Manifest, targets: &[OsString]) -> anyhow::Result<Vec<BuildId>> {
+    let graph = &manifest.graph;
+    let roots: Vec<FileId> = if !targets.is_empty() {
+        targets
+            .iter()
+            .map(|name| graph.lookup_target(name))
+            .collect::<anyhow::Result<_>>()?
+    } else if !manifest.default.is_empty() {
+        manifest.default.clone()
+    } else {
+        graph.files.all_ids().collect()
+    };
+
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    // Depth first, with each build pushed once for entering and again, with
+    // done set, for leaving, once its inputs are all in order.
+    let mut stack: Vec<(BuildId, bool)> = roots
+        .iter()
+        .rev()
+        .filter_map(|&id| Some((graph.file(id).input?, false)))
+        .collect();
+    while let Some((id, done)) = stack.pop() {
+        if done {
+            order.push(id);
+            continue;
+        }
+        if !seen.insert(id) {
+            continue;
+        }
+        stack.push((id, true));
+        for &input in graph.builds[id].ordering_ins().iter().rev() {
+            if let Some(bid) = graph.file(input).input {
+                if !seen.contains(&bid) {
+                    stack.push((bid, false));
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// `-t commands`: the commands that building the targets runs, in an order
+/// they could run in.
+pub fn commands(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+    format: Format,
+) -> anyhow::Result<i32> {
+    let manifest = load::read_manifest(build_filenames, options)?;
+    let graph = &manifest.graph;
+    let builds: Vec<BuildId> = builds_needed(&manifest, targets)?
+        .into_iter()
+        .filter(|&id| graph.builds[id].cmdline.is_some())
+        .collect();
+    print(|w| match format {
+        Format::Text => {
+            for &id in &builds {
+                writeln!(w, "{}", graph.builds[id].cmdline.as_deref().unwrap())?;
+            }
+            Ok(())
+        }
+        Format::Json => write_array(w, &builds, |w, &id| {
+            let build = &graph.builds[id];
+            write!(w, "{{\"outputs\": ")?;
+            plan::write_files(w, graph, build.outs())?;
+            write!(w, ", \"command\": ")?;
+            plan::write_string(w, build.cmdline.as_deref().unwrap())?;
+            write!(w, "}}")
+        }),
+    })
+}
+
+/// `-t deps`: the dependencies the db has recorded as discovered for the
+/// targets, or for every output if there are none.
+pub fn deps(
+    build_filenames: &[String],
+    options: &load::Options,
+    targets: &[OsString],
+    format: Format,
+) -> anyhow::Result<i32> {
+    let mut manifest = load::read_manifest(build_filenames, options)?;
+    let path = manifest.db_path.clone();
+    let contents = if path.exists() {
+        Some(
+            db::read_contents(&path, &mut manifest.graph)
+                .map_err(|err| anyhow!("{}: {}", path.display(), err))?,
+        )
+    } else {
+        None
+    };
+    let graph = &manifest.graph;
+    let ids: Vec<FileId> = if !targets.is_empty() {
+        targets
+            .iter()
+            .map(|name| graph.lookup_target(name))
+            .collect::<anyhow::Result<_>>()?
+    } else {
+        let mut ids: Vec<FileId> = graph
+            .files
+            .all_ids()
+            .filter(|&id| graph.file(id).input.is_some())
+            .collect();
+        ids.sort_by(|&a, &b| graph.file(a).name.cmp(&graph.file(b).name));
+        ids
+    };
+    // The deps of the newest successful record of each target's build.
+    let deps: Vec<(FileId, Option<&[FileId]>)> = ids
+        .iter()
+        .map(|&id| {
+            let recorded = contents.as_ref().and_then(|contents| {
+                let build = graph.file(id).input?;
+                let record = contents
+                    .records
+                    .iter()
+                    .rev()
+                    .find(|r| r.build == Some(build) && !r.fields.failed)?;
+                Some(record.deps.as_slice())
+            });
+            (id, recorded)
+        })
+        .collect();
+    print(|w| match format {
+        Format::Text => {
+            for (id, recorded) in &deps {
+                let name = &graph.file(*id).name;
+                match recorded {
+                    None => writeln!(w, "{}: no deps recorded", name)?,
+                    Some(deps) => {
+                        writeln!(w, "{}: #deps {}", name, deps.len())?;
+                        for &dep in deps.iter() {
+                            writeln!(w, "    {}", graph.file(dep).name)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        Format::Json => write_array(w, &deps, |w, (id, recorded)| {
+            write!(w, "{{\"target\": ")?;
+            plan::write_string(w, &graph.file(*id).name)?;
+            write!(w, ", \"deps\": ")?;
+            match recorded {
+                None => write!(w, "null")?,
+                Some(deps) => plan::write_files(w, graph, deps)?,
+            }
+            write!(w, "}}")
+        }),
+    })
+}
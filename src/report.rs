@@ -0,0 +1,385 @@
+//! Writes a self-contained HTML report on the tasks a build ran, as requested
+//! by --report, for looking into build performance and failures without
+//! command-line tools.
+
+use crate::graph::{BuildId, Graph};
+use crate::process::Termination;
+use crate::task::FinishedTask;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// A task that ran, with times relative to the start of the build.
+struct TaskRecord {
+    id: BuildId,
+    tid: usize,
+    start: Duration,
+    end: Duration,
+    failed: bool,
+    /// Only kept for tasks that failed, as only theirs is shown, and only up
+    /// to MAX_OUTPUT of it.
+    output: Vec<u8>,
+}
+
+/// The most output of a failed task to keep, as with --keep-going many may
+/// fail, and the first errors are the ones that matter.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Truncate output to MAX_OUTPUT, noting how much was dropped.
+fn truncate_output(output: &[u8]) -> Vec<u8> {
+    if output.len() <= MAX_OUTPUT {
+        return output.to_vec();
+    }
+    let mut kept = output[..MAX_OUTPUT].to_vec();
+    kept.extend_from_slice(
+        format!(
+            "\n[n2: {} more bytes of output omitted]\n",
+            output.len() - MAX_OUTPUT
+        )
+        .as_bytes(),
+    );
+    kept
+}
+
+impl TaskRecord {
+    fn duration(&self) -> Duration {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+pub struct Report {
+    start: Instant,
+    tasks: Vec<TaskRecord>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            start: Instant::now(),
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, task: &FinishedTask) {
+        let output = if task.result.termination == Termination::Success {
+            Vec::new()
+        } else {
+            truncate_output(&task.result.output)
+        };
+        self.tasks.push(TaskRecord {
+            id: task.buildid,
+            tid: task.tid,
+            start: task.span.0.saturating_duration_since(self.start),
+            end: task.span.1.saturating_duration_since(self.start),
+            failed: false,
+            output,
+        });
+    }
+
+    pub fn mark_failed(&mut self, id: BuildId) {
+        if let Some(task) = self.tasks.iter_mut().rev().find(|t| t.id == id) {
+            task.failed = true;
+        }
+    }
+
+    /// The builds that ran that the given build waited on, looking through
+    /// phony builds.
+    fn ran_deps(&self, graph: &Graph, ran: &HashMap<BuildId, usize>, id: BuildId) -> Vec<usize> {
+        let mut deps = Vec::new();
+        let mut stack = vec![id];
+        let mut seen = HashSet::new();
+        while let Some(id) = stack.pop() {
+            for &file in graph.builds[id].ordering_ins() {
+                let input = match graph.file(file).input {
+                    Some(input) => input,
+                    None => continue,
+                };
+                if !seen.insert(input) {
+                    continue;
+                }
+                match ran.get(&input) {
+                    Some(&index) => deps.push(index),
+                    None if graph.builds[input].cmdline.is_none() => stack.push(input),
+                    None => {}
+                }
+            }
+        }
+        deps
+    }
+
+    /// The chain of tasks, each waiting on the previous, that took the
+    /// longest in total, as indices into self.tasks in order.
+    fn critical_path(&self, graph: &Graph) -> Vec<usize> {
+        let ran: HashMap<BuildId, usize> = self
+            .tasks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.id, i))
+            .collect();
+        // A task's dependencies always finish before it starts, so visiting
+        // in order of finishing sees them first.
+        let mut order: Vec<usize> = (0..self.tasks.len()).collect();
+        order.sort_by_key(|&i| self.tasks[i].end);
+        let mut longest = vec![Duration::ZERO; self.tasks.len()];
+        let mut prev: Vec<Option<usize>> = vec![None; self.tasks.len()];
+        for &i in &order {
+            let best = self
+                .ran_deps(graph, &ran, self.tasks[i].id)
+                .into_iter()
+                .max_by_key(|&dep| longest[dep]);
+            longest[i] = self.tasks[i].duration() + best.map_or(Duration::ZERO, |dep| longest[dep]);
+            prev[i] = best;
+        }
+        let mut path = Vec::new();
+        let mut cur = (0..self.tasks.len()).max_by_key(|&i| longest[i]);
+        while let Some(i) = cur {
+            path.push(i);
+            cur = prev[i];
+        }
+        path.reverse();
+        path
+    }
+
+    pub fn write_html(&self, w: &mut dyn Write, graph: &Graph) -> std::io::Result<()> {
+        let total = self
+            .tasks
+            .iter()
+            .map(|t| t.end)
+            .max()
+            .unwrap_or_default()
+            .max(Duration::from_millis(1));
+        let critical = self.critical_path(graph);
+        let on_critical: HashSet<usize> = critical.iter().copied().collect();
+        let failed = self.tasks.iter().filter(|t| t.failed).count();
+
+        writeln!(w, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">")?;
+        writeln!(
+            w,
+            "<title>n2 build report</title>\n<style>{}</style>",
+            STYLE
+        )?;
+        writeln!(w, "</head><body>\n<h1>n2 build report</h1>")?;
+        writeln!(
+            w,
+            "<p>{} tasks, {} failed, {} from the first task's start to the last's end.</p>",
+            self.tasks.len(),
+            failed,
+            seconds(total)
+        )?;
+
+        writeln!(w, "<h2>Timeline</h2>\n<div class=\"timeline\">")?;
+        // One lane per task slot, as in the -d trace output.
+        let mut lanes: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, task) in self.tasks.iter().enumerate() {
+            lanes.entry(task.tid).or_default().push(i);
+        }
+        for tasks in lanes.values() {
+            write!(w, "<div class=\"lane\">")?;
+            for &i in tasks {
+                let task = &self.tasks[i];
+                let mut class = "task".to_string();
+                if on_critical.contains(&i) {
+                    class.push_str(" critical");
+                }
+                if task.failed {
+                    class.push_str(" failed");
+                }
+                write!(
+                    w,
+                    "<div class=\"{}\" style=\"left: {:.3}%; width: {:.3}%\" title=\"",
+                    class,
+                    percent(task.start, total),
+                    percent(task.duration(), total)
+                )?;
+                write_escaped(w, &self.describe(graph, i))?;
+                write!(w, "\"></div>")?;
+            }
+            writeln!(w, "</div>")?;
+        }
+        writeln!(w, "</div>")?;
+
+        writeln!(w, "<h2>Critical path</h2>\n<ol>")?;
+        for &i in &critical {
+            write!(w, "<li>")?;
+            write_escaped(w, &self.describe(graph, i))?;
+            writeln!(w, "</li>")?;
+        }
+        writeln!(w, "</ol>")?;
+
+        if failed > 0 {
+            writeln!(w, "<h2>Failures</h2>")?;
+            for task in self.tasks.iter().filter(|t| t.failed) {
+                let build = &graph.builds[task.id];
+                write!(w, "<h3>")?;
+                write_escaped(w, crate::progress::build_message(build))?;
+                write!(w, "</h3>\n<p>")?;
+                write_escaped(w, &build.location.to_string())?;
+                write!(w, "</p>\n<pre>")?;
+                write_escaped(w, &String::from_utf8_lossy(&task.output))?;
+                writeln!(w, "</pre>")?;
+            }
+        }
+
+        writeln!(
+            w,
+            "<h2>Rules</h2>\n<table id=\"rules\"><thead><tr>\
+             <th>rule</th><th>tasks</th><th>total</th><th>mean</th><th>max</th>\
+             </tr></thead><tbody>"
+        )?;
+        for (rule, durations) in self.by_rule(graph) {
+            let sum: Duration = durations.iter().sum();
+            let max = durations.iter().max().copied().unwrap_or_default();
+            write!(w, "<tr><td>")?;
+            write_escaped(w, rule)?;
+            writeln!(
+                w,
+                "</td><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td></tr>",
+                durations.len(),
+                sum.as_secs_f64(),
+                sum.as_secs_f64() / durations.len() as f64,
+                max.as_secs_f64()
+            )?;
+        }
+        writeln!(w, "</tbody></table>\n<script>{}</script>", SCRIPT)?;
+        writeln!(w, "</body></html>")
+    }
+
+    fn describe(&self, graph: &Graph, i: usize) -> String {
+        let task = &self.tasks[i];
+        format!(
+            "{} ({})",
+            crate::progress::build_message(&graph.builds[task.id]),
+            seconds(task.duration())
+        )
+    }
+
+    /// The durations of tasks, grouped by the rule they used.
+    fn by_rule<'a>(&self, graph: &'a Graph) -> BTreeMap<&'a str, Vec<Duration>> {
+        let mut rules: BTreeMap<&str, Vec<Duration>> = BTreeMap::new();
+        for task in &self.tasks {
+            rules
+                .entry(graph.builds[task.id].rule.as_str())
+                .or_default()
+                .push(task.duration());
+        }
+        rules
+    }
+}
+
+fn seconds(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+fn percent(d: Duration, total: Duration) -> f64 {
+    d.as_secs_f64() * 100.0 / total.as_secs_f64()
+}
+
+fn write_escaped(w: &mut dyn Write, s: &str) -> std::io::Result<()> {
+    // The page is UTF-8, which paths needn't be.
+    for c in String::from_utf8_lossy(s.as_bytes()).chars() {
+        match c {
+            '&' => write!(w, "&amp;")?,
+            '<' => write!(w, "&lt;")?,
+            '>' => write!(w, "&gt;")?,
+            '"' => write!(w, "&quot;")?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    Ok(())
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+.timeline { border: 1px solid #ccc; }
+.lane { position: relative; height: 1.2em; border-bottom: 1px solid #eee; }
+.task { position: absolute; top: 0.1em; height: 1em; min-width: 1px;
+        background: #8ab; box-sizing: border-box; border-right: 1px solid #fff; }
+.task.critical { background: #e80; }
+.task.failed { background: #c22; }
+pre { background: #f4f4f4; padding: 0.5em; overflow-x: auto; }
+table { border-collapse: collapse; }
+th, td { padding: 0.2em 0.8em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+th { cursor: pointer; border-bottom: 1px solid #ccc; }
+";
+
+/// Sorts the rules table by a column when its header is clicked.
+const SCRIPT: &str = "
+document.querySelectorAll('#rules th').forEach((th, col) => {
+  th.addEventListener('click', () => {
+    const body = document.querySelector('#rules tbody');
+    const rows = Array.from(body.rows);
+    const desc = th.dataset.order !== 'desc';
+    th.dataset.order = desc ? 'desc' : 'asc';
+    const key = (row) => {
+      const text = row.cells[col].textContent;
+      return col === 0 ? text : parseFloat(text);
+    };
+    rows.sort((a, b) => {
+      const [x, y] = [key(a), key(b)];
+      const cmp = x < y ? -1 : x > y ? 1 : 0;
+      return desc ? -cmp : cmp;
+    });
+    rows.forEach((row) => body.appendChild(row));
+  });
+});
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        let mut out = Vec::new();
+        write_escaped(&mut out, "a<b> & \"c\"").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a&lt;b&gt; &amp; &quot;c&quot;"
+        );
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate_output(b"short"), b"short");
+        let out = truncate_output(&vec![b'x'; MAX_OUTPUT + 10]);
+        assert!(out.ends_with(b"x\n[n2: 10 more bytes of output omitted]\n"));
+    }
+
+    #[test]
+    fn critical_path() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc
+build a: cc
+build b: cc
+build ab: phony a b
+build c: cc ab
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let id = |name: &str| {
+            let file = graph.files.lookup(name).unwrap();
+            graph.file(file).input.unwrap()
+        };
+        let secs = Duration::from_secs;
+        let task = |name: &str, start: u64, end: u64| TaskRecord {
+            id: id(name),
+            tid: 0,
+            start: secs(start),
+            end: secs(end),
+            failed: false,
+            output: Vec::new(),
+        };
+        let report = Report {
+            start: Instant::now(),
+            tasks: vec![task("a", 0, 1), task("b", 0, 3), task("c", 3, 4)],
+        };
+        // c waits on b through the phony build.
+        assert_eq!(report.critical_path(&graph), vec![1, 2]);
+        Ok(())
+    }
+}
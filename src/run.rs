@@ -1,71 +1,164 @@
 use crate::{
-    load,
-    progress::{DumbConsoleProgress, FancyConsoleProgress, Progress},
-    terminal, trace, work,
+    canon, check, compare, dbinfo, flamegraph, load, metrics, pick,
+    progress::{self, DumbConsoleProgress, FancyConsoleProgress, Progress},
+    query, serve, signal, task, terminal, trace, work,
 };
 use anyhow::anyhow;
-use std::path::Path;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
-fn build(
+/// What to report after a dry run, in which nothing is built.
+#[derive(Clone, Copy)]
+pub(crate) enum DryRun {
+    /// --dump-plan: the builds that would run.
+    Plan,
+    /// -t explain: whether each target is dirty.
+    Explain,
+}
+
+/// Whether to let the user choose what to build, with pick::pick.
+#[derive(Clone, Copy)]
+pub(crate) enum Pick {
+    Never,
+    /// --pick.
+    Always,
+    /// Only if no targets were given and the build files have no defaults,
+    /// as when the command line runs on a terminal.
+    IfNoDefault,
+}
+
+/// With --watchman or --usn-journal, or under --serve, trust the recorded
+/// states of the files that haven't changed since the last build, kept next
+/// to the db.
+fn trust_unchanged(work: &mut work::Work, options: &work::Options, db_path: &Path) {
+    if options.watchman {
+        work.use_watchman(db_path.with_file_name(".n2_watchman"));
+    }
+    if options.usn_journal {
+        // Elsewhere run_impl refuses it.
+        #[cfg(windows)]
+        work.use_usn_journal(db_path.with_file_name(".n2_usn"));
+    }
+    if options.inotify && !options.watchman {
+        // Elsewhere serve::run doesn't ask for it.
+        #[cfg(target_os = "linux")]
+        work.use_inotify(db_path.with_file_name(".n2_inotify"));
+    }
+}
+
+pub(crate) fn build(
     options: work::Options,
-    build_filename: String,
-    targets: Vec<String>,
-    verbose: bool,
+    build_filenames: Vec<String>,
+    targets: Vec<OsString>,
+    progress: &dyn Progress,
+    load_options: &load::Options,
+    dry_run: Option<DryRun>,
+    pick: Pick,
 ) -> anyhow::Result<Option<usize>> {
-    let (dumb_console, fancy_console);
-    let progress: &dyn Progress = if terminal::use_fancy() {
-        fancy_console = FancyConsoleProgress::new(verbose);
-        &fancy_console
-    } else {
-        dumb_console = DumbConsoleProgress::new(verbose);
-        &dumb_console
-    };
-
-    let mut state = trace::scope("load::read", || load::read(&build_filename))?;
+    let start = std::time::Instant::now();
+    let mut state = trace::scope("load::read", || load::read(&build_filenames, load_options))?;
+    for warning in &state.warnings {
+        progress.log(warning);
+    }
+    trace::place(&state.builddir).map_err(|err| anyhow!("write trace: {}", err))?;
     let mut work = work::Work::new(
         state.graph,
         state.hashes,
+        state.durations,
         state.db,
         &options,
         progress,
         state.pools,
     );
+    work.trust_stats(state.prestat);
+    trust_unchanged(&mut work, &options, &state.db_path);
 
     let mut tasks_finished = 0;
 
-    // Attempt to rebuild build.ninja.
-    let build_file_target = work.lookup(&build_filename);
+    // Attempt to rebuild build.ninja.  Only the first build file is regenerated,
+    // as any others are overlays on top of it.  A manifest read from stdin
+    // can't be regenerated, as there'd be no way to read it again.
+    let build_filename = &build_filenames[0];
+    let build_file_target = if build_filename == "-" {
+        None
+    } else {
+        work.lookup(build_filename)
+    };
     if let Some(target) = build_file_target {
         work.want_file(target)?;
         match trace::scope("work.run", || work.run())? {
             None => return Ok(None),
             Some(0) => {
                 // build.ninja already up to date.
-                // TODO: this logic is not right in the case where a build has
-                // a step that doesn't touch build.ninja.  We should instead
-                // verify the specific FileId was updated.
+            }
+            Some(n) if !load::sources_changed(&state.sources) => {
+                // Ran something, but the build files read back the same, as
+                // when the generator only touched them or only other files
+                // were out of date; carry on with what's loaded.
+                tasks_finished = n;
             }
             Some(n) => {
                 // Regenerated build.ninja; start over.
                 tasks_finished = n;
-                state = trace::scope("load::read", || load::read(&build_filename))?;
+                let prev_db = work.into_db();
+                state = trace::scope("load::read", || load::read(&build_filenames, load_options))?;
+                state.db.continue_run(prev_db);
+                for warning in &state.warnings {
+                    progress.log(warning);
+                }
                 work = work::Work::new(
                     state.graph,
                     state.hashes,
+                    state.durations,
                     state.db,
                     &options,
                     progress,
                     state.pools,
                 );
+                work.trust_stats(state.prestat);
+                trust_unchanged(&mut work, &options, &state.db_path);
             }
         }
     }
 
-    if !targets.is_empty() {
-        for name in &targets {
-            let target = work
-                .lookup(name)
-                .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+    let pick = match pick {
+        Pick::Never => false,
+        Pick::Always => true,
+        Pick::IfNoDefault => targets.is_empty() && state.default.is_empty(),
+    };
+    let targets = if pick {
+        let names = work.target_names();
+        let mut picked = Ok(None);
+        progress.interact(&mut || picked = pick::pick(&names));
+        match picked? {
+            Some(picked) => picked.into_iter().map(OsString::from).collect(),
+            None => anyhow::bail!("nothing picked"),
+        }
+    } else {
+        targets
+    };
+
+    // The targets asked for, with a `dir/...` standing for every file that's
+    // built under dir, as in Bazel.
+    let mut wanted = Vec::new();
+    for name in &targets {
+        match name
+            .to_str()
+            .and_then(|name| name.strip_suffix("..."))
+            .filter(|dir| dir.is_empty() || dir.ends_with('/'))
+        {
+            Some(dir) => {
+                let found = work.lookup_under(dir);
+                if found.is_empty() {
+                    anyhow::bail!("no targets under {:?}", dir);
+                }
+                wanted.extend(found);
+            }
+            None => wanted.push(work.lookup_target(name)?),
+        }
+    }
+    if !wanted.is_empty() {
+        for &target in &wanted {
             if Some(target) == build_file_target {
                 // Already built above.
                 continue;
@@ -80,11 +173,69 @@ fn build(
         work.want_every_file(build_file_target)?;
     }
 
+    if !options.touched.is_empty() {
+        work.restrict_to_affected(&options.touched);
+    }
+    if options.failed {
+        work.restrict_to_failed();
+    }
     let tasks = trace::scope("work.run", || work.run())?;
+    if let Some(path) = &options.report {
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(path).map_err(|err| anyhow!("create {}: {}", path, err))?,
+        );
+        work.write_report(&mut file)?;
+    }
+    // A dry run builds nothing, so has nothing to report.
+    if let Some(sink) = options.metrics.as_ref().filter(|_| !options.dry_run) {
+        let mut stats = work.stats().clone();
+        stats.duration = start.elapsed();
+        // Failing to report on the build doesn't fail the build.
+        if let Err(err) = sink.push(&stats) {
+            progress.log(&format!("n2: warn: push metrics: {}", err));
+        }
+    }
+    match dry_run {
+        Some(DryRun::Plan) => work.write_plan(&mut std::io::stdout().lock())?,
+        Some(DryRun::Explain) => {
+            for &target in &wanted {
+                let state = if work.would_update(target) {
+                    "dirty"
+                } else {
+                    "up to date"
+                };
+                progress.log(&format!("n2: {} is {}", work.file_name(target), state));
+            }
+        }
+        None => {}
+    }
     // Include any tasks from initial build in final count of steps.
     Ok(tasks.map(|n| n + tasks_finished))
 }
 
+/// A random (version 4) UUID to identify this run of n2.
+pub(crate) fn new_build_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let random = || {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        hasher.write_u32(std::process::id());
+        hasher.finish()
+    };
+    let hi = (random() & !0xf000) | 0x4000;
+    let lo = (random() & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
 fn default_parallelism() -> anyhow::Result<usize> {
     // Ninja uses available processors + a constant, but I don't think the
     // difference matters too much.
@@ -92,16 +243,51 @@ fn default_parallelism() -> anyhow::Result<usize> {
     Ok(usize::from(par))
 }
 
+/// The machine's physical memory, as the default budget for builds' `mem`.
+#[cfg(unix)]
+fn default_mem_limit() -> Option<u64> {
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if pages <= 0 || page_size <= 0 {
+        return None;
+    }
+    Some(pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn default_mem_limit() -> Option<u64> {
+    None
+}
+
+/// For `-C auto`, the nearest directory that has the build file in it, from
+/// the current one up, as git finds .git.
+fn find_build_dir(build_file: &str) -> anyhow::Result<PathBuf> {
+    if build_file == "-" {
+        anyhow::bail!("-C auto needs a build file to look for, not stdin");
+    }
+    let cwd = std::env::current_dir()?;
+    cwd.ancestors()
+        .find(|dir| dir.join(build_file).is_file())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| anyhow!("no {} here or in any directory above", build_file))
+}
+
 #[derive(argh::FromArgs)] // this struct generates the flags and --help output
 /// n2, a ninja compatible build system
 struct Args {
-    /// chdir before running
+    /// chdir before running; auto for the nearest directory, from this one
+    /// up, that has the build file in it
     #[argh(option, short = 'C')]
     chdir: Option<String>,
 
-    /// input build file [default=build.ninja]
-    #[argh(option, short = 'f', default = "(\"build.ninja\".into())")]
-    build_file: String,
+    /// input build file, or - for stdin; may be repeated to load more files
+    /// on top of the first [default=build.ninja]
+    #[argh(option, short = 'f')]
+    build_file: Vec<String>,
 
     /// debugging tools
     #[argh(option, short = 'd')]
@@ -111,14 +297,130 @@ struct Args {
     #[argh(option, short = 't')]
     tool: Option<String>,
 
+    /// output format of -t targets, query, commands and deps: text or json
+    /// [default=text]
+    #[argh(option, default = "String::from(\"text\")")]
+    format: String,
+
+    /// adjust warnings
+    #[argh(option, short = 'w')]
+    warning: Vec<String>,
+
     /// parallelism [default uses system thread count]
     #[argh(option, short = 'j')] // tododefault_parallelism()")]
     parallelism: Option<usize>,
 
+    /// memory available to builds that declare `mem`, like 16G
+    /// [default uses physical memory]
+    #[argh(option)]
+    mem_limit: Option<String>,
+
+    /// output kept per task, beyond which only its head and tail are shown
+    /// (0 means unlimited) [default=1M]
+    #[argh(option, default = "String::from(\"1M\")")]
+    output_limit: String,
+
+    /// hide task output lines matching this regex
+    #[argh(option)]
+    filter_output: Option<String>,
+
+    /// show paths in file:line references in task output as absolute paths
+    /// ("absolute") or relative to this directory, e.g. the source root
+    #[argh(option)]
+    rewrite_paths: Option<String>,
+
+    /// on a terminal, make paths in file:line references in task output
+    /// links to the files
+    #[argh(switch)]
+    hyperlinks: bool,
+
+    /// on a terminal, make paths in file:line references in task output
+    /// links to this URL rather than to the files, with {{path}}, {{abspath}} and {{line}} replaced
+    #[argh(option)]
+    hyperlink_url: Option<String>,
+
+    /// start builds in a reproducible order, one at a time unless -j is given
+    #[argh(switch)]
+    deterministic: bool,
+
+    /// take turns between the requested targets in starting builds, so all
+    /// make progress at once
+    #[argh(switch)]
+    fair: bool,
+
+    /// load the build files and report what's wrong with them, such as
+    /// dependency cycles or missing inputs, without building anything
+    #[argh(switch)]
+    check: bool,
+
+    /// print the builds that would run as JSON, without running them
+    #[argh(switch)]
+    dump_plan: bool,
+
+    /// choose the targets to build interactively; this is the default on a
+    /// terminal when there are no targets given or defaults
+    #[argh(switch)]
+    pick: bool,
+
+    /// only build what this file affects; may be repeated
+    #[argh(option)]
+    touched: Vec<String>,
+
+    /// only build what failed last time, and what that needs built first
+    #[argh(switch)]
+    failed: bool,
+
+    /// push build metrics to statsd://HOST:PORT or a Prometheus pushgateway
+    /// at http://HOST:PORT[/PATH] when done
+    #[argh(option)]
+    metrics: Option<String>,
+
+    /// write an HTML report on the tasks run to this file
+    #[argh(option)]
+    report: Option<String>,
+
+    /// ask a running Watchman which files changed since the last build,
+    /// rather than statting every file
+    #[argh(switch)]
+    watchman: bool,
+
+    /// on Windows, ask the NTFS change journal which files changed, rather
+    /// than statting every file; needs administrator rights
+    #[argh(switch)]
+    usn_journal: bool,
+
+    /// record the state of each input of the builds that run, so that
+    /// -d explain can name the inputs that changed next time
+    #[argh(switch)]
+    input_digests: bool,
+
+    /// on Linux, run each command under strace and warn of the files it
+    /// reads that its build doesn't declare as inputs
+    #[argh(switch)]
+    audit: bool,
+
+    /// on Linux and macOS, keep commands from writing to the directories of
+    /// the sources, the inputs that no build produces
+    #[argh(switch)]
+    read_only_sources: bool,
+
+    /// label for pushed metrics, as KEY=VALUE; may be repeated
+    #[argh(option)]
+    metrics_label: Vec<String>,
+
     /// keep going until at least N failures (0 means infinity) [default=1]
     #[argh(option, short = 'k', default = "1")]
     keep_going: usize,
 
+    /// on the first failure, terminate the tasks still running and stop
+    #[argh(switch)]
+    fail_fast: bool,
+
+    /// when stopping tasks early, give them this long to exit on their own
+    /// before killing them [default=500]
+    #[argh(option)]
+    kill_grace_ms: Option<u64>,
+
     /// print version (required by cmake)
     #[argh(switch, hidden_help)]
     version: bool,
@@ -128,6 +430,52 @@ struct Args {
     #[argh(switch, short = 'x', hidden_help)]
     expand_rspfile: bool,
 
+    /// treat paths that differ only in case as the same file
+    #[argh(switch)]
+    case_insensitive: bool,
+
+    /// ignore `default` statements in subninja files
+    #[argh(switch)]
+    top_level_defaults: bool,
+
+    /// read .n2_db but don't write anything learned back to it
+    #[argh(switch)]
+    no_db_write: bool,
+
+    /// keep build state in this file rather than .n2_db in the builddir
+    #[argh(option)]
+    db_path: Option<String>,
+
+    /// judge symlinks by their own mtimes rather than their targets'
+    #[argh(switch)]
+    lstat: bool,
+
+    /// reset modification times in the future to the present
+    #[argh(switch)]
+    clamp_future_mtimes: bool,
+
+    /// run commands under this wrapper program (e.g. a compiler cache)
+    #[argh(option)]
+    wrap: Option<String>,
+
+    /// show a status line for each job slot, with the task running in it
+    #[argh(switch)]
+    slot_status: bool,
+
+    /// show the progress in the terminal's title, e.g. "[42%] n2: target"
+    #[argh(switch)]
+    title: bool,
+
+    /// redraw the status at most this often [default=50]; when output isn't
+    /// a terminal, print a status line at most this often [default=10000]
+    #[argh(option)]
+    status_refresh_ms: Option<u64>,
+
+    /// take requests to build as JSON-RPC on stdin, answering on stdout,
+    /// for editors and other tools to drive n2
+    #[argh(switch)]
+    serve: bool,
+
     /// print executed command lines
     #[argh(switch, short = 'v')]
     verbose: bool,
@@ -137,44 +485,243 @@ struct Args {
     targets: Vec<String>,
 }
 
+/// Like argh::from_env(), but taking targets that aren't UTF-8, such as the
+/// names of files in a tree with Latin-1 names, and returning the targets as
+/// OS strings.  argh only takes strs, so such arguments go to it as
+/// placeholders, which are swapped back among the targets; anywhere else
+/// they're refused.
+fn args_from_env() -> anyhow::Result<(Args, Vec<OsString>)> {
+    use argh::FromArgs;
+    let raw: Vec<OsString> = std::env::args_os().collect();
+    // Arguments can't hold a NUL, so placeholders starting with one can't be
+    // mistaken for them.
+    let strings: Vec<String> = raw
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| match arg.to_str() {
+            Some(arg) => arg.to_owned(),
+            None => format!("\0{}", i),
+        })
+        .collect();
+    let cmd = Path::new(&raw[0])
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("n2");
+    let strs: Vec<&str> = strings.iter().map(|s| s.as_str()).collect();
+    let args = Args::from_args(&[cmd], &strs[1..]).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    early_exit.output, cmd
+                );
+                1
+            }
+        })
+    });
+    let placeholder = |arg: &str| Some(arg.strip_prefix('\0')?.parse::<usize>().unwrap());
+    let targets: Vec<OsString> = args
+        .targets
+        .iter()
+        .map(|target| match placeholder(target) {
+            Some(i) => raw[i].clone(),
+            None => OsString::from(target),
+        })
+        .collect();
+    let swapped = args.targets.iter().filter_map(|t| placeholder(t)).count();
+    if let Some(i) = strings[1..]
+        .iter()
+        .filter_map(|s| placeholder(s))
+        .nth(swapped)
+    {
+        anyhow::bail!(
+            "argument {:?} isn't UTF-8, as all but targets must be",
+            raw[i]
+        );
+    }
+    Ok((args, targets))
+}
+
 fn run_impl() -> anyhow::Result<i32> {
-    let mut fake_ninja_compat = Path::new(&std::env::args().next().unwrap())
+    let mut fake_ninja_compat = Path::new(&std::env::args_os().next().unwrap())
         .file_name()
         .unwrap()
         == std::ffi::OsStr::new(&format!("ninja{}", std::env::consts::EXE_SUFFIX));
 
-    let args: Args = argh::from_env();
+    let (args, targets) = args_from_env()?;
 
     let mut options = work::Options {
         parallelism: match args.parallelism {
             Some(p) => p,
+            None if args.deterministic => 1,
             None => default_parallelism()?,
         },
         failures_left: Some(args.keep_going).filter(|&n| n > 0),
+        embedded: false,
+        no_stdin: args.serve,
         explain: false,
+        explain_json: false,
         adopt: false,
+        follow_symlinks: !args.lstat,
+        clamp_future: args.clamp_future_mtimes,
+        wrapper: args.wrap,
+        mem_limit: match &args.mem_limit {
+            Some(size) => Some(
+                load::parse_size(size).ok_or_else(|| anyhow!("invalid --mem-limit {:?}", size))?,
+            ),
+            None => default_mem_limit(),
+        },
+        output_limit: match load::parse_size(&args.output_limit) {
+            Some(0) => None,
+            Some(size) => Some(size as usize),
+            None => anyhow::bail!("invalid --output-limit {:?}", args.output_limit),
+        },
+        filter_output: match &args.filter_output {
+            Some(filter) => Some(
+                regex::bytes::Regex::new(filter)
+                    .map_err(|err| anyhow!("invalid --filter-output: {}", err))?,
+            ),
+            None => None,
+        },
+        rewrite_paths: match args.rewrite_paths.as_deref() {
+            None => None,
+            Some("absolute") => Some(task::PathStyle::Absolute),
+            // Relative to where n2 was started, not to any -C.
+            Some(dir) => {
+                let dir = std::env::current_dir()?.join(dir);
+                Some(task::PathStyle::RelativeTo(PathBuf::from(
+                    canon::canon_path(
+                        dir.to_str()
+                            .ok_or_else(|| anyhow!("invalid --rewrite-paths {:?}", dir))?,
+                    ),
+                )))
+            }
+        },
+        // Other than on a terminal, the escape sequences would just be noise.
+        hyperlinks: match args.hyperlink_url {
+            _ if !terminal::use_fancy() => None,
+            Some(url) => Some(url),
+            None if args.hyperlinks => Some(String::from("file://{abspath}")),
+            None => None,
+        },
+        deterministic: args.deterministic,
+        fair: args.fair,
+        dry_run: args.dump_plan,
+        touched: args.touched,
+        failed: args.failed,
+        metrics: match &args.metrics {
+            Some(url) => Some(metrics::Sink::parse(url, &args.metrics_label)?),
+            None => None,
+        },
+        report: args.report,
+        watchman: args.watchman,
+        usn_journal: args.usn_journal,
+        inotify: false,
+        input_digests: args.input_digests,
+        audit: args.audit,
+        read_only_sources: args.read_only_sources,
+        fail_fast: args.fail_fast,
+        slot_status: args.slot_status,
+        status_refresh: args.status_refresh_ms.map(std::time::Duration::from_millis),
     };
+    if args.usn_journal && (args.watchman || !cfg!(windows)) {
+        anyhow::bail!("--usn-journal is only for Windows, and not with --watchman");
+    }
+    if args.audit && !cfg!(target_os = "linux") {
+        anyhow::bail!("--audit is only for Linux");
+    }
+    if args.read_only_sources && !cfg!(any(target_os = "linux", target_os = "macos")) {
+        anyhow::bail!("--read-only-sources is only for Linux and macOS");
+    }
+    if args.fail_fast && args.keep_going != 1 {
+        anyhow::bail!("--fail-fast and -k don't go together");
+    }
+    if let Some(ms) = args.kill_grace_ms {
+        crate::process::set_terminate_grace(std::time::Duration::from_millis(ms));
+    }
 
     if let Some(dir) = args.chdir {
-        let dir = Path::new(&dir);
-        std::env::set_current_dir(dir).map_err(|err| anyhow!("chdir {:?}: {}", dir, err))?;
+        let dir = if dir == "auto" {
+            let build_file = args
+                .build_file
+                .first()
+                .map_or("build.ninja", String::as_str);
+            let found = find_build_dir(build_file)?;
+            if found != std::env::current_dir()? {
+                println!("n2: Entering directory `{}'", found.display());
+            }
+            found
+        } else {
+            PathBuf::from(dir)
+        };
+        std::env::set_current_dir(canon::long_path(&dir))
+            .map_err(|err| anyhow!("chdir {:?}: {}", dir, err))?;
     }
 
     if let Some(debug) = args.debug {
         match debug.as_str() {
             "ninja_compat" => fake_ninja_compat = true,
             "explain" => options.explain = true,
+            "explain=json" => {
+                options.explain = true;
+                options.explain_json = true;
+            }
             "list" => {
                 println!("debug tools:");
                 println!("  explain  print why each target is considered out of date");
+                println!("  explain=json  the same, as a JSON object per line");
                 println!("  trace    generate json performance trace");
+                println!("  trace=PATH  the same, written to PATH rather than the builddir");
                 return Ok(1);
             }
-            "trace" => trace::open("trace.json")?,
+            "trace" => trace::open("trace.json"),
+            debug if debug.starts_with("trace=") => {
+                let path = Path::new(&debug["trace=".len()..]);
+                trace::create(path)
+                    .map_err(|err| anyhow!("write trace {}: {}", path.display(), err))?;
+            }
             _ => anyhow::bail!("unknown -d {:?}, use -d list to list", debug),
         }
     }
 
+    // Commands inherit this, so that whatever they record can be tied back
+    // to this run of n2, as can the trace.
+    let build_id = new_build_id();
+    std::env::set_var("N2_BUILD_ID", &build_id);
+    trace::if_enabled(|t| {
+        t.write_process_name(&format!("n2 {}", build_id));
+        let argv: Vec<String> = std::env::args_os()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        t.write_build_info(&argv, options.parallelism);
+    });
+
+    let mut load_options = load::Options {
+        fold_case: args.case_insensitive,
+        dupbuild_warn: false,
+        top_level_defaults: args.top_level_defaults,
+        no_db_write: args.no_db_write,
+        db_path: args.db_path.map(Into::into),
+        lstat: args.lstat,
+        files: None,
+    };
+    for warning in args.warning {
+        match warning.as_str() {
+            "list" => {
+                println!("warning flags:");
+                println!("  dupbuild={{err,warn}}  multiple build lines for one target");
+                return Ok(1);
+            }
+            "dupbuild=err" => load_options.dupbuild_warn = false,
+            "dupbuild=warn" => load_options.dupbuild_warn = true,
+            _ => anyhow::bail!("unknown -w {:?}, use -w list to list", warning),
+        }
+    }
+
     if args.version {
         if fake_ninja_compat {
             // CMake requires a particular Ninja version.
@@ -186,17 +733,88 @@ fn run_impl() -> anyhow::Result<i32> {
         return Ok(0);
     }
 
+    if args.pick && !terminal::is_interactive() {
+        anyhow::bail!("--pick needs a terminal");
+    }
+
+    let build_filenames = if args.build_file.is_empty() {
+        vec!["build.ninja".to_string()]
+    } else {
+        args.build_file
+    };
+
+    let mut dry_run = if args.dump_plan {
+        Some(DryRun::Plan)
+    } else {
+        None
+    };
     if let Some(tool) = args.tool {
         match tool.as_str() {
             "list" => {
                 println!("subcommands:");
-                println!("  (none yet, but see README if you're looking here trying to get CMake to work)");
+                println!(
+                    "  explain  print why the given targets are or aren't dirty, without building"
+                );
+                println!(
+                    "  dbinfo   print statistics about the db, and its records of any targets"
+                );
+                println!(
+                    "  compare  print what the last build ran that the one before didn't, and why"
+                );
+                println!(
+                    "  flamegraph  print recorded durations along dependency chains, for flamegraph.pl"
+                );
+                println!("  targets  print every file that a build produces, with its rule");
+                println!("  query    print the inputs, metadata and dependents of the given files");
+                println!("  commands print the commands that building the targets runs, in order");
+                println!("  deps     print the discovered dependencies recorded for the targets");
+                #[cfg(windows)]
+                println!("  wincodepage  print the encoding commands are run with");
                 return Ok(1);
             }
+            "explain" => {
+                if targets.is_empty() {
+                    anyhow::bail!("-t explain needs a target");
+                }
+                options.explain = true;
+                options.dry_run = true;
+                dry_run = Some(DryRun::Explain);
+            }
+            "dbinfo" => {
+                return dbinfo::run(&build_filenames, &load_options, &targets);
+            }
+            "compare" => {
+                return compare::run(&build_filenames, &load_options, &targets);
+            }
+            "flamegraph" => {
+                return flamegraph::run(&build_filenames, &load_options, &targets);
+            }
+            "targets" | "query" | "commands" | "deps" => {
+                let format = query::Format::parse(&args.format)?;
+                let tool = match tool.as_str() {
+                    "targets" => query::targets,
+                    "query" => query::query,
+                    "commands" => query::commands,
+                    _ => query::deps,
+                };
+                return tool(&build_filenames, &load_options, &targets, format);
+            }
             "compdb" if fake_ninja_compat => {
                 // meson wants to invoke this tool.
                 return Ok(0); // do nothing; TODO
             }
+            #[cfg(windows)]
+            "wincodepage" => {
+                // CMake asks this, as Ninja has it, to decide how to encode
+                // response files.
+                let encoding = if crate::process::ansi_is_utf8() {
+                    "UTF-8"
+                } else {
+                    "ANSI"
+                };
+                println!("Build file encoding: {}", encoding);
+                return Ok(0);
+            }
             "recompact" if fake_ninja_compat => {
                 // CMake unconditionally invokes this tool, yuck.
                 return Ok(0); // do nothing
@@ -213,11 +831,63 @@ fn run_impl() -> anyhow::Result<i32> {
         }
     }
 
-    match build(options, args.build_file, args.targets, args.verbose)? {
+    if args.check {
+        return check::run(&build_filenames, &load_options);
+    }
+
+    if args.serve {
+        return serve::run(options, &build_filenames, &load_options);
+    }
+
+    let title = if args.title {
+        Some(progress::title_suffix(&targets))
+    } else {
+        None
+    };
+    let (dumb_console, fancy_console);
+    let progress: &dyn Progress = if terminal::use_fancy() {
+        fancy_console = FancyConsoleProgress::new(
+            args.verbose,
+            options.parallelism,
+            options.slot_status,
+            options.status_refresh,
+            title,
+        );
+        &fancy_console
+    } else {
+        dumb_console = DumbConsoleProgress::new(args.verbose, options.status_refresh);
+        &dumb_console
+    };
+    // With nothing in particular to build, let the user choose if we can ask.
+    // Only here, as hosts embedding n2 may be on a terminal of their own.
+    let pick = if args.pick {
+        Pick::Always
+    } else if targets.is_empty() && dry_run.is_none() && terminal::is_interactive() {
+        Pick::IfNoDefault
+    } else {
+        Pick::Never
+    };
+    match build(
+        options,
+        build_filenames,
+        targets,
+        progress,
+        &load_options,
+        dry_run,
+        pick,
+    )? {
+        None if signal::was_interrupted() => {
+            // Results of the tasks that did finish were still recorded.
+            println!("n2: interrupted");
+            return Ok(130);
+        }
         None => {
             // Don't print any summary, the failing task is enough info.
             return Ok(1);
         }
+        Some(_) if dry_run.is_some() => {
+            // Nothing was built, so there's nothing to summarize.
+        }
         Some(0) => {
             // Special case: don't print numbers when no work done.
             println!("n2: no work to do");
@@ -236,6 +906,8 @@ fn run_impl() -> anyhow::Result<i32> {
 
 pub fn run() -> anyhow::Result<i32> {
     let res = run_impl();
-    trace::close();
-    res
+    let closed = trace::close();
+    let code = res?;
+    closed.map_err(|err| anyhow!("write trace: {}", err))?;
+    Ok(code)
 }
@@ -94,42 +94,69 @@ impl<'a> Scanner<'a> {
         })
     }
 
+    /// Maps a byte offset in the buffer to a 1-based (line, column) pair,
+    /// where the column counts characters rather than bytes.
+    pub fn line_col(&self, ofs: usize) -> (usize, usize) {
+        let before = &self.buf[..ofs];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let line_start = before
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |i| i + 1);
+        let col = String::from_utf8_lossy(&before[line_start..])
+            .chars()
+            .count()
+            + 1;
+        (line, col)
+    }
+
     pub fn format_parse_error(&self, filename: &Path, err: ParseError) -> String {
-        let mut ofs = 0;
-        let lines = self.buf.split(|&c| c == b'\n');
-        for (line_number, line) in lines.enumerate() {
-            if ofs + line.len() >= err.ofs {
-                let mut msg = "parse error: ".to_string();
-                msg.push_str(&err.msg);
-                msg.push('\n');
-
-                let prefix = format!("{}:{}: ", filename.display(), line_number + 1);
-                msg.push_str(&prefix);
-
-                let mut context = unsafe { std::str::from_utf8_unchecked(line) };
-                let mut col = err.ofs - ofs;
-                if col > 40 {
-                    // Trim beginning of line to fit it on screen.
-                    msg.push_str("...");
-                    context = &context[col - 20..];
-                    col = 3 + 20;
-                }
-                if context.len() > 40 {
-                    context = &context[0..40];
-                    msg.push_str(context);
-                    msg.push_str("...");
-                } else {
-                    msg.push_str(context);
-                }
-                msg.push('\n');
-
-                msg.push_str(&" ".repeat(prefix.len() + col));
-                msg.push_str("^\n");
-                return msg;
-            }
-            ofs += line.len() + 1;
+        let (line, col) = self.line_col(err.ofs);
+        let start = self.buf[..err.ofs]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |i| i + 1);
+        let end = self.buf[err.ofs..]
+            .iter()
+            .position(|&c| c == b'\n' || c == b'\0')
+            .map_or(self.buf.len(), |i| err.ofs + i);
+        let text = String::from_utf8_lossy(&self.buf[start..end]);
+        let chars: Vec<char> = text.trim_end_matches('\r').chars().collect();
+
+        let mut msg = "parse error: ".to_string();
+        msg.push_str(&err.msg);
+        msg.push('\n');
+
+        let prefix = format!("{}:{}:{}: ", filename.display(), line, col);
+        msg.push_str(&prefix);
+        // The caret is lined up with the excerpt using the same whitespace,
+        // so that tabs in the source don't throw it off.
+        let mut underline = " ".repeat(prefix.len());
+
+        let mut first = 0;
+        if col > 40 {
+            // Trim beginning of line to fit it on screen.
+            first = col - 1 - 20;
+            msg.push_str("...");
+            underline.push_str("   ");
+        }
+        let last = chars.len().min(first + 40);
+        for &c in &chars[first.min(last)..last] {
+            msg.push(c);
         }
-        panic!("invalid offset when formatting error")
+        if last < chars.len() {
+            msg.push_str("...");
+        }
+        msg.push('\n');
+
+        for &c in chars.iter().take(col - 1).skip(first) {
+            underline.push(if c == '\t' { '\t' } else { ' ' });
+        }
+        // An error at the end of the line points just past its text.
+        underline.push_str(&" ".repeat((col - 1).saturating_sub(chars.len().max(first))));
+        msg.push_str(&underline);
+        msg.push_str("^\n");
+        msg
     }
 }
 
@@ -140,7 +167,7 @@ pub fn read_file_with_nul(path: &Path) -> std::io::Result<Vec<u8>> {
     // causes us to allocate a buffer the size of the file, then grow it to push
     // the nul, copying the entire file(!).  So instead create a buffer of the
     // right size up front.
-    let mut file = std::fs::File::open(path)?;
+    let mut file = std::fs::File::open(crate::canon::long_path(path))?;
     let size = file.metadata()?.len() as usize;
     let mut bytes = Vec::with_capacity(size + 1);
     unsafe {
@@ -150,3 +177,79 @@ pub fn read_file_with_nul(path: &Path) -> std::io::Result<Vec<u8>> {
     bytes.push(0);
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(text: &str, ofs: usize) -> String {
+        let mut buf = text.as_bytes().to_vec();
+        buf.push(0);
+        let scanner = Scanner::new(&buf);
+        let err = ParseError {
+            msg: "oops".to_string(),
+            ofs,
+        };
+        scanner.format_parse_error(Path::new("build.ninja"), err)
+    }
+
+    #[test]
+    fn line_col() {
+        let buf = b"ab\ncd\r\n\xc3\xa9f\0";
+        let scanner = Scanner::new(buf);
+        assert_eq!(scanner.line_col(0), (1, 1));
+        assert_eq!(scanner.line_col(1), (1, 2));
+        assert_eq!(scanner.line_col(3), (2, 1));
+        assert_eq!(scanner.line_col(7), (3, 1));
+        // Columns count characters, not bytes.
+        assert_eq!(scanner.line_col(9), (3, 2));
+    }
+
+    #[test]
+    fn excerpt() {
+        assert_eq!(
+            format("x = 1\nbuild a: b\n", 13),
+            "parse error: oops\n\
+             build.ninja:2:8: build a: b\n\
+             \x20                       ^\n"
+        );
+    }
+
+    #[test]
+    fn excerpt_tabs() {
+        assert_eq!(
+            format("\tfoo bar\n", 5),
+            "parse error: oops\n\
+             build.ninja:1:6: \tfoo bar\n\
+             \x20                \t    ^\n"
+        );
+    }
+
+    #[test]
+    fn excerpt_end_of_line() {
+        assert_eq!(
+            format("abc\r\n", 3),
+            "parse error: oops\n\
+             build.ninja:1:4: abc\n\
+             \x20                   ^\n"
+        );
+    }
+
+    #[test]
+    fn excerpt_long_line() {
+        let line = format!("{}X{}", "a".repeat(50), "b".repeat(50));
+        let out = format(&line, 50);
+        let mut lines = out.lines().skip(1);
+        let text = lines.next().unwrap();
+        let caret = lines.next().unwrap();
+        assert_eq!(
+            text,
+            format!(
+                "build.ninja:1:51: ...{}X{}...",
+                "a".repeat(20),
+                "b".repeat(19)
+            )
+        );
+        assert_eq!(caret.find('^'), text.find('X'));
+    }
+}
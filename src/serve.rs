@@ -0,0 +1,349 @@
+//! `--serve`: take requests from another program, such as an editor plugin,
+//! as JSON-RPC 2.0 on stdin, one message per line, and answer them on stdout
+//! the same way.  The methods are:
+//!
+//! - `targets`: `{"targets": [...], "defaults": [...]}`, the files that builds
+//!   produce and the ones built by default.
+//! - `build`, with params `{"targets": [...]}` or none for the defaults:
+//!   build, sending `task_started` (with the build's `meta_NAME` bindings),
+//!   `task_output`, `task_finished` and `log` notifications along the way,
+//!   and answering `{"ok": true, "tasks": N}` or
+//!   `{"ok": false, "interrupted": bool}`.
+//! - `explain`, with the same params: `{"explain": [...]}`, why each build the
+//!   targets need is dirty, as the objects of `-d explain=json`, without
+//!   building anything.
+//! - `cancel`: interrupt the build in progress, if any, answering whether
+//!   there was one.
+//!
+//! Builds run one at a time, in the order they were asked for, and each reads
+//! the manifest afresh, so edits to it are picked up, and has an
+//! `N2_BUILD_ID` of its own.  On Linux, the files builds stat are watched
+//! with inotify, so that later builds only stat those that changed; see
+//! inotify.rs.  Commands don't get stdin, which carries the requests, even
+//! in the console pool.  n2 exits at the end of its input.
+
+use crate::{
+    densemap::Index,
+    graph::{Build, BuildId},
+    json::{self, Value},
+    load, plan,
+    process::Termination,
+    progress::{build_message, Progress},
+    run, signal,
+    task::TaskResult,
+    work::{self, Estimate, StateCounts},
+};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+// Error codes defined by JSON-RPC.
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// For a request that was understood, but failed, like a build of an unknown
+/// target.
+const REQUEST_FAILED: i32 = -32000;
+
+/// Write a message as a line of its own.  Messages come from both the thread
+/// building and the one reading requests, so each is written whole.
+fn send(msg: &[u8]) {
+    let mut out = std::io::stdout().lock();
+    // With nobody to read them, the answers don't matter.
+    let _ = out.write_all(msg);
+    let _ = out.write_all(b"\n");
+    let _ = out.flush();
+}
+
+/// Write a request's id back, which may be a number, a string or null.
+fn write_id(w: &mut Vec<u8>, id: &Value) {
+    match id {
+        Value::Number(n) if n.fract() == 0.0 => w.extend_from_slice(format!("{}", n).as_bytes()),
+        Value::String(s) => plan::write_string(w, s).unwrap(),
+        _ => w.extend_from_slice(b"null"),
+    }
+}
+
+fn respond(id: &Value, result: &[u8]) {
+    let mut w = Vec::new();
+    w.extend_from_slice(b"{\"jsonrpc\": \"2.0\", \"id\": ");
+    write_id(&mut w, id);
+    w.extend_from_slice(b", \"result\": ");
+    w.extend_from_slice(result);
+    w.extend_from_slice(b"}");
+    send(&w);
+}
+
+fn respond_error(id: &Value, code: i32, message: &str) {
+    let mut w = Vec::new();
+    w.extend_from_slice(b"{\"jsonrpc\": \"2.0\", \"id\": ");
+    write_id(&mut w, id);
+    w.extend_from_slice(format!(", \"error\": {{\"code\": {}, \"message\": ", code).as_bytes());
+    plan::write_string(&mut w, message).unwrap();
+    w.extend_from_slice(b"}}");
+    send(&w);
+}
+
+fn notify(method: &str, params: &[u8]) {
+    let mut w = Vec::new();
+    w.extend_from_slice(b"{\"jsonrpc\": \"2.0\", \"method\": ");
+    plan::write_string(&mut w, method).unwrap();
+    w.extend_from_slice(b", \"params\": ");
+    w.extend_from_slice(params);
+    w.extend_from_slice(b"}");
+    send(&w);
+}
+
+/// Sends the progress of a build as notifications.
+#[derive(Default)]
+struct ServeProgress {
+    /// For `explain`, the lines logged, each a JSON object, which are
+    /// collected for the answer rather than sent as they come.
+    explained: Option<RefCell<Vec<String>>>,
+}
+
+impl ServeProgress {
+    fn task_params(id: BuildId, build: &Build) -> Vec<u8> {
+        let mut w = Vec::new();
+        w.extend_from_slice(format!("{{\"id\": {}, \"description\": ", id.index()).as_bytes());
+        plan::write_string(&mut w, build_message(build)).unwrap();
+        w
+    }
+}
+
+impl Progress for ServeProgress {
+    fn update(&self, _counts: &StateCounts, _estimate: &Estimate) {}
+
+    fn task_started(&self, id: BuildId, build: &Build, _expected: Option<std::time::Duration>) {
+        let mut w = Self::task_params(id, build);
+        w.extend_from_slice(b", \"meta\": ");
+        plan::write_meta(&mut w, &build.meta).unwrap();
+        w.extend_from_slice(b"}");
+        notify("task_started", &w);
+    }
+
+    fn task_output(&self, id: BuildId, line: Vec<u8>) {
+        let mut w = Vec::new();
+        w.extend_from_slice(format!("{{\"id\": {}, \"line\": ", id.index()).as_bytes());
+        plan::write_string(&mut w, &String::from_utf8_lossy(&line)).unwrap();
+        w.extend_from_slice(b"}");
+        notify("task_output", &w);
+    }
+
+    fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
+        let mut w = Self::task_params(id, build);
+        w.extend_from_slice(
+            format!(
+                ", \"success\": {}, \"output\": ",
+                result.termination == Termination::Success
+            )
+            .as_bytes(),
+        );
+        plan::write_string(&mut w, &String::from_utf8_lossy(&result.output)).unwrap();
+        w.extend_from_slice(b"}");
+        notify("task_finished", &w);
+    }
+
+    fn log(&self, msg: &str) {
+        match &self.explained {
+            Some(explained) if msg.starts_with('{') => explained.borrow_mut().push(msg.to_owned()),
+            _ => {
+                let mut w = Vec::new();
+                w.extend_from_slice(b"{\"message\": ");
+                plan::write_string(&mut w, msg).unwrap();
+                w.extend_from_slice(b"}");
+                notify("log", &w);
+            }
+        }
+    }
+
+    fn interact(&self, f: &mut dyn FnMut()) {
+        f();
+    }
+}
+
+/// State shared with the thread reading requests, for cancelling builds.
+#[derive(Default)]
+struct Cancel {
+    /// Whether a build is in progress.
+    building: AtomicBool,
+    /// Held while cancelling, so that the interrupt isn't forgotten before
+    /// the build's tasks are all gone.
+    cancelling: Mutex<()>,
+}
+
+pub fn run(
+    mut options: work::Options,
+    build_filenames: &[String],
+    load_options: &load::Options,
+) -> anyhow::Result<i32> {
+    // Builds after the first needn't stat what hasn't changed since.
+    options.inotify = cfg!(target_os = "linux");
+    let cancel = Arc::new(Cancel::default());
+    let (tx, rx) = mpsc::channel();
+    {
+        let cancel = cancel.clone();
+        // Reads requests while a build runs, so that it can be cancelled.
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let request = match json::parse(&line) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        respond_error(&Value::Null, PARSE_ERROR, &err.to_string());
+                        continue;
+                    }
+                };
+                if request.get("method").and_then(Value::as_str) == Some("cancel") {
+                    let _cancelling = cancel.cancelling.lock().unwrap();
+                    let building = cancel.building.load(Ordering::SeqCst);
+                    if building {
+                        signal::interrupt();
+                    }
+                    if let Some(id) = request.get("id") {
+                        respond(id, building.to_string().as_bytes());
+                    }
+                    continue;
+                }
+                if tx.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    for request in rx {
+        // Without an id, it's a notification, which gets no answer.
+        let id = request.get("id");
+        let answer = handle(&request, &options, build_filenames, load_options, &cancel);
+        match (id, answer) {
+            (None, _) => {}
+            (Some(id), Ok(result)) => respond(id, &result),
+            (Some(id), Err((code, message))) => respond_error(id, code, &message),
+        }
+    }
+    Ok(0)
+}
+
+/// The targets given in a request's params.
+fn targets(request: &Value) -> Result<Vec<OsString>, (i32, String)> {
+    let invalid = || {
+        (
+            INVALID_PARAMS,
+            String::from("targets must be an array of strings"),
+        )
+    };
+    match request
+        .get("params")
+        .and_then(|params| params.get("targets"))
+    {
+        None => Ok(Vec::new()),
+        Some(targets) => targets
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|target| target.as_str().map(OsString::from).ok_or_else(invalid))
+            .collect(),
+    }
+}
+
+/// Carry out a request, returning the JSON of its result or an error code
+/// and message.
+fn handle(
+    request: &Value,
+    options: &work::Options,
+    build_filenames: &[String],
+    load_options: &load::Options,
+    cancel: &Cancel,
+) -> Result<Vec<u8>, (i32, String)> {
+    let failed = |err: anyhow::Error| (REQUEST_FAILED, err.to_string());
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .ok_or_else(|| (INVALID_REQUEST, String::from("no method")))?;
+    let mut w = Vec::new();
+    match method {
+        "targets" => {
+            let manifest = load::read_manifest(build_filenames, load_options).map_err(failed)?;
+            let graph = &manifest.graph;
+            let mut targets: Vec<_> = graph
+                .files
+                .all_ids()
+                .filter(|&id| graph.file(id).input.is_some())
+                .collect();
+            targets.sort_by(|&a, &b| graph.file(a).name.cmp(&graph.file(b).name));
+            w.extend_from_slice(b"{\"targets\": ");
+            plan::write_files(&mut w, graph, &targets).unwrap();
+            w.extend_from_slice(b", \"defaults\": ");
+            plan::write_files(&mut w, graph, &manifest.default).unwrap();
+            w.extend_from_slice(b"}");
+        }
+        "build" => {
+            let targets = targets(request)?;
+            let progress = ServeProgress::default();
+            // Each build is a run of its own to the commands; see run_impl.
+            std::env::set_var("N2_BUILD_ID", run::new_build_id());
+            cancel.building.store(true, Ordering::SeqCst);
+            let result = run::build(
+                options.clone(),
+                build_filenames.to_vec(),
+                targets,
+                &progress,
+                load_options,
+                None,
+                run::Pick::Never,
+            );
+            cancel.building.store(false, Ordering::SeqCst);
+            let interrupted = {
+                let _cancelling = cancel.cancelling.lock().unwrap();
+                let interrupted = signal::was_interrupted();
+                signal::clear_interrupted();
+                interrupted
+            };
+            match result.map_err(failed)? {
+                Some(tasks) => w.extend_from_slice(
+                    format!("{{\"ok\": true, \"tasks\": {}}}", tasks).as_bytes(),
+                ),
+                None => w.extend_from_slice(
+                    format!("{{\"ok\": false, \"interrupted\": {}}}", interrupted).as_bytes(),
+                ),
+            }
+        }
+        "explain" => {
+            let targets = targets(request)?;
+            let progress = ServeProgress {
+                explained: Some(RefCell::default()),
+            };
+            let mut options = options.clone();
+            options.explain = true;
+            options.explain_json = true;
+            options.dry_run = true;
+            // It records no file states, so would only leave the next build
+            // unable to trust those of the last.
+            options.inotify = false;
+            run::build(
+                options,
+                build_filenames.to_vec(),
+                targets,
+                &progress,
+                load_options,
+                None,
+                run::Pick::Never,
+            )
+            .map_err(failed)?;
+            let explained = progress.explained.unwrap().into_inner();
+            w.extend_from_slice(format!("{{\"explain\": [{}]}}", explained.join(", ")).as_bytes());
+        }
+        _ => return Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method))),
+    }
+    Ok(w)
+}
@@ -1,32 +1,124 @@
-//! Unix signal handling (SIGINT).
+//! Interrupt handling (SIGINT, or ctl-c on Windows).
 //!
-//! We let the first SIGINT reach child processes, which ought to build-fail
-//! and let the parent properly print that progress.  This also lets us still
-//! write out pending debug traces, too.
+//! An interrupted build stops starting new tasks, waits for the running ones
+//! and records their results before exiting.
+//!
+//! Subprocesses run in process groups of their own, so they don't see the
+//! SIGINT the terminal sends on ctl-c.  Instead we forward the first SIGINT to
+//! them, which ought to build-fail and let the parent properly print that
+//! progress.  This also lets us still write out pending debug traces, too.
+//! Anything still running a little while later, or after a second SIGINT, is
+//! terminated outright.  A third SIGINT is left to kill n2 itself.
 
 use std::sync::atomic::AtomicBool;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-static mut INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Write end of a pipe the signal handler uses to wake the forwarding thread,
+/// as a signal handler itself can't safely do much.
+#[cfg(unix)]
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(unix)]
+static SIGINT_COUNT: AtomicI32 = AtomicI32::new(0);
+
+/// How long subprocesses get to exit after a forwarded SIGINT before they are
+/// terminated.
+#[cfg(unix)]
+const INTERRUPT_GRACE: Duration = Duration::from_secs(2);
 
 #[cfg(unix)]
 extern "C" fn sigint_handler(_sig: libc::c_int) {
-    unsafe {
-        INTERRUPTED.store(true, std::sync::atomic::Ordering::Relaxed);
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    if SIGINT_COUNT.fetch_add(1, Ordering::Relaxed) >= 1 {
+        // Let the next SIGINT kill n2 as normal.
+        unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    }
+    let fd = WAKE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"!".as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+/// Start the thread that forwards SIGINTs to subprocesses.
+#[cfg(unix)]
+fn spawn_forwarder() {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        // Subprocesses just won't be interrupted; not worth failing over.
+        return;
     }
-    // SA_RESETHAND should clear the handler.
+    WAKE_FD.store(fds[1], Ordering::Relaxed);
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        if unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, 1) } != 1 {
+            return;
+        }
+        crate::process::signal_all(libc::SIGINT);
+        // Some processes ignore SIGINT, like background jobs of a shell.
+        let deadline = Instant::now() + INTERRUPT_GRACE;
+        while crate::process::any_running()
+            && Instant::now() < deadline
+            && SIGINT_COUNT.load(Ordering::Relaxed) < 2
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        crate::process::terminate_all();
+    });
 }
 
 #[cfg(unix)]
 pub fn register_sigint() {
+    static FORWARDER: std::sync::Once = std::sync::Once::new();
+    FORWARDER.call_once(spawn_forwarder);
+
     // Safety: registering a signal handler is libc unsafe code.
     unsafe {
         let mut sa: libc::sigaction = std::mem::zeroed();
         sa.sa_sigaction = sigint_handler as libc::sighandler_t;
-        sa.sa_flags = libc::SA_RESETHAND;
+        sa.sa_flags = libc::SA_RESTART;
         libc::sigaction(libc::SIGINT, &sa, std::ptr::null_mut());
     }
 }
 
+/// On Windows subprocesses share our console and so see the ctl-c themselves;
+/// we just take note of it.  A second ctl-c is left to kill n2.
+#[cfg(windows)]
+unsafe extern "system" fn ctrl_handler(ctrl_type: u32) -> windows_sys::Win32::Foundation::BOOL {
+    use windows_sys::Win32::System::Console::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+    if ctrl_type != CTRL_C_EVENT && ctrl_type != CTRL_BREAK_EVENT {
+        return 0;
+    }
+    !INTERRUPTED.swap(true, std::sync::atomic::Ordering::Relaxed) as _
+}
+
+#[cfg(windows)]
+pub fn register_sigint() {
+    unsafe {
+        windows_sys::Win32::System::Console::SetConsoleCtrlHandler(Some(ctrl_handler), 1);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn register_sigint() {}
+
+/// Interrupt the build as if by ctl-c, for a build cancelled some other way:
+/// no more tasks are started, and the running ones are terminated.  Returns
+/// once they have exited.
+pub fn interrupt() {
+    INTERRUPTED.store(true, std::sync::atomic::Ordering::Relaxed);
+    crate::process::terminate_all();
+}
+
+/// Forget about an interrupt, so that another build may run.
+pub fn clear_interrupted() {
+    INTERRUPTED.store(false, std::sync::atomic::Ordering::Relaxed);
+}
+
 pub fn was_interrupted() -> bool {
-    unsafe { INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed) }
+    INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed)
 }
@@ -9,14 +9,19 @@
 //! parsing of depfiles.
 
 use crate::{
+    audit, canon,
+    densemap::Index,
     depfile,
-    graph::{Build, BuildId, RspFile},
+    graph::{Build, BuildId, GraphFiles, RspFile},
     process,
     scanner::{self, Scanner},
+    work,
 };
 use anyhow::{anyhow, bail};
+use std::fs::File;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::time::Instant;
 
 pub struct FinishedTask {
@@ -34,10 +39,13 @@ pub struct TaskResult {
     /// Console output.
     pub output: Vec<u8>,
     pub discovered_deps: Option<Vec<String>>,
+    pub usage: process::ResourceUsage,
+    /// With --audit, the files the command read; see audit::parse_trace.
+    pub opened: Option<Vec<String>>,
 }
 
 /// Reads dependencies from a .d file path.
-fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
+pub fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
     let bytes = match scanner::read_file_with_nul(path) {
         Ok(b) => b,
         // See discussion of missing depfiles in #80.
@@ -60,9 +68,9 @@ fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
 
 fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
     if let Some(parent) = rspfile.path.parent() {
-        std::fs::create_dir_all(parent)?;
+        std::fs::create_dir_all(canon::long_path(parent))?;
     }
-    std::fs::write(&rspfile.path, &rspfile.content)?;
+    std::fs::write(canon::long_path(&rspfile.path), &rspfile.content)?;
     Ok(())
 }
 
@@ -91,6 +99,252 @@ fn extract_showincludes(output: Vec<u8>) -> (Vec<String>, Vec<u8>) {
     (includes, filtered_output)
 }
 
+/// Buffers a task's output.  Past a size limit only the head and tail of the
+/// output are kept, so a runaway tool can't exhaust our memory; the full
+/// output then goes to a log file instead.
+struct OutputBuffer {
+    /// None if unlimited.
+    limit: Option<usize>,
+    /// All of the output, until it exceeds the limit.
+    head: Vec<u8>,
+    /// Recent output, once over the limit.
+    tail: Vec<u8>,
+    total: usize,
+    log_path: PathBuf,
+    log: Option<File>,
+}
+
+impl OutputBuffer {
+    fn new(limit: Option<usize>, log_path: PathBuf) -> Self {
+        OutputBuffer {
+            limit,
+            head: Vec::new(),
+            tail: Vec::new(),
+            total: 0,
+            log_path,
+            log: None,
+        }
+    }
+
+    fn push(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.total += buf.len();
+        let limit = match self.limit {
+            Some(limit) if self.log.is_some() || self.total > limit => limit,
+            _ => {
+                self.head.extend_from_slice(buf);
+                return Ok(());
+            }
+        };
+        match &mut self.log {
+            None => {
+                self.head.extend_from_slice(buf);
+                let mut log = File::create(&self.log_path)?;
+                log.write_all(&self.head)?;
+                self.tail = self.head.split_off(limit / 2);
+                self.log = Some(log);
+            }
+            Some(log) => {
+                log.write_all(buf)?;
+                self.tail.extend_from_slice(buf);
+            }
+        }
+        // Trim only occasionally, to not move bytes around on every push.
+        if self.tail.len() > limit {
+            self.trim_tail(limit);
+        }
+        Ok(())
+    }
+
+    fn trim_tail(&mut self, limit: usize) {
+        let keep = limit - limit / 2;
+        if self.tail.len() > keep {
+            self.tail.drain(..self.tail.len() - keep);
+        }
+    }
+
+    /// The most recent output, for showing progress.
+    fn recent(&self) -> &[u8] {
+        if self.log.is_some() {
+            &self.tail
+        } else {
+            &self.head
+        }
+    }
+
+    fn finish(mut self) -> std::io::Result<Vec<u8>> {
+        let (limit, mut log) = match (self.limit, self.log.take()) {
+            (Some(limit), Some(log)) => (limit, log),
+            _ => return Ok(self.head),
+        };
+        log.flush()?;
+        self.trim_tail(limit);
+        let omitted = self.total - self.head.len() - self.tail.len();
+        let mut output = self.head;
+        if !output.ends_with(b"\n") {
+            output.push(b'\n');
+        }
+        writeln!(
+            output,
+            "[n2: {} bytes of output omitted, see {} for all of it]",
+            omitted,
+            self.log_path.display()
+        )?;
+        output.extend_from_slice(&self.tail);
+        Ok(output)
+    }
+}
+
+/// Remove a task's log, if it left one, as it does unless its output names
+/// the log.
+fn remove_log(path: &Path) {
+    // Usually there's none to remove.
+    let _ = std::fs::remove_file(path);
+}
+
+/// Drop the lines of output that match any of the filters, returning the rest
+/// and how many were dropped.
+fn filter_lines(output: &[u8], filters: &[regex::bytes::Regex]) -> (Vec<u8>, usize) {
+    let mut filtered = Vec::new();
+    let mut hidden = 0;
+    for line in output.split_inclusive(|&c| c == b'\n') {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        if filters.iter().any(|filter| filter.is_match(text)) {
+            hidden += 1;
+        } else {
+            filtered.extend_from_slice(line);
+        }
+    }
+    (filtered, hidden)
+}
+
+/// How --rewrite-paths shows the paths in tasks' output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathStyle {
+    Absolute,
+    /// Relative to this directory, which is absolute.
+    RelativeTo(PathBuf),
+}
+
+/// Rewrites the paths of `file:line` references in tasks' output, such as
+/// those in compiler errors, which are relative to the build dir, so that an
+/// editor started elsewhere can find the files, and/or makes them links.
+pub struct PathRewriter {
+    /// A path followed by `:LINE` or `(LINE`, as MSVC writes it.
+    reference: regex::bytes::Regex,
+    build_dir: PathBuf,
+    /// None leaves paths as they are.
+    style: Option<PathStyle>,
+    /// If set, the URL template paths are linked to; see work::Options.
+    link: Option<String>,
+}
+
+impl PathRewriter {
+    pub fn new(build_dir: PathBuf, style: Option<PathStyle>, link: Option<String>) -> Self {
+        PathRewriter {
+            reference: regex::bytes::Regex::new(
+                r#"(^|[\s"'`(\[<])([^\s:"'`()\[\]<>]+)(?::|\()(\d+)"#,
+            )
+            .unwrap(),
+            build_dir,
+            style,
+            link,
+        }
+    }
+
+    /// The rewriter for commands run in the current directory, the build
+    /// dir, if there's anything to rewrite.
+    pub fn for_current_dir(style: Option<PathStyle>, link: Option<String>) -> Option<Self> {
+        if style.is_none() && link.is_none() {
+            return None;
+        }
+        let build_dir = std::env::current_dir().ok()?;
+        Some(PathRewriter::new(build_dir, style, link))
+    }
+
+    /// Only paths that name an existing file are rewritten, to leave alone
+    /// anything else that happens to look like a reference, and absolute ones
+    /// are only made links.
+    fn rewrite(&self, output: &[u8]) -> Vec<u8> {
+        self.reference
+            .replace_all(output, |caps: &regex::bytes::Captures| {
+                let (whole, path) = (caps.get(0).unwrap(), caps.get(2).unwrap());
+                let line = std::str::from_utf8(&caps[3]).unwrap();
+                let rewritten = std::str::from_utf8(path.as_bytes())
+                    .ok()
+                    .and_then(|path| self.rewrite_path(path, line));
+                let mut replaced = caps[1].to_vec();
+                replaced.extend_from_slice(
+                    rewritten.as_ref().map_or(path.as_bytes(), |p| p.as_bytes()),
+                );
+                replaced.extend_from_slice(&output[path.end()..whole.end()]);
+                replaced
+            })
+            .into_owned()
+    }
+
+    fn rewrite_path(&self, path: &str, line: &str) -> Option<String> {
+        let absolute = Path::new(path).is_absolute();
+        if absolute && self.link.is_none() {
+            return None;
+        }
+        let full = self.build_dir.join(path);
+        if !full.exists() {
+            return None;
+        }
+        let full = canon::canon_path(full.to_str()?);
+        let shown = match &self.style {
+            _ if absolute => path.to_owned(),
+            None => path.to_owned(),
+            Some(PathStyle::Absolute) => full.clone(),
+            Some(PathStyle::RelativeTo(dir)) => {
+                relative_path(dir, Path::new(&full)).to_str()?.to_owned()
+            }
+        };
+        Some(match &self.link {
+            None => shown,
+            Some(template) => {
+                let url = template
+                    .replace("{abspath}", &url_escape(&full))
+                    .replace("{path}", &url_escape(&shown))
+                    .replace("{line}", line);
+                // OSC 8, as in https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda
+                format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, shown)
+            }
+        })
+    }
+}
+
+/// Percent-encode what a path may hold that can't be in the path of a URL,
+/// like spaces and `#`.
+fn url_escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/:@!$&'()*+,;=".contains(&b) {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+/// The path from the directory `from` to `to`, both absolute and without any
+/// `.` or `..`.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
 /// Find the span of the last line of text in buf, ignoring trailing empty
 /// lines.
 fn find_last_line(buf: &[u8]) -> &[u8] {
@@ -109,37 +363,146 @@ fn find_last_line(buf: &[u8]) -> &[u8] {
     &buf[start..end]
 }
 
+/// The parts of a Build needed to run its command, copied out so they can
+/// be sent to the task's thread.
+struct TaskCommand {
+    cmdline: String,
+    depfile: Option<PathBuf>,
+    /// With Build::atomic_outputs, where the command may write the depfile
+    /// instead, if it names it after an output; see Build::staged_path.
+    staged_depfile: Option<PathBuf>,
+    parse_showincludes: bool,
+    rspfile: Option<RspFile>,
+    shell: Option<Vec<String>>,
+    pty: bool,
+    /// See Build::no_network.
+    no_network: bool,
+    /// See Runner::read_only.
+    read_only: Option<Arc<Vec<String>>>,
+    /// Whether the command reads our stdin; see process::run_command.
+    stdin: bool,
+    env: process::Env,
+    /// Output lines matching any of these are hidden.
+    filters: Vec<regex::bytes::Regex>,
+    rewriter: Option<Arc<PathRewriter>>,
+    /// With --audit, where strace writes its trace of the command.
+    trace: Option<PathBuf>,
+}
+
 /// Executes a build task as a subprocess.
 /// Returns an Err() if we failed outside of the process itself.
 /// This is run as a separate thread from the main n2 process and will block
 /// on the subprocess, so any additional per-subprocess work we can do belongs
 /// here.
 fn run_task(
-    cmdline: &str,
-    depfile: Option<&Path>,
-    parse_showincludes: bool,
-    rspfile: Option<&RspFile>,
+    command: &TaskCommand,
+    mut output: OutputBuffer,
     mut last_line_cb: impl FnMut(&[u8]),
 ) -> anyhow::Result<TaskResult> {
-    if let Some(rspfile) = rspfile {
+    if let Some(rspfile) = &command.rspfile {
         write_rspfile(rspfile)?;
     }
 
-    let mut output = Vec::new();
-    let termination = process::run_command(cmdline, |buf| {
-        output.extend_from_slice(buf);
-        last_line_cb(find_last_line(&output));
-    })?;
+    let mut shell = command.shell.clone();
+    let mut cmdline = command.cmdline.clone();
+    // The command may run under other programs, each wrapping the last.
+    if let Some(trace) = &command.trace {
+        let (words, wrapped) = audit::wrap(shell.as_deref(), &cmdline, trace);
+        (shell, cmdline) = (Some(words), wrapped);
+    }
+    if command.no_network {
+        let (words, wrapped) = process::isolate_network(shell.as_deref(), &cmdline)?;
+        (shell, cmdline) = (Some(words), wrapped);
+    }
+    if let Some(prefix) = &command.read_only {
+        let (words, wrapped) = process::wrap_command(prefix, shell.as_deref(), &cmdline);
+        (shell, cmdline) = (Some(words), wrapped);
+    }
+
+    let mut log_err = None;
+    let (termination, usage) = process::run_command(
+        &cmdline,
+        shell.as_deref(),
+        command.pty,
+        command.stdin,
+        &command.env,
+        |buf| {
+            if let Err(err) = output.push(buf) {
+                log_err.get_or_insert(err);
+            }
+            last_line_cb(find_last_line(output.recent()));
+        },
+    )?;
+    if let Some(err) = log_err {
+        bail!("write {}: {}", output.log_path.display(), err);
+    }
+    let log_path = output.log_path.clone();
+    let spilled = output.log.is_some();
+    let mut output = output.finish()?;
+
+    let opened = match &command.trace {
+        Some(trace) => {
+            let read = std::fs::read(trace);
+            let _ = std::fs::remove_file(trace);
+            let read = read.map_err(|err| anyhow!("read {}: {}", trace.display(), err))?;
+            Some(audit::parse_trace(&String::from_utf8_lossy(&read)))
+        }
+        None => None,
+    };
 
     let mut discovered_deps = None;
-    if parse_showincludes {
+    if command.parse_showincludes {
         // Remove /showIncludes lines from output, regardless of success/fail.
         let (includes, filtered) = extract_showincludes(output);
         output = filtered;
         discovered_deps = Some(includes);
     }
+    // Whether the output names the log, which is otherwise removed.
+    let mut logged = spilled;
+    if !command.filters.is_empty() {
+        let (filtered, hidden) = filter_lines(&output, &command.filters);
+        // A failure may lie in what was hidden, so keep it all in a log,
+        // where the output didn't already go to one.
+        if hidden > 0 && termination != process::Termination::Success && !spilled {
+            std::fs::write(&log_path, &output)
+                .map_err(|err| anyhow!("write {}: {}", log_path.display(), err))?;
+            logged = true;
+            output = filtered;
+            if !output.is_empty() && !output.ends_with(b"\n") {
+                output.push(b'\n');
+            }
+            writeln!(
+                output,
+                "[n2: {} filtered lines hidden, see {} for all of the output]",
+                hidden,
+                log_path.display()
+            )?;
+        } else {
+            output = filtered;
+        }
+    }
+    if !logged {
+        // Also removes any log left by the build's last run.
+        remove_log(&log_path);
+    }
+    if let Some(rewriter) = &command.rewriter {
+        output = rewriter.rewrite(&output);
+    }
+    if let (Some(depfile), Some(staged)) = (&command.depfile, &command.staged_depfile) {
+        let result = if termination == process::Termination::Success {
+            std::fs::rename(staged, depfile)
+        } else {
+            std::fs::remove_file(staged)
+        };
+        match result {
+            Ok(()) => {}
+            // Written in place, or not at all.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => bail!("move {}: {}", staged.display(), err),
+        }
+    }
     if termination == process::Termination::Success {
-        if let Some(depfile) = depfile {
+        if let Some(depfile) = &command.depfile {
             discovered_deps = Some(read_depfile(depfile)?);
         }
     }
@@ -147,6 +510,8 @@ fn run_task(
         termination,
         output,
         discovered_deps,
+        usage,
+        opened,
     })
 }
 
@@ -182,23 +547,70 @@ enum Message {
     Done(FinishedTask),
 }
 
+/// The environment variables a build's command sets: its own, plus
+/// N2_EDGE_ID to identify the build within this run of n2, as in the trace.
+/// Commands also inherit N2_BUILD_ID from n2 itself.
+fn task_env(id: BuildId, build: &Build) -> Vec<(String, String)> {
+    let mut env = build.env.clone();
+    if !env.iter().any(|(name, _)| name == "N2_EDGE_ID") {
+        env.push(("N2_EDGE_ID".to_string(), id.index().to_string()));
+    }
+    env
+}
+
 pub struct Runner {
     tx: mpsc::Sender<Message>,
     rx: mpsc::Receiver<Message>,
     pub running: usize,
     tids: ThreadIds,
     parallelism: usize,
+    /// See work::Options::wrapper.
+    wrapper: Option<String>,
+    /// See work::Options::output_limit.
+    output_limit: Option<usize>,
+    /// See work::Options::filter_output.
+    filter_output: Option<regex::bytes::Regex>,
+    /// See work::Options::rewrite_paths and hyperlinks.
+    rewriter: Option<Arc<PathRewriter>>,
+    /// See work::Options::audit.
+    audit: bool,
+    /// With work::Options::read_only_sources, what to run commands under; see
+    /// process::read_only_prefix.
+    read_only: Option<Arc<Vec<String>>>,
+    /// Whether our stdin is a terminal, which console pool commands then read,
+    /// as they may ask the user something, and isn't kept from them by
+    /// work::Options::no_stdin.
+    stdin_is_terminal: bool,
+    /// Our environment, which commands run in with task_env's variables set.
+    env: process::Env,
+    /// The directory commands run in; see log_path.
+    dir: PathBuf,
 }
 
 impl Runner {
-    pub fn new(parallelism: usize) -> Self {
+    /// A runner for commands as the options say, and under read_only if
+    /// given; see read_only above.
+    pub fn new(options: &work::Options, read_only: Option<Vec<String>>) -> Self {
         let (tx, rx) = mpsc::channel();
         Runner {
             tx,
             rx,
             running: 0,
             tids: ThreadIds::default(),
-            parallelism,
+            parallelism: options.parallelism,
+            wrapper: options.wrapper.clone(),
+            output_limit: options.output_limit,
+            filter_output: options.filter_output.clone(),
+            rewriter: PathRewriter::for_current_dir(
+                options.rewrite_paths.clone(),
+                options.hyperlinks.clone(),
+            )
+            .map(Arc::new),
+            audit: options.audit,
+            read_only: read_only.map(Arc::new),
+            stdin_is_terminal: !options.no_stdin && std::io::stdin().is_terminal(),
+            env: process::Env::ours(),
+            dir: std::env::current_dir().unwrap_or_default(),
         }
     }
 
@@ -210,29 +622,79 @@ impl Runner {
         self.running > 0
     }
 
-    pub fn start(&mut self, id: BuildId, build: &Build) {
-        let cmdline = build.cmdline.clone().unwrap();
-        let depfile = build.depfile.clone().map(PathBuf::from);
-        let rspfile = build.rspfile.clone();
-        let parse_showincludes = build.parse_showincludes;
+    /// Where the full output of a build, first output out, goes if it's
+    /// truncated or filtered.  The same build gets the same log on each run,
+    /// so that logs don't pile up.
+    fn log_path(&self, out: &str) -> PathBuf {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = crate::graph::FnvHasher::default();
+        self.dir.hash(&mut hasher);
+        out.hash(&mut hasher);
+        std::env::temp_dir().join(format!("n2-{:016x}.log", hasher.finish()))
+    }
+
+    /// Start running a build, whose files are among files.
+    pub fn start(&mut self, id: BuildId, build: &Build, files: &GraphFiles) {
+        let mut cmdline = build.cmdline.clone().unwrap();
+        let wrapper = build.wrapper.as_deref().or(self.wrapper.as_deref());
+        if let Some(wrapper) = wrapper.filter(|w| !w.is_empty()) {
+            cmdline = format!("{} {}", wrapper, cmdline);
+        }
+        let command = TaskCommand {
+            cmdline,
+            depfile: build.depfile.clone().map(PathBuf::from),
+            staged_depfile: match &build.depfile {
+                Some(depfile) if build.atomic_outputs => build.staged_path(files, depfile),
+                _ => None,
+            },
+            parse_showincludes: build.parse_showincludes,
+            rspfile: build.rspfile.clone(),
+            shell: build.shell.clone(),
+            pty: build.pty,
+            no_network: build.no_network,
+            read_only: self.read_only.clone(),
+            stdin: self.stdin_is_terminal && build.pool.as_deref() == Some("console"),
+            env: self.env.with(task_env(id, build)),
+            filters: build
+                .filter_output
+                .iter()
+                .chain(self.filter_output.iter())
+                .cloned()
+                .collect(),
+            rewriter: self.rewriter.clone(),
+            trace: self.audit.then(|| {
+                std::env::temp_dir().join(format!(
+                    "n2-{}-{}.strace",
+                    std::process::id(),
+                    id.index()
+                ))
+            }),
+        };
+        // /showIncludes output holds the build's deps, so it can't be cut.
+        let output = OutputBuffer::new(
+            self.output_limit.filter(|_| !command.parse_showincludes),
+            self.log_path(&files.by_id[build.outs()[0]].name),
+        );
+
+        let log_path = output.log_path.clone();
 
         let tid = self.tids.claim();
         let tx = self.tx.clone();
         std::thread::spawn(move || {
             let start = Instant::now();
-            let result = run_task(
-                &cmdline,
-                depfile.as_deref(),
-                parse_showincludes,
-                rspfile.as_ref(),
-                |line| {
-                    let _ = tx.send(Message::Output((id, line.to_owned())));
-                },
-            )
-            .unwrap_or_else(|err| TaskResult {
-                termination: process::Termination::Failure,
-                output: format!("{}\n", err).into_bytes(),
-                discovered_deps: None,
+            let result = run_task(&command, output, |line| {
+                let _ = tx.send(Message::Output((id, line.to_owned())));
+            })
+            .unwrap_or_else(|err| {
+                // The error replaces any output that named the log.
+                remove_log(&log_path);
+                TaskResult {
+                    termination: process::Termination::Failure,
+                    output: format!("{}\n", err).into_bytes(),
+                    discovered_deps: None,
+                    usage: process::ResourceUsage::default(),
+                    opened: None,
+                }
             });
             let finish = Instant::now();
 
@@ -263,6 +725,16 @@ impl Runner {
     }
 }
 
+impl Drop for Runner {
+    /// If the build stops while tasks are still running, e.g. because it was
+    /// interrupted or hit too many failures, don't leave them running behind.
+    fn drop(&mut self) {
+        if self.is_running() {
+            process::terminate_all();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -308,4 +780,130 @@ more text
         let deps = read_depfile(Path::new("/missing/dep/file")).unwrap();
         assert_eq!(deps.len(), 0);
     }
+
+    #[test]
+    fn output_buffer_unlimited() -> std::io::Result<()> {
+        let mut output = OutputBuffer::new(None, PathBuf::from("unused"));
+        output.push(b"hello ")?;
+        output.push(b"world")?;
+        assert_eq!(output.recent(), b"hello world");
+        assert_eq!(output.finish()?, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn output_buffer_truncates() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_path = dir.path().join("task.log");
+        let mut output = OutputBuffer::new(Some(8), log_path.clone());
+        output.push(b"abc")?;
+        output.push(b"defghij")?;
+        output.push(b"klmnop")?;
+        assert_eq!(output.recent(), b"mnop");
+        let text = String::from_utf8(output.finish()?).unwrap();
+        assert_eq!(
+            text,
+            format!(
+                "abcd\n[n2: 8 bytes of output omitted, see {} for all of it]\nmnop",
+                log_path.display()
+            )
+        );
+        assert_eq!(std::fs::read(&log_path)?, b"abcdefghijklmnop");
+        Ok(())
+    }
+
+    #[test]
+    fn filter_output_lines() {
+        let filters = [regex::bytes::Regex::new("^Creating library").unwrap()];
+        assert_eq!(
+            filter_lines(
+                b"a.obj\r\nCreating library a.lib\r\nwarning\nCreating library b.lib",
+                &filters
+            ),
+            (b"a.obj\r\nwarning\n".to_vec(), 2)
+        );
+        assert_eq!(filter_lines(b"kept", &filters), (b"kept".to_vec(), 0));
+    }
+
+    #[test]
+    fn rewrite_paths() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().canonicalize()?;
+        std::fs::create_dir_all(root.join("src"))?;
+        std::fs::create_dir_all(root.join("out"))?;
+        std::fs::write(root.join("src/a.c"), "")?;
+        let output = b"../src/a.c:3:1: error\n\
+In file included from ../src/a.c:2,\n\
+../src/b.c:4: missing\n\
+../src/a.c(5): warning C4996\n\
+note:12 stays\n";
+
+        let rewriter = PathRewriter::new(root.join("out"), Some(PathStyle::Absolute), None);
+        let a = root.join("src/a.c");
+        let a = a.to_str().unwrap();
+        assert_eq!(
+            String::from_utf8(rewriter.rewrite(output))?,
+            format!(
+                "{a}:3:1: error\n\
+In file included from {a}:2,\n\
+../src/b.c:4: missing\n\
+{a}(5): warning C4996\n\
+note:12 stays\n",
+                a = a
+            )
+        );
+
+        let rewriter = PathRewriter::new(
+            root.join("out"),
+            Some(PathStyle::RelativeTo(root.clone())),
+            None,
+        );
+        assert_eq!(
+            rewriter.rewrite(b"../src/a.c:3: error"),
+            b"src/a.c:3: error"
+        );
+        let rewriter = PathRewriter::new(
+            root.join("out"),
+            Some(PathStyle::RelativeTo(root.join("out/sub"))),
+            None,
+        );
+        assert_eq!(
+            rewriter.rewrite(b"../src/a.c:3: error"),
+            b"../../src/a.c:3: error"
+        );
+
+        let rewriter = PathRewriter::new(
+            root.join("out"),
+            Some(PathStyle::RelativeTo(root.clone())),
+            Some(String::from("https://example.com/{path}#L{line}")),
+        );
+        assert_eq!(
+            String::from_utf8(rewriter.rewrite(b"../src/a.c:3: error"))?,
+            "\x1b]8;;https://example.com/src/a.c#L3\x1b\\src/a.c\x1b]8;;\x1b\\:3: error"
+        );
+
+        // Absolute paths are made links too, but otherwise left as they are,
+        // and paths in URLs are escaped.
+        std::fs::write(root.join("src/c#d.c"), "")?;
+        let rewriter = PathRewriter::new(
+            root.join("out"),
+            Some(PathStyle::RelativeTo(root.clone())),
+            Some(String::from("file://{abspath}")),
+        );
+        let c = root.join("src/c#d.c");
+        let c = c.to_str().unwrap();
+        assert_eq!(
+            String::from_utf8(rewriter.rewrite(format!("{}:3: error", c).as_bytes()))?,
+            format!(
+                "\x1b]8;;file://{}\x1b\\{}\x1b]8;;\x1b\\:3: error",
+                url_escape(c),
+                c
+            )
+        );
+        assert!(url_escape(c).ends_with("/src/c%23d.c"));
+        let rewriter = PathRewriter::new(root.join("out"), Some(PathStyle::Absolute), None);
+        let unchanged = format!("{}:3: error", root.join("src/a.c").display());
+        assert_eq!(rewriter.rewrite(unchanged.as_bytes()), unchanged.as_bytes());
+        Ok(())
+    }
 }
@@ -19,6 +19,55 @@ mod unix {
             Some(winsize.ws_col as usize)
         }
     }
+
+    pub fn get_rows() -> Option<usize> {
+        unsafe {
+            let mut winsize = std::mem::zeroed::<libc::winsize>();
+            if libc::ioctl(0, libc::TIOCGWINSZ, &mut winsize) < 0 || winsize.ws_row == 0 {
+                return None;
+            }
+            Some(winsize.ws_row as usize)
+        }
+    }
+
+    /// Whether both stdin and stdout are a terminal, so we can interact with
+    /// the user.
+    pub fn is_interactive() -> bool {
+        unsafe {
+            libc::isatty(/* stdin */ 0) == 1 && libc::isatty(/* stdout */ 1) == 1
+        }
+    }
+
+    /// Puts the terminal into raw mode, where keys are read as they're typed
+    /// and not echoed, until dropped.
+    pub struct RawMode(libc::termios);
+
+    impl RawMode {
+        pub fn enter() -> std::io::Result<RawMode> {
+            unsafe {
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                if libc::tcgetattr(0, &mut termios) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let saved = termios;
+                libc::cfmakeraw(&mut termios);
+                // Keep output processing so that \n still goes to a new line.
+                termios.c_oflag |= libc::OPOST;
+                if libc::tcsetattr(0, libc::TCSANOW, &termios) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(RawMode(saved))
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(0, libc::TCSANOW, &self.0);
+            }
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -60,6 +109,63 @@ mod windows {
             Some(csbi.dwSize.X as usize)
         }
     }
+
+    pub fn get_rows() -> Option<usize> {
+        unsafe {
+            let console = GetStdHandle(STD_OUTPUT_HANDLE);
+            if console == INVALID_HANDLE_VALUE {
+                return None;
+            }
+            let mut csbi = ::std::mem::zeroed::<CONSOLE_SCREEN_BUFFER_INFO>();
+            if GetConsoleScreenBufferInfo(console, &mut csbi) == 0 {
+                return None;
+            }
+            // The visible window, rather than the whole scrollback buffer.
+            Some((csbi.srWindow.Bottom - csbi.srWindow.Top + 1) as usize)
+        }
+    }
+
+    /// Whether both stdin and stdout are a console, so we can interact with
+    /// the user.
+    pub fn is_interactive() -> bool {
+        unsafe {
+            let mut mode = 0;
+            GetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), &mut mode) != 0
+                && GetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), &mut mode) != 0
+        }
+    }
+
+    /// Puts the console into raw mode, where keys are read as they're typed
+    /// and not echoed, until dropped.  Keys like arrows arrive as the same
+    /// escape sequences as on Unix terminals.
+    pub struct RawMode(CONSOLE_MODE);
+
+    impl RawMode {
+        pub fn enter() -> std::io::Result<RawMode> {
+            unsafe {
+                let handle = GetStdHandle(STD_INPUT_HANDLE);
+                let mut mode = 0;
+                if GetConsoleMode(handle, &mut mode) == 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let raw = (mode
+                    & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT))
+                    | ENABLE_VIRTUAL_TERMINAL_INPUT;
+                if SetConsoleMode(handle, raw) == 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(RawMode(mode))
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), self.0);
+            }
+        }
+    }
 }
 
 #[cfg(windows)]
@@ -74,6 +180,22 @@ mod wasm {
     pub fn get_cols() -> Option<usize> {
         None
     }
+
+    pub fn get_rows() -> Option<usize> {
+        None
+    }
+
+    pub fn is_interactive() -> bool {
+        false
+    }
+
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enter() -> std::io::Result<RawMode> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -1,26 +1,75 @@
 //! Chrome trace output.
+//!
+//! Events are written out as they happen, each on its own line, so that a
+//! build that crashes or is killed still leaves a trace behind: the format
+//! allows the closing `]` to be missing.
 
+use crate::plan;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::time::Instant;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
 static mut TRACE: Option<Trace> = None;
 
+/// Where events go: they're held in memory until the trace is placed, as
+/// the builddir it belongs in isn't known until the manifest is loaded.
+enum Output {
+    Pending(Vec<u8>),
+    File(LineWriter<File>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Pending(v) => v.write(buf),
+            Output::File(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Pending(_) => Ok(()),
+            Output::File(f) => f.flush(),
+        }
+    }
+}
+
 pub struct Trace {
     start: Instant,
-    w: BufWriter<File>,
+    /// The wall clock time at start, for the build info.
+    start_time: SystemTime,
+    /// The trace's file name, within the directory it's placed in.
+    name: PathBuf,
+    w: Output,
     count: usize,
 }
 
 impl Trace {
-    fn new(path: &str) -> std::io::Result<Self> {
-        let mut w = BufWriter::new(File::create(path)?);
-        writeln!(w, "[")?;
-        Ok(Trace {
+    fn new(name: PathBuf) -> Self {
+        let mut w = Output::Pending(Vec::new());
+        writeln!(w, "[").unwrap();
+        Trace {
             start: Instant::now(),
+            start_time: SystemTime::now(),
+            name,
             w,
             count: 0,
-        })
+        }
+    }
+
+    /// Start writing the trace to its file in `dir`, unless it already is.
+    fn place(&mut self, dir: &Path) -> std::io::Result<()> {
+        if let Output::Pending(events) = &self.w {
+            let path = dir.join(&self.name);
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut f = LineWriter::new(File::create(path)?);
+            f.write_all(events)?;
+            self.w = Output::File(f);
+        }
+        Ok(())
     }
 
     fn write_event_prefix(&mut self, name: &str, ts: Instant) {
@@ -38,14 +87,66 @@ impl Trace {
     }
 
     pub fn write_complete(&mut self, name: &str, tid: usize, start: Instant, end: Instant) {
+        self.write_complete_with_args(name, tid, start, end, &[]);
+    }
+
+    /// Like write_complete, with numeric args shown alongside the event.
+    pub fn write_complete_with_args(
+        &mut self,
+        name: &str,
+        tid: usize,
+        start: Instant,
+        end: Instant,
+        args: &[(&str, u64)],
+    ) {
         self.write_event_prefix(name, start);
-        writeln!(
+        write!(
             self.w,
-            "\"tid\": {}, \"ph\":\"X\", \"dur\":{}}}",
+            "\"tid\": {}, \"ph\":\"X\", \"dur\":{}",
             tid,
             end.duration_since(start).as_micros()
         )
         .unwrap();
+        if !args.is_empty() {
+            write!(self.w, ", \"args\":{{").unwrap();
+            for (i, (name, value)) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(self.w, ",").unwrap();
+                }
+                write!(self.w, "\"{}\":{}", name, value).unwrap();
+            }
+            write!(self.w, "}}").unwrap();
+        }
+        writeln!(self.w, "}}").unwrap();
+    }
+
+    /// Name the process in the trace viewer.
+    pub fn write_process_name(&mut self, name: &str) {
+        self.write_event_prefix("process_name", self.start);
+        writeln!(self.w, "\"ph\":\"M\", \"args\":{{\"name\":{:?}}}}}", name).unwrap();
+    }
+
+    /// Record what the build was, as a metadata event: the command line, the
+    /// time it started, and how many tasks it runs at once.
+    pub fn write_build_info(&mut self, argv: &[String], parallelism: usize) {
+        self.write_event_prefix("build_info", self.start);
+        write!(self.w, "\"ph\":\"M\", \"args\":{{\"argv\":[").unwrap();
+        for (i, arg) in argv.iter().enumerate() {
+            if i > 0 {
+                write!(self.w, ",").unwrap();
+            }
+            plan::write_string(&mut self.w, arg).unwrap();
+        }
+        let start_ms = self
+            .start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        writeln!(
+            self.w,
+            "], \"start_unix_ms\":{}, \"parallelism\":{}}}}}",
+            start_ms, parallelism
+        )
+        .unwrap();
     }
 
     fn scope<T>(&mut self, name: &str, f: impl FnOnce() -> T) -> T {
@@ -81,20 +182,36 @@ impl Trace {
     }
     */
 
-    fn close(&mut self) {
+    fn close(&mut self) -> std::io::Result<()> {
+        // If the build never got as far as knowing its builddir.
+        self.place(Path::new(""))?;
         self.write_complete("main", 0, self.start, Instant::now());
         writeln!(self.w, "]").unwrap();
-        self.w.flush().unwrap();
+        self.w.flush()
     }
 }
 
-pub fn open(path: &str) -> std::io::Result<()> {
-    let trace = Trace::new(path)?;
+/// Start tracing, to a file of the given name that's created once place()
+/// says which directory it goes in.
+pub fn open(name: impl Into<PathBuf>) {
+    let trace = Trace::new(name.into());
     // Safety: accessing global mut, not threadsafe.
     unsafe {
         TRACE = Some(trace);
     }
-    Ok(())
+}
+
+/// Start tracing to the file at `path`, wherever the builddir turns out to be.
+pub fn create(path: &Path) -> std::io::Result<()> {
+    open(path);
+    place(Path::new(""))
+}
+
+/// Write the trace in `dir`, the builddir, unless it's already been placed.
+pub fn place(dir: &Path) -> std::io::Result<()> {
+    let mut result = Ok(());
+    if_enabled(|t| result = t.place(dir));
+    result
 }
 
 #[inline]
@@ -119,6 +236,8 @@ pub fn scope<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
     }
 }
 
-pub fn close() {
-    if_enabled(|t| t.close());
+pub fn close() -> std::io::Result<()> {
+    let mut result = Ok(());
+    if_enabled(|t| result = t.close());
+    result
 }
@@ -0,0 +1,337 @@
+//! Batched stats through io_uring, which keeps many in flight at once rather
+//! than making a syscall for each, for the stat()s at the start of a build
+//! on a cold cache.  See FileState::prefetch.
+//!
+//! The kernel interface is used directly, as it's small enough not to be
+//! worth a dependency.  Kernels without io_uring, or without its statx
+//! operation (added in 5.6), are detected and left to plain stat().
+
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Size of the submission queue, and so how many stats are in flight at once.
+const ENTRIES: u32 = 256;
+
+const IORING_OP_STATX: u8 = 21;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+/// Set once io_uring is found not to work, so we stop trying.
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// struct io_sqring_offsets.
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_cqring_offsets.
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_uring_params.
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+/// struct io_uring_sqe, with the fields named as used for statx.
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    statxbuf: u64,
+    pathname: u64,
+    mask: u32,
+    statx_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+/// struct io_uring_cqe.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region of memory shared with the kernel.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> Option<Mmap> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// A pointer to something at an offset given by the kernel.
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.add(offset as usize) as *mut T
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+struct Ring {
+    params: Params,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    // Declared last so the mappings go first.
+    fd: OwnedFd,
+}
+
+impl Ring {
+    fn new() -> Option<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        Some(Ring {
+            sq: Mmap::new(&fd, sq_len, IORING_OFF_SQ_RING)?,
+            cq: Mmap::new(&fd, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mmap::new(&fd, sqes_len, IORING_OFF_SQES)?,
+            params,
+            fd,
+        })
+    }
+
+    /// Stat up to sq_entries paths into bufs, returning the result of each:
+    /// zero or a negated errno.  None if io_uring itself failed.
+    fn statx(&self, paths: &[CString], bufs: &mut [libc::statx], flags: i32) -> Option<Vec<i32>> {
+        let p = &self.params;
+        let n = paths.len() as u32;
+        assert!(n <= p.sq_entries);
+        // Safety: the offsets come from the kernel, which sized the mappings,
+        // and the paths and buffers outlive the operations, which are all
+        // reaped before returning.
+        unsafe {
+            let sq_tail = &*self.sq.at::<AtomicU32>(p.sq_off.tail);
+            let sq_mask = *self.sq.at::<u32>(p.sq_off.ring_mask);
+            let sq_array = self.sq.at::<u32>(p.sq_off.array);
+            let sqes = self.sqes.ptr as *mut Sqe;
+            // Only we write the tail.
+            let mut tail = sq_tail.load(Ordering::Relaxed);
+            for (i, (path, buf)) in paths.iter().zip(bufs.iter_mut()).enumerate() {
+                let index = tail & sq_mask;
+                sqes.add(index as usize).write(Sqe {
+                    opcode: IORING_OP_STATX,
+                    flags: 0,
+                    ioprio: 0,
+                    fd: libc::AT_FDCWD,
+                    statxbuf: buf as *mut libc::statx as u64,
+                    pathname: path.as_ptr() as u64,
+                    mask: libc::STATX_MTIME,
+                    statx_flags: flags as u32,
+                    user_data: i as u64,
+                    buf_index: 0,
+                    personality: 0,
+                    splice_fd_in: 0,
+                    pad: [0; 2],
+                });
+                *sq_array.add(index as usize) = index;
+                tail = tail.wrapping_add(1);
+            }
+            sq_tail.store(tail, Ordering::Release);
+
+            let cq_head = &*self.cq.at::<AtomicU32>(p.cq_off.head);
+            let cq_tail = &*self.cq.at::<AtomicU32>(p.cq_off.tail);
+            let cq_mask = *self.cq.at::<u32>(p.cq_off.ring_mask);
+            let cqes = self.cq.at::<Cqe>(p.cq_off.cqes);
+            let mut results = vec![0; paths.len()];
+            let mut submitted = 0;
+            let mut reaped = 0;
+            while reaped < n {
+                let ret = libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    n - submitted,
+                    1,
+                    IORING_ENTER_GETEVENTS,
+                    std::ptr::null::<libc::c_void>(),
+                    0usize,
+                );
+                if ret < 0 {
+                    if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return None;
+                }
+                submitted += ret as u32;
+                let mut head = cq_head.load(Ordering::Relaxed);
+                let tail = cq_tail.load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = &*cqes.add((head & cq_mask) as usize);
+                    results[cqe.user_data as usize] = cqe.res;
+                    head = head.wrapping_add(1);
+                    reaped += 1;
+                }
+                cq_head.store(head, Ordering::Release);
+            }
+            Some(results)
+        }
+    }
+}
+
+fn timestamp(t: &libc::statx_timestamp) -> SystemTime {
+    let secs = Duration::from_secs(t.tv_sec.unsigned_abs());
+    let base = if t.tv_sec >= 0 {
+        SystemTime::UNIX_EPOCH + secs
+    } else {
+        SystemTime::UNIX_EPOCH - secs
+    };
+    base + Duration::from_nanos(t.tv_nsec as u64)
+}
+
+/// The mtimes of the given paths, as with std::fs::metadata() (or
+/// symlink_metadata() if not following symlinks), or None if io_uring isn't
+/// usable here.
+pub fn mtimes(paths: &[&Path], follow_symlinks: bool) -> Option<Vec<std::io::Result<SystemTime>>> {
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let ring = match Ring::new() {
+        Some(ring) => ring,
+        None => {
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+            return None;
+        }
+    };
+    let flags = if follow_symlinks {
+        0
+    } else {
+        libc::AT_SYMLINK_NOFOLLOW
+    };
+    let mut mtimes = Vec::with_capacity(paths.len());
+    for chunk in paths.chunks(ring.params.sq_entries as usize) {
+        let paths = chunk
+            .iter()
+            .map(|path| CString::new(path.as_os_str().as_bytes()).ok())
+            .collect::<Option<Vec<_>>>()?;
+        // Safety: statx is plain old data.
+        let mut bufs = vec![unsafe { std::mem::zeroed::<libc::statx>() }; paths.len()];
+        for (res, buf) in ring.statx(&paths, &mut bufs, flags)?.into_iter().zip(&bufs) {
+            // Kernels that predate the statx operation fail each this way.
+            if res == -libc::EINVAL {
+                UNSUPPORTED.store(true, Ordering::Relaxed);
+                return None;
+            }
+            mtimes.push(if res < 0 {
+                Err(std::io::Error::from_raw_os_error(-res))
+            } else {
+                Ok(timestamp(&buf.stx_mtime))
+            });
+        }
+    }
+    Some(mtimes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_metadata() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        // Enough to need more than one batch.
+        for i in 0..(ENTRIES as usize + 10) {
+            let path = dir.path().join(format!("f{}", i));
+            std::fs::write(&path, "")?;
+            paths.push(path);
+        }
+        paths.push(dir.path().join("missing"));
+        let refs: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let mtimes = match mtimes(&refs, true) {
+            Some(mtimes) => mtimes,
+            // Not available here, e.g. due to a seccomp filter.
+            None => return Ok(()),
+        };
+        assert_eq!(mtimes.len(), paths.len());
+        for (path, mtime) in paths.iter().zip(&mtimes) {
+            match std::fs::metadata(path) {
+                Ok(meta) => assert_eq!(mtime.as_ref().unwrap(), &meta.modified()?),
+                Err(err) => assert_eq!(mtime.as_ref().unwrap_err().kind(), err.kind()),
+            }
+        }
+        Ok(())
+    }
+}
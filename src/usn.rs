@@ -0,0 +1,278 @@
+//! Asking NTFS's change journal, the USN journal, which files changed since
+//! the last build, so the states recorded then can be trusted for the rest as
+//! with Watchman.  See Work::use_usn_journal.
+//!
+//! The journal records changes by file name and parent directory rather than
+//! by path, so a cached file counts as changed if any file of the same name
+//! changed, in whatever directory.  That can only err on the side of
+//! statting.  A directory renamed or deleted moves or removes the files in it
+//! without a record of their own, so then, as when the journal has wrapped or
+//! been recreated since the last build, everything is statted.
+//!
+//! Reading the journal takes a handle to the volume, which takes
+//! administrator rights.
+
+use crate::watchman::{Cache, Changes};
+use anyhow::{anyhow, bail};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+use std::path::Path;
+use windows_sys::Win32::{
+    Foundation::INVALID_HANDLE_VALUE,
+    Storage::FileSystem::{
+        CreateFileW, GetVolumeNameForVolumeMountPointW, GetVolumePathNameW, FILE_SHARE_READ,
+        FILE_SHARE_WRITE, OPEN_EXISTING,
+    },
+    System::IO::DeviceIoControl,
+};
+
+const GENERIC_READ: u32 = 0x8000_0000;
+const FSCTL_QUERY_USN_JOURNAL: u32 = 0x0009_00f4;
+const FSCTL_READ_USN_JOURNAL: u32 = 0x0009_00bb;
+const USN_REASON_FILE_DELETE: u32 = 0x0000_0200;
+const USN_REASON_RENAME_OLD_NAME: u32 = 0x0000_1000;
+const USN_REASON_RENAME_NEW_NAME: u32 = 0x0000_2000;
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+
+/// USN_JOURNAL_DATA_V0.
+#[repr(C)]
+#[derive(Default)]
+struct JournalData {
+    journal_id: u64,
+    first_usn: i64,
+    next_usn: i64,
+    lowest_valid_usn: i64,
+    max_usn: i64,
+    maximum_size: u64,
+    allocation_delta: u64,
+}
+
+/// READ_USN_JOURNAL_DATA_V0.
+#[repr(C)]
+struct ReadJournalData {
+    start_usn: i64,
+    reason_mask: u32,
+    return_only_on_close: u32,
+    timeout: u64,
+    bytes_to_wait_for: u64,
+    journal_id: u64,
+}
+
+fn wide(s: &std::ffi::OsStr) -> Vec<u16> {
+    s.encode_wide().chain(std::iter::once(0)).collect()
+}
+
+fn from_wide(buf: &[u16]) -> Vec<u16> {
+    buf.iter().copied().take_while(|&c| c != 0).collect()
+}
+
+/// Open the volume a directory is on, by way of its volume GUID path so that
+/// volumes mounted in folders work too.
+fn open_volume(dir: &Path) -> anyhow::Result<OwnedHandle> {
+    let mut mount_point = [0u16; 1024];
+    let ok = unsafe {
+        GetVolumePathNameW(
+            wide(dir.as_os_str()).as_ptr(),
+            mount_point.as_mut_ptr(),
+            mount_point.len() as u32,
+        )
+    };
+    if ok == 0 {
+        bail!("find volume: {}", std::io::Error::last_os_error());
+    }
+    let mut name = [0u16; 64];
+    let ok = unsafe {
+        GetVolumeNameForVolumeMountPointW(
+            mount_point.as_ptr(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if ok == 0 {
+        bail!("find volume name: {}", std::io::Error::last_os_error());
+    }
+    // \\?\Volume{GUID}\ names the root directory; the volume has no slash.
+    let mut name = from_wide(&name);
+    if name.last() == Some(&(b'\\' as u16)) {
+        name.pop();
+    }
+    name.push(0);
+    let handle = unsafe {
+        CreateFileW(
+            name.as_ptr(),
+            GENERIC_READ,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        bail!("open volume: {}", std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle as _) })
+}
+
+fn ioctl<T>(
+    volume: &OwnedHandle,
+    code: u32,
+    input: Option<&T>,
+    out: &mut [u8],
+) -> anyhow::Result<usize> {
+    let (input, input_len) = match input {
+        Some(input) => (
+            input as *const T as *const c_void,
+            std::mem::size_of::<T>() as u32,
+        ),
+        None => (std::ptr::null(), 0),
+    };
+    let mut returned = 0u32;
+    let ok = unsafe {
+        DeviceIoControl(
+            volume.as_raw_handle() as _,
+            code,
+            input,
+            input_len,
+            out.as_mut_ptr() as *mut c_void,
+            out.len() as u32,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        bail!("read journal: {}", std::io::Error::last_os_error());
+    }
+    Ok(returned as usize)
+}
+
+fn query_journal(volume: &OwnedHandle) -> anyhow::Result<JournalData> {
+    let mut data = JournalData::default();
+    // Safety: JournalData is plain old data.
+    let out = unsafe {
+        std::slice::from_raw_parts_mut(
+            &mut data as *mut JournalData as *mut u8,
+            std::mem::size_of::<JournalData>(),
+        )
+    };
+    ioctl::<()>(volume, FSCTL_QUERY_USN_JOURNAL, None, out)?;
+    Ok(data)
+}
+
+fn read_u16(buf: &[u8], ofs: usize) -> u16 {
+    u16::from_le_bytes(buf[ofs..ofs + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], ofs: usize) -> u32 {
+    u32::from_le_bytes(buf[ofs..ofs + 4].try_into().unwrap())
+}
+
+/// The lowercased names of the files changed from the given USN to the end of
+/// the journal as of now, or None if a directory was renamed or deleted.
+fn changed_names(
+    volume: &OwnedHandle,
+    journal: &JournalData,
+    since: i64,
+) -> anyhow::Result<Option<HashSet<String>>> {
+    let mut names = HashSet::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut start = since;
+    while start < journal.next_usn {
+        let request = ReadJournalData {
+            start_usn: start,
+            reason_mask: u32::MAX,
+            return_only_on_close: 0,
+            timeout: 0,
+            bytes_to_wait_for: 0,
+            journal_id: journal.journal_id,
+        };
+        let len = ioctl(volume, FSCTL_READ_USN_JOURNAL, Some(&request), &mut buf)?;
+        let out = &buf[..len];
+        // The USN to read from next, then USN_RECORD_V2s.
+        let next = i64::from_le_bytes(out[0..8].try_into().unwrap());
+        let mut ofs = 8;
+        while ofs + 60 <= out.len() {
+            let record = &out[ofs..];
+            let record_len = read_u32(record, 0) as usize;
+            if record_len == 0 || read_u16(record, 4) != 2 {
+                return Ok(None);
+            }
+            let reason = read_u32(record, 40);
+            let attributes = read_u32(record, 52);
+            if attributes & FILE_ATTRIBUTE_DIRECTORY != 0
+                && reason
+                    & (USN_REASON_FILE_DELETE
+                        | USN_REASON_RENAME_OLD_NAME
+                        | USN_REASON_RENAME_NEW_NAME)
+                    != 0
+            {
+                return Ok(None);
+            }
+            let name_len = read_u16(record, 56) as usize;
+            let name_ofs = read_u16(record, 58) as usize;
+            let name: Vec<u16> = record[name_ofs..name_ofs + name_len]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            names.insert(String::from_utf16_lossy(&name).to_lowercase());
+            ofs += record_len;
+        }
+        if next <= start {
+            break;
+        }
+        start = next;
+    }
+    Ok(Some(names))
+}
+
+/// Ask the journal for the files changed in the working directory since the
+/// cache was written.  The clock is the journal's id and the USN it had
+/// reached.
+pub fn query(cache: Option<&Cache>) -> anyhow::Result<Changes> {
+    let cwd = std::env::current_dir()?;
+    let dir = cwd
+        .to_str()
+        .ok_or_else(|| anyhow!("non-UTF-8 working directory {:?}", cwd))?
+        .to_string();
+    let volume = open_volume(&cwd)?;
+    let journal = query_journal(&volume)?;
+    let clock = format!("{:x}:{}", journal.journal_id, journal.next_usn);
+
+    let since = cache.filter(|cache| cache.dir == dir).and_then(|cache| {
+        let (id, usn) = cache.clock.split_once(':')?;
+        Some((
+            cache,
+            u64::from_str_radix(id, 16).ok()?,
+            usn.parse::<i64>().ok()?,
+        ))
+    });
+    let changed = match since {
+        Some((cache, id, usn)) if id == journal.journal_id && usn >= journal.first_usn => {
+            changed_names(&volume, &journal, usn)?.map(|names| {
+                cache
+                    .entries
+                    .iter()
+                    .map(|(name, _, _)| name)
+                    .filter(|name| {
+                        let base = name
+                            .rsplit(|c| c == '/' || c == '\\')
+                            .next()
+                            .unwrap_or(name);
+                        names.contains(&base.to_lowercase())
+                    })
+                    .cloned()
+                    .collect()
+            })
+        }
+        // A first build, or the journal was recreated or has wrapped since.
+        _ => None,
+    };
+    Ok(Changes {
+        dir,
+        clock,
+        changed,
+    })
+}
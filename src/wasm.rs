@@ -0,0 +1,242 @@
+//! Exports for the wasm32 build, so that a web page can load build files and
+//! explore the graph they describe with the same parser n2 builds with.
+//! There are no processes or db there, so nothing is built.
+//!
+//! The page copies each build file in by path with n2_add_file, using memory
+//! from n2_alloc, then calls n2_load with the path of the top-level file.
+//! That returns the length of the graph as JSON, which is at n2_result():
+//!
+//! ```text
+//! {"files": ["out", "in.c", ...],
+//!  "builds": [{"location": "build.ninja:3", "rule": "cc",
+//!              "description": null, "command": "cc -c in.c -o out",
+//!              "outs": [0], "implicit_outs": [], "ins": [1],
+//!              "implicit_ins": [], "order_only_ins": [], "validations": [],
+//!              "meta": {"owner": "team-x"}},
+//!             ...],
+//!  "defaults": [0]}
+//! ```
+//!
+//! with files referred to by their index in "files", or `{"error": "..."}`
+//! if the files couldn't be loaded.
+
+use crate::{
+    densemap::Index,
+    graph::{FileId, Graph},
+    load, plan,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+thread_local! {
+    /// The build files added by n2_add_file, by path.
+    static FILES: RefCell<HashMap<String, Vec<u8>>> = RefCell::new(HashMap::new());
+    /// The JSON from the last n2_load.
+    static RESULT: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// Allocate len bytes for the page to copy a string into, to be freed with
+/// n2_free.
+#[no_mangle]
+pub extern "C" fn n2_alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Free memory from n2_alloc.
+#[no_mangle]
+pub unsafe extern "C" fn n2_free(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(std::slice::from_raw_parts_mut(ptr, len)));
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(ptr, len)
+}
+
+/// Add a build file, replacing any added before with the same path.  Paths
+/// are as build files refer to them, relative to the top-level file's
+/// directory.
+#[no_mangle]
+pub unsafe extern "C" fn n2_add_file(
+    path: *const u8,
+    path_len: usize,
+    content: *const u8,
+    len: usize,
+) {
+    let path = String::from_utf8_lossy(bytes(path, path_len)).into_owned();
+    let content = bytes(content, len).to_vec();
+    FILES.with(|files| files.borrow_mut().insert(path, content));
+}
+
+/// Forget the build files added so far.
+#[no_mangle]
+pub extern "C" fn n2_clear_files() {
+    FILES.with(|files| files.borrow_mut().clear());
+}
+
+/// Load the build files added, starting from the one at path, and return the
+/// length of the JSON describing them.
+#[no_mangle]
+pub unsafe extern "C" fn n2_load(path: *const u8, path_len: usize) -> usize {
+    let path = String::from_utf8_lossy(bytes(path, path_len)).into_owned();
+    let options = load::Options {
+        files: Some(Arc::new(FILES.with(|files| files.borrow().clone()))),
+        ..Default::default()
+    };
+    let mut w = Vec::new();
+    match load::read_manifest(&[path], &options) {
+        Ok(manifest) => write_graph(&mut w, &manifest.graph, &manifest.default).unwrap(),
+        Err(err) => {
+            w.extend_from_slice(b"{\"error\": ");
+            plan::write_string(&mut w, &format!("{:#}", err)).unwrap();
+            w.extend_from_slice(b"}");
+        }
+    }
+    let len = w.len();
+    RESULT.with(|result| *result.borrow_mut() = w);
+    len
+}
+
+/// The JSON from the last n2_load, valid until the next.
+#[no_mangle]
+pub extern "C" fn n2_result() -> *const u8 {
+    RESULT.with(|result| result.borrow().as_ptr())
+}
+
+fn write_ids(w: &mut dyn Write, ids: &[FileId]) -> std::io::Result<()> {
+    write!(w, "[")?;
+    for (i, id) in ids.iter().enumerate() {
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+        write!(w, "{}", id.index())?;
+    }
+    write!(w, "]")
+}
+
+fn write_optional(w: &mut dyn Write, s: Option<&str>) -> std::io::Result<()> {
+    match s {
+        Some(s) => plan::write_string(w, s),
+        None => write!(w, "null"),
+    }
+}
+
+/// Write the graph as JSON, in the form described above.
+fn write_graph(w: &mut dyn Write, graph: &Graph, default: &[FileId]) -> std::io::Result<()> {
+    let files: Vec<FileId> = graph.files.all_ids().collect();
+    write!(w, "{{\"files\": ")?;
+    plan::write_files(w, graph, &files)?;
+    write!(w, ", \"builds\": [")?;
+    for (i, id) in graph.build_ids().enumerate() {
+        let build = &graph.builds[id];
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+        write!(w, "{{\"location\": ")?;
+        plan::write_string(w, &build.location.to_string())?;
+        write!(w, ", \"rule\": ")?;
+        plan::write_string(w, &build.rule)?;
+        write!(w, ", \"description\": ")?;
+        write_optional(w, build.desc.as_deref())?;
+        write!(w, ", \"command\": ")?;
+        write_optional(w, build.cmdline.as_deref())?;
+        let explicit_outs = build.explicit_outs();
+        let explicit_ins = build.explicit_ins();
+        let dirtying_ins = build.dirtying_ins();
+        let ordering_ins = build.ordering_ins();
+        write!(w, ", \"outs\": ")?;
+        write_ids(w, explicit_outs)?;
+        write!(w, ", \"implicit_outs\": ")?;
+        write_ids(w, &build.outs()[explicit_outs.len()..])?;
+        write!(w, ", \"ins\": ")?;
+        write_ids(w, explicit_ins)?;
+        write!(w, ", \"implicit_ins\": ")?;
+        write_ids(w, &dirtying_ins[explicit_ins.len()..])?;
+        write!(w, ", \"order_only_ins\": ")?;
+        write_ids(w, &ordering_ins[dirtying_ins.len()..])?;
+        write!(w, ", \"validations\": ")?;
+        write_ids(w, build.validation_ins())?;
+        write!(w, ", \"meta\": ")?;
+        plan::write_meta(w, &build.meta)?;
+        write!(w, "}}")?;
+    }
+    write!(w, "], \"defaults\": ")?;
+    write_ids(w, default)?;
+    write!(w, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{self, Value};
+
+    fn add_file(path: &str, content: &str) {
+        unsafe { n2_add_file(path.as_ptr(), path.len(), content.as_ptr(), content.len()) };
+    }
+
+    fn load(path: &str) -> Value {
+        let len = unsafe { n2_load(path.as_ptr(), path.len()) };
+        let result = unsafe { std::slice::from_raw_parts(n2_result(), len) };
+        json::parse(std::str::from_utf8(result).unwrap()).unwrap()
+    }
+
+    fn names(graph: &Value, ids: &Value) -> Vec<String> {
+        let files = graph.get("files").unwrap().as_array().unwrap();
+        ids.as_array()
+            .unwrap()
+            .iter()
+            .map(|id| match id {
+                Value::Number(n) => files[*n as usize].as_str().unwrap().to_owned(),
+                _ => panic!("not an id: {:?}", id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn load_from_memory() {
+        n2_clear_files();
+        add_file("rules.ninja", "rule cc\n  command = cc -c $in -o $out\n");
+        add_file(
+            "build.ninja",
+            "include rules.ninja\nbuild out | out.d: cc in.c | in.h || gen\nbuild gen: phony\ndefault out\n",
+        );
+        let graph = load("build.ninja");
+        let builds = graph.get("builds").unwrap().as_array().unwrap();
+        assert_eq!(builds.len(), 2);
+        let build = &builds[0];
+        assert_eq!(
+            build.get("location").unwrap().as_str(),
+            Some("build.ninja:2")
+        );
+        assert_eq!(
+            build.get("command").unwrap().as_str(),
+            Some("cc -c in.c -o out")
+        );
+        assert_eq!(names(&graph, build.get("outs").unwrap()), vec!["out"]);
+        assert_eq!(
+            names(&graph, build.get("implicit_outs").unwrap()),
+            vec!["out.d"]
+        );
+        assert_eq!(names(&graph, build.get("ins").unwrap()), vec!["in.c"]);
+        assert_eq!(
+            names(&graph, build.get("implicit_ins").unwrap()),
+            vec!["in.h"]
+        );
+        assert_eq!(
+            names(&graph, build.get("order_only_ins").unwrap()),
+            vec!["gen"]
+        );
+        assert_eq!(builds[1].get("command"), Some(&Value::Null));
+        assert_eq!(names(&graph, graph.get("defaults").unwrap()), vec!["out"]);
+
+        n2_clear_files();
+        add_file("build.ninja", "include missing.ninja\n");
+        let error = load("build.ninja");
+        let error = error.get("error").unwrap().as_str().unwrap();
+        assert!(error.contains("missing.ninja: no such file"), "{}", error);
+    }
+}
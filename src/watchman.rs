@@ -0,0 +1,250 @@
+//! Asking a running Watchman which files changed since the last build, so
+//! the mtimes recorded then can be trusted for the rest rather than statting
+//! every input again.  See Work::use_watchman.
+
+use crate::canon::canon_path;
+use crate::graph::MTime;
+use crate::json::{self, Value};
+use crate::plan::write_string;
+use anyhow::{anyhow, bail};
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+/// File states recorded at the end of a build, along with the Watchman clock
+/// from its start: any file that changed since isn't in the changes reported
+/// for that clock.
+pub struct Cache {
+    /// The directory the build ran in.
+    pub dir: String,
+    pub clock: String,
+    pub entries: Vec<(String, MTime, Option<u64>)>,
+}
+
+/// What Watchman says changed in the working directory.
+pub struct Changes {
+    pub dir: String,
+    /// The clock as of now, to query against next time.
+    pub clock: String,
+    /// Names of files changed since the cached clock, relative to the working
+    /// directory, or None if it can't say, e.g. for a first build or if
+    /// Watchman restarted since.
+    pub changed: Option<HashSet<String>>,
+}
+
+/// Whether Watchman can tell us about changes to a file, which must be
+/// within the working directory.
+fn watched(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with("..")
+        && !Path::new(name).is_absolute()
+        && !name.contains('\n')
+}
+
+/// Run a Watchman command given as JSON.
+fn command(request: &[u8]) -> anyhow::Result<Value> {
+    let mut child = Command::new("watchman")
+        .args(["-j", "--no-pretty"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("run watchman: {}", err))?;
+    child.stdin.take().unwrap().write_all(request)?;
+    let output = child.wait_with_output()?;
+    // Errors come back as JSON too, but only if Watchman got that far.
+    let response = std::str::from_utf8(&output.stdout)
+        .map_err(anyhow::Error::from)
+        .and_then(json::parse);
+    match response {
+        Ok(response) => {
+            if let Some(err) = response.get("error").and_then(Value::as_str) {
+                bail!("{}", err);
+            }
+            Ok(response)
+        }
+        Err(_) if !output.status.success() => {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn get_str<'a>(response: &'a Value, key: &str) -> anyhow::Result<&'a str> {
+    response
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("watchman response lacks {:?}", key))
+}
+
+/// Ask Watchman for the files changed in the working directory since the
+/// cache was written.
+pub fn query(cache: Option<&Cache>) -> anyhow::Result<Changes> {
+    let dir = std::env::current_dir()?;
+    let dir = dir
+        .to_str()
+        .ok_or_else(|| anyhow!("non-UTF-8 working directory {:?}", dir))?
+        .to_string();
+
+    let mut request = Vec::new();
+    request.extend_from_slice(b"[\"watch-project\", ");
+    write_string(&mut request, &dir)?;
+    request.extend_from_slice(b"]");
+    let watch = command(&request)?;
+    let root = get_str(&watch, "watch")?;
+    let relative = watch.get("relative_path").and_then(Value::as_str);
+
+    let since = match cache {
+        Some(cache) if cache.dir == dir => &cache.clock,
+        _ => {
+            let mut request = Vec::new();
+            request.extend_from_slice(b"[\"clock\", ");
+            write_string(&mut request, root)?;
+            request.extend_from_slice(b"]");
+            let clock = get_str(&command(&request)?, "clock")?.to_string();
+            return Ok(Changes {
+                dir,
+                clock,
+                changed: None,
+            });
+        }
+    };
+
+    let mut request = Vec::new();
+    request.extend_from_slice(b"[\"query\", ");
+    write_string(&mut request, root)?;
+    request.extend_from_slice(b", {\"since\": ");
+    write_string(&mut request, since)?;
+    request.extend_from_slice(b", \"fields\": [\"name\"]");
+    if let Some(relative) = relative {
+        request.extend_from_slice(b", \"relative_root\": ");
+        write_string(&mut request, relative)?;
+    }
+    request.extend_from_slice(b"}]");
+    let response = command(&request)?;
+    let clock = get_str(&response, "clock")?.to_string();
+    let changed = if response.get("is_fresh_instance").and_then(Value::as_bool) == Some(true) {
+        None
+    } else {
+        let files = response
+            .get("files")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("watchman response lacks \"files\""))?;
+        Some(
+            files
+                .iter()
+                .filter_map(Value::as_str)
+                .map(canon_path)
+                .collect(),
+        )
+    };
+    Ok(Changes {
+        dir,
+        clock,
+        changed,
+    })
+}
+
+/// Read the cache, or None if there is none or it's unusable, in which case
+/// it's as if no file states are known.
+pub fn read_cache(path: &Path) -> Option<Cache> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut lines = std::io::BufReader::new(file).lines();
+    if lines.next()?.ok()? != CACHE_HEADER {
+        return None;
+    }
+    let dir = lines.next()?.ok()?;
+    let clock = lines.next()?.ok()?;
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line.ok()?;
+        let mut fields = line.splitn(3, ' ');
+        let mtime = match fields.next()? {
+            "missing" => MTime::Missing,
+            stamp => {
+                let (secs, nanos) = stamp.split_once('.')?;
+                let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+                MTime::Stamp(SystemTime::UNIX_EPOCH + since_epoch)
+            }
+        };
+        let fingerprint = match fields.next()? {
+            "-" => None,
+            hex => Some(u64::from_str_radix(hex, 16).ok()?),
+        };
+        entries.push((fields.next()?.to_string(), mtime, fingerprint));
+    }
+    Some(Cache {
+        dir,
+        clock,
+        entries,
+    })
+}
+
+const CACHE_HEADER: &str = "# n2 watchman cache 1";
+
+/// Write the cache, skipping files Watchman can't tell us about.
+pub fn write_cache<'a>(
+    path: &Path,
+    dir: &str,
+    clock: &str,
+    entries: impl Iterator<Item = (&'a str, MTime, Option<u64>)>,
+) -> std::io::Result<()> {
+    // Write to the side and rename, so a reader never sees half a cache.
+    let tmp = path.with_extension("tmp");
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    writeln!(w, "{}\n{}\n{}", CACHE_HEADER, dir, clock)?;
+    for (name, mtime, fingerprint) in entries {
+        if !watched(name) {
+            continue;
+        }
+        match mtime {
+            MTime::Missing => write!(w, "missing")?,
+            MTime::Stamp(stamp) => match stamp.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => write!(w, "{}.{:09}", d.as_secs(), d.subsec_nanos())?,
+                // Not worth the trouble.
+                Err(_) => continue,
+            },
+        }
+        match fingerprint {
+            Some(fingerprint) => write!(w, " {:x}", fingerprint)?,
+            None => write!(w, " -")?,
+        }
+        writeln!(w, " {}", name)?;
+    }
+    w.flush()?;
+    drop(w);
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_round_trip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_watchman");
+        let stamp = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+        let entries = [
+            ("a b.c", MTime::Stamp(stamp), None),
+            ("out/x", MTime::Missing, None),
+            ("coarse", MTime::Stamp(stamp), Some(0xabc)),
+            ("../outside", MTime::Missing, None),
+        ];
+        write_cache(&path, "/src", "c:1:2", entries.iter().copied())?;
+        let cache = read_cache(&path).unwrap();
+        assert_eq!(cache.dir, "/src");
+        assert_eq!(cache.clock, "c:1:2");
+        let expected: Vec<(String, MTime, Option<u64>)> = entries[..3]
+            .iter()
+            .map(|&(name, mtime, fingerprint)| (name.to_string(), mtime, fingerprint))
+            .collect();
+        assert_eq!(cache.entries, expected);
+
+        std::fs::write(&path, "something else\n")?;
+        assert!(read_cache(&path).is_none());
+        Ok(())
+    }
+}
@@ -1,11 +1,22 @@
 //! Build runner, choosing and executing tasks as determined by out of date inputs.
 
 use crate::{
-    canon::canon_path, db, densemap::DenseMap, graph::*, hash, process, progress,
-    progress::Progress, signal, smallmap::SmallMap, task, trace,
+    canon::{self, canon_path},
+    db,
+    densemap::{DenseMap, Index},
+    graph::*,
+    hash::{self, BuildHash, InputDigest, ManifestParts},
+    metrics, plan, process, progress,
+    progress::Progress,
+    report, signal,
+    smallmap::SmallMap,
+    task, trace, watchman,
 };
-use std::collections::HashSet;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Build steps go through this sequence of states.
 /// See "Build states" in the design notes.
@@ -67,27 +78,154 @@ impl StateCounts {
     }
 }
 
+/// Recorded durations of builds that are known to need to run (i.e. are
+/// queued) but haven't started yet.  Like StateCounts, this is only used for
+/// display, to estimate the time left in the build.
+#[derive(Clone, Debug, Default)]
+pub struct Estimate {
+    /// Sum of the recorded durations of queued builds that have one.
+    pub known: Duration,
+    /// Number of queued builds with a recorded duration.
+    pub known_count: usize,
+    /// Number of queued builds that have no recorded duration.
+    pub unknown_count: usize,
+}
+impl Estimate {
+    pub fn add(&mut self, duration: Option<Duration>) {
+        match duration {
+            Some(d) => {
+                self.known += d;
+                self.known_count += 1;
+            }
+            None => self.unknown_count += 1,
+        }
+    }
+    pub fn remove(&mut self, duration: Option<Duration>) {
+        match duration {
+            Some(d) => {
+                self.known -= d;
+                self.known_count -= 1;
+            }
+            None => self.unknown_count -= 1,
+        }
+    }
+}
+
+/// Tasks using at least this much memory are reported at the end of the
+/// build, as a hint for sizing pools.
+const MEMORY_HOG_THRESHOLD: u64 = 1 << 30;
+/// How many of those tasks to report.
+const MEMORY_HOG_COUNT: usize = 3;
+
+/// The finished tasks that used the most memory, most first.
+#[derive(Default)]
+struct MemoryHogs(Vec<(BuildId, u64)>);
+impl MemoryHogs {
+    fn add(&mut self, id: BuildId, peak_rss: u64) {
+        if peak_rss < MEMORY_HOG_THRESHOLD {
+            return;
+        }
+        let pos = self.0.partition_point(|&(_, rss)| rss >= peak_rss);
+        self.0.insert(pos, (id, peak_rss));
+        self.0.truncate(MEMORY_HOG_COUNT);
+    }
+}
+
+/// Machine resources shared by running builds; see Build::mem and
+/// Build::cores.
+struct Resources {
+    cores_total: usize,
+    cores_used: usize,
+    /// None if memory isn't limited.
+    mem_total: Option<u64>,
+    mem_used: u64,
+}
+
+impl Resources {
+    fn new(cores_total: usize, mem_total: Option<u64>) -> Self {
+        Resources {
+            cores_total,
+            cores_used: 0,
+            mem_total,
+            mem_used: 0,
+        }
+    }
+
+    /// Whether a build fits in the resources left.  A build needing more
+    /// than the total may run once nothing else is using it, rather than never.
+    fn fits(&self, build: &Build) -> bool {
+        let cores_fit = self.cores_used == 0 || self.cores_used + build.cores <= self.cores_total;
+        let mem_fits = match self.mem_total {
+            Some(total) => {
+                build.mem == 0 || self.mem_used == 0 || self.mem_used + build.mem <= total
+            }
+            None => true,
+        };
+        cores_fit && mem_fits
+    }
+
+    fn acquire(&mut self, build: &Build) {
+        self.cores_used += build.cores;
+        self.mem_used += build.mem;
+    }
+
+    fn release(&mut self, build: &Build) {
+        self.cores_used -= build.cores;
+        self.mem_used -= build.mem;
+    }
+}
+
 /// Pools gather collections of running builds.
 /// Each running build is running "in" a pool; there's a default unbounded
 /// pool for builds that don't specify one.
 /// See "Tracking build state" in the design notes.
 struct PoolState {
-    /// A queue of builds that are ready to be executed in this pool.
-    queued: VecDeque<BuildId>,
+    /// The builds that are ready to be executed in this pool, by the group
+    /// they're wanted for under Options::fair, and then in the order they're
+    /// to run in; see BuildStates::enqueue.
+    queued: BTreeMap<usize, BTreeSet<QueueKey>>,
     /// The number of builds currently running in this pool.
     running: usize,
     /// The total depth of the pool.  0 means unbounded.
     depth: usize,
 }
 
+/// Orders the queued builds of a group: those that go first, then by the
+/// order they're to run in, the build itself being last.
+type QueueKey = (bool, i64, BuildId);
+
 impl PoolState {
     fn new(depth: usize) -> Self {
         PoolState {
-            queued: VecDeque::new(),
+            queued: BTreeMap::new(),
             running: 0,
             depth,
         }
     }
+
+    /// Whether the pool has room for another build to run.
+    fn has_room(&self) -> bool {
+        self.depth == 0 || self.running < self.depth
+    }
+
+    /// The first queued build of the group that fits.  Builds that don't fit
+    /// are passed over, which takes a scan, but they're few at a time.
+    fn first(&self, group: usize, fits: impl Fn(BuildId) -> bool) -> Option<QueueKey> {
+        self.queued
+            .get(&group)?
+            .iter()
+            .find(|&&(_, _, id)| fits(id))
+            .copied()
+    }
+
+    fn remove(&mut self, group: usize, key: QueueKey) -> BuildId {
+        let queue = self.queued.get_mut(&group).unwrap();
+        queue.remove(&key);
+        if queue.is_empty() {
+            self.queued.remove(&group);
+        }
+        key.2
+    }
 }
 
 /// BuildStates tracks progress of each Build step through the build.
@@ -102,16 +240,44 @@ struct BuildStates {
     /// (done or failed).
     total_pending: usize,
 
-    /// Builds in the ready state, stored redundantly for quick access.
-    ready: VecDeque<BuildId>,
+    /// Builds in the ready state, stored redundantly for quick access, by
+    /// the order they're to be taken in: that they became ready in, or with
+    /// Options::deterministic, their ids.
+    ready: BTreeMap<i64, BuildId>,
+
+    /// How many builds have become ready, to keep them in that order.
+    readied: i64,
 
     /// Named pools of queued and running builds.
     /// Builds otherwise default to using an unnamed infinite pool.
     pools: SmallMap<String, PoolState>,
+
+    /// Resources used by running builds.
+    resources: Resources,
+
+    /// See Options::deterministic.
+    deterministic: bool,
+
+    /// See Options::fair.
+    fair: bool,
+
+    /// For each build, the requested target it was first wanted for, as an
+    /// index in the order they were requested; see pop_queued_fair.
+    groups: DenseMap<BuildId, usize>,
+
+    /// The group builds visited now are wanted for.
+    group: usize,
+
+    /// The group and the index of the pool that get the next turn under
+    /// pop_queued_fair.
+    next_turn: (usize, usize),
+
+    /// How many builds have been enqueued, to keep them in that order.
+    enqueued: i64,
 }
 
 impl BuildStates {
-    fn new(size: BuildId, depths: SmallMap<String, usize>) -> Self {
+    fn new(size: BuildId, depths: SmallMap<String, usize>, resources: Resources) -> Self {
         let mut pools = SmallMap::default();
         // The implied default pool.
         pools.insert(String::from(""), PoolState::new(0));
@@ -124,8 +290,16 @@ impl BuildStates {
             states: DenseMap::new_sized(size, BuildState::Unknown),
             counts: StateCounts::default(),
             total_pending: 0,
-            ready: VecDeque::new(),
+            ready: BTreeMap::new(),
+            readied: 0,
             pools,
+            resources,
+            deterministic: false,
+            fair: false,
+            groups: DenseMap::new_sized(size, 0),
+            group: 0,
+            next_turn: (0, 0),
+            enqueued: 0,
         }
     }
 
@@ -147,6 +321,7 @@ impl BuildStates {
         } else {
             if prev == BuildState::Running {
                 self.get_pool(build).unwrap().running -= 1;
+                self.resources.release(build);
             }
             if !skip_ui_count {
                 self.counts.add(prev, -1);
@@ -155,7 +330,13 @@ impl BuildStates {
 
         match state {
             BuildState::Ready => {
-                self.ready.push_back(id);
+                self.readied += 1;
+                let order = if self.deterministic {
+                    id.index() as i64
+                } else {
+                    self.readied
+                };
+                self.ready.insert(order, id);
             }
             BuildState::Running => {
                 // Trace instants render poorly in the old Chrome UI, and
@@ -164,6 +345,7 @@ impl BuildStates {
                 //     trace::if_enabled(|t| t.write_instant("first build"));
                 // }
                 self.get_pool(build).unwrap().running += 1;
+                self.resources.acquire(build);
             }
             BuildState::Done | BuildState::Failed => {
                 self.total_pending -= 1;
@@ -211,6 +393,7 @@ impl BuildStates {
 
         let build = &graph.builds[id];
         let mut state = BuildState::Want;
+        self.groups[id] = self.group;
 
         // Any Build whose inputs are already in place is ready.
         let mut ready = true;
@@ -277,7 +460,7 @@ impl BuildStates {
     pub fn pop_ready(&mut self) -> Option<BuildId> {
         // Here is where we might consider prioritizing from among the available
         // ready set.
-        self.ready.pop_front()
+        self.ready.pop_first().map(|(_, id)| id)
     }
 
     /// Look up a PoolState by name.
@@ -291,10 +474,23 @@ impl BuildStates {
         None
     }
 
-    /// Mark a build as ready to run.
+    /// Mark a build as ready to run.  If first, it goes ahead of the builds
+    /// already queued in its pool, as builds that failed last time do.
+    /// Otherwise builds run in the order they're enqueued, or with
+    /// Options::deterministic, in order of id.
     /// May fail if the build references an unknown pool.
-    pub fn enqueue(&mut self, id: BuildId, build: &Build) -> anyhow::Result<()> {
+    pub fn enqueue(&mut self, id: BuildId, build: &Build, first: bool) -> anyhow::Result<()> {
         self.set(id, build, BuildState::Queued);
+        self.enqueued += 1;
+        let order = if self.deterministic {
+            id.index() as i64
+        } else if first {
+            // The latest to go first goes ahead of those before it.
+            -self.enqueued
+        } else {
+            self.enqueued
+        };
+        let group = if self.fair { self.groups[id] } else { 0 };
         let pool = self.get_pool(build).ok_or_else(|| {
             anyhow::anyhow!(
                 "{}: unknown pool {:?}",
@@ -304,31 +500,198 @@ impl BuildStates {
                 build.pool.as_ref().unwrap()
             )
         })?;
-        pool.queued.push_back(id);
+        pool.queued
+            .entry(group)
+            .or_default()
+            .insert((!first, order, id));
         Ok(())
     }
 
     /// Pop a ready to run queued build.
-    pub fn pop_queued(&mut self) -> Option<BuildId> {
+    /// Builds that don't fit in the resources left are passed over for now.
+    pub fn pop_queued(&mut self, builds: &DenseMap<BuildId, Build>) -> Option<BuildId> {
+        if self.fair {
+            return self.pop_queued_fair(builds);
+        }
+        if self.deterministic {
+            return self.pop_queued_lowest(builds);
+        }
+        let resources = &self.resources;
+        let fits = |id| resources.fits(&builds[id]);
         for (_, pool) in self.pools.iter_mut() {
-            if pool.depth == 0 || pool.running < pool.depth {
-                if let Some(id) = pool.queued.pop_front() {
-                    return Some(id);
+            if pool.has_room() {
+                if let Some(key) = pool.first(0, fits) {
+                    return Some(pool.remove(0, key));
                 }
             }
         }
         None
     }
+
+    /// Like pop_queued, but takes turns between the requested targets, then
+    /// between pools, so that each makes progress rather than one target's
+    /// builds all running before the next's.
+    fn pop_queued_fair(&mut self, builds: &DenseMap<BuildId, Build>) -> Option<BuildId> {
+        let resources = &self.resources;
+        let fits = |id| resources.fits(&builds[id]);
+        let group_count = self.group.max(1);
+        let pool_count = self.pools.iter().count();
+        let (next_group, next_pool) = self.next_turn;
+        // How many turns until a group or pool is next.
+        let turns = |i: usize, next: usize, count: usize| (i + count - next % count) % count;
+        // Each pool's first build that fits, from the group next in turn
+        // that has one.
+        let (_, p, group, key) = self
+            .pools
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, pool))| pool.has_room())
+            .filter_map(|(p, (_, pool))| {
+                let next = next_group % group_count;
+                let (group, key) = pool
+                    .queued
+                    .range(next..)
+                    .chain(pool.queued.range(..next))
+                    .find_map(|(&group, _)| Some((group, pool.first(group, fits)?)))?;
+                let turn = (
+                    turns(group, next_group, group_count),
+                    turns(p, next_pool, pool_count),
+                );
+                Some((turn, p, group, key))
+            })
+            .min()?;
+        self.next_turn = (group + 1, p + 1);
+        Some(self.pools.iter_mut().nth(p).unwrap().1.remove(group, key))
+    }
+
+    /// Like pop_queued, but pops the runnable build with the lowest id from
+    /// all pools, for a reproducible order.
+    fn pop_queued_lowest(&mut self, builds: &DenseMap<BuildId, Build>) -> Option<BuildId> {
+        let resources = &self.resources;
+        let fits = |id| resources.fits(&builds[id]);
+        let (key, p) = self
+            .pools
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, pool))| pool.has_room())
+            .filter_map(|(p, (_, pool))| Some((pool.first(0, fits)?, p)))
+            .min()?;
+        Some(self.pools.iter_mut().nth(p).unwrap().1.remove(0, key))
+    }
 }
 
-#[derive(Clone)]
+/// Why a build is dirty, as reported by Options::explain.
+enum DirtyReason {
+    /// The given file, an input or an output, is missing.
+    MissingFile(FileId),
+    /// There's no record of the build having run before.
+    NoPreviousState,
+    /// The manifest differs from that of the last run.  The parts of the
+    /// manifest, previous then current, are known if they were recorded.
+    /// The inputs that changed are known exactly if their digests were, and
+    /// otherwise guessed from their mtimes.
+    ManifestChanged {
+        prev_hash: BuildHash,
+        hash: BuildHash,
+        parts: Option<(ManifestParts, ManifestParts)>,
+        inputs: Vec<hash::ChangedInput>,
+    },
+    /// Under Options::dry_run, the given input would be rebuilt first.
+    InputRebuilt(FileId),
+    /// The content of the given input, whose mtime is too coarse to tell,
+    /// differs from that of the last run.
+    ContentChanged(FileId),
+}
+
+#[derive(Clone, Default)]
 pub struct Options {
     pub failures_left: Option<usize>,
+    /// When true, n2 is a library in some other program, as through capi,
+    /// whose SIGINT isn't n2's to take; builds are cancelled with
+    /// signal::interrupt instead.
+    pub embedded: bool,
+    /// When true, commands never read our stdin, not even those in the
+    /// console pool, as with --serve, where it carries the requests.
+    pub no_stdin: bool,
     pub parallelism: usize,
     /// When true, verbosely explain why targets are considered dirty.
     pub explain: bool,
+    /// When true, along with explain, explanations are JSON records; see
+    /// Work::explain.
+    pub explain_json: bool,
     /// When true, just mark targets up to date without running anything.
     pub adopt: bool,
+    /// When false, symlinks are judged by their own mtimes rather than their
+    /// targets'.
+    pub follow_symlinks: bool,
+    /// When true, reset mtimes in the future to the present.
+    pub clamp_future: bool,
+    /// Program to run commands under, unless their build says otherwise.
+    pub wrapper: Option<String>,
+    /// Memory available to builds in bytes, shared according to their
+    /// Build::mem.  None means unlimited.
+    pub mem_limit: Option<u64>,
+    /// Output kept per task in bytes, beyond which only its head and tail
+    /// are kept.  None means unlimited.
+    pub output_limit: Option<usize>,
+    /// Output lines matching this are hidden, for all builds.
+    pub filter_output: Option<regex::bytes::Regex>,
+    /// If set, the paths of `file:line` references in output are rewritten
+    /// to be absolute or relative to another directory than the build dir.
+    pub rewrite_paths: Option<task::PathStyle>,
+    /// If set, the paths of `file:line` references in output are made OSC 8
+    /// hyperlinks to this URL template, where `{abspath}` is replaced by the
+    /// absolute path, `{path}` by the path as shown and `{line}` by the line.
+    pub hyperlinks: Option<String>,
+    /// When true, builds that are ready at the same time start in a fixed
+    /// order (that of the build files) rather than the order they became
+    /// ready.  Only with a parallelism of 1 is the whole order reproducible.
+    pub deterministic: bool,
+    /// When true, builds wanted for each requested target, and builds in each
+    /// pool, take turns in starting, so that all targets make progress at
+    /// once and early ones don't hold up the results of later ones.
+    pub fair: bool,
+    /// When true, don't run anything, but work out which builds would run;
+    /// see Work::write_plan and Work::would_update.
+    pub dry_run: bool,
+    /// If non-empty, only run builds affected by these files; see
+    /// Work::restrict_to_affected.
+    pub touched: Vec<String>,
+    /// When true, only run builds that failed last time; see
+    /// Work::restrict_to_failed.
+    pub failed: bool,
+    /// Where to push Work::stats once the build is over, if anywhere.
+    pub metrics: Option<metrics::Sink>,
+    /// If set, record the tasks that run, for Work::write_report to write an
+    /// HTML report on to this path.
+    pub report: Option<String>,
+    /// When true, ask Watchman which files changed rather than statting them
+    /// all; see Work::use_watchman.
+    pub watchman: bool,
+    /// When true, ask the NTFS change journal which files changed rather
+    /// than statting them all; see Work::use_usn_journal.
+    pub usn_journal: bool,
+    /// When true, watch the files statted with inotify, so that later builds
+    /// by the same process needn't stat them again; see Work::use_inotify.
+    pub inotify: bool,
+    /// When true, record a digest of each input of the builds that run, so
+    /// that a later explain can name the inputs that changed.
+    pub input_digests: bool,
+    /// When true, run each command under strace and warn of the files it
+    /// read that its build doesn't declare; see Work::audit_opened.
+    pub audit: bool,
+    /// When true, commands can't write to the directories of the sources;
+    /// see Work::source_dirs.
+    pub read_only_sources: bool,
+    /// When true, the first failure terminates the tasks still running,
+    /// rather than letting them finish; see Work::run_tasks.
+    pub fail_fast: bool,
+    /// When true, the progress display has a line per job slot; see
+    /// progress::FancyConsoleProgress::new.
+    pub slot_status: bool,
+    /// How often the progress display refreshes, if not the default; see
+    /// progress::FancyConsoleProgress::new and DumbConsoleProgress::new.
+    pub status_refresh: Option<Duration>,
 }
 
 pub struct Work<'a> {
@@ -338,20 +701,53 @@ pub struct Work<'a> {
     options: Options,
     file_state: FileState,
     last_hashes: Hashes,
+    last_durations: Durations,
     build_states: BuildStates,
+    estimate: Estimate,
+    /// With Options::dry_run, the builds that would run, in order.
+    plan: Vec<BuildId>,
+    /// With Options::dry_run, the builds (including phony ones) that would
+    /// update their outputs.
+    planned: HashSet<BuildId>,
+    /// If set, only these builds may run; see restrict_to_affected and
+    /// restrict_to_failed.
+    affected: Option<HashSet<BuildId>>,
+    /// Counts of what happened over all runs, for metrics.
+    stats: metrics::Stats,
+    /// With Options::report, the tasks that ran.
+    report: Option<report::Report>,
+    /// With use_watchman, use_usn_journal or use_inotify, where to record
+    /// file states and the clock they're current as of.
+    watchman: Option<(PathBuf, watchman::Changes)>,
+    /// Builds in the ready queue checked ahead of their turn by hash_ready,
+    /// with their hash if all their files were present.
+    ready_hashes: HashMap<BuildId, Option<BuildHash>>,
+    /// How many threads hash_ready may use.
+    threads: usize,
 }
 
+/// Below this many ready builds, hashing them isn't worth starting threads for.
+const PARALLEL_HASH_MIN: usize = 64;
+
 impl<'a> Work<'a> {
     pub fn new(
         graph: Graph,
         last_hashes: Hashes,
+        last_durations: Durations,
         db: db::Writer,
         options: &Options,
         progress: &'a dyn Progress,
         pools: SmallMap<String, usize>,
     ) -> Self {
-        let file_state = FileState::new(&graph);
+        let file_state = FileState::new(&graph, options.follow_symlinks, options.clamp_future);
         let build_count = graph.builds.next_id();
+        let mut build_states = BuildStates::new(
+            build_count,
+            pools,
+            Resources::new(options.parallelism, options.mem_limit),
+        );
+        build_states.deterministic = options.deterministic;
+        build_states.fair = options.fair;
         Work {
             graph,
             db,
@@ -359,7 +755,125 @@ impl<'a> Work<'a> {
             options: options.clone(),
             file_state,
             last_hashes,
-            build_states: BuildStates::new(build_count, pools),
+            last_durations,
+            build_states,
+            estimate: Estimate::default(),
+            plan: Vec::new(),
+            planned: HashSet::new(),
+            affected: None,
+            stats: metrics::Stats {
+                parallelism: options.parallelism,
+                ..Default::default()
+            },
+            report: options.report.as_ref().map(|_| report::Report::new()),
+            watchman: None,
+            ready_hashes: HashMap::new(),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Rather than statting every file, ask Watchman which have changed since
+    /// the last build and trust the states recorded in `cache` for the rest,
+    /// recording them anew there after each run.  If Watchman isn't
+    /// available, warns and stats everything as usual.
+    pub fn use_watchman(&mut self, cache: PathBuf) {
+        self.use_changes(cache, "watchman", watchman::query);
+    }
+
+    /// Like use_watchman, but asking the NTFS change journal of the volume
+    /// the build is on; see usn.rs.
+    #[cfg(windows)]
+    pub fn use_usn_journal(&mut self, cache: PathBuf) {
+        self.use_changes(cache, "usn journal", crate::usn::query);
+    }
+
+    /// Like use_watchman, but with a watcher of this process's own, which
+    /// helps later builds of a long-lived process like --serve's; see
+    /// inotify.rs.
+    #[cfg(target_os = "linux")]
+    pub fn use_inotify(&mut self, cache: PathBuf) {
+        self.use_changes(cache, "inotify", crate::inotify::query);
+    }
+
+    fn use_changes(
+        &mut self,
+        cache: PathBuf,
+        source: &str,
+        query: fn(Option<&watchman::Cache>) -> anyhow::Result<watchman::Changes>,
+    ) {
+        let cached = watchman::read_cache(&cache);
+        let mut changes = match query(cached.as_ref()) {
+            Ok(changes) => changes,
+            Err(err) => {
+                self.progress.log(&format!("n2: warn: {}: {}", source, err));
+                // Without a clock the cache could go stale unnoticed.
+                if !self.db.read_only() {
+                    let _ = std::fs::remove_file(&cache);
+                }
+                return;
+            }
+        };
+        if let (Some(cached), Some(changed)) = (cached, changes.changed.take()) {
+            for (name, mtime, fingerprint) in cached.entries {
+                if changed.contains(&name) {
+                    continue;
+                }
+                if let Some(id) = self.graph.files.lookup(&name) {
+                    self.file_state.trust(id, mtime, fingerprint);
+                }
+            }
+        }
+        self.watchman = Some((cache, changes));
+    }
+
+    /// Take the mtimes of files statted while loading as current, as with
+    /// FileState::trust; see load::State::prestat.  Only source files are
+    /// taken, as another's first stat may follow the build that writes it.
+    pub fn trust_stats(&mut self, stats: Vec<(FileId, MTime)>) {
+        for (id, mtime) in stats {
+            if self.graph.file(id).input.is_none() {
+                self.file_state.trust(id, mtime, None);
+            }
+        }
+    }
+
+    /// Stat the source files that wanted builds read all at once up front,
+    /// rather than one by one as the builds become ready, where that's faster;
+    /// see FileState::prefetch.
+    fn prefetch_stats(&mut self) {
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+        for id in (0..self.graph.builds.next_id().index()).map(BuildId::from) {
+            if self.build_states.get(id) == BuildState::Unknown {
+                continue;
+            }
+            let build = &self.graph.builds[id];
+            for &file in build.ordering_ins().iter().chain(build.discovered_ins()) {
+                if self.graph.file(file).input.is_none()
+                    && self.file_state.known(file).is_none()
+                    && seen.insert(file)
+                {
+                    files.push((file, self.graph.file(file).path()));
+                }
+            }
+        }
+        self.file_state.prefetch(&files);
+    }
+
+    /// With use_watchman, use_usn_journal or use_inotify, record the file
+    /// states known for the next build.
+    fn write_watchman_cache(&self) {
+        let (path, changes) = match &self.watchman {
+            Some(watchman) if !self.db.read_only() => watchman,
+            _ => return,
+        };
+        let entries = self.graph.files.all_ids().filter_map(|id| {
+            let (mtime, fingerprint) = self.file_state.known(id)?;
+            Some((self.graph.file(id).name.as_str(), mtime, fingerprint))
+        });
+        if let Err(err) = watchman::write_cache(path, &changes.dir, &changes.clock, entries) {
+            self.progress
+                .log(&format!("n2: warn: write {}: {}", path.display(), err));
         }
     }
 
@@ -367,9 +881,58 @@ impl<'a> Work<'a> {
         self.graph.files.lookup(&canon_path(name))
     }
 
+    /// The db, for what follows this Work; see db::Writer::continue_run.
+    pub fn into_db(self) -> db::Writer {
+        self.db
+    }
+
+    /// See Graph::lookup_target.
+    pub fn lookup_target(&self, name: &OsStr) -> anyhow::Result<FileId> {
+        self.graph.lookup_target(name)
+    }
+
+    pub fn file_name(&self, id: FileId) -> &str {
+        &self.graph.file(id).name
+    }
+
+    /// The files that some build produces under the directory dir, which is
+    /// relative to the build's as file names are; "." or "" for all of them.
+    pub fn lookup_under(&self, dir: &str) -> Vec<FileId> {
+        let dir = canon_path(dir);
+        let prefix = match dir.as_str() {
+            "" | "." => String::new(),
+            dir if dir.ends_with('/') => dir.to_owned(),
+            dir => format!("{}/", dir),
+        };
+        self.graph
+            .files
+            .all_ids()
+            .filter(|&id| {
+                let file = self.graph.file(id);
+                file.input.is_some() && file.name.starts_with(&prefix)
+            })
+            .collect()
+    }
+
+    /// The names of all files that some build produces, sorted.
+    pub fn target_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .graph
+            .files
+            .all_ids()
+            .filter(|&id| self.graph.file(id).input.is_some())
+            .map(|id| self.graph.file(id).name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
     pub fn want_file(&mut self, id: FileId) -> anyhow::Result<()> {
         let mut stack = Vec::new();
         self.build_states.want_file(&self.graph, &mut stack, id)?;
+        // Builds wanted by the next target take turns with these under
+        // Options::fair.
+        self.build_states.group += 1;
         Ok(())
     }
 
@@ -385,6 +948,58 @@ impl<'a> Work<'a> {
         Ok(())
     }
 
+    /// Only run builds affected by changes to the given files, that is, builds
+    /// that depend on them, directly or indirectly, including through
+    /// discovered deps.  Names that aren't part of the build are ignored.
+    pub fn restrict_to_affected(&mut self, names: &[String]) {
+        // File::dependents doesn't cover discovered deps, so gather our own.
+        let mut users: HashMap<FileId, Vec<BuildId>> = HashMap::new();
+        for id in self.graph.build_ids() {
+            let build = &self.graph.builds[id];
+            for &file in build.dirtying_ins().iter().chain(build.discovered_ins()) {
+                users.entry(file).or_default().push(id);
+            }
+        }
+
+        let mut affected = HashSet::new();
+        let mut stack: Vec<FileId> = names.iter().filter_map(|name| self.lookup(name)).collect();
+        while let Some(file) = stack.pop() {
+            for &id in users.get(&file).into_iter().flatten() {
+                if affected.insert(id) {
+                    stack.extend_from_slice(self.graph.builds[id].outs());
+                }
+            }
+        }
+        self.restrict(affected);
+    }
+
+    /// Only run builds that failed the last time they ran, along with those
+    /// they need built first.
+    pub fn restrict_to_failed(&mut self) {
+        let mut needed = HashSet::new();
+        let mut stack: Vec<BuildId> = self
+            .graph
+            .build_ids()
+            .filter(|&id| self.last_durations.failed(id))
+            .collect();
+        while let Some(id) = stack.pop() {
+            if needed.insert(id) {
+                let ins = self.graph.builds[id].ordering_ins();
+                stack.extend(ins.iter().filter_map(|&file| self.graph.file(file).input));
+            }
+        }
+        self.restrict(needed);
+    }
+
+    /// Only run the given builds, and only those of them that any earlier
+    /// restriction allowed.
+    fn restrict(&mut self, builds: HashSet<BuildId>) {
+        self.affected = Some(match self.affected.take() {
+            Some(affected) => affected.intersection(&builds).copied().collect(),
+            None => builds,
+        });
+    }
+
     /// Check whether a given build is ready, generally after one of its inputs
     /// has been updated.
     fn recheck_ready(&self, build: &Build) -> bool {
@@ -452,15 +1067,22 @@ impl<'a> Work<'a> {
     }
 
     /// Given a task that just finished, record any discovered deps and hash.
+    /// Returns an output the task failed to create, unless the build allows
+    /// for missing outputs.
     /// Postcondition: all outputs have been stat()ed.
-    fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<()> {
+    fn record_finished(
+        &mut self,
+        id: BuildId,
+        result: task::TaskResult,
+        duration: Duration,
+    ) -> anyhow::Result<Option<FileId>> {
         let build = &self.graph.builds[id];
 
         // Update the deps discovered from the task.
         let mut deps = Vec::new();
         if let Some(names) = result.discovered_deps {
             for name in names {
-                let fileid = self.graph.files.id_from_canonical(canon_path(name));
+                let fileid = self.graph.files.id_from_canonical(canon_path(name))?;
                 // Filter duplicates from the file list.
                 if deps.contains(&fileid) {
                     continue;
@@ -475,6 +1097,9 @@ impl<'a> Work<'a> {
             }
         }
         self.graph.builds[id].set_discovered_ins(deps);
+        if let Some(opened) = &result.opened {
+            self.audit_opened(id, opened);
+        }
         let build = &self.graph.builds[id];
 
         // Unconditionally stat all inputs and outputs.
@@ -484,23 +1109,135 @@ impl<'a> Work<'a> {
         // everything.
         let mut input_was_missing = false;
         for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
-            if self.file_state.stat(id, self.graph.file(id).path())? == MTime::Missing {
+            let before = self.file_state.get(id);
+            let mtime = self.file_state.stat(id, self.graph.file(id).path())?;
+            if mtime == MTime::Missing {
                 input_was_missing = true;
             }
+            // An input generated by another build changing while this one ran
+            // likely means two builds write the same file.
+            let file = self.graph.file(id);
+            if let (Some(producer), Some(before)) = (file.input, before) {
+                if before != mtime {
+                    self.progress.log(&format!(
+                        "n2: warn: {}: {:?}, an output of {}, changed while this build ran",
+                        build.location, file.name, self.graph.builds[producer].location
+                    ));
+                }
+            }
         }
-        let output_was_missing =
-            Self::stat_all_outputs(&self.graph, &mut self.file_state, build)?.is_some();
+        let missing_output = Self::stat_all_outputs(&self.graph, &mut self.file_state, build)?;
 
-        if input_was_missing || output_was_missing {
+        if input_was_missing || missing_output.is_some() {
             // If a file is missing, don't record the build in in the db.
             // It will be considered dirty next time anyway due to the missing file.
-            return Ok(());
+            return Ok(missing_output.filter(|_| !build.allow_missing_outputs));
         }
 
-        let hash = hash::hash_build(&self.graph.files, &mut self.file_state, build);
-        self.db.write_build(&self.graph, id, hash)?;
+        let fields = db::BuildFields {
+            hash: hash::hash_build(&self.graph.files, &self.file_state, build),
+            parts: hash::hash_build_parts(&self.graph.files, &self.file_state, build),
+            duration,
+            usage: result.usage,
+            written: db::now_secs(),
+            failed: false,
+        };
+        let digests = Self::input_digests(
+            &self.graph,
+            &mut self.file_state,
+            build,
+            self.options.input_digests,
+        );
+        self.db.write_build(&self.graph, id, &digests, &fields)?;
 
-        Ok(())
+        Ok(None)
+    }
+
+    /// With Options::read_only_sources, the directories commands mayn't write
+    /// to: those of the declared inputs that no build produces, as absolute
+    /// paths, but for any that an output, the working directory or the
+    /// temporary one is in.
+    fn source_dirs(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let cwd = std::env::current_dir()?;
+        let dir_of = |id: FileId| {
+            let path = canon_path(cwd.join(self.graph.file(id).path()).to_string_lossy());
+            PathBuf::from(path).parent().map(Path::to_path_buf)
+        };
+        let mut writable = vec![cwd.clone(), std::env::temp_dir()];
+        let mut dirs = Vec::new();
+        for id in self.graph.build_ids() {
+            let build = &self.graph.builds[id];
+            writable.extend(build.outs().iter().filter_map(|&out| dir_of(out)));
+            dirs.extend(
+                build
+                    .dirtying_ins()
+                    .iter()
+                    .filter(|&&input| self.graph.file(input).input.is_none())
+                    .filter_map(|&input| dir_of(input)),
+            );
+        }
+        dirs.sort_unstable();
+        dirs.dedup();
+        let mut read_only: Vec<PathBuf> = Vec::new();
+        for dir in dirs {
+            // Sorted, so any directory above this one came first.
+            if read_only.iter().any(|above| dir.starts_with(above))
+                || writable.iter().any(|path| path.starts_with(&dir))
+                || !dir.is_dir()
+            {
+                continue;
+            }
+            read_only.push(dir);
+        }
+        Ok(read_only)
+    }
+
+    /// With Options::audit, warn of the files in the tree a build's command
+    /// read that are neither declared as its inputs nor discovered as deps,
+    /// as changes to them won't make it dirty.
+    fn audit_opened(&self, id: BuildId, opened: &[String]) {
+        let build = &self.graph.builds[id];
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let mut undeclared = Vec::new();
+        for name in opened {
+            let path = Path::new(name);
+            let path = if path.is_absolute() {
+                match path.strip_prefix(&cwd) {
+                    Ok(path) => path,
+                    // Outside the tree, e.g. the compiler and system headers.
+                    Err(_) => continue,
+                }
+            } else {
+                path
+            };
+            let name = canon_path(path.to_string_lossy());
+            if name.starts_with("../") || name == ".." || !Path::new(&name).is_file() {
+                continue;
+            }
+            let declared = match self.graph.files.lookup(&name) {
+                Some(file) => {
+                    build.dirtying_ins().contains(&file)
+                        || build.discovered_ins().contains(&file)
+                        || build.outs().contains(&file)
+                }
+                None => false,
+            };
+            let generated = build.depfile.as_deref() == Some(name.as_str())
+                || build
+                    .rspfile
+                    .as_ref()
+                    .is_some_and(|rspfile| rspfile.path == Path::new(&name));
+            if !declared && !generated && !undeclared.contains(&name) {
+                undeclared.push(name);
+            }
+        }
+        if !undeclared.is_empty() {
+            self.progress.log(&format!(
+                "n2: warn: {}: read undeclared inputs: {}",
+                build.location,
+                undeclared.join(", ")
+            ));
+        }
     }
 
     /// Given a build that just finished, check whether its dependent builds are now ready.
@@ -612,11 +1349,15 @@ impl<'a> Work<'a> {
     /// Check a ready build for whether it needs to run, returning true if so.
     /// Prereq: any dependent input is already generated.
     fn check_build_dirty(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        // hash_ready may have already found the files present and hashed them.
+        let hashed = self.ready_hashes.remove(&id).flatten();
         let build = &self.graph.builds[id];
         let phony = build.cmdline.is_none();
         let file_missing = if phony {
             Self::check_build_files_missing_phony(&self.graph, &mut self.file_state, build)?;
             return Ok(false); // Phony builds never need to run anything.
+        } else if hashed.is_some() {
+            None
         } else {
             Self::check_build_files_missing(&self.graph, &mut self.file_state, build)?
         };
@@ -625,11 +1366,7 @@ impl<'a> Work<'a> {
         // to consider hashes.
         if let Some(missing) = file_missing {
             if self.options.explain {
-                self.progress.log(&format!(
-                    "explain: {}: input {} missing",
-                    build.location,
-                    self.graph.file(missing).name
-                ));
+                self.explain(id, DirtyReason::MissingFile(missing));
             }
             return Ok(true);
         }
@@ -643,33 +1380,452 @@ impl<'a> Work<'a> {
         let prev_hash = match self.last_hashes.get(id) {
             None => {
                 if self.options.explain {
-                    self.progress.log(&format!(
-                        "explain: {}: no previous state known",
-                        build.location
-                    ));
+                    self.explain(id, DirtyReason::NoPreviousState);
                 }
                 return Ok(true);
             }
             Some(prev_hash) => prev_hash,
         };
 
-        let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
+        let hash =
+            hashed.unwrap_or_else(|| hash::hash_build(&self.graph.files, &self.file_state, build));
         if prev_hash != hash {
             if self.options.explain {
-                self.progress
-                    .log(&format!("explain: {}: manifest changed", build.location));
-                self.progress.log(&hash::explain_hash_build(
-                    &self.graph.files,
-                    &self.file_state,
-                    build,
-                ));
+                let parts = self.last_hashes.get_parts(id).map(|prev_parts| {
+                    let parts = hash::hash_build_parts(&self.graph.files, &self.file_state, build);
+                    (prev_parts, parts)
+                });
+                let digests = hash::hash_inputs(&self.graph.files, &self.file_state, build);
+                let inputs_changed = parts.map_or(true, |(prev_parts, parts)| {
+                    parts.ins != prev_parts.ins || parts.discovered != prev_parts.discovered
+                });
+                let inputs = match self.last_hashes.get_digests(id) {
+                    Some(prev) => hash::changed_inputs(prev, &digests),
+                    None if inputs_changed => hash::inputs_modified_since(
+                        &digests,
+                        self.last_hashes.get_written(id).unwrap_or(0),
+                    ),
+                    None => Vec::new(),
+                };
+                self.explain(
+                    id,
+                    DirtyReason::ManifestChanged {
+                        prev_hash,
+                        hash,
+                        parts,
+                        inputs,
+                    },
+                );
             }
             return Ok(true);
         }
 
+        // Inputs with coarse mtimes may have been edited again within the
+        // mtime of the last run, so where their mtimes are as recorded, their
+        // content is double checked.
+        if self.last_hashes.get_digests(id).is_some() {
+            let digests = Self::input_digests(&self.graph, &mut self.file_state, build, false);
+            let prev = self.last_hashes.get_digests(id).unwrap();
+            let prev: HashMap<FileId, InputDigest> = prev.iter().copied().collect();
+            let changed = digests.iter().find(|(file, digest)| {
+                prev.get(file)
+                    .is_some_and(|prev| prev.digest != digest.digest)
+            });
+            if let Some(&(file, _)) = changed {
+                if self.options.explain {
+                    self.explain(id, DirtyReason::ContentChanged(file));
+                }
+                return Ok(true);
+            }
+        }
+
         Ok(false)
     }
 
+    /// The digests of a build's inputs to record, as made by hash::hash_inputs:
+    /// those of all of them if all is set, and otherwise just those with
+    /// coarse mtimes, whose content check_build_dirty checks.
+    /// Prerequisite: as with hash::hash_build.
+    fn input_digests(
+        graph: &Graph,
+        file_state: &mut FileState,
+        build: &Build,
+        all: bool,
+    ) -> Vec<(FileId, InputDigest)> {
+        let ins = build.dirtying_ins().iter().chain(build.discovered_ins());
+        let mut coarse = HashSet::new();
+        for &id in ins {
+            if file_state
+                .read_fingerprint(id, graph.file(id).path())
+                .is_some()
+            {
+                coarse.insert(id);
+            }
+        }
+        let mut digests = hash::hash_inputs(&graph.files, file_state, build);
+        if !all {
+            digests.retain(|(id, _)| coarse.contains(id));
+        }
+        digests
+    }
+
+    /// Take the next build from the ready queue, first hashing the builds
+    /// there with hash_ready if those it hashed last have all been taken.
+    fn pop_ready(&mut self) -> anyhow::Result<Option<BuildId>> {
+        if self.ready_hashes.is_empty() {
+            self.hash_ready()?;
+        }
+        Ok(self.build_states.pop_ready())
+    }
+
+    /// Check ahead of their turn whether the files of the builds in the ready
+    /// queue are present, and hash those that are across threads, for
+    /// check_build_dirty to pick up.  Up to date builds each hashing many
+    /// discovered deps would otherwise keep the main loop busy for a while.
+    fn hash_ready(&mut self) -> anyhow::Result<()> {
+        if self.threads == 1
+            || self.options.dry_run
+            || self.build_states.ready.len() < PARALLEL_HASH_MIN
+        {
+            return Ok(());
+        }
+        let mut jobs = Vec::new();
+        for &id in self.build_states.ready.values() {
+            if self
+                .affected
+                .as_ref()
+                .is_some_and(|affected| !affected.contains(&id))
+            {
+                continue;
+            }
+            self.ready_hashes.insert(id, None);
+            let build = &self.graph.builds[id];
+            // Builds that are dirty regardless are left to check_build_dirty,
+            // so as not to stat their files twice.
+            if build.cmdline.is_none() || self.last_hashes.get(id).is_none() {
+                continue;
+            }
+            if Self::check_build_files_missing(&self.graph, &mut self.file_state, build)?.is_none()
+            {
+                jobs.push(id);
+            }
+        }
+        if jobs.len() < PARALLEL_HASH_MIN {
+            // Keep ready_hashes, so that the builds checked above aren't
+            // checked again each time one is taken from the queue.
+            for id in jobs {
+                let hash =
+                    hash::hash_build(&self.graph.files, &self.file_state, &self.graph.builds[id]);
+                self.ready_hashes.insert(id, Some(hash));
+            }
+            return Ok(());
+        }
+
+        let (files, file_state, builds) = (&self.graph.files, &self.file_state, &self.graph.builds);
+        let hash = |jobs: &[BuildId]| -> Vec<BuildHash> {
+            jobs.iter()
+                .map(|&id| hash::hash_build(files, file_state, &builds[id]))
+                .collect()
+        };
+        let chunk_size = jobs.len().div_ceil(self.threads);
+        let hashes: Vec<BuildHash> = trace::scope("hash_ready", || {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = jobs
+                    .chunks(chunk_size)
+                    .map(|chunk| scope.spawn(move || hash(chunk)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        });
+        for (id, hash) in jobs.into_iter().zip(hashes) {
+            self.ready_hashes.insert(id, Some(hash));
+        }
+        Ok(())
+    }
+
+    /// For Options::explain, report why a build is dirty, either as text or,
+    /// with Options::explain_json, as a single line JSON object like
+    ///   {"build": "build.ninja:3", "outputs": [...], "reason": "...", ...}
+    /// where the remaining fields depend on the reason.
+    fn explain(&self, id: BuildId, reason: DirtyReason) {
+        let build = &self.graph.builds[id];
+        if !self.options.explain_json {
+            match reason {
+                DirtyReason::MissingFile(file) => self.progress.log(&format!(
+                    "explain: {}: input {} missing",
+                    build.location,
+                    self.graph.file(file).name
+                )),
+                DirtyReason::NoPreviousState => self.progress.log(&format!(
+                    "explain: {}: no previous state known",
+                    build.location
+                )),
+                DirtyReason::ManifestChanged { parts, inputs, .. } => {
+                    self.progress
+                        .log(&format!("explain: {}: manifest changed", build.location));
+                    if let Some((prev_parts, parts)) = parts {
+                        for change in parts.describe_changes(&prev_parts) {
+                            self.progress
+                                .log(&format!("explain: {}: {}", build.location, change));
+                        }
+                    }
+                    for input in inputs {
+                        let mtimes = match input.old_mtime {
+                            Some(old_mtime) => format!("mtime {} -> {}", old_mtime, input.mtime),
+                            None => format!("mtime now {}", input.mtime),
+                        };
+                        self.progress.log(&format!(
+                            "explain: {}: input {} changed ({})",
+                            build.location,
+                            self.graph.file(input.id).name,
+                            mtimes
+                        ));
+                    }
+                    self.progress.log(&hash::explain_hash_build(
+                        &self.graph.files,
+                        &self.file_state,
+                        build,
+                    ));
+                }
+                DirtyReason::InputRebuilt(_) => self.progress.log(&format!(
+                    "explain: {}: an input would be rebuilt",
+                    build.location
+                )),
+                DirtyReason::ContentChanged(file) => self.progress.log(&format!(
+                    "explain: {}: content of input {} changed",
+                    build.location,
+                    self.graph.file(file).name
+                )),
+            }
+            return;
+        }
+
+        // Writes to a Vec can't fail.
+        let mut w = Vec::new();
+        w.extend_from_slice(b"{\"build\": ");
+        plan::write_string(&mut w, &build.location.to_string()).unwrap();
+        w.extend_from_slice(b", \"outputs\": ");
+        plan::write_files(&mut w, &self.graph, build.outs()).unwrap();
+        w.extend_from_slice(b", \"reason\": ");
+        match reason {
+            DirtyReason::MissingFile(file) => {
+                plan::write_string(&mut w, "missing_file").unwrap();
+                w.extend_from_slice(b", \"file\": ");
+                plan::write_string(&mut w, &self.graph.file(file).name).unwrap();
+            }
+            DirtyReason::InputRebuilt(file) => {
+                plan::write_string(&mut w, "input_rebuilt").unwrap();
+                w.extend_from_slice(b", \"file\": ");
+                plan::write_string(&mut w, &self.graph.file(file).name).unwrap();
+            }
+            DirtyReason::ContentChanged(file) => {
+                plan::write_string(&mut w, "content_changed").unwrap();
+                w.extend_from_slice(b", \"file\": ");
+                plan::write_string(&mut w, &self.graph.file(file).name).unwrap();
+            }
+            DirtyReason::NoPreviousState => {
+                plan::write_string(&mut w, "no_previous_state").unwrap();
+            }
+            DirtyReason::ManifestChanged {
+                prev_hash,
+                hash,
+                parts,
+                inputs,
+            } => {
+                plan::write_string(&mut w, "manifest_changed").unwrap();
+                // Hashes are strings, as JSON numbers can't hold all of a u64.
+                w.extend_from_slice(
+                    format!(
+                        ", \"old_hash\": \"{:016x}\", \"new_hash\": \"{:016x}\"",
+                        prev_hash.0, hash.0
+                    )
+                    .as_bytes(),
+                );
+                // Which parts changed isn't known for builds recorded before
+                // parts were.
+                w.extend_from_slice(b", \"changed\": ");
+                match parts {
+                    Some((prev_parts, parts)) => {
+                        let changed = parts.changed_parts(&prev_parts);
+                        let changed = changed.iter().map(|part| format!("\"{}\"", part));
+                        w.extend_from_slice(
+                            format!("[{}]", changed.collect::<Vec<_>>().join(", ")).as_bytes(),
+                        );
+                        w.extend_from_slice(
+                            format!(
+                                ", \"cmdline_changed\": {}",
+                                parts.cmdline != prev_parts.cmdline
+                            )
+                            .as_bytes(),
+                        );
+                    }
+                    None => w.extend_from_slice(b"null, \"cmdline_changed\": null"),
+                }
+                // The inputs that changed, with their mtimes before if their
+                // digests were recorded.
+                w.extend_from_slice(b", \"changed_inputs\": [");
+                for (i, input) in inputs.iter().enumerate() {
+                    if i > 0 {
+                        w.extend_from_slice(b", ");
+                    }
+                    w.extend_from_slice(b"{\"file\": ");
+                    plan::write_string(&mut w, &self.graph.file(input.id).name).unwrap();
+                    let old_mtime = input
+                        .old_mtime
+                        .map_or_else(|| "null".to_string(), |mtime| mtime.to_string());
+                    w.extend_from_slice(
+                        format!(
+                            ", \"old_mtime\": {}, \"new_mtime\": {}}}",
+                            old_mtime, input.mtime
+                        )
+                        .as_bytes(),
+                    );
+                }
+                w.push(b']');
+            }
+        }
+        w.push(b'}');
+        self.progress.log(&String::from_utf8_lossy(&w));
+    }
+
+    /// For builds with atomic_outputs, move staged outputs into place if the
+    /// command succeeded, or otherwise clean them up.
+    fn finish_staged_outputs(&self, id: BuildId, success: bool) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        if !build.atomic_outputs {
+            return Ok(());
+        }
+        for &out in build.outs() {
+            let file = self.graph.file(out);
+            let path = file.path();
+            let staged = match build.staged_path(&self.graph.files, &file.name) {
+                Some(staged) => staged,
+                None => continue,
+            };
+            let result = if success {
+                std::fs::rename(&staged, path)
+            } else {
+                std::fs::remove_file(&staged)
+            };
+            match result {
+                Ok(()) => {}
+                // Not created; if needed, reported as a missing output.
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => anyhow::bail!("{}: move {:?}: {}", build.location, staged, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// After a build fails, remove any outputs it wrote, so a partially
+    /// written output isn't mistaken for a good one next time.  As in Ninja,
+    /// generator and restat builds are left alone.
+    fn remove_touched_outputs(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        if build.generator || build.restat {
+            return Ok(());
+        }
+        for &out in build.outs() {
+            let before = self.file_state.get(out);
+            let path = self.graph.file(out).path();
+            let after = self.file_state.stat(out, path)?;
+            if after == MTime::Missing || before == Some(after) {
+                continue;
+            }
+            if let Err(err) = std::fs::remove_file(canon::long_path(path)) {
+                self.progress
+                    .log(&format!("n2: warn: remove {}: {}", path.display(), err));
+                continue;
+            }
+            self.file_state.stat(out, path)?;
+        }
+        Ok(())
+    }
+
+    /// For Options::dry_run, record whether a ready build would run.
+    /// It would if it's dirty now, or if any of its inputs would be updated
+    /// by another build that would run.
+    fn plan_build(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        let input_planned = build
+            .dirtying_ins()
+            .iter()
+            .copied()
+            .find(|&file| self.would_update(file));
+        if !self.check_build_dirty(id)? && input_planned.is_none() {
+            return Ok(());
+        }
+        if let Some(file) = input_planned {
+            if self.options.explain {
+                self.explain(id, DirtyReason::InputRebuilt(file));
+            }
+        }
+        self.planned.insert(id);
+        if self.graph.builds[id].cmdline.is_some() {
+            self.plan.push(id);
+        }
+        Ok(())
+    }
+
+    /// Under Options::dry_run, whether the given file would be updated.
+    pub fn would_update(&self, id: FileId) -> bool {
+        self.graph
+            .file(id)
+            .input
+            .is_some_and(|producer| self.planned.contains(&producer))
+    }
+
+    pub fn stats(&self) -> &metrics::Stats {
+        &self.stats
+    }
+
+    /// Write the HTML report on the tasks recorded under Options::report.
+    pub fn write_report(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        match &self.report {
+            Some(report) => report.write_html(w, &self.graph),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the plan recorded under Options::dry_run as JSON.
+    pub fn write_plan(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        crate::plan::write_json(w, &self.graph, &self.plan, &self.last_durations)
+    }
+
+    /// Report the tasks that used the most memory, if any used a lot.
+    fn report_memory_hogs(&self, hogs: &MemoryHogs) {
+        if hogs.0.is_empty() {
+            return;
+        }
+        self.progress.log("n2: tasks with the highest memory use:");
+        for &(id, peak_rss) in &hogs.0 {
+            self.progress.log(&format!(
+                "  {:.1} GiB  {}",
+                peak_rss as f64 / (1u64 << 30) as f64,
+                progress::build_message(&self.graph.builds[id])
+            ));
+        }
+    }
+
+    /// Warn about any files newly found to have mtimes in the future.
+    fn warn_future_mtimes(&mut self) {
+        for (id, ahead) in self.file_state.take_future() {
+            self.progress.log(&format!(
+                "n2: warn: {}: modification time is {}s in the future{}",
+                self.graph.file(id).name,
+                ahead.as_secs(),
+                if self.options.clamp_future {
+                    ", reset to now"
+                } else {
+                    ""
+                }
+            ));
+        }
+    }
+
     /// Create the parent directories of a given list of fileids.
     /// Used to create directories used for outputs.
     /// TODO: do this within the thread executing the subtask?
@@ -680,7 +1836,7 @@ impl<'a> Work<'a> {
                 if dirs.iter().any(|&p| p == parent) {
                     continue;
                 }
-                std::fs::create_dir_all(parent)?;
+                std::fs::create_dir_all(canon::long_path(parent))?;
                 dirs.push(parent);
             }
         }
@@ -689,14 +1845,46 @@ impl<'a> Work<'a> {
 
     /// Runs the build.
     /// Returns the number of tasks executed on successful builds, or None on failed builds.
+    /// If interrupted, stops starting new tasks but still waits for (and
+    /// records the results of) the tasks already running, which shouldn't
+    /// take long as they get interrupted too.
     pub fn run(&mut self) -> anyhow::Result<Option<usize>> {
-        #[cfg(unix)]
-        signal::register_sigint();
+        let result = self.run_tasks();
+        self.write_watchman_cache();
+        result
+    }
+
+    fn run_tasks(&mut self) -> anyhow::Result<Option<usize>> {
+        if !self.options.embedded {
+            signal::register_sigint();
+        }
+        trace::scope("prefetch_stats", || self.prefetch_stats());
         let mut tasks_done = 0;
         let mut tasks_failed = 0;
-        let mut runner = task::Runner::new(self.options.parallelism);
+        let mut interrupted = false;
+        // Set once Options::failures_left runs out.  As in Ninja, no more tasks
+        // are started, but those running are waited for.
+        let mut failed_enough = false;
+        // Set once Options::fail_fast terminated the tasks running.  Those
+        // tasks then end as interrupted, their output being of no interest.
+        let mut cancelled = false;
+        let mut memory_hogs = MemoryHogs::default();
+        let read_only = if self.options.read_only_sources {
+            let dirs = self.source_dirs()?;
+            if dirs.is_empty() {
+                None
+            } else {
+                Some(process::read_only_prefix(&dirs)?)
+            }
+        } else {
+            None
+        };
+        let mut runner = task::Runner::new(&self.options, read_only);
         while self.build_states.unfinished() {
-            self.progress.update(&self.build_states.counts);
+            interrupted |= signal::was_interrupted();
+            self.warn_future_mtimes();
+            self.progress
+                .update(&self.build_states.counts, &self.estimate);
 
             // Approach:
             // - First make sure we're running as many queued tasks as the runner
@@ -709,22 +1897,37 @@ impl<'a> Work<'a> {
             //   loop.
 
             let mut made_progress = false;
-            while runner.can_start_more() {
-                let id = match self.build_states.pop_queued() {
+            while !interrupted && !failed_enough && runner.can_start_more() {
+                let id = match self.build_states.pop_queued(&self.graph.builds) {
                     Some(id) => id,
                     None => break,
                 };
                 let build = &self.graph.builds[id];
+                let expected = self.last_durations.get(id);
+                self.estimate.remove(expected);
                 self.build_states.set(id, build, BuildState::Running);
                 self.create_parent_dirs(build.outs())?;
-                runner.start(id, build);
-                self.progress.task_started(id, build);
+                runner.start(id, build, &self.graph.files);
+                self.progress.task_started(id, build, expected);
                 made_progress = true;
             }
 
-            while let Some(id) = self.build_states.pop_ready() {
-                if !self.check_build_dirty(id)? {
+            while let Some(id) = self.pop_ready()?.filter(|_| !interrupted && !failed_enough) {
+                if self
+                    .affected
+                    .as_ref()
+                    .is_some_and(|affected| !affected.contains(&id))
+                {
+                    // Leave it be, even if dirty.
+                    self.ready_dependents(id);
+                } else if self.options.dry_run {
+                    self.plan_build(id)?;
+                    self.ready_dependents(id);
+                } else if !self.check_build_dirty(id)? {
                     // Not dirty; go directly to the Done state.
+                    if self.graph.builds[id].cmdline.is_some() {
+                        self.stats.up_to_date += 1;
+                    }
                     self.ready_dependents(id);
                 } else if self.options.adopt {
                     // Act as if the target already finished.
@@ -734,11 +1937,19 @@ impl<'a> Work<'a> {
                             termination: process::Termination::Success,
                             output: vec![],
                             discovered_deps: None,
+                            usage: process::ResourceUsage::default(),
+                            opened: None,
                         },
+                        self.last_durations.get(id).unwrap_or_default(),
                     )?;
                     self.ready_dependents(id);
                 } else {
-                    self.build_states.enqueue(id, &self.graph.builds[id])?;
+                    // Finding out whether a failure was fixed is likely
+                    // what the build is for.
+                    let first = self.last_durations.failed(id);
+                    self.build_states
+                        .enqueue(id, &self.graph.builds[id], first)?;
+                    self.estimate.add(self.last_durations.get(id));
                 }
                 made_progress = true;
             }
@@ -748,43 +1959,116 @@ impl<'a> Work<'a> {
             }
 
             if !runner.is_running() {
-                if tasks_failed > 0 {
+                if tasks_failed > 0 || interrupted {
                     // No more progress can be made, hopefully due to tasks that failed.
                     break;
                 }
                 panic!("BUG: no work to do and runner not running");
             }
 
-            let task = runner.wait(|id, line| {
+            let mut task = runner.wait(|id, line| {
                 self.progress.task_output(id, line);
             });
+            if cancelled && task.result.termination != process::Termination::Success {
+                task.result.termination = process::Termination::Interrupted;
+                task.result.output.clear();
+            }
             let build = &self.graph.builds[task.buildid];
+            let (span, usage) = (task.span, task.result.usage);
             trace::if_enabled(|t| {
                 let desc = progress::build_message(build);
-                t.write_complete(desc, task.tid + 1, task.span.0, task.span.1);
+                let usage = &task.result.usage;
+                t.write_complete_with_args(
+                    desc,
+                    task.tid + 1,
+                    task.span.0,
+                    task.span.1,
+                    &[
+                        ("edge_id", task.buildid.index() as u64),
+                        ("peak_rss_kb", usage.peak_rss / 1024),
+                        ("user_ms", usage.user.as_millis() as u64),
+                        ("sys_ms", usage.sys.as_millis() as u64),
+                    ],
+                );
             });
+            memory_hogs.add(task.buildid, task.result.usage.peak_rss);
+            self.stats.busy += task.span.1.duration_since(task.span.0);
+            if let Some(report) = &mut self.report {
+                report.add(&task);
+            }
 
             self.progress
                 .task_finished(task.buildid, build, &task.result);
-            match task.result.termination {
-                process::Termination::Failure => {
-                    if let Some(failures_left) = &mut self.options.failures_left {
-                        *failures_left -= 1;
-                        if *failures_left == 0 {
-                            return Ok(None);
+            let termination = match task.result.termination {
+                process::Termination::Success => {
+                    self.finish_staged_outputs(task.buildid, true)?;
+                    let duration = task.span.1.duration_since(task.span.0);
+                    match self.record_finished(task.buildid, task.result, duration)? {
+                        Some(missing) => {
+                            self.progress.log(&format!(
+                                "n2: error: {}: command succeeded but didn't create output {}",
+                                self.graph.builds[task.buildid].location,
+                                self.graph.file(missing).name
+                            ));
+                            process::Termination::Failure
                         }
+                        None => process::Termination::Success,
+                    }
+                }
+                termination => termination,
+            };
+            if termination != process::Termination::Success {
+                if let Some(report) = &mut self.report {
+                    report.mark_failed(task.buildid);
+                }
+            }
+            match termination {
+                process::Termination::Failure => {
+                    // Tasks commonly fail when interrupted; in that case keep
+                    // waiting for the others so their results get recorded.
+                    interrupted |= signal::was_interrupted();
+                    self.stats.tasks_failed += 1;
+                    self.finish_staged_outputs(task.buildid, false)?;
+                    self.remove_touched_outputs(task.buildid)?;
+                    if !interrupted {
+                        let duration = span.1.duration_since(span.0);
+                        self.db
+                            .write_failure(&self.graph, task.buildid, duration, usage)?;
+                    }
+                    if let Some(failures_left) =
+                        self.options.failures_left.as_mut().filter(|_| !interrupted)
+                    {
+                        // Failures among the tasks still running once it ran out
+                        // don't count.
+                        *failures_left = failures_left.saturating_sub(1);
+                        failed_enough |= *failures_left == 0;
+                    }
+                    if self.options.fail_fast && !interrupted && !cancelled {
+                        cancelled = true;
+                        failed_enough = true;
+                        process::terminate_all();
                     }
                     tasks_failed += 1;
-                    self.build_states
-                        .set(task.buildid, build, BuildState::Failed);
+                    self.build_states.set(
+                        task.buildid,
+                        &self.graph.builds[task.buildid],
+                        BuildState::Failed,
+                    );
                 }
                 process::Termination::Interrupted => {
-                    // If the task was interrupted bail immediately.
-                    return Ok(None);
+                    // Stop starting new work, but let the remaining tasks finish.
+                    interrupted = true;
+                    self.finish_staged_outputs(task.buildid, false)?;
+                    self.remove_touched_outputs(task.buildid)?;
+                    self.build_states.set(
+                        task.buildid,
+                        &self.graph.builds[task.buildid],
+                        BuildState::Failed,
+                    );
                 }
                 process::Termination::Success => {
                     tasks_done += 1;
-                    self.record_finished(task.buildid, task.result)?;
+                    self.stats.tasks_run += 1;
                     self.ready_dependents(task.buildid);
                 }
             };
@@ -794,7 +2078,9 @@ impl<'a> Work<'a> {
         // But at least for the LLVM test suite it can catch sigint and print
         // "interrupted by user" and exit with success, and in that case we
         // don't want n2 to print a "succeeded" message afterwards.
-        let success = tasks_failed == 0 && !signal::was_interrupted();
+        self.warn_future_mtimes();
+        self.report_memory_hogs(&memory_hogs);
+        let success = tasks_failed == 0 && !interrupted && !signal::was_interrupted();
         Ok(success.then_some(tasks_done))
     }
 }
@@ -811,8 +2097,12 @@ build b: phony c
 build c: phony a
 ";
         let mut graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
-        let a_id = graph.files.id_from_canonical("a".to_owned());
-        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
+        let a_id = graph.files.id_from_canonical("a".to_owned())?;
+        let mut states = BuildStates::new(
+            graph.builds.next_id(),
+            SmallMap::default(),
+            Resources::new(1, None),
+        );
         let mut stack = Vec::new();
         match states.want_file(&graph, &mut stack, a_id) {
             Ok(_) => panic!("expected build cycle error"),
@@ -820,4 +2110,180 @@ build c: phony a
         }
         Ok(())
     }
+
+    #[test]
+    fn memory_hogs() {
+        let gib = 1u64 << 30;
+        let mut hogs = MemoryHogs::default();
+        hogs.add(BuildId::from(0), gib);
+        hogs.add(BuildId::from(1), 1);
+        hogs.add(BuildId::from(2), 3 * gib);
+        hogs.add(BuildId::from(3), 2 * gib);
+        hogs.add(BuildId::from(4), 4 * gib);
+        assert_eq!(
+            hogs.0,
+            vec![
+                (BuildId::from(4), 4 * gib),
+                (BuildId::from(2), 3 * gib),
+                (BuildId::from(3), 2 * gib),
+            ]
+        );
+    }
+
+    #[test]
+    fn resources() -> anyhow::Result<()> {
+        let file = "
+rule big
+    command = big
+    mem = 6G
+    cores = 2
+rule small
+    command = small
+build big1: big
+build big2: big
+build small: small
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let big = &graph.builds[BuildId::from(0)];
+        let small = &graph.builds[BuildId::from(2)];
+
+        let mut resources = Resources::new(4, Some(8 << 30));
+        assert!(resources.fits(big));
+        resources.acquire(big);
+        // Memory is exhausted, but small doesn't use any.
+        assert!(!resources.fits(big));
+        assert!(resources.fits(small));
+        resources.acquire(small);
+        resources.acquire(small);
+        // Now cores are exhausted.
+        assert!(!resources.fits(small));
+        resources.release(big);
+        assert!(resources.fits(big));
+
+        // A build bigger than the whole machine still gets to run alone.
+        let resources = Resources::new(1, Some(1 << 30));
+        assert!(resources.fits(big));
+        Ok(())
+    }
+
+    #[test]
+    fn deterministic_order() -> anyhow::Result<()> {
+        let file = "
+pool p
+  depth = 1
+rule cmd
+    command = cmd
+build a: cmd
+build b: cmd
+    pool = p
+build c: cmd
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let mut pools = SmallMap::default();
+        pools.insert("p".to_owned(), 1);
+        let mut states = BuildStates::new(graph.builds.next_id(), pools, Resources::new(4, None));
+        states.deterministic = true;
+        for id in [2, 1, 0].map(BuildId::from) {
+            states.enqueue(id, &graph.builds[id], false)?;
+        }
+        let order: Vec<BuildId> = std::iter::from_fn(|| states.pop_queued(&graph.builds)).collect();
+        assert_eq!(order, [0, 1, 2].map(BuildId::from));
+        Ok(())
+    }
+
+    #[test]
+    fn deterministic_ready_order() -> anyhow::Result<()> {
+        let file = "
+rule cmd
+    command = cmd
+build a: cmd
+build b: cmd
+build c: cmd
+build all: phony c a b
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        for (deterministic, expected) in [(false, ["c", "a", "b"]), (true, ["a", "b", "c"])] {
+            let mut states = BuildStates::new(
+                graph.builds.next_id(),
+                SmallMap::default(),
+                Resources::new(4, None),
+            );
+            states.deterministic = deterministic;
+            let mut stack = Vec::new();
+            states.want_file(&graph, &mut stack, graph.files.lookup("all").unwrap())?;
+            let order: Vec<&str> = std::iter::from_fn(|| states.pop_ready())
+                .map(|id| graph.file(graph.builds[id].outs()[0]).name.as_str())
+                .collect();
+            assert_eq!(order, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn first_goes_first() -> anyhow::Result<()> {
+        let file = "
+rule cmd
+    command = cmd
+build a: cmd
+build b: cmd
+build c: cmd
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        for (deterministic, fair, expected) in [
+            (false, false, [2, 1, 0]),
+            (true, false, [1, 2, 0]),
+            (false, true, [2, 1, 0]),
+        ] {
+            let mut states = BuildStates::new(
+                graph.builds.next_id(),
+                SmallMap::default(),
+                Resources::new(4, None),
+            );
+            states.deterministic = deterministic;
+            states.fair = fair;
+            for (id, first) in [(0, false), (1, true), (2, true)] {
+                let id = BuildId::from(id);
+                states.enqueue(id, &graph.builds[id], first)?;
+            }
+            let order: Vec<BuildId> =
+                std::iter::from_fn(|| states.pop_queued(&graph.builds)).collect();
+            assert_eq!(order, expected.map(BuildId::from));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fair_order() -> anyhow::Result<()> {
+        let file = "
+rule cmd
+    command = cmd
+build a1: cmd
+build a2: cmd
+build a3: cmd
+build a: phony a1 a2 a3
+build b1: cmd
+build b2: cmd
+build b: phony b1 b2
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let mut states = BuildStates::new(
+            graph.builds.next_id(),
+            SmallMap::default(),
+            Resources::new(4, None),
+        );
+        states.fair = true;
+        for name in ["a", "b"] {
+            let mut stack = Vec::new();
+            states.want_file(&graph, &mut stack, graph.files.lookup(name).unwrap())?;
+            states.group += 1;
+        }
+        while let Some(id) = states.pop_ready() {
+            states.enqueue(id, &graph.builds[id], false)?;
+        }
+        let order: Vec<&str> = std::iter::from_fn(|| states.pop_queued(&graph.builds))
+            .map(|id| graph.file(graph.builds[id].outs()[0]).name.as_str())
+            .collect();
+        assert_eq!(order, ["a1", "b1", "a2", "b2", "a3"]);
+        Ok(())
+    }
 }
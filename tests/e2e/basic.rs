@@ -198,6 +198,8 @@ fn explain() -> anyhow::Result<()> {
     let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
     // The main "explain" log line:
     assert_output_contains(&out, "explain: build.ninja:6: manifest changed");
+    assert_output_contains(&out, "explain: build.ninja:6: inputs changed");
+    assert_output_not_contains(&out, "command line changed");
     // The dump of the file manifest after includes mtimes that we don't want
     // to be sensitive to, so just look for some bits we know show up there.
     assert_output_contains(&out, "discovered:");
@@ -205,6 +207,60 @@ fn explain() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn explain_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain=json", "out"]))?;
+    assert_output_contains(
+        &out,
+        r#"{"build": "build.ninja:6", "outputs": ["out"], "reason": "missing_file", "file": "out"}"#,
+    );
+
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain=json", "out"]))?;
+    assert_output_contains(&out, r#""reason": "manifest_changed", "old_hash": ""#);
+    assert_output_contains(
+        &out,
+        r#", "changed": ["inputs"], "cmdline_changed": false, "changed_inputs": [{"file": "in", "old_mtime": null, "new_mtime": "#,
+    );
+    assert_output_not_contains(&out, "explain:");
+
+    Ok(())
+}
+
+#[test]
+fn explain_input_digests() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in1 in2", ""].join("\n"),
+    )?;
+    space.write("in1", "")?;
+    space.write("in2", "")?;
+    space.run_expect(&mut n2_command(vec!["--input-digests", "out"]))?;
+
+    space.sub_mtime("in2", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(&out, "explain: build.ninja:6: input in2 changed (mtime ");
+    assert_output_not_contains(&out, "mtime now");
+    assert_output_not_contains(&out, "input in1 changed");
+
+    // That run didn't record digests, so the inputs modified since it are
+    // named instead, without their mtimes before.
+    space.write("in1", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain=json", "out"]))?;
+    assert_output_contains(
+        &out,
+        r#""changed_inputs": [{"file": "in1", "old_mtime": null, "new_mtime": "#,
+    );
+    Ok(())
+}
+
 /// Meson generates a build step that writes to one of its inputs.
 #[test]
 fn write_to_input() -> anyhow::Result<()> {
@@ -249,6 +305,7 @@ fn showincludes() -> anyhow::Result<()> {
 build out: echo
   text = Note: including file: foo
   deps = msvc
+  allow_missing_outputs = 1
 ",
         ]
         .join("\n"),
@@ -280,6 +337,7 @@ rule custom
   command = $cmd $var
 build out: custom
   cmd = echo $var hello
+  allow_missing_outputs = 1
 ",
         ]
         .join("\n"),
@@ -311,7 +369,7 @@ build out3: phony out2
     Ok(())
 }
 
-// builddir controls where .n2_db is written.
+// builddir controls where .n2_db and other metadata are written.
 #[test]
 fn builddir() -> anyhow::Result<()> {
     let space = TestSpace::new()?;
@@ -327,6 +385,10 @@ fn builddir() -> anyhow::Result<()> {
     )?;
     space.run_expect(&mut n2_command(vec!["foo/bar"]))?;
     space.read("foo/.n2_db")?;
+    // As does the trace.
+    space.run_expect(&mut n2_command(vec!["-d", "trace", "foo/bar"]))?;
+    space.read("foo/trace.json")?;
+    assert!(space.read("trace.json").is_err());
     Ok(())
 }
 
@@ -450,3 +512,1928 @@ build foo: write_file
     assert_eq!(space.read("foo")?, b"Hello, world!\n");
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn pty() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch_if_tty
+    command = [ -t 1 ] && [ -t 2 ] && touch $out
+
+build tty: touch_if_tty
+    pty = 1
+build pipe: touch_if_tty
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["tty"]))?;
+    assert!(space.read("tty").is_ok());
+    let out = space.run(&mut n2_command(vec!["pipe"]))?;
+    assert!(!out.status.success());
+    Ok(())
+}
+
+/// env_ bindings set the command's environment and affect the build hash.
+#[cfg(unix)]
+#[test]
+fn rule_env() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo_env
+    command = echo $$GREETING $$SUBJECT > $out
+    env_GREETING = hello
+
+build out: echo_env
+    env_SUBJECT = world
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"hello world\n");
+
+    space.write(
+        "build.ninja",
+        "
+rule echo_env
+    command = echo $$GREETING $$SUBJECT > $out
+    env_GREETING = hello
+
+build out: echo_env
+    env_SUBJECT = there
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"hello there\n");
+    Ok(())
+}
+
+/// A PATH set by env_PATH is where the command's program is looked for, as
+/// the shell would, even when n2 runs it directly.
+#[cfg(unix)]
+#[test]
+fn env_path() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.run_expect(std::process::Command::new("mkdir").args(["bin", "ours"]))?;
+    space.write("bin/greet", "#!/bin/sh\necho hello > $1\n")?;
+    // One by the same name in n2's own PATH isn't the one run.
+    space.write("ours/greet", "#!/bin/sh\necho wrong > $1\n")?;
+    space.run_expect(std::process::Command::new("chmod").args([
+        "+x",
+        "bin/greet",
+        "ours/greet",
+    ]))?;
+    space.write(
+        "build.ninja",
+        "
+rule greet
+    command = greet $out
+    env_PATH = bin:/usr/bin:/bin
+build out: greet
+",
+    )?;
+    space.run_expect(
+        n2_command(vec!["out"]).env("PATH", format!("ours:{}", std::env::var("PATH").unwrap())),
+    )?;
+    assert_eq!(space.read("out")?, b"hello\n");
+    Ok(())
+}
+
+/// --wrap prefixes commands, unless the build sets its own wrapper, and
+/// doesn't affect whether builds are up to date.
+#[cfg(unix)]
+#[test]
+fn wrap() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo_wrapped
+    command = sh -c 'echo $$WRAPPED' > $out
+
+build wrapped: echo_wrapped
+build unwrapped: echo_wrapped
+    wrapper =
+build rewrapped: echo_wrapped
+    wrapper = env WRAPPED=build
+",
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "--wrap",
+        "env WRAPPED=flag",
+        "wrapped",
+        "unwrapped",
+        "rewrapped",
+    ]))?;
+    assert_eq!(space.read("wrapped")?, b"flag\n");
+    assert_eq!(space.read("unwrapped")?, b"\n");
+    assert_eq!(space.read("rewrapped")?, b"build\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["wrapped"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// Commands run without a shell still report missing programs.
+#[cfg(unix)]
+#[test]
+fn missing_program() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule missing
+    command = n2-no-such-program $out
+
+build out: missing
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2-no-such-program");
+    assert_output_contains(&out, "not found");
+    Ok(())
+}
+
+/// Tasks' resource usage shows up in the trace.
+#[test]
+fn trace_resource_usage() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["-d", "trace", "out"]))?;
+    let trace = String::from_utf8(space.read("trace.json")?)?;
+    assert!(trace.contains("\"peak_rss_kb\":"));
+    assert!(trace.contains("\"user_ms\":"));
+    assert!(trace.contains("\"edge_id\":"));
+    assert!(trace.contains("\"name\":\"process_name\""));
+    Ok(())
+}
+
+/// `-d trace=PATH` writes the trace there as the build goes, so that it
+/// survives n2 being killed.
+#[cfg(unix)]
+#[test]
+fn trace_path() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = touch $out.started && sleep 30
+build out: slow
+",
+    )?;
+    let mut n2 = space.spawn(&mut n2_command(vec!["-d", "trace=traces/t.json", "out"]))?;
+    space.wait_for("out.started")?;
+    n2.kill()?;
+    n2.wait()?;
+    let trace = String::from_utf8(space.read("traces/t.json")?)?;
+    assert!(trace.contains("\"name\":\"build_info\""));
+    assert!(trace.contains("\"argv\":["));
+    assert!(trace.contains("\"parallelism\":"));
+    assert!(trace.contains("\"name\":\"load::read\""));
+    assert!(space.read("trace.json").is_err());
+    Ok(())
+}
+
+/// Commands can tell which run of n2, and which build in it, they're for.
+#[cfg(unix)]
+#[test]
+fn build_id_env() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule ids
+    command = echo $$N2_BUILD_ID $$N2_EDGE_ID > $out
+
+build a: ids
+build b: ids
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+    let a = String::from_utf8(space.read("a")?)?;
+    let b = String::from_utf8(space.read("b")?)?;
+    let (a_build, a_edge) = a.trim().split_once(' ').unwrap();
+    let (b_build, b_edge) = b.trim().split_once(' ').unwrap();
+    let uuid =
+        regex::Regex::new("^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$")?;
+    assert!(uuid.is_match(a_build), "{}", a_build);
+    assert_eq!(a_build, b_build);
+    assert_ne!(a_edge, b_edge);
+    Ok(())
+}
+
+/// Builds whose declared memory doesn't fit together don't run together.
+#[cfg(unix)]
+#[test]
+fn mem_limit() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule exclusive
+    command = mkdir lock && sleep 0.2 && rmdir lock && touch $out
+    mem = 600M
+
+build a: exclusive
+build b: exclusive
+",
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-j",
+        "2",
+        "--mem-limit",
+        "1G",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
+
+/// Huge task output is cut down to its head and tail.
+#[cfg(unix)]
+#[test]
+fn output_limit() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule spew
+    command = echo first; seq 100000; echo last; false
+
+build out: spew
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["--output-limit", "1K", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "first");
+    assert_output_contains(&out, "bytes of output omitted");
+    assert_output_contains(&out, "last");
+    assert_output_not_contains(&out, "50000");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let log = stdout
+        .split("omitted, see ")
+        .nth(1)
+        .and_then(|rest| rest.split(" for all").next())
+        .expect("log path")
+        .to_owned();
+    assert!(std::fs::read_to_string(&log)?.contains("50000"));
+
+    // The log goes once the build runs without needing it.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "50000");
+    assert!(!std::path::Path::new(&log).exists());
+    Ok(())
+}
+
+/// filter_output and --filter-output hide matching lines of output.
+#[cfg(unix)]
+#[test]
+fn filter_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule noisy
+    command = printf '%s\\n' 'Creating lib''rary' 'progress'' 1' 'real'' error'; false
+    filter_output = ^Creating
+
+build out: noisy
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_not_contains(&out, "Creating library");
+    assert_output_contains(&out, "progress 1");
+    assert_output_contains(&out, "real error");
+    // As the command failed, the hidden line is kept in a log.
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let log = stdout
+        .split("1 filtered lines hidden, see ")
+        .nth(1)
+        .and_then(|rest| rest.split(" for all").next())
+        .expect("log path");
+    let log = std::fs::read_to_string(log)?;
+    assert!(log.contains("Creating library"));
+    assert!(log.contains("real error"));
+
+    let out = space.run(&mut n2_command(vec!["--filter-output", "^progress", "out"]))?;
+    assert_output_not_contains(&out, "Creating library");
+    assert_output_not_contains(&out, "progress 1");
+    assert_output_contains(&out, "real error");
+    Ok(())
+}
+
+/// --deterministic runs builds in the order of the build file.
+#[cfg(unix)]
+#[test]
+fn deterministic() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule log
+    command = echo $out >> log && touch $out
+
+build c: log
+build a: log
+build b: log
+build d: log c a b
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["--deterministic", "d", "b", "a"]))?;
+    assert_eq!(space.read("log")?, b"c\na\nb\nd\n");
+    Ok(())
+}
+
+/// --dump-plan lists the builds that would run, without running them.
+#[test]
+fn dump_plan() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out", "other"]))?;
+
+    // Only "in" changed, but "out" depends on "mid", which would change.
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--dump-plan", "out", "other"]))?;
+    let plan = std::str::from_utf8(&out.stdout)?;
+    let mid = plan.find("\"outputs\": [\"mid\"]").expect("mid in plan");
+    let out_pos = plan.find("\"outputs\": [\"out\"]").expect("out in plan");
+    assert!(mid < out_pos);
+    assert!(!plan.contains("other"));
+    assert!(plan.contains("\"predicted_ms\": "));
+    assert!(plan.trim_start().starts_with('['));
+    assert!(plan.trim_end().ends_with(']'));
+
+    // Nothing actually ran.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+/// --touched only builds what the given files affect.
+#[test]
+fn touched() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in1",
+            "build out: touch mid",
+            "build other: touch in2",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in1", "")?;
+    space.write("in2", "")?;
+    space.run_expect(&mut n2_command(vec!["out", "other"]))?;
+
+    space.write("in1", "changed")?;
+    space.write("in2", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "--touched",
+        "in1",
+        "--touched",
+        "unrelated",
+        "out",
+        "other",
+    ]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    let out = space.run_expect(&mut n2_command(vec!["out", "other"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// -t explain says why a target is dirty, without building it.
+#[test]
+fn explain_tool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "explain", "out"]))?;
+    assert_output_contains(&out, "n2: out is up to date");
+
+    space.write("in", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "explain", "out"]))?;
+    assert_output_contains(&out, "explain: build.ninja:");
+    assert_output_contains(&out, "an input would be rebuilt");
+    assert_output_contains(&out, "n2: out is dirty");
+
+    // Nothing was built.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+#[test]
+fn parse_error_include_chain() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "x = 1\ninclude mid.ninja\n")?;
+    space.write("mid.ninja", "# comment\n\nsubninja bad.ninja\n")?;
+    space.write(
+        "bad.ninja",
+        "rule touch\n  command = touch $out\nbuild out touch in\n",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "parse error: expected ':'");
+    assert_output_contains(&out, "bad.ninja:3:19: build out touch in\n");
+    assert_output_contains(
+        &out,
+        "^\nincluded from mid.ninja:3\nincluded from build.ninja:2\n",
+    );
+    Ok(())
+}
+
+#[test]
+fn manifest_from_stdin() -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let space = TestSpace::new()?;
+    space.write("in", "")?;
+    space.write("rules.ninja", TOUCH_RULE)?;
+    let mut child =
+        space.spawn(n2_command(vec!["-f", "-", "out"]).stdin(std::process::Stdio::piped()))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"include rules.ninja\nbuild out: touch in\n")?;
+    let out = child.wait_with_output()?;
+    assert!(out.status.success());
+    assert_output_contains(&out, "ran 1 task");
+    space.read("out")?;
+    Ok(())
+}
+
+#[test]
+fn multiple_build_files() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", "default out", ""].join("\n"),
+    )?;
+    // A local overlay, using the rule from the main file.
+    space.write("local.ninja", "build extra: touch out\ndefault extra\n")?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-f",
+        "build.ninja",
+        "-f",
+        "local.ninja",
+    ]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    space.read("extra")?;
+    Ok(())
+}
+
+#[test]
+fn include_cycle() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "include a.ninja\n")?;
+    space.write("a.ninja", "include b.ninja\n")?;
+    space.write("b.ninja", "subninja a.ninja\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "include cycle: a.ninja -> b.ninja -> a.ninja\n");
+    Ok(())
+}
+
+#[test]
+fn pick_needs_terminal() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["--pick"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "--pick needs a terminal");
+    // Without a terminal and without targets, everything is built as usual.
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn metrics_statsd() -> anyhow::Result<()> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    socket.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    let url = format!("statsd://{}", socket.local_addr()?);
+
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+
+    // A dry run pushes nothing.
+    space.run_expect(&mut n2_command(vec!["--metrics", &url, "--dump-plan"]))?;
+    socket.set_nonblocking(true)?;
+    let mut buf = [0u8; 1024];
+    assert!(socket.recv(&mut buf).is_err());
+    socket.set_nonblocking(false)?;
+
+    space.run_expect(&mut n2_command(vec![
+        "--metrics",
+        &url,
+        "--metrics-label",
+        "team=build",
+    ]))?;
+
+    let len = socket.recv(&mut buf)?;
+    let packet = std::str::from_utf8(&buf[..len])?;
+    assert!(
+        packet.contains("n2.tasks_run:1|c|#team:build\n"),
+        "{}",
+        packet
+    );
+    assert!(
+        packet.contains("n2.tasks_failed:0|c|#team:build\n"),
+        "{}",
+        packet
+    );
+    Ok(())
+}
+
+#[test]
+fn html_report() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule fail",
+            "  command = echo 'it <broke>' && exit 1",
+            "build mid: touch",
+            "build out: fail mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["--report", "report.html", "out"]))?;
+    assert!(!out.status.success());
+    let report = String::from_utf8(space.read("report.html")?)?;
+    assert!(report.contains("2 tasks, 1 failed"), "{}", report);
+    assert!(
+        report.contains("<pre>it &lt;broke&gt;\n</pre>"),
+        "{}",
+        report
+    );
+    assert!(report.contains("<td>touch</td><td>1</td>"), "{}", report);
+    assert!(
+        report.contains("class=\"task critical failed\""),
+        "{}",
+        report
+    );
+    Ok(())
+}
+
+/// Stands in for Watchman, reporting as changed the files listed in the file
+/// "changed".
+#[cfg(unix)]
+const FAKE_WATCHMAN: &str = r#"#!/bin/sh
+req=$(cat)
+case "$req" in
+  *'"query"'*) echo "{\"clock\": \"c:2\", \"files\": [$(cat changed)]}" ;;
+  *'"clock"'*) echo '{"clock": "c:1"}' ;;
+  *) echo "{\"watch\": \"$(pwd)\"}" ;;
+esac
+"#;
+
+#[cfg(unix)]
+#[test]
+fn watchman() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+build out: cp in
+",
+    )?;
+    space.write("watchman", FAKE_WATCHMAN)?;
+    space.run_expect(std::process::Command::new("chmod").args(["+x", "watchman"]))?;
+    let path = format!(".:{}", std::env::var("PATH")?);
+    let n2 = || {
+        let mut cmd = n2_command(vec!["--watchman"]);
+        cmd.env("PATH", &path);
+        cmd
+    };
+
+    space.write("in", "a")?;
+    space.write("changed", "")?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Watchman says nothing changed, so the change goes unnoticed.
+    space.write("in", "b")?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("changed", "\"in\"")?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"b");
+
+    // If Watchman fails, everything is statted.
+    space.write("in", "c")?;
+    space.write("changed", "garbage")?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "n2: warn: watchman:");
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// Paths needn't be UTF-8, as in trees with Latin-1 file names.  (Linux only
+/// as other systems insist on UTF-8 file names.)
+#[cfg(target_os = "linux")]
+#[test]
+fn non_utf8_paths() -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let space = TestSpace::new()?;
+    let sh = |script: &str| {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    };
+    space.run_expect(&mut sh(
+        r"printf 'rule cp\n  command = cp $in $out\nbuild caf\351.out: cp caf\351.in\n' > build.ninja
+          printf x > $(printf 'caf\351.in')",
+    ))?;
+
+    let target = std::ffi::OsStr::from_bytes(b"caf\xe9.out");
+    let out = space.run_expect(n2_command(vec![]).arg(target))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(n2_command(vec![]).arg(target))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Output that must be UTF-8 gets replacement characters.
+    space.run_expect(&mut sh(r"touch $(printf 'caf\351.in')"))?;
+    let out = space.run_expect(&mut n2_command(vec!["--dump-plan"]))?;
+    let plan = String::from_utf8(out.stdout)?;
+    assert!(plan.contains("\"caf\u{fffd}.out\""), "{}", plan);
+    Ok(())
+}
+
+#[test]
+fn subninja_defaults() -> anyhow::Result<()> {
+    let setup = || -> anyhow::Result<TestSpace> {
+        let space = TestSpace::new()?;
+        space.write(
+            "build.ninja",
+            &[
+                TOUCH_RULE,
+                "build top: touch",
+                "default top",
+                "subninja sub.ninja",
+                "",
+            ]
+            .join("\n"),
+        )?;
+        space.write(
+            "sub.ninja",
+            "build sub: touch\ndefault sub\nbuild other: touch\n",
+        )?;
+        Ok(space)
+    };
+
+    // Defaults in subninjas count along with the top-level ones.
+    let space = setup()?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    space.read("sub")?;
+    assert!(space.read("other").is_err());
+
+    let space = setup()?;
+    let out = space.run_expect(&mut n2_command(vec!["--top-level-defaults"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.read("top")?;
+    assert!(space.read("sub").is_err());
+    Ok(())
+}
+
+#[test]
+fn dbinfo() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    // Rebuild, superseding the first record.
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "dbinfo", "out"]))?;
+    assert_output_contains(&out, "build records: 2\n");
+    assert_output_contains(&out, "superseded or obsolete: 1 (50%)\n");
+    assert_output_contains(&out, "out:\n  build: build.ninja:");
+    assert_output_contains(&out, "  outs:\n    out\n");
+    // Inspecting the db doesn't build anything.
+    assert_output_not_contains(&out, "ran ");
+    Ok(())
+}
+
+#[test]
+fn no_db_write() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--no-db-write", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    // No db is created, so the next build doesn't know out is up to date.
+    assert!(space.read(".n2_db").is_err());
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // An existing db is read but left as it was.
+    let db = space.read(".n2_db")?;
+    let out = space.run_expect(&mut n2_command(vec!["--no-db-write", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--no-db-write", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read(".n2_db")?, db);
+    Ok(())
+}
+
+#[test]
+fn db_path() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--db-path", "state/db", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.read("state/db")?;
+    assert!(space.read(".n2_db").is_err());
+    let out = space.run_expect(&mut n2_command(vec!["--db-path", "state/db", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// Enough ready builds at once are hashed across threads.
+#[test]
+fn many_ready_builds() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let mut manifest = vec![TOUCH_RULE.to_string()];
+    let mut all = "build all: phony".to_string();
+    for i in 0..200 {
+        manifest.push(format!("build out{}: touch in{}", i, i));
+        all.push_str(&format!(" out{}", i));
+        space.write(&format!("in{}", i), "")?;
+    }
+    manifest.push(all);
+    manifest.push(String::new());
+    space.write("build.ninja", &manifest.join("\n"))?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 200 tasks");
+
+    space.sub_mtime("in150", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "all"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_contains(&out, "explain: build.ninja:156: manifest changed");
+
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// -k N stops starting tasks after N failures, but not before.
+#[cfg(unix)]
+#[test]
+fn keep_going() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule fail",
+            "  command = exit 1",
+            "build fail1: fail",
+            "build fail2: fail",
+            "build fail3: fail",
+            "build out: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let targets = vec!["fail1", "fail2", "fail3", "out"];
+
+    let out = space.run(&mut n2_command(
+        [&["-j", "1", "-k", "2"][..], &targets].concat(),
+    ))?;
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?.matches("failed:").count(), 2);
+
+    // With no limit, everything that can run does.
+    let out = space.run(&mut n2_command([&["-k", "0"][..], &targets].concat()))?;
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?.matches("failed:").count(), 3);
+    space.read("out")?;
+    Ok(())
+}
+
+/// Running out of failures lets the tasks still running finish.
+#[cfg(unix)]
+#[test]
+fn keep_going_waits() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 0.3 && touch $out
+rule fail
+  command = exit 1
+build slow: slow
+build fail: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["-j", "2", "slow", "fail"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "failed:");
+    space.read("slow")?;
+
+    // It was recorded as built, too.
+    let out = space.run_expect(&mut n2_command(vec!["slow"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// --fail-fast terminates the tasks still running on the first failure.
+#[cfg(unix)]
+#[test]
+fn fail_fast() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 10 && touch $out
+  description = slow $out
+rule fail
+  command = sleep 0.1 && exit 1
+  description = fail $out
+build slow: slow
+build fail: fail
+",
+    )?;
+    let start = std::time::Instant::now();
+    let out = space.run(&mut n2_command(vec![
+        "-j",
+        "2",
+        "--fail-fast",
+        "slow",
+        "fail",
+    ]))?;
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(!out.status.success());
+    assert_output_contains(&out, "failed: fail fail");
+    assert_output_contains(&out, "interrupted: slow slow");
+    assert!(space.read("slow").is_err());
+    Ok(())
+}
+
+/// --kill-grace-ms gives terminated tasks time to clean up after themselves.
+#[cfg(unix)]
+#[test]
+fn kill_grace() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = trap 'sleep 1; touch cleaned; exit 1' TERM; sleep 10; touch $out
+rule fail
+  command = sleep 0.1 && exit 1
+build slow: slow
+build fail: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec![
+        "-j",
+        "2",
+        "--fail-fast",
+        "--kill-grace-ms",
+        "5000",
+        "slow",
+        "fail",
+    ]))?;
+    assert!(!out.status.success());
+    assert!(space.read("cleaned").is_ok());
+    assert!(space.read("slow").is_err());
+    Ok(())
+}
+
+/// Builds that failed last time start ahead of others.
+#[cfg(unix)]
+#[test]
+fn failed_first() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo
+  command = echo ran $out && test -f $in.ok && touch $out
+build a: echo a.in
+build b: echo b.in
+",
+    )?;
+    space.write("a.in", "")?;
+    space.write("b.in", "")?;
+    space.write("a.in.ok", "")?;
+    let out = space.run(&mut n2_command(vec!["-j", "1", "-k", "0", "a", "b"]))?;
+    assert_output_contains(&out, "failed: echo ran b");
+
+    space.write("b.in.ok", "")?;
+    space.sub_mtime("a.in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-j", "1", "a", "b"]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert!(stdout.find("ran b").unwrap() < stdout.find("ran a").unwrap());
+
+    // Once it succeeds, it takes its turn again.
+    space.sub_mtime("a.in", std::time::Duration::from_secs(2))?;
+    space.sub_mtime("b.in", std::time::Duration::from_secs(2))?;
+    let out = space.run_expect(&mut n2_command(vec!["-j", "1", "a", "b"]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert!(stdout.find("ran a").unwrap() < stdout.find("ran b").unwrap());
+    Ok(())
+}
+
+/// --failed only runs what failed last time, and what it needs first.
+#[cfg(unix)]
+#[test]
+fn only_failed() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo
+  command = echo ran $out && test -f $in.ok && touch $out
+build a: echo a.in
+build gen: echo gen.in
+build b: echo b.in || gen
+build c: echo c.in | b
+",
+    )?;
+    for name in ["a", "b", "c", "gen"] {
+        space.write(&format!("{}.in", name), "")?;
+    }
+    space.write("a.in.ok", "")?;
+    space.write("gen.in.ok", "")?;
+    space.write("c.in.ok", "")?;
+    let out = space.run(&mut n2_command(vec!["-k", "0", "a", "c"]))?;
+    assert_output_contains(&out, "failed: echo ran b");
+
+    space.write("b.in.ok", "")?;
+    space.sub_mtime("a.in", std::time::Duration::from_secs(1))?;
+    space.sub_mtime("gen.in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--failed", "a", "c"]))?;
+    assert_output_contains(&out, "ran gen");
+    assert_output_contains(&out, "ran b");
+    assert_output_not_contains(&out, "ran a");
+    assert_output_not_contains(&out, "ran c");
+
+    let out = space.run_expect(&mut n2_command(vec!["a", "c"]))?;
+    assert_output_contains(&out, "ran a");
+    assert_output_contains(&out, "ran c");
+    Ok(())
+}
+
+/// Output that isn't to a terminal gets a status line now and then.
+#[cfg(unix)]
+#[test]
+fn status_lines() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch", "build b: touch a", ""].join("\n"),
+    )?;
+    // By default they're ten seconds apart, so a quick build has none.
+    let out = space.run_expect(&mut n2_command(vec!["b"]))?;
+    assert_output_not_contains(&out, " done, ");
+
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch a",
+            "build c: touch b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    // a and b are up to date, so count as done.
+    let out = space.run_expect(&mut n2_command(vec!["--status-refresh-ms", "0", "c"]))?;
+    assert_output_contains(&out, "[2/3 done, 1 running]");
+    Ok(())
+}
+
+/// --rewrite-paths makes references to files in output relative to another
+/// directory than the build dir.
+#[cfg(unix)]
+#[test]
+fn rewrite_paths() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("a.c", "")?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = echo a.c:1: error && exit 1
+build a.o: cc a.c
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["a.o"]))?;
+    assert_output_contains(&out, "\na.c:1: error");
+    let out = space.run(&mut n2_command(vec!["--rewrite-paths", "sub", "a.o"]))?;
+    assert_output_contains(&out, "\n../a.c:1: error");
+    Ok(())
+}
+
+/// -t flamegraph lays out the durations recorded for builds by the targets
+/// that need them.
+#[cfg(unix)]
+#[test]
+fn flamegraph() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 0.1 && touch $out
+build gen: slow
+build out: slow gen
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "flamegraph"]))?;
+    let lines: Vec<_> = std::str::from_utf8(&out.stdout)?
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0)
+        .collect();
+    assert_eq!(lines, vec!["out", "out;gen"]);
+    Ok(())
+}
+
+/// -t compare says what the last build ran that the one before didn't, and
+/// why.
+#[cfg(unix)]
+#[test]
+fn compare_runs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cat
+  command = cat $in > $out
+build a: cat a.in
+build b: cat b.in
+build out: cat a b
+",
+    )?;
+    space.write("a.in", "")?;
+    space.write("b.in", "")?;
+    space.run_expect(&mut n2_command(vec!["--input-digests", "out"]))?;
+    let out = space.run(&mut n2_command(vec!["-t", "compare"]))?;
+    assert_output_contains(&out, "records of 1 run(s)");
+
+    space.write("a.in", "a")?;
+    space.run_expect(&mut n2_command(vec!["--input-digests", "out"]))?;
+    space.write("b.in", "b")?;
+    space.run_expect(&mut n2_command(vec!["--input-digests", "out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "compare"]))?;
+    assert_output_contains(&out, "ran in B but not A: 1\n");
+    assert_output_contains(&out, "s  b: inputs changed (b.in)\n");
+    assert_output_contains(&out, "ran in both: 1\n");
+    assert_output_contains(&out, "s  out: ");
+    Ok(())
+}
+
+/// A `shell` variable runs commands under another shell, or none.  At the top
+/// level, for all rules, it's `n2_shell`, and a `shell` there isn't ours.
+#[cfg(unix)]
+#[test]
+fn shell() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+shell = /no/such/shell
+n2_shell = bash -e -o pipefail -c
+rule pipe
+  command = false | touch $out
+rule direct
+  command = echo '$$HOME' > $out
+  shell =
+rule sh
+  command = false | touch $out
+  shell = /bin/sh -c
+build bash: pipe
+build direct: direct
+build sh: sh
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["-k", "0", "bash", "direct", "sh"]))?;
+    assert_output_contains(&out, "failed: false | touch bash");
+    assert_output_contains(&out, "$HOME > direct");
+    assert!(space.read("sh").is_ok());
+    assert!(space.read("direct").is_err());
+    Ok(())
+}
+
+/// command_argv runs a command without a shell to quote its arguments for.
+#[cfg(unix)]
+#[test]
+fn command_argv() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        r#"
+rule touch
+  command_argv = ["touch", "$out"]
+build it's$ here "quoted": touch
+"#,
+    )?;
+    space.run_expect(&mut n2_command(vec![]))?;
+    assert!(space.read("it's here").is_ok());
+    assert!(space.read("\"quoted\"").is_ok());
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// --serve answers JSON-RPC requests on stdin.
+#[cfg(unix)]
+#[test]
+fn serve() -> anyhow::Result<()> {
+    use std::io::Write;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+rule slow
+  command = touch started && sleep 10 && touch $out
+build a: touch
+build slow: slow
+default a
+",
+    )?;
+    let start = std::time::Instant::now();
+    let mut child = space.spawn(n2_command(vec!["--serve"]).stdin(std::process::Stdio::piped()))?;
+    let mut stdin = child.stdin.take().unwrap();
+    for request in vec![
+        r#"{"jsonrpc": "2.0", "id": 1, "method": "targets"}"#,
+        r#"{"jsonrpc": "2.0", "id": 2, "method": "explain", "params": {"targets": ["a"]}}"#,
+        r#"{"jsonrpc": "2.0", "id": 3, "method": "build", "params": {"targets": ["a"]}}"#,
+        r#"{"jsonrpc": "2.0", "id": 4, "method": "nope"}"#,
+        r#"{"jsonrpc": "2.0", "id": 5, "method": "build", "params": {"targets": ["slow"]}}"#,
+    ] {
+        writeln!(stdin, "{}", request)?;
+    }
+    space.wait_for("started")?;
+    writeln!(
+        stdin,
+        r#"{{"jsonrpc": "2.0", "id": 6, "method": "cancel"}}"#
+    )?;
+    drop(stdin);
+    let out = child.wait_with_output()?;
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(out.status.success());
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "id": 1, "result": {"targets": ["a", "slow"], "defaults": ["a"]}}"#,
+    );
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "id": 2, "result": {"explain": [{"build": "build.ninja:6""#,
+    );
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "method": "task_finished", "params": {"id": 0, "description": "touch a", "success": true"#,
+    );
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "id": 3, "result": {"ok": true, "tasks": 1}}"#,
+    );
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "id": 4, "error": {"code": -32601"#,
+    );
+    assert_output_contains(&out, r#"{"jsonrpc": "2.0", "id": 6, "result": true}"#);
+    assert_output_contains(
+        &out,
+        r#"{"jsonrpc": "2.0", "id": 5, "result": {"ok": false, "interrupted": true}}"#,
+    );
+    Ok(())
+}
+
+/// Under --serve on Linux, later builds trust the states of files inotify
+/// saw no change to, and rebuild for those it did.
+#[cfg(target_os = "linux")]
+#[test]
+fn serve_inotify() -> anyhow::Result<()> {
+    use std::io::{BufRead, Write};
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build sub/out: touch sub/in", ""].join("\n"),
+    )?;
+    space.run_expect(std::process::Command::new("mkdir").arg("sub"))?;
+    space.write("sub/in", "")?;
+    let mut child = space.spawn(n2_command(vec!["--serve"]).stdin(std::process::Stdio::piped()))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut id = 0;
+    let mut build = || -> anyhow::Result<String> {
+        id += 1;
+        writeln!(
+            stdin,
+            r#"{{"jsonrpc": "2.0", "id": {}, "method": "build", "params": {{"targets": ["sub/out"]}}}}"#,
+            id
+        )?;
+        let prefix = format!(r#"{{"jsonrpc": "2.0", "id": {}, "result": "#, id);
+        loop {
+            let mut line = String::new();
+            if stdout.read_line(&mut line)? == 0 {
+                anyhow::bail!("no answer to build {}", id);
+            }
+            if let Some(result) = line.strip_prefix(&prefix) {
+                return Ok(result.trim_end().to_string());
+            }
+        }
+    };
+    let ran = |tasks: usize| format!(r#"{{"ok": true, "tasks": {}}}}}"#, tasks);
+
+    assert_eq!(build()?, ran(1));
+    // The directories are watched from here on, so the third build trusts
+    // what the second statted.
+    assert_eq!(build()?, ran(0));
+    assert_eq!(build()?, ran(0));
+    assert!(space.metadata(".n2_inotify").is_ok());
+
+    space.write("sub/in", "x")?;
+    assert_eq!(build()?, ran(1));
+    assert_eq!(build()?, ran(0));
+
+    space.run_expect(std::process::Command::new("rm").arg("sub/out"))?;
+    assert_eq!(build()?, ran(1));
+    assert_eq!(build()?, ran(0));
+
+    drop(stdin);
+    child.wait()?;
+    Ok(())
+}
+
+/// Under --serve, nothing but messages goes to stdout, warnings and console
+/// pool output included, and each build is a run of its own to commands.
+#[cfg(unix)]
+#[test]
+fn serve_stdout() -> anyhow::Result<()> {
+    use std::io::Write;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+rule id
+  command = echo $$N2_BUILD_ID >> ids && echo hello
+  pool = console
+build a: touch
+build a: touch
+build b b: touch
+build id: id
+",
+    )?;
+    let mut child = space.spawn(
+        n2_command(vec!["--serve", "-w", "dupbuild=warn"]).stdin(std::process::Stdio::piped()),
+    )?;
+    let mut stdin = child.stdin.take().unwrap();
+    for id in 1..=2 {
+        writeln!(
+            stdin,
+            r#"{{"jsonrpc": "2.0", "id": {}, "method": "build", "params": {{"targets": ["id"]}}}}"#,
+            id
+        )?;
+    }
+    drop(stdin);
+    let out = child.wait_with_output()?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    for line in stdout.lines() {
+        assert!(line.starts_with(r#"{"jsonrpc": "2.0", "#), "{:?}", line);
+    }
+    assert_output_contains(&out, "is already an output");
+    assert_output_contains(&out, "is repeated in output list");
+    assert_output_contains(&out, "hello");
+    let ids = space.read("ids")?;
+    let ids: Vec<&[u8]> = ids
+        .split(|&b| b == b'\n')
+        .filter(|id| !id.is_empty())
+        .collect();
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+    Ok(())
+}
+
+/// `meta_NAME` bindings are kept for tools, from the rule and build, and
+/// don't affect the build.
+#[test]
+fn meta() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+  meta_owner = build-team
+build mid: touch in
+  meta_labels = codegen
+build out: touch mid
+  meta_owner = team-x
+",
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "query", "mid"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "mid:\n  input: touch\n    in\n  meta:\n    labels = codegen\n    owner = build-team\n  outputs:\n    out\n"
+    );
+    let out = space.run_expect(&mut n2_command(vec!["--dump-plan", "out"]))?;
+    assert_output_contains(&out, "\"meta\": {\"owner\": \"team-x\"}");
+    Ok(())
+}
+
+/// `dir/...` builds everything built under dir.
+#[test]
+fn dir_targets() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build src/net/a: touch",
+            "build src/net/sub/b: touch",
+            "build src/network: touch",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["src/net/..."]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    space.read("src/net/a")?;
+    space.read("src/net/sub/b")?;
+    assert!(space.read("src/network").is_err());
+    assert!(space.read("other").is_err());
+
+    let out = space.run(&mut n2_command(vec!["src/nothing/..."]))?;
+    assert_output_contains(&out, "no targets under \"src/nothing/\"");
+    Ok(())
+}
+
+/// The query tools print text, or JSON with --format json.
+#[test]
+fn query_tools() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build all: phony out",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "targets", "all"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "all: phony\nmid: touch\nout: touch\n"
+    );
+    let out = space.run_expect(&mut n2_command(vec!["-t", "targets", "--format", "json"]))?;
+    assert_output_contains(&out, "{\"target\": \"mid\", \"rule\": \"touch\"},");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "commands", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "touch mid\ntouch out\n");
+    let out = space.run_expect(&mut n2_command(vec![
+        "-t", "commands", "--format", "json", "out",
+    ]))?;
+    assert_output_contains(
+        &out,
+        "{\"outputs\": [\"mid\"], \"command\": \"touch mid\"},",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "-t", "query", "--format", "json", "mid",
+    ]))?;
+    assert_output_contains(&out, "{\"target\": \"mid\", \"input\": {\"rule\": \"touch\", \"explicit\": [\"in\"], \"implicit\": [], \"order_only\": [], \"validations\": [], \"meta\": {}}, \"outputs\": [\"out\"]}");
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "-t", "deps", "--format", "json", "out",
+    ]))?;
+    assert_output_contains(&out, "{\"target\": \"out\", \"deps\": null}");
+
+    let out = space.run(&mut n2_command(vec!["-t", "deps", "--format", "xml"]))?;
+    assert_output_contains(&out, "unknown --format \"xml\"");
+    Ok(())
+}
+
+/// --check reports the problems with the build files without building.
+#[test]
+fn check() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--check"]))?;
+    assert_output_contains(&out, "no problems found");
+    assert!(space.read("out").is_err());
+    assert!(space.read(".n2_db").is_err());
+
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule cc",
+            "  command = touch $out",
+            "  depfile = $out.d",
+            "build a: touch b",
+            "build b: touch a",
+            "build out: cc in missing",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("out.d", "out in\n")?;
+    let out = space.run(&mut n2_command(vec!["--check"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "dependency cycle: a -> b -> a");
+    assert_output_contains(&out, "\"missing\" is missing, and no build makes it");
+    assert_output_contains(&out, "parse error: expected ':'");
+    assert_output_contains(&out, "3 problems found");
+
+    space.write("build.ninja", "build out: nosuchrule in\n")?;
+    let out = space.run(&mut n2_command(vec!["--check"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "unknown rule");
+    Ok(())
+}
+
+/// --usn-journal is refused where there's no NTFS change journal to read.
+#[cfg(not(windows))]
+#[test]
+fn usn_journal_needs_windows() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "")?;
+    let out = space.run(&mut n2_command(vec!["--usn-journal"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "--usn-journal is only for Windows");
+    Ok(())
+}
+
+/// Stands in for strace, writing a trace of the command reading its input
+/// and a header it doesn't declare, and writing its output.
+const FAKE_STRACE: &str = r#"#!/bin/sh
+while [ "$1" != -- ]; do
+  if [ "$1" = -o ]; then trace=$2; fi
+  shift
+done
+shift
+cat > "$trace" <<EOF
+7 execve("/bin/sh", ["sh"], 0x0 /* 1 var */) = 0
+7 openat(AT_FDCWD, "/etc/ld.so.cache", O_RDONLY|O_CLOEXEC) = 3
+7 openat(AT_FDCWD, "in", O_RDONLY) = 3
+7 openat(AT_FDCWD, "$(pwd)/gen.h", O_RDONLY) = 3
+7 openat(AT_FDCWD, "missing.h", O_RDONLY) = -1 ENOENT (No such file or directory)
+7 openat(AT_FDCWD, "out", O_WRONLY|O_CREAT|O_TRUNC, 0666) = 4
+EOF
+exec "$@"
+"#;
+
+#[cfg(target_os = "linux")]
+#[test]
+fn audit() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+build out: cp in
+build ok: cp in | gen.h
+",
+    )?;
+    space.write("in", "")?;
+    space.write("gen.h", "")?;
+    space.write("strace", FAKE_STRACE)?;
+    space.run_expect(std::process::Command::new("chmod").args(["+x", "strace"]))?;
+    let path = format!(".:{}", std::env::var("PATH")?);
+
+    let out = space.run_expect(n2_command(vec!["--audit", "out"]).env("PATH", &path))?;
+    assert_output_contains(&out, "build.ninja:4: read undeclared inputs: gen.h\n");
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(n2_command(vec!["--audit", "ok"]).env("PATH", &path))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("undeclared"));
+    Ok(())
+}
+
+/// A `network = none` command sees no network devices but loopback.
+#[cfg(target_os = "linux")]
+#[test]
+fn network_none() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule devices
+  command = tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ' > $out
+  network = none
+build out: devices
+",
+    )?;
+    // Some systems don't let users make namespaces.
+    let unshare = std::process::Command::new("unshare")
+        .args(["--net", "--map-current-user", "true"])
+        .status();
+    if unshare.map_or(true, |status| !status.success()) {
+        return Ok(());
+    }
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"lo\n");
+
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+  network = host
+build out: touch
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "invalid network attribute \"host\", expected none");
+    Ok(())
+}
+
+/// With --read-only-sources, a command that writes to the source tree fails,
+/// and the build dir stays writable.
+#[cfg(target_os = "linux")]
+#[test]
+fn read_only_sources() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+rule fix
+  command = echo fixed > $in && cp $in $out
+build out/good: cp src/in
+build bad: fix src/in
+",
+    )?;
+    space.run_expect(std::process::Command::new("mkdir").arg("src"))?;
+    space.write("src/in", "a")?;
+    // Some systems don't let users make namespaces.
+    let unshare = std::process::Command::new("unshare")
+        .args(["--mount", "--map-root-user", "true"])
+        .status();
+    if unshare.map_or(true, |status| !status.success()) {
+        return Ok(());
+    }
+
+    space.run_expect(&mut n2_command(vec!["--read-only-sources", "out/good"]))?;
+    assert_eq!(space.read("out/good")?, b"a");
+
+    let out = space.run(&mut n2_command(vec!["--read-only-sources", "bad"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "Read-only file system");
+    assert_eq!(space.read("src/in")?, b"a");
+    Ok(())
+}
+
+#[test]
+fn unknown_target_suggestions() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out/main.o: touch",
+            "build all: phony out/main.o",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out/mian.o"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "unknown path requested: \"out/mian.o\", did you mean \"out/main.o\"?",
+    );
+    let out = space.run(&mut n2_command(vec!["xyzzy"]))?;
+    assert_output_contains(&out, "unknown path requested: \"xyzzy\"\n");
+    Ok(())
+}
+
+/// `-C auto` finds the build file in a directory above.
+#[cfg(unix)]
+#[test]
+fn chdir_auto() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    space.run_expect(std::process::Command::new("mkdir").args(["-p", "sub/deep"]))?;
+    let n2_in = |dir: &str| {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("cd {} && exec \"$0\" -C auto out", dir))
+            .arg(n2_binary());
+        cmd
+    };
+
+    let out = space.run_expect(&mut n2_in("sub/deep"))?;
+    assert_output_contains(&out, "n2: Entering directory `");
+    assert_output_contains(&out, "ran 1 task");
+    space.read("out")?;
+
+    let out = space.run_expect(&mut n2_in("."))?;
+    assert_output_not_contains(&out, "Entering directory");
+    assert_output_contains(&out, "no work to do");
+
+    // The nearest one wins.
+    space.write("sub/build.ninja", "")?;
+    let out = space.run(&mut n2_in("sub/deep"))?;
+    assert_output_contains(&out, "unknown path requested: \"out\"");
+    Ok(())
+}
+
+/// A dangling symlink is a perfectly good output.
+#[cfg(unix)]
+#[test]
+fn dangling_symlink_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule symlink
+    command = ln -sf nonexistent $out
+
+build out: symlink
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["--lstat", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// Edits that leave a coarse mtime unchanged are still noticed.
+#[cfg(unix)]
+#[test]
+fn coarse_mtime() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+    command = cp $in $out
+
+build out: cp in
+",
+    )?;
+    let set_mtime = || -> anyhow::Result<()> {
+        space.run_expect(std::process::Command::new("touch").args(["-t", "202001010000", "in"]))?;
+        Ok(())
+    };
+    space.write("in", "one")?;
+    set_mtime()?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"one");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("in", "two")?;
+    set_mtime()?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(&out, "content of input in changed");
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"two");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn future_mtime() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+    command = cp $in $out
+
+build out: cp in
+",
+    )?;
+    space.write("in", "")?;
+    space.run_expect(std::process::Command::new("touch").args(["-t", "209901010000", "in"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "in: modification time is");
+    let out = space.run_expect(&mut n2_command(vec!["--clamp-future-mtimes", "out"]))?;
+    assert_output_contains(&out, "reset to now");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_not_contains(&out, "in the future");
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn duplicate_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "build out other: touch", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["other"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "is already an output");
+
+    let out = space.run_expect(&mut n2_command(vec!["-w", "dupbuild=warn", "other"]))?;
+    assert_output_contains(&out, "ignoring it here");
+    assert!(space.read("other").is_ok());
+
+    // Tools' output may be read by other programs, so warnings go to stderr.
+    let out = space.run_expect(&mut n2_command(vec![
+        "-w",
+        "dupbuild=warn",
+        "-t",
+        "targets",
+    ]))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("ignoring it here"));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("ignoring it here"));
+    Ok(())
+}
+
+/// A build that writes to another build's output is flagged.
+#[cfg(unix)]
+#[test]
+fn conflicting_output_writer() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+    command = echo gen > $out
+rule clobber
+    command = sleep 0.01; echo clobbered > $in; touch $out
+
+build gen: gen
+build out: clobber gen
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "changed while this build ran");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn atomic_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+    command = echo partial > $out && [ -z \"$fail\" ] && echo done > $out
+    atomic_outputs = 1
+
+build good: write
+build bad: write
+    fail = 1
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["good"]))?;
+    assert_eq!(space.read("good")?, b"done\n");
+
+    let out = space.run(&mut n2_command(vec!["bad"]))?;
+    assert!(!out.status.success());
+    assert!(space.read("bad").is_err());
+    assert!(space.read("bad.n2tmp").is_err());
+    Ok(())
+}
+
+/// Outputs and depfiles named after $out are staged along with it, while
+/// depfile and description name the final paths.
+#[cfg(unix)]
+#[test]
+fn atomic_outputs_named_after_out() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+    command = cat $in > $out && echo pdb > $out.pdb && echo \"$out: $in\" > $out.d
+    depfile = $out.d
+    description = WRITE $out
+    atomic_outputs = 1
+
+build out | out.pdb: write in
+",
+    )?;
+    space.write("in", "in")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "WRITE out\n");
+    assert_eq!(space.read("out")?, b"in");
+    assert_eq!(space.read("out.pdb")?, b"pdb\n");
+    assert!(space.read("out.n2tmp.pdb").is_err());
+    assert!(space.read("out.n2tmp.d").is_err());
+    assert!(space.read("out.d").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn failed_outputs_removed() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write_and_fail
+    command = echo partial > $out; exit 1
+
+build out: write_and_fail
+build gen: write_and_fail
+    generator = 1
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert!(space.read("out").is_err());
+
+    let out = space.run(&mut n2_command(vec!["gen"]))?;
+    assert!(!out.status.success());
+    assert!(space.read("gen").is_ok());
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Tests for behavior when the build is interrupted.
+
+#[cfg(unix)]
+use crate::e2e::*;
+
+/// Whether a process with the given pid is still running.
+#[cfg(unix)]
+fn is_running(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// ctl-c reaches not just the subprocess but everything it spawned, even
+/// though subprocesses run in their own process groups.
+#[cfg(unix)]
+#[test]
+fn interrupt_kills_process_tree() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule spawn
+  command = sleep 30 & echo $$! > pid.tmp && mv pid.tmp pid && wait
+build out: spawn
+",
+    )?;
+    let mut n2 = space.spawn(&mut n2_command(vec!["out"]))?;
+    space.wait_for("pid")?;
+    let pid: libc::pid_t = std::str::from_utf8(&space.read("pid")?)?.trim().parse()?;
+    assert!(is_running(pid));
+
+    // Signal just n2, not its process group, as a terminal would.
+    unsafe { libc::kill(n2.id() as libc::pid_t, libc::SIGINT) };
+    let status = n2.wait()?;
+    assert!(!status.success());
+
+    // The sleep may take a moment to be reaped by init.
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while is_running(pid) {
+        if std::time::Instant::now() > deadline {
+            panic!("grandchild {} survived the interrupt", pid);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// Tasks that manage to finish despite the interrupt still get recorded, so
+/// they aren't rerun next time.
+#[cfg(unix)]
+#[test]
+fn interrupt_records_finished_tasks() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = touch $out.started; sleep 30; touch $out
+rule stubborn
+  command = trap 'sleep 0.5; touch $out; exit 0' INT; touch $out.started; while :; do sleep 0.1; done
+build x: slow
+build y: stubborn
+",
+    )?;
+    let n2 = space.spawn(&mut n2_command(vec!["-j", "2", "x", "y"]))?;
+    space.wait_for("x.started")?;
+    space.wait_for("y.started")?;
+
+    unsafe { libc::kill(n2.id() as libc::pid_t, libc::SIGINT) };
+    let out = n2.wait_with_output()?;
+    assert_eq!(out.status.code(), Some(130));
+    assert_output_contains(&out, "n2: interrupted");
+    assert!(space.read("y").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["y"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// Console pool tasks reading a terminal stay in n2's process group rather
+/// than getting one of their own, but are still terminated by --fail-fast.
+#[cfg(unix)]
+#[test]
+fn fail_fast_terminates_console_tasks() -> anyhow::Result<()> {
+    use std::os::fd::FromRawFd;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 10
+  pool = console
+rule fail
+  command = sleep 0.1 && exit 1
+build slow: slow
+build fail: fail
+",
+    )?;
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(ret, 0);
+    let master = unsafe { std::fs::File::from_raw_fd(master) };
+    let slave = unsafe { std::fs::File::from_raw_fd(slave) };
+    let start = std::time::Instant::now();
+    let out = space.run(n2_command(vec!["-j", "2", "--fail-fast", "slow", "fail"]).stdin(slave))?;
+    drop(master);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(!out.status.success());
+    Ok(())
+}
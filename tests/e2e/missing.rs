@@ -24,7 +24,8 @@ fn missing_generated() -> anyhow::Result<()> {
         &[
             TOUCH_RULE,
             ECHO_RULE,
-            "build mid: echo",      // never writes output
+            "build mid: echo", // never writes output
+            "  allow_missing_outputs = 1",
             "build out: touch mid", // uses never-written output
             "",
         ]
@@ -40,6 +41,29 @@ fn missing_generated() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn missing_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            ECHO_RULE,
+            "build mid: echo",      // never writes output
+            "build out: touch mid", // uses never-written output
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "didn't create output mid");
+    assert_output_not_contains(&out, "touch out");
+
+    Ok(())
+}
+
 #[test]
 fn missing_phony() -> anyhow::Result<()> {
     let space = TestSpace::new()?;
@@ -3,6 +3,7 @@
 mod basic;
 mod directories;
 mod discovered;
+mod interrupt;
 mod missing;
 mod regen;
 mod validations;
@@ -33,7 +34,7 @@ fn print_output(out: &std::process::Output) {
 }
 
 pub fn assert_output_contains(out: &std::process::Output, text: &str) {
-    let out = std::str::from_utf8(&out.stdout).unwrap();
+    let out = String::from_utf8_lossy(&out.stdout);
     if !out.contains(text) {
         panic!(
             "assertion failed; expected output to contain {:?} but got:\n{}",
@@ -43,7 +44,7 @@ pub fn assert_output_contains(out: &std::process::Output, text: &str) {
 }
 
 pub fn assert_output_not_contains(out: &std::process::Output, text: &str) {
-    let out = std::str::from_utf8(&out.stdout).unwrap();
+    let out = String::from_utf8_lossy(&out.stdout);
     if out.contains(text) {
         panic!(
             "assertion failed; expected output to not contain {:?} but got:\n{}",
@@ -90,6 +91,27 @@ impl TestSpace {
         cmd.current_dir(self.dir.path()).output()
     }
 
+    /// Start n2 in the background, returning the child process.
+    pub fn spawn(&self, cmd: &mut std::process::Command) -> std::io::Result<std::process::Child> {
+        cmd.current_dir(self.dir.path())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+    }
+
+    /// Wait for a file to show up in the working space, e.g. as written by a
+    /// running build step.
+    pub fn wait_for(&self, path: &str) -> anyhow::Result<()> {
+        let path = self.dir.path().join(path);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !path.exists() {
+            if std::time::Instant::now() > deadline {
+                anyhow::bail!("timed out waiting for {}", path.display());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
     /// Like run, but also print output if the build failed.
     pub fn run_expect(
         &self,
@@ -143,6 +143,54 @@ rule regen
     Ok(())
 }
 
+/// A generator that leaves build.ninja as it was doesn't cause a reload.
+#[test]
+fn regenerate_unchanged() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build build.ninja: touch in",
+            "build out: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-d", "trace", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let trace = String::from_utf8(space.read("trace.json")?)?;
+    assert_eq!(trace.matches("\"name\":\"load::read\"").count(), 1);
+    Ok(())
+}
+
+/// A generator that changes build.ninja causes a reload that sees the change.
+#[cfg(unix)]
+#[test]
+fn regenerate_changed() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let build_ninja = [
+        TOUCH_RULE,
+        "rule regen",
+        "  command = cp build.ninja.in build.ninja",
+        "  generator = 1",
+        "build build.ninja: regen build.ninja.in",
+        "",
+    ]
+    .join("\n");
+    space.write("build.ninja", &build_ninja)?;
+    space.write(
+        "build.ninja.in",
+        &[&build_ninja, "build out: touch", ""].join("\n"),
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
 /// Use "-t restat" to mark the build.ninja up to date ahead of time.
 #[cfg(unix)] // TODO: this ought to work on Windows, hrm.
 #[test]